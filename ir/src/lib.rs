@@ -1,6 +1,4 @@
-use std::process::{Command, Stdio};
 use strong_xml::{XmlRead, XmlWrite};
-use tempfile::tempdir;
 
 #[derive(Debug, Default, XmlWrite, XmlRead, PartialEq, Eq)]
 #[xml(tag = "mei")]
@@ -18,7 +16,113 @@ pub struct Mei {
 #[derive(Debug, Default, XmlWrite, XmlRead, PartialEq, Eq)]
 #[xml(tag = "meiHead")]
 pub struct MeiHead {
-    //TODO: complete
+    #[xml(child = "fileDesc")]
+    pub file_desc: Option<FileDesc>,
+    #[xml(child = "encodingDesc")]
+    pub encoding_desc: Option<EncodingDesc>,
+    #[xml(child = "workList")]
+    pub work_list: Option<WorkList>,
+}
+
+#[derive(Debug, Default, XmlWrite, XmlRead, PartialEq, Eq)]
+#[xml(tag = "fileDesc")]
+pub struct FileDesc {
+    #[xml(child = "titleStmt")]
+    pub title_stmt: Option<TitleStmt>,
+    #[xml(child = "pubStmt")]
+    pub pub_stmt: Option<PubStmt>,
+}
+
+#[derive(Debug, Default, XmlWrite, XmlRead, PartialEq, Eq)]
+#[xml(tag = "titleStmt")]
+pub struct TitleStmt {
+    #[xml(child = "title")]
+    pub titles: Vec<Title>,
+    #[xml(child = "respStmt")]
+    pub resp_stmts: Vec<RespStmt>,
+}
+
+#[derive(Debug, Default, XmlWrite, XmlRead, PartialEq, Eq)]
+#[xml(tag = "title")]
+pub struct Title {
+    #[xml(attr = "type")]
+    pub title_type: Option<String>,
+    #[xml(text)]
+    pub text: String,
+}
+
+#[derive(Debug, Default, XmlWrite, XmlRead, PartialEq, Eq)]
+#[xml(tag = "respStmt")]
+pub struct RespStmt {
+    #[xml(child = "persName")]
+    pub pers_names: Vec<PersName>,
+}
+
+// Verovio's page header picks names out by role, like "composer" or "lyricist"
+#[derive(Debug, Default, XmlWrite, XmlRead, PartialEq, Eq)]
+#[xml(tag = "persName")]
+pub struct PersName {
+    #[xml(attr = "role")]
+    pub role: Option<String>,
+    #[xml(text)]
+    pub text: String,
+}
+
+#[derive(Debug, Default, XmlWrite, XmlRead, PartialEq, Eq)]
+#[xml(tag = "pubStmt")]
+pub struct PubStmt {
+    #[xml(flatten_text = "publisher")]
+    pub publisher: Option<String>,
+    #[xml(child = "date")]
+    pub date: Option<Date>,
+}
+
+#[derive(Debug, Default, XmlWrite, XmlRead, PartialEq, Eq)]
+#[xml(tag = "date")]
+pub struct Date {
+    #[xml(attr = "isodate")]
+    pub isodate: Option<String>,
+    #[xml(text)]
+    pub text: String,
+}
+
+#[derive(Debug, Default, XmlWrite, XmlRead, PartialEq, Eq)]
+#[xml(tag = "encodingDesc")]
+pub struct EncodingDesc {
+    #[xml(child = "appInfo")]
+    pub app_info: Option<AppInfo>,
+}
+
+#[derive(Debug, Default, XmlWrite, XmlRead, PartialEq, Eq)]
+#[xml(tag = "appInfo")]
+pub struct AppInfo {
+    #[xml(child = "application")]
+    pub applications: Vec<Application>,
+}
+
+#[derive(Debug, Default, XmlWrite, XmlRead, PartialEq, Eq)]
+#[xml(tag = "application")]
+pub struct Application {
+    #[xml(attr = "version")]
+    pub version: Option<String>,
+    #[xml(flatten_text = "name")]
+    pub name: String,
+}
+
+#[derive(Debug, Default, XmlWrite, XmlRead, PartialEq, Eq)]
+#[xml(tag = "workList")]
+pub struct WorkList {
+    #[xml(child = "work")]
+    pub works: Vec<Work>,
+}
+
+#[derive(Debug, Default, XmlWrite, XmlRead, PartialEq, Eq)]
+#[xml(tag = "work")]
+pub struct Work {
+    #[xml(child = "title")]
+    pub titles: Vec<Title>,
+    #[xml(flatten_text = "composer")]
+    pub composer: Option<String>,
 }
 
 #[derive(Debug, Default, XmlWrite, XmlRead, PartialEq, Eq)]
@@ -67,6 +171,8 @@ pub struct ScoreDef {
     pub key_sig: Option<String>,
     #[xml(attr = "key.mode")]
     pub key_mode: Option<String>,
+    #[xml(attr = "midi.bpm")]
+    pub midi_bpm: Option<u32>,
     #[xml(child = "staffGrp")]
     pub staff_grp: Option<StaffGrp>,
 }
@@ -75,8 +181,22 @@ pub struct ScoreDef {
 #[xml(tag = "staffGrp")]
 pub struct StaffGrp {
     //TODO: complete
-    #[xml(child = "staffDef")]
-    pub staff_def: Option<StaffDef>,
+    #[xml(attr = "label")]
+    pub label: Option<String>,
+    #[xml(attr = "symbol")]
+    pub symbol: Option<String>,
+    #[xml(attr = "bar.thru")]
+    pub bar_thru: Option<bool>,
+    #[xml(child = "staffGrp", child = "staffDef")]
+    pub children: Vec<StaffGrpLike>,
+}
+
+#[derive(Debug, XmlWrite, XmlRead, PartialEq, Eq)]
+pub enum StaffGrpLike {
+    #[xml(tag = "staffGrp")]
+    StaffGrp(StaffGrp),
+    #[xml(tag = "staffDef")]
+    StaffDef(StaffDef),
 }
 
 #[derive(Debug, Default, XmlWrite, XmlRead, PartialEq, Eq)]
@@ -84,6 +204,8 @@ pub struct StaffGrp {
 pub struct StaffDef {
     #[xml(attr = "n")]
     pub n: Option<u32>,
+    #[xml(attr = "label")]
+    pub label: Option<String>,
     #[xml(attr = "clef.line")]
     pub clef_line: Option<u32>,
     #[xml(attr = "clef.shape")]
@@ -100,8 +222,16 @@ pub struct StaffDef {
 #[xml(tag = "section")]
 pub struct Section {
     //TODO: complete
-    #[xml(child = "measure")]
-    pub measures: Vec<Measure>,
+    #[xml(child = "measure", child = "scoreDef")]
+    pub children: Vec<SectionLike>,
+}
+
+#[derive(Debug, XmlWrite, XmlRead, PartialEq, Eq)]
+pub enum SectionLike {
+    #[xml(tag = "measure")]
+    Measure(Measure),
+    #[xml(tag = "scoreDef")]
+    ScoreDef(ScoreDef),
 }
 
 #[derive(Debug, Default, XmlWrite, XmlRead, PartialEq, Eq)]
@@ -112,12 +242,36 @@ pub struct Measure {
     pub n: Option<u32>,
     #[xml(child = "staff")]
     pub staves: Vec<Staff>,
+    #[xml(child = "dynam")]
+    pub dynams: Vec<Dynam>,
+}
+
+#[derive(Debug, Default, XmlWrite, XmlRead, PartialEq, Eq)]
+#[xml(tag = "dynam")]
+pub struct Dynam {
+    #[xml(attr = "staff")]
+    pub staff: Option<u32>,
+    #[xml(attr = "tstamp")]
+    pub tstamp: Option<String>,
+    #[xml(text)]
+    pub text: String,
 }
 
 #[derive(Debug, Default, XmlWrite, XmlRead, PartialEq, Eq)]
 #[xml(tag = "beam")]
 pub struct Beam {
-    #[xml(child = "note", child = "note", child = "rest", child = "chord", child = "beam")]
+    #[xml(child = "note", child = "rest", child = "space", child = "chord", child = "beam", child = "tuplet", child = "clef")]
+    pub events: Vec<EventLike>,
+}
+
+#[derive(Debug, Default, XmlWrite, XmlRead, PartialEq, Eq)]
+#[xml(tag = "tuplet")]
+pub struct Tuplet {
+    #[xml(attr = "num")]
+    pub num: Option<u32>,
+    #[xml(attr = "numbase")]
+    pub numbase: Option<u32>,
+    #[xml(child = "note", child = "rest", child = "space", child = "chord", child = "beam", child = "tuplet", child = "clef")]
     pub events: Vec<EventLike>,
 }
 
@@ -125,6 +279,8 @@ pub struct Beam {
 #[xml(tag = "staff")]
 pub struct Staff {
     //TODO: complete
+    #[xml(attr = "n")]
+    pub n: Option<u32>,
     #[xml(child = "layer")]
     pub layers: Vec<Layer>,
 }
@@ -135,15 +291,21 @@ pub struct Layer {
     //TODO: complete
     #[xml(attr = "n")]
     pub n: Option<u32>,
-    #[xml(child = "note", child = "note", child = "rest", child = "chord", child = "beam")]
+    #[xml(child = "note", child = "rest", child = "space", child = "chord", child = "beam", child = "tuplet", child = "clef")]
     pub events: Vec<EventLike>,
 }
 
 #[derive(Debug, Default, XmlWrite, XmlRead, PartialEq, Eq)]
 #[xml(tag = "chord")]
 pub struct Chord {
+    #[xml(attr = "xml:id")]
+    pub xml_id: Option<String>,
     #[xml(attr = "dur")]
     pub dur: Option<u32>,
+    #[xml(attr = "dots")]
+    pub dots: Option<u32>,
+    #[xml(attr = "tie")]
+    pub tie: Option<String>,
     #[xml(child = "note")]
     pub notes: Vec<Note>,
 }
@@ -154,10 +316,16 @@ pub enum EventLike {
     Note(Note),
     #[xml(tag = "rest")]
     Rest(Rest),
+    #[xml(tag = "space")]
+    Space(Space),
     #[xml(tag = "chord")]
     Chord(Chord),
     #[xml(tag = "beam")]
     Beam(Beam),
+    #[xml(tag = "tuplet")]
+    Tuplet(Tuplet),
+    #[xml(tag = "clef")]
+    Clef(Clef),
 }
 
 #[derive(Debug, Default, XmlWrite, XmlRead, PartialEq, Eq)]
@@ -172,10 +340,31 @@ pub struct Note {
     pub pclass: Option<u32>,
     #[xml(attr = "accid")]
     pub accid: Option<String>,
+    #[xml(attr = "accid.ges")]
+    pub accid_ges: Option<String>,
     #[xml(attr = "oct")]
     pub oct: u32,
     #[xml(attr = "dur")]
     pub dur: Option<u32>,
+    #[xml(attr = "dots")]
+    pub dots: Option<u32>,
+    #[xml(attr = "tie")]
+    pub tie: Option<String>,
+    #[xml(child = "accid")]
+    pub accids: Vec<Accid>,
+}
+
+#[derive(Debug, Default, XmlWrite, XmlRead, PartialEq, Eq)]
+#[xml(tag = "accid")]
+pub struct Accid {
+    #[xml(attr = "accid")]
+    pub accid: Option<String>,
+    #[xml(attr = "accid.ges")]
+    pub accid_ges: Option<String>,
+    #[xml(attr = "func")]
+    pub func: Option<String>,
+    #[xml(attr = "enclose")]
+    pub enclose: Option<String>,
 }
 
 #[derive(Debug, Default, XmlWrite, XmlRead, PartialEq, Eq)]
@@ -184,4 +373,28 @@ pub struct Rest {
     //TODO: complete
     #[xml(attr = "dur")]
     pub dur: Option<u32>,
+    #[xml(attr = "dots")]
+    pub dots: Option<u32>,
+}
+
+#[derive(Debug, Default, XmlWrite, XmlRead, PartialEq, Eq)]
+#[xml(tag = "space")]
+pub struct Space {
+    #[xml(attr = "dur")]
+    pub dur: Option<u32>,
+    #[xml(attr = "dots")]
+    pub dots: Option<u32>,
+}
+
+#[derive(Debug, Default, XmlWrite, XmlRead, PartialEq, Eq)]
+#[xml(tag = "clef")]
+pub struct Clef {
+    #[xml(attr = "shape")]
+    pub shape: Option<String>,
+    #[xml(attr = "line")]
+    pub line: Option<u32>,
+    #[xml(attr = "dis")]
+    pub dis: Option<u32>,
+    #[xml(attr = "dis.place")]
+    pub dis_place: Option<String>,
 }
@@ -1,40 +1,17 @@
-use operations::{Score, Note, Beat, Pitch, Selection, Context, Location, Selections, PrependNote, Operation, Octave, PitchName};
+use operations::{AppendNote, Context, Note, NoteValue, Octave, Pitch, PitchName};
+use strong_xml::XmlWrite;
 
 fn main() {
-    let mut ctx = Context {
-        score: Score::default(),
-        selections: Selections(vec![Selection { begin: Location(Beat(0)), end: Location(Beat(0)) }])
-    };
-
-    let operation = PrependNote {
-        note: Note {
-            pitch: Pitch {
-                class: PitchName::A,
-                ..Default::default()
-            },
-            octave: Octave(4)
-        },
-        duration: Beat(4),
-        selections: None,
-    };
-
-    operation.apply(&mut ctx);
-
-    let operation = PrependNote {
-        note: Note {
-            pitch: Pitch {
-                class: PitchName::B,
-                ..Default::default()
-            },
-            octave: Octave(4)
-        },
-        duration: Beat(4),
-        selections: None,
-    };
-
-    operation.apply(&mut ctx);
+    let mut ctx = Context::default();
 
+    for class in [PitchName::A, PitchName::B] {
+        ctx.apply(&AppendNote {
+            note: Note::new(Pitch { class, ..Default::default() }, Octave(4)),
+            duration: NoteValue::new(4),
+            selections: None,
+        });
+    }
 
     let mei = ctx.score.to_mei();
-    std::fs::write("/tmp/test.svg", &mei.to_svg());
+    std::fs::write("/tmp/test.mei", mei.to_string().unwrap()).unwrap();
 }
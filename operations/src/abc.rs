@@ -0,0 +1,1121 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    mei::set_at, Accidental, AccidentalDisplay, AccidentalState, Clef, ClefShape, Dynamic, Event, Key, Mark, Meter, Mode, Note, NoteValue, Octave, Part, Pitch,
+    PitchName, Pulse, Score, Segment, Tempo, Timeline, Tuplet, TupletGroup, SHORTEST_BASE, VoiceFormat, VoiceWriter,
+};
+
+fn accidental_text(accidental: Accidental) -> &'static str {
+    match accidental {
+        Accidental::Natural => "=",
+        Accidental::Sharp => "^",
+        Accidental::Flat => "_",
+        Accidental::DoubleSharp => "^^",
+        Accidental::DoubleFlat => "__",
+        Accidental::QuarterSharp => "^/",
+        Accidental::QuarterFlat => "_/",
+        Accidental::ThreeQuarterSharp => "^3/2",
+        Accidental::ThreeQuarterFlat => "_3/2",
+    }
+}
+
+// C is middle C and c the octave above, with ' and , for anything further out
+fn note_text(note: &Note, display: AccidentalDisplay) -> String {
+    let accidental = if display == AccidentalDisplay::Hidden { "" } else { accidental_text(note.pitch.accidental) };
+    let octave = note.octave.0 as i32;
+    let letter = note.pitch.class.to_string();
+    if octave >= 5 {
+        format!("{}{}{}", accidental, letter, "'".repeat((octave - 5) as usize))
+    } else {
+        format!("{}{}{}", accidental, letter.to_uppercase(), ",".repeat((4 - octave) as usize))
+    }
+}
+
+// A length as a multiple of the unit note length, leaving out the 1s
+fn length_text(length: Pulse, unit: Pulse) -> String {
+    let ratio = length / unit;
+    match (ratio.num(), ratio.den()) {
+        (1, 1) => String::new(),
+        (n, 1) => n.to_string(),
+        (1, 2) => "/".to_string(),
+        (1, d) => format!("/{}", d),
+        (n, d) => format!("{}/{}", n, d),
+    }
+}
+
+fn key_text(key: Key) -> String {
+    let tonic = key.tonic();
+    let accidental = match tonic.accidental {
+        Accidental::Sharp => "#",
+        Accidental::Flat => "b",
+        _ => "",
+    };
+    let mode = match key.mode {
+        Mode::Major => "",
+        Mode::Minor => "m",
+    };
+    format!("{}{}{}", tonic.class.to_string().to_uppercase(), accidental, mode)
+}
+
+// Clefs off their usual line carry the line number, like bass3
+fn clef_text(clef: Clef) -> String {
+    let (name, line) = match (clef.shape, clef.line) {
+        (ClefShape::C, 4) => ("tenor", 4),
+        (ClefShape::G, line) => ("treble", line),
+        (ClefShape::F, line) => ("bass", line),
+        (ClefShape::C, line) => ("alto", line),
+    };
+    let usual = match name {
+        "treble" => 2,
+        "bass" | "tenor" => 4,
+        _ => 3,
+    };
+    let line = if line == usual { String::new() } else { line.to_string() };
+    let octave = match clef.octave {
+        0 => String::new(),
+        octave => format!("{}{}", if octave < 0 { "-" } else { "+" }, 7 * octave.abs() + 1),
+    };
+    format!("{}{}{}", name, line, octave)
+}
+
+fn parse_clef(text: &str) -> Option<Clef> {
+    let (text, octave) = if let Some((name, dis)) = text.split_once(['+', '-']) {
+        let octaves = (dis.parse::<i32>().ok()? - 1) / 7;
+        (name, if text.contains('-') { -octaves } else { octaves })
+    } else {
+        (text, 0)
+    };
+    let name = text.trim_end_matches(|c: char| c.is_ascii_digit());
+    let clef = match name {
+        "treble" => Clef::treble(),
+        "bass" => Clef::bass(),
+        "alto" => Clef::alto(),
+        "tenor" => Clef::tenor(),
+        "baritone" => Clef { shape: ClefShape::F, line: 3, octave: 0 },
+        "soprano" => Clef { shape: ClefShape::C, line: 1, octave: 0 },
+        "mezzo" | "mezzosoprano" => Clef { shape: ClefShape::C, line: 2, octave: 0 },
+        _ => return None,
+    };
+    let line = match &text[name.len()..] {
+        "" => clef.line,
+        line => line.parse().ok()?,
+    };
+    Some(Clef { line, octave, ..clef })
+}
+
+// Keys are a tonic and a mode. The church modes are read as the major or
+// minor key with the same signature since that's all a Key can hold.
+fn parse_key(text: &str) -> Option<Key> {
+    if text == "none" || text == "HP" || text == "Hp" {
+        return Some(Key::default());
+    }
+    let mut chars = text.chars();
+    let class: PitchName = chars.next()?.to_ascii_lowercase().to_string().parse().ok()?;
+    let rest = chars.as_str();
+    let (alteration, rest) = if let Some(rest) = rest.strip_prefix('#') {
+        (1, rest)
+    } else if let Some(rest) = rest.strip_prefix('b') {
+        (-1, rest)
+    } else {
+        (0, rest)
+    };
+    let mode = rest.to_ascii_lowercase();
+    let (offset, mode) = match mode.get(..3).unwrap_or(&mode) {
+        "" | "maj" | "ion" => (0, Mode::Major),
+        "m" | "min" | "aeo" => (-3, Mode::Minor),
+        "mix" => (-1, Mode::Major),
+        "dor" => (-2, Mode::Major),
+        "phr" => (-4, Mode::Major),
+        "lyd" => (1, Mode::Major),
+        "loc" => (-5, Mode::Major),
+        _ => return None,
+    };
+    let fifths = class.fifths() + alteration * 7 + offset;
+    if fifths.abs() > 7 {
+        return None;
+    }
+    Some(Key { fifths, mode })
+}
+
+fn parse_meter(text: &str) -> Option<Meter> {
+    match text {
+        "C" => Some(Meter { count: 4, unit: 4 }),
+        "C|" => Some(Meter { count: 2, unit: 2 }),
+        _ => text.parse().ok(),
+    }
+}
+
+fn parse_fraction(text: &str) -> Option<Pulse> {
+    let (num, den) = text.trim().split_once('/')?;
+    let (num, den): (i64, i64) = (num.trim().parse().ok()?, den.trim().parse().ok()?);
+    if num <= 0 || den <= 0 {
+        return None;
+    }
+    Some(Pulse::new(num, den))
+}
+
+// Tempos like 1/4=120 or "Allegro" 3/8=60, or a bare count of unit notes
+fn parse_tempo(text: &str, unit: Pulse) -> Option<Tempo> {
+    let text = match (text.find('"'), text.rfind('"')) {
+        (Some(open), Some(close)) if close > open => format!("{}{}", &text[..open], &text[close + 1..]),
+        _ => text.to_string(),
+    };
+    let (beat, bpm) = match text.split_once('=') {
+        Some((beat, bpm)) => (beat.split_whitespace().filter_map(parse_fraction).fold(Pulse::default(), |a, b| a + b), bpm),
+        None => (unit, text.as_str()),
+    };
+    let bpm: f64 = bpm.trim().parse().ok()?;
+    let quarters = bpm * (beat.num() as f64 * 4.0) / beat.den() as f64;
+    if quarters < 1.0 {
+        return None;
+    }
+    Some(Tempo { bpm: quarters.round() as u32 })
+}
+
+// Notes shorter than a quarter are beamed within a beat, which is a dotted
+// quarter in compound meters
+fn beam_length(meter: Meter) -> Pulse {
+    if meter.unit == 8 && meter.count.is_multiple_of(3) && meter.count > 3 {
+        Pulse::new(3, 8)
+    } else {
+        Pulse::new(1, meter.unit as i64)
+    }
+}
+
+// How bars of a voice are written. Each token has the beat it's beamed in, if
+// it can be beamed, and tokens in the same beat are written without a space.
+struct AbcVoice {
+    unit: Pulse,
+    // Decorations go straight before the next note or rest
+    decorations: String,
+}
+
+impl AbcVoice {
+    fn new(unit: Pulse) -> Self {
+        Self {
+            unit,
+            decorations: String::new(),
+        }
+    }
+
+    fn note(&mut self, text: String, beam: Option<i64>) -> (String, Option<i64>) {
+        (format!("{}{}", std::mem::take(&mut self.decorations), text), beam)
+    }
+}
+
+impl VoiceFormat for AbcVoice {
+    type Token = (String, Option<i64>);
+
+    // z rests in the first voice, invisible x rests in overlays
+    fn rest(&mut self, value: NoteValue, rests: bool) -> Self::Token {
+        let text = format!("{}{}", if rests { "z" } else { "x" }, length_text(value.written_length(), self.unit));
+        self.note(text, None)
+    }
+
+    // Changes are inline fields like [K:D], dynamics are decorations which
+    // wait for a note
+    fn mark(&mut self, mark: Mark) -> Option<Self::Token> {
+        let text = match mark {
+            Mark::Clef(clef) => format!("[K:clef={}]", clef_text(clef)),
+            Mark::Key(key) => format!("[K:{}]", key_text(key)),
+            Mark::Meter(meter) => format!("[M:{}]", meter),
+            Mark::Tempo(tempo) => format!("[Q:1/4={}]", tempo.bpm),
+            Mark::Dynamic(dynamic) => {
+                self.decorations.push_str(&format!("!{}!", dynamic));
+                return None;
+            }
+        };
+        Some((text, None))
+    }
+
+    // (3 is enough for a plain triplet, anything else spells out p:q:r
+    fn tuplet(&mut self, group: TupletGroup<Self::Token>) -> Vec<Self::Token> {
+        let prefix = if group.tuplet == Tuplet::triplet() && group.values == 3 {
+            "(3".to_string()
+        } else {
+            format!("({}:{}:{}", group.tuplet.num, group.tuplet.numbase, group.values)
+        };
+        let mut tokens = group.tokens;
+        if let Some((text, _)) = tokens.first_mut() {
+            text.insert_str(0, &prefix);
+        }
+        tokens
+    }
+}
+
+fn bar_text(bar: VoiceWriter<AbcVoice>) -> String {
+    let (mut voice, mut tokens) = bar.finish();
+    if !voice.decorations.is_empty() {
+        tokens.push((std::mem::take(&mut voice.decorations), None));
+    }
+    let mut out = String::new();
+    let mut previous = None;
+    for (i, (text, beam)) in tokens.iter().enumerate() {
+        if i > 0 && (beam.is_none() || *beam != previous || text.starts_with('(')) {
+            out.push(' ');
+        }
+        out.push_str(text);
+        previous = *beam;
+    }
+    out
+}
+
+const UNIT: (i64, i64) = (1, 8);
+const BARS_PER_LINE: usize = 4;
+
+impl Score {
+    // Writes the score as a single ABC tune with an eighth as the unit note
+    // length. Every staff is a voice and the layers of a staff are overlaid
+    // with &. ABC has no fields for a lyricist or arranger, so only the title
+    // and composer are kept.
+    pub fn to_abc(&self) -> String {
+        let mut measures = self.measures();
+        if measures.is_empty() {
+            measures.push((Pulse::default(), self.meter_at(Pulse::default()).length()));
+        }
+        let segments = self.segments(&measures);
+        let unit = Pulse::new(UNIT.0, UNIT.1);
+        let start = Pulse::default();
+
+        let mut lines = vec!["X:1".to_string()];
+        if !self.metadata.title.is_empty() {
+            lines.push(format!("T:{}", self.metadata.title));
+        }
+        if !self.metadata.composer.is_empty() {
+            lines.push(format!("C:{}", self.metadata.composer));
+        }
+        lines.push(format!("M:{}", self.meter_at(start)));
+        lines.push(format!("L:{}/{}", UNIT.0, UNIT.1));
+        if let Some(tempo) = self.tempos.at(start) {
+            lines.push(format!("Q:1/4={}", tempo.bpm));
+        }
+        let voices = self.staff_count() > 1;
+        if voices {
+            let mut staff = 0;
+            for part in &self.parts {
+                for (i, staff_info) in part.staves.iter().enumerate() {
+                    let name = if i == 0 && !part.name.is_empty() { format!(" name=\"{}\"", part.name) } else { String::new() };
+                    lines.push(format!("V:{}{} clef={}", staff + 1, name, clef_text(staff_info.clef_at(start))));
+                    staff += 1;
+                }
+            }
+            lines.push(format!("K:{}", key_text(self.key_at(start))));
+        } else {
+            let clef = self.staff(0).map(|s| s.clef_at(start)).unwrap_or_default();
+            let clef = if clef == Clef::treble() { String::new() } else { format!(" clef={}", clef_text(clef)) };
+            lines.push(format!("K:{}{}", key_text(self.key_at(start)), clef));
+        }
+
+        for staff in 0..self.staff_count() {
+            if voices {
+                lines.push(format!("V:{}", staff + 1));
+            }
+            let bars = self.abc_bars(staff, &measures, &segments, unit);
+            let count = bars.len();
+            let bars: Vec<String> = bars.into_iter().enumerate()
+                .map(|(i, bar)| format!("{} {}", bar, if i + 1 == count { "|]" } else { "|" }))
+                .collect();
+            for line in bars.chunks(BARS_PER_LINE) {
+                lines.push(line.join(" "));
+            }
+        }
+        let mut out = lines.join("\n");
+        out.push('\n');
+        out
+    }
+
+    fn abc_bars(&self, staff: u32, measures: &[(Pulse, Pulse)], segments: &BTreeMap<(u32, u32), Vec<Segment<'_>>>, unit: Pulse) -> Vec<String> {
+        // The opening clef, key, meter and tempo are in the header
+        let mut marks: Vec<(Pulse, Mark)> = self.marks(staff).into_iter()
+            .filter(|(p, mark)| *p > Pulse::default() || matches!(mark, Mark::Dynamic(_)))
+            .collect();
+        let mut state = AccidentalState::new(self.key_at(Pulse::default()));
+        let mut bars = vec![];
+        for &(measure_start, measure_end) in measures {
+            state.next_measure(self.key_at(measure_start));
+            let accidentals = crate::accidentals_in_measure(&mut state, segments, staff, (measure_start, measure_end));
+            let beam_length = beam_length(self.meter_at(measure_start));
+            let mut layers = vec![];
+            for (i, (_, layer_segments)) in segments.range((staff, 0)..=(staff, u32::MAX)).enumerate() {
+                let first = i == 0;
+                let in_measure = |s: &&Segment| s.start >= measure_start && s.start < measure_end;
+                // Overlays are only written in bars where they have something
+                if !first && !layer_segments.iter().any(|s| in_measure(&s)) {
+                    continue;
+                }
+                let mut no_marks = vec![];
+                let marks = if first { &mut marks } else { &mut no_marks };
+                let mut bar = VoiceWriter::new(AbcVoice::new(unit));
+                let mut beat = measure_start;
+                for segment in layer_segments.iter().filter(in_measure) {
+                    bar.fill(&mut beat, segment.start, first, marks, segment.event.duration.tuplet);
+                    bar.push_marks_at(segment.start, marks);
+                    let mut at = segment.start;
+                    for (n, (value, tie)) in segment.values().into_iter().enumerate() {
+                        // Only the start of an event shows accidentals, the rest is tied to it
+                        let shown = if segment.piece == 0 && n == 0 { accidentals.get(&segment.event.id()) } else { None };
+                        let display = |i: usize| shown.and_then(|a| a.get(i)).copied().unwrap_or(AccidentalDisplay::Hidden);
+                        let notes = if let [note] = segment.event.notes.as_slice() {
+                            note_text(note, display(0))
+                        } else {
+                            let notes: Vec<String> = segment.event.notes.iter().enumerate().map(|(i, note)| note_text(note, display(i))).collect();
+                            format!("[{}]", notes.concat())
+                        };
+                        let tie = if matches!(tie, Some("i") | Some("m")) { "-" } else { "" };
+                        let beam = if value.written_length() < Pulse::new(1, 4) {
+                            let beats = (at - measure_start) / beam_length;
+                            Some(beats.num().div_euclid(beats.den()))
+                        } else {
+                            None
+                        };
+                        let token = bar.format.note(format!("{}{}{}", notes, length_text(value.written_length(), unit), tie), beam);
+                        bar.push(value, token);
+                        at += value.length();
+                    }
+                    beat = segment.start + segment.length;
+                }
+                bar.fill(&mut beat, measure_end, first, marks, None);
+                layers.push(bar_text(bar));
+            }
+            bars.push(layers.join(" & "));
+        }
+        bars
+    }
+}
+
+// Where a voice has got to in the tune
+struct Voice {
+    id: String,
+    staff: u32,
+    layer: u32,
+    position: Pulse,
+    bar_start: Pulse,
+    // The furthest any overlay has got in the current bar
+    bar_end: Pulse,
+    // Whether a barline has been passed after some music, which is when a
+    // short first bar is taken as a pickup
+    measured: bool,
+    repeat_start: Pulse,
+    ending_start: Option<Pulse>,
+    // Accidentals written earlier in the bar, which hold until the barline
+    accidentals: HashMap<(PitchName, u32), Accidental>,
+    last_event: Option<usize>,
+    tie: bool,
+    broken: Option<Pulse>,
+    tuplet: Option<(Tuplet, u32)>,
+}
+
+impl Voice {
+    fn new(id: &str, staff: u32) -> Self {
+        Self {
+            id: id.to_string(),
+            staff,
+            layer: 0,
+            position: Pulse::default(),
+            bar_start: Pulse::default(),
+            bar_end: Pulse::default(),
+            measured: false,
+            repeat_start: Pulse::default(),
+            ending_start: None,
+            accidentals: HashMap::new(),
+            last_event: None,
+            tie: false,
+            broken: None,
+            tuplet: None,
+        }
+    }
+}
+
+// Copies the changes between `begin` and `end` to `shift` later, leaving out
+// any which the value already in effect there makes redundant
+fn copy_changes<T: Copy + PartialEq>(timeline: &mut Timeline<T>, begin: Pulse, end: Pulse, shift: Pulse) {
+    let changes: Vec<(Pulse, T)> = timeline.iter().filter(|(p, _)| *p >= begin && *p < end).map(|(p, v)| (p + shift, *v)).collect();
+    for (pulse, value) in changes {
+        if timeline.at(pulse) != Some(&value) {
+            timeline.insert(pulse, value);
+        }
+    }
+}
+
+// Fields which only describe the tune
+const INFORMATION_FIELDS: &str = "ABDFGHNORSZr";
+
+struct Parser {
+    score: Score,
+    // Each event with whether each of its notes had an accidental written
+    // that it would have had anyway
+    events: Vec<(Event, Vec<bool>)>,
+    voices: Vec<Voice>,
+    current: usize,
+    unit: Option<Pulse>,
+    in_body: bool,
+    unsupported: BTreeMap<String, u32>,
+}
+
+impl Parser {
+    fn unsupported(&mut self, name: &str) {
+        *self.unsupported.entry(name.to_string()).or_default() += 1;
+    }
+
+    fn unit(&self) -> Pulse {
+        self.unit.unwrap_or_else(|| Pulse::new(1, 8))
+    }
+
+    // The first voice takes the part the score starts with and the rest get
+    // a part each, in the order they turn up
+    fn select_voice(&mut self, id: &str) -> usize {
+        if let Some(index) = self.voices.iter().position(|v| v.id == id) {
+            self.current = index;
+            return index;
+        }
+        if let [voice] = self.voices.as_mut_slice() {
+            if voice.id.is_empty() && voice.last_event.is_none() && voice.position.is_zero() {
+                voice.id = id.to_string();
+                self.current = 0;
+                return 0;
+            }
+        }
+        if !self.voices.is_empty() {
+            self.score.parts.push(Part::default());
+        }
+        self.voices.push(Voice::new(id, self.score.staff_count() - 1));
+        self.current = self.voices.len() - 1;
+        self.current
+    }
+
+    fn voice(&mut self) -> &mut Voice {
+        if self.voices.is_empty() {
+            self.select_voice("");
+        }
+        &mut self.voices[self.current]
+    }
+
+    // Where fields in the body take effect. Those in the header are at the start.
+    fn position(&mut self) -> Pulse {
+        if self.in_body {
+            self.voice().position
+        } else {
+            Pulse::default()
+        }
+    }
+
+    fn set_clef(&mut self, clef: Clef) {
+        let position = self.position();
+        let staff = self.voice().staff;
+        if let Some(staff) = self.score.staff_mut(staff) {
+            set_at(&mut staff.clefs, position, clef);
+        }
+    }
+
+    fn field(&mut self, name: char, value: &str) {
+        let value = value.trim();
+        match name {
+            'T' => {
+                if self.score.metadata.title.is_empty() {
+                    self.score.metadata.title = value.to_string();
+                }
+            }
+            'C' => {
+                if self.score.metadata.composer.is_empty() {
+                    self.score.metadata.composer = value.to_string();
+                }
+            }
+            'M' => {
+                if value == "none" {
+                    return;
+                }
+                match parse_meter(value) {
+                    Some(meter) => {
+                        let position = self.position();
+                        set_at(&mut self.score.meters, position, meter);
+                    }
+                    None => log::warn!("Unsupported meter {}", value),
+                }
+            }
+            'L' => match parse_fraction(value) {
+                Some(unit) => self.unit = Some(unit),
+                None => log::warn!("Bad unit note length {}", value),
+            },
+            'Q' => match parse_tempo(value, self.unit()) {
+                Some(tempo) => {
+                    let position = self.position();
+                    set_at(&mut self.score.tempos, position, tempo);
+                }
+                None => log::warn!("Bad tempo {}", value),
+            },
+            'K' => {
+                let mut words = value.split_whitespace().peekable();
+                let key_word = words.peek().filter(|w| !w.starts_with("clef=") && parse_clef(w).is_none()).copied();
+                if let Some(word) = key_word {
+                    words.next();
+                    match parse_key(word) {
+                        Some(key) => {
+                            let position = self.position();
+                            set_at(&mut self.score.keys, position, key);
+                        }
+                        None => log::warn!("Unsupported key {}", word),
+                    }
+                }
+                for word in words {
+                    if let Some(clef) = parse_clef(word.strip_prefix("clef=").unwrap_or(word)) {
+                        self.set_clef(clef);
+                    }
+                }
+                // The default unit length depends on the meter the header ends with
+                if !self.in_body && self.unit.is_none() {
+                    let short = self.score.meter_at(Pulse::default()).length() < Pulse::new(3, 4);
+                    self.unit = Some(Pulse::new(1, if short { 16 } else { 8 }));
+                }
+                self.in_body = true;
+            }
+            'V' => {
+                let id = value.split_whitespace().next().unwrap_or("");
+                self.select_voice(id);
+                let name = ["name=\"", "nm=\""].iter().find_map(|prefix| {
+                    let start = value.find(prefix)? + prefix.len();
+                    let end = value[start..].find('"')? + start;
+                    Some(value[start..end].to_string())
+                });
+                if let Some(name) = name {
+                    let staff = self.voice().staff;
+                    if let Some(part) = self.score.part_of_staff(staff) {
+                        self.score.parts[part].name = name;
+                    }
+                }
+                for word in value.split_whitespace().skip(1) {
+                    if let Some(clef) = parse_clef(word.strip_prefix("clef=").unwrap_or(word)) {
+                        self.set_clef(clef);
+                    }
+                }
+            }
+            'W' | 'w' => self.unsupported("lyrics"),
+            _ if INFORMATION_FIELDS.contains(name) => {}
+            _ => self.unsupported(&format!("{}: field", name)),
+        }
+    }
+
+    fn barline(&mut self) {
+        let first_voice = self.current == 0;
+        let voice = self.voice();
+        voice.position = voice.position.max(voice.bar_end);
+        let position = voice.position;
+        let pickup = !voice.measured && !position.is_zero();
+        if pickup {
+            voice.measured = true;
+        }
+        voice.bar_start = position;
+        voice.bar_end = position;
+        voice.layer = 0;
+        voice.accidentals.clear();
+        // A short first bar is cut off by starting the meter again after it
+        if pickup && first_voice {
+            let meter = self.score.meter_at(Pulse::default());
+            if position < meter.length() {
+                self.score.meters.insert(position, meter);
+            }
+        }
+    }
+
+    // The model has no repeats, so the repeated music is written out again,
+    // leaving out the first ending
+    fn repeat(&mut self) {
+        let first_voice = self.current == 0;
+        let voice = self.voice();
+        let staff = voice.staff;
+        let begin = voice.repeat_start;
+        let end = voice.ending_start.take().unwrap_or(voice.position);
+        let shift = voice.position - begin;
+        voice.position += end - begin;
+        voice.bar_start = voice.position;
+        voice.bar_end = voice.position;
+        voice.repeat_start = voice.position;
+        voice.last_event = None;
+        voice.tie = false;
+
+        let copies: Vec<(Event, Vec<bool>)> = self.events.iter()
+            .filter(|(e, _)| e.staff == staff && e.start >= begin && e.start < end)
+            .map(|(e, shown)| (Event { start: e.start + shift, ..e.clone() }, shown.clone()))
+            .collect();
+        self.events.extend(copies);
+        if let Some(staff) = self.score.staff_mut(staff) {
+            copy_changes(&mut staff.clefs, begin, end, shift);
+            copy_changes(&mut staff.dynamics, begin, end, shift);
+        }
+        if first_voice {
+            copy_changes(&mut self.score.meters, begin, end, shift);
+            copy_changes(&mut self.score.keys, begin, end, shift);
+            copy_changes(&mut self.score.tempos, begin, end, shift);
+        }
+    }
+
+    // Reads a note starting at `chars[*i]`, with whether its accidental was
+    // written and would have applied anyway
+    fn note(&mut self, chars: &[char], i: &mut usize) -> Option<(Note, bool)> {
+        let mut written = None;
+        let peek = |i: usize, c: char| chars.get(i) == Some(&c);
+        if peek(*i, '^') || peek(*i, '_') {
+            let sharp = peek(*i, '^');
+            let (accidental, length) = if peek(*i + 1, chars[*i]) {
+                (if sharp { Accidental::DoubleSharp } else { Accidental::DoubleFlat }, 2)
+            } else if peek(*i + 1, '/') {
+                (if sharp { Accidental::QuarterSharp } else { Accidental::QuarterFlat }, 2)
+            } else if peek(*i + 1, '3') && peek(*i + 2, '/') && peek(*i + 3, '2') {
+                (if sharp { Accidental::ThreeQuarterSharp } else { Accidental::ThreeQuarterFlat }, 4)
+            } else {
+                (if sharp { Accidental::Sharp } else { Accidental::Flat }, 1)
+            };
+            written = Some(accidental);
+            *i += length;
+        } else if peek(*i, '=') {
+            written = Some(Accidental::Natural);
+            *i += 1;
+        }
+        let letter = *chars.get(*i)?;
+        if !matches!(letter, 'A'..='G' | 'a'..='g') {
+            self.unsupported("accidental without a note");
+            return None;
+        }
+        *i += 1;
+        let class: PitchName = letter.to_ascii_lowercase().to_string().parse().ok()?;
+        let mut octave: i32 = if letter.is_ascii_uppercase() { 4 } else { 5 };
+        while let Some(&c) = chars.get(*i) {
+            match c {
+                '\'' => octave += 1,
+                ',' => octave -= 1,
+                _ => break,
+            }
+            *i += 1;
+        }
+        let octave = octave.max(0) as u32;
+
+        let position = self.position();
+        let key = self.score.key_at(position);
+        let last_event = self.voice().last_event;
+        let tie = self.voice().tie;
+        // A note tied over the barline keeps the accidental it was tied from
+        let tied_from = if tie { last_event.and_then(|e| self.events[e].0.notes.iter().find(|n| n.pitch.class == class && n.octave.0 == octave).map(|n| n.pitch.accidental)) } else { None };
+        let voice = self.voice();
+        let in_effect = voice.accidentals.get(&(class, octave)).copied().or(tied_from).unwrap_or_else(|| Accidental::from_alteration(key.alteration(class)).unwrap_or(Accidental::Natural));
+        let accidental = written.unwrap_or(in_effect);
+        if written.is_some() {
+            voice.accidentals.insert((class, octave), accidental);
+        }
+        Some((Note::new(Pitch { class, accidental }, Octave(octave)), written == Some(in_effect)))
+    }
+
+    // Note lengths: 2, /, /4, 3/2, // and so on. Lengths too big or too
+    // finely divided to work with are reported and None.
+    fn length(&mut self, chars: &[char], i: &mut usize) -> Option<Pulse> {
+        let start = *i;
+        let digits = |i: &mut usize, missing: i64| -> Option<i64> {
+            let start = *i;
+            while chars.get(*i).map(|c| c.is_ascii_digit()).unwrap_or(false) {
+                *i += 1;
+            }
+            if start == *i {
+                return Some(missing);
+            }
+            chars[start..*i].iter().collect::<String>().parse().ok()
+        };
+        let num = digits(i, 1);
+        let mut den = Some(1i64);
+        while chars.get(*i) == Some(&'/') {
+            *i += 1;
+            den = den.zip(digits(i, 2)).and_then(|(den, d)| den.checked_mul(d));
+        }
+        match (num, den) {
+            (Some(num), Some(den)) if den <= SHORTEST_BASE as i64 => Some(Pulse::new(num.max(1), den.max(1))),
+            _ => {
+                self.unsupported(&format!("length {}", chars[start..*i].iter().collect::<String>()));
+                None
+            }
+        }
+    }
+
+    // Applies broken rhythm and tuplets to a written length
+    fn value(&mut self, written: Pulse) -> NoteValue {
+        let voice = self.voice();
+        let written = match voice.broken.take() {
+            Some(factor) => written * factor,
+            None => written,
+        };
+        let tuplet = match voice.tuplet {
+            Some((tuplet, remaining)) => {
+                voice.tuplet = if remaining > 1 { Some((tuplet, remaining - 1)) } else { None };
+                Some(tuplet)
+            }
+            None => None,
+        };
+        let value = NoteValue::from_length(written).unwrap_or_else(|| {
+            log::warn!("No single note value is {:?} long", written);
+            NoteValue::longest_within(written)
+        });
+        NoteValue { tuplet, ..value }
+    }
+
+    fn rest(&mut self, written: Pulse) {
+        let length = self.value(written).length();
+        let voice = self.voice();
+        voice.position += length;
+        voice.last_event = None;
+        voice.tie = false;
+    }
+
+    // Notes tied to the same notes just before them lengthen that event
+    // where one value covers both
+    fn push(&mut self, notes: Vec<(Note, bool)>, written: Pulse) {
+        let value = self.value(written);
+        let voice = &mut self.voices[self.current];
+        let start = voice.position;
+        voice.position += value.length();
+        if std::mem::take(&mut voice.tie) {
+            if let Some(index) = voice.last_event {
+                let tied = &mut self.events[index].0;
+                let same = tied.notes.iter().map(|n| n.pitch.semitones() + 12 * n.octave.0 as i32).eq(notes.iter().map(|(n, _)| n.pitch.semitones() + 12 * n.octave.0 as i32));
+                if same && tied.end() == start && tied.duration.tuplet.is_none() && value.tuplet.is_none() {
+                    if let Some(joined) = NoteValue::from_length(tied.length() + value.length()) {
+                        tied.duration = joined;
+                        return;
+                    }
+                }
+            }
+        }
+        voice.last_event = Some(self.events.len());
+        let (staff, layer) = (voice.staff, voice.layer);
+        self.events.push((Event {
+            event_id: 0,
+            staff,
+            layer,
+            notes: notes.iter().map(|(note, _)| *note).collect(),
+            duration: value,
+            start,
+        }, notes.iter().map(|(_, shown)| *shown).collect()));
+    }
+
+    // a>b makes the first note dotted and halves the second, a<b the other way
+    fn broken_rhythm(&mut self, longer_first: bool, count: u32) {
+        let long = Pulse::new((1 << (count + 1)) - 1, 1 << count);
+        let short = Pulse::new(1, 1 << count);
+        let (first, second) = if longer_first { (long, short) } else { (short, long) };
+        // There may be no voice yet if the body starts with the broken rhythm
+        let voice = self.voice();
+        voice.broken = Some(second);
+        let index = match voice.last_event {
+            Some(index) => index,
+            None => {
+                voice.broken = None;
+                return;
+            }
+        };
+        let event = &mut self.events[index].0;
+        match NoteValue::from_length(event.duration.written_length() * first) {
+            Some(value) => {
+                let value = NoteValue { tuplet: event.duration.tuplet, ..value };
+                let change = value.length() - event.length();
+                event.duration = value;
+                self.voice().position += change;
+            }
+            None => {
+                log::warn!("Can't apply broken rhythm to {:?}", event.duration);
+                self.voice().broken = None;
+            }
+        }
+    }
+
+    fn music(&mut self, line: &str) {
+        let chars: Vec<char> = line.chars().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            let next = chars.get(i + 1).copied();
+            match c {
+                '%' => break,
+                '"' => {
+                    i += 1;
+                    while i < chars.len() && chars[i] != '"' {
+                        i += 1;
+                    }
+                    i += 1;
+                    self.unsupported("chord symbol or annotation");
+                }
+                '!' | '+' => {
+                    let end = chars[i + 1..].iter().position(|&e| e == c).map(|p| p + i + 1).unwrap_or(chars.len());
+                    let name: String = chars[(i + 1).min(end)..end].iter().collect();
+                    match name.parse::<Dynamic>() {
+                        Ok(dynamic) => {
+                            let position = self.voice().position;
+                            let staff = self.voice().staff;
+                            if let Some(staff) = self.score.staff_mut(staff) {
+                                staff.dynamics.insert(position, dynamic);
+                            }
+                        }
+                        Err(_) => self.unsupported("decoration"),
+                    }
+                    i = end + 1;
+                }
+                '.' | '~' | 'H' | 'L' | 'M' | 'O' | 'P' | 'S' | 'T' | 'u' | 'v' => {
+                    self.unsupported("decoration");
+                    i += 1;
+                }
+                '{' => {
+                    while i < chars.len() && chars[i] != '}' {
+                        i += 1;
+                    }
+                    i += 1;
+                    self.unsupported("grace notes");
+                }
+                '(' if next.map(|n| n.is_ascii_digit()).unwrap_or(false) => {
+                    i += 1;
+                    let mut numbers = vec![];
+                    loop {
+                        let start = i;
+                        while chars.get(i).map(|c| c.is_ascii_digit()).unwrap_or(false) {
+                            i += 1;
+                        }
+                        numbers.push(chars[start..i].iter().collect::<String>().parse::<u32>().ok());
+                        if chars.get(i) == Some(&':') && numbers.len() < 3 {
+                            i += 1;
+                        } else {
+                            break;
+                        }
+                    }
+                    let p = numbers[0].unwrap_or(3).max(1);
+                    let position = self.voice().position;
+                    let compound = self.score.meter_at(position).count.is_multiple_of(3);
+                    let q = numbers.get(1).copied().flatten().unwrap_or(match p {
+                        2 | 4 | 8 => 3,
+                        3 | 6 => 2,
+                        _ if compound => 3,
+                        _ => 2,
+                    });
+                    let r = numbers.get(2).copied().flatten().unwrap_or(p);
+                    self.voice().tuplet = Some((Tuplet { num: p, numbase: q }, r.max(1)));
+                }
+                // Slurs
+                '(' | ')' => i += 1,
+                '-' => {
+                    self.voice().tie = true;
+                    i += 1;
+                }
+                '>' | '<' => {
+                    let mut count = 0;
+                    while chars.get(i) == Some(&c) {
+                        count += 1;
+                        i += 1;
+                    }
+                    self.broken_rhythm(c == '>', count.min(3));
+                }
+                '&' => {
+                    let voice = self.voice();
+                    voice.bar_end = voice.bar_end.max(voice.position);
+                    voice.position = voice.bar_start;
+                    voice.layer += 1;
+                    voice.last_event = None;
+                    voice.tie = false;
+                    i += 1;
+                }
+                '[' if next.map(|n| n.is_ascii_alphabetic()).unwrap_or(false) && chars.get(i + 2) == Some(&':') => {
+                    let end = chars[i..].iter().position(|&e| e == ']').map(|p| p + i).unwrap_or(chars.len());
+                    let value: String = chars[i + 3..end].iter().collect();
+                    self.field(chars[i + 1], &value);
+                    i = end + 1;
+                }
+                '[' if next.map(|n| n.is_ascii_digit()).unwrap_or(false) => {
+                    i += 1;
+                    self.ending(&chars, &mut i);
+                }
+                '|' | ':' | '[' if c != '[' || next == Some('|') => {
+                    let mut bar = String::new();
+                    while let Some(&b) = chars.get(i) {
+                        if b == '|' || b == ':' || (b == ']' && bar.contains('|')) || (b == '[' && chars.get(i + 1) == Some(&'|')) {
+                            bar.push(b);
+                            i += 1;
+                        } else {
+                            break;
+                        }
+                    }
+                    if bar.starts_with(':') {
+                        self.barline();
+                        self.repeat();
+                    } else {
+                        self.barline();
+                    }
+                    if bar.ends_with(':') {
+                        let voice = self.voice();
+                        voice.repeat_start = voice.position;
+                        voice.ending_start = None;
+                    }
+                    if chars.get(i).map(|c| c.is_ascii_digit()).unwrap_or(false) {
+                        self.ending(&chars, &mut i);
+                    }
+                }
+                '[' => {
+                    i += 1;
+                    let mut notes = vec![];
+                    let mut first_length = None;
+                    while i < chars.len() && chars[i] != ']' {
+                        if matches!(chars[i], '^' | '_' | '=' | 'A'..='G' | 'a'..='g') {
+                            let start = i;
+                            match self.note(&chars, &mut i) {
+                                Some(note) => notes.push(note),
+                                None => i = start + 1,
+                            }
+                            if let Some(length) = self.length(&chars, &mut i) {
+                                first_length.get_or_insert(length);
+                            }
+                        } else {
+                            i += 1;
+                        }
+                    }
+                    i += 1;
+                    let length = self.length(&chars, &mut i).map(|length| first_length.unwrap_or_else(|| Pulse::whole(1)) * length);
+                    if let (Some(length), false) = (length, notes.is_empty()) {
+                        self.push(notes, length * self.unit());
+                    }
+                    if chars.get(i) == Some(&'-') {
+                        self.voice().tie = true;
+                        i += 1;
+                    }
+                }
+                'z' | 'x' => {
+                    i += 1;
+                    if let Some(length) = self.length(&chars, &mut i) {
+                        self.rest(length * self.unit());
+                    }
+                }
+                'Z' | 'X' => {
+                    i += 1;
+                    if let Some(bars) = self.length(&chars, &mut i) {
+                        let position = self.voice().position;
+                        let length = self.score.meter_at(position).length() * bars;
+                        let voice = self.voice();
+                        voice.position += length;
+                        voice.last_event = None;
+                    }
+                }
+                '^' | '_' | '=' | 'A'..='G' | 'a'..='g' => {
+                    let start = i;
+                    match self.note(&chars, &mut i) {
+                        Some(note) => {
+                            if let Some(length) = self.length(&chars, &mut i) {
+                                self.push(vec![note], length * self.unit());
+                            }
+                        }
+                        None => i = start + 1,
+                    }
+                }
+                ' ' | '\t' | '\\' | '`' | 'y' | '$' | ';' => i += 1,
+                other => {
+                    self.unsupported(&format!("'{}'", other));
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    // Endings are numbered [1 or |1. The first is left out when the repeat is
+    // written out and the rest are played straight through.
+    fn ending(&mut self, chars: &[char], i: &mut usize) {
+        let start = *i;
+        while chars.get(*i).map(|c| c.is_ascii_digit() || *c == ',' || *c == '-').unwrap_or(false) {
+            *i += 1;
+        }
+        let numbers: String = chars[start..*i].iter().collect();
+        if numbers.split([',', '-']).next() == Some("1") {
+            let voice = self.voice();
+            voice.ending_start = Some(voice.position);
+        }
+    }
+
+    // Numbers the events once their cautionary accidentals are settled,
+    // which needs the measures of the whole tune
+    fn finish(mut self) -> Score {
+        let mut score = self.score;
+        let events = std::mem::take(&mut self.events);
+        for (event, _) in &events {
+            score.events.insert(event.clone());
+        }
+        let measures = score.measures();
+        score.events.clear();
+
+        let mut order: Vec<usize> = (0..events.len()).collect();
+        order.sort_by_key(|&i| (events[i].0.staff, events[i].0.start, events[i].0.layer));
+        let mut events = events;
+        for staff in 0..score.staff_count() {
+            let mut state = AccidentalState::new(score.key_at(Pulse::default()));
+            for &(measure_start, measure_end) in &measures {
+                state.next_measure(score.key_at(measure_start));
+                let starting: Vec<usize> = order.iter().copied()
+                    .filter(|&i| events[i].0.staff == staff && events[i].0.start >= measure_start && events[i].0.start < measure_end)
+                    .collect();
+                for index in starting {
+                    let (event, shown) = &mut events[index];
+                    crate::settle_cautionary(&mut state, event, shown);
+                }
+            }
+        }
+        for (id, (mut event, _)) in events.into_iter().enumerate() {
+            event.event_id = id as u32;
+            score.events.insert(event);
+        }
+        for (name, count) in &self.unsupported {
+            log::warn!("Ignored {} unsupported ABC {}(s)", count, name);
+        }
+        score
+    }
+}
+
+impl Score {
+    // Reads the first tune of some ABC, which may be a whole file or a pasted
+    // tune without an X: line. Each voice becomes a part, overlays with & are
+    // layers and repeats are written out in full.
+    pub fn from_abc(text: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            score: Score::default(),
+            events: vec![],
+            voices: vec![],
+            current: 0,
+            unit: None,
+            in_body: false,
+            unsupported: BTreeMap::new(),
+        };
+        let mut tunes = 0;
+        for line in text.lines() {
+            let trimmed = line.trim();
+            if trimmed.starts_with('%') {
+                continue;
+            }
+            // A blank line ends the tune
+            if trimmed.is_empty() {
+                if parser.in_body {
+                    break;
+                }
+                continue;
+            }
+            let mut chars = trimmed.chars();
+            let field = match (chars.next(), chars.next()) {
+                (Some(name), Some(':')) if name.is_ascii_alphabetic() && !chars.as_str().starts_with('|') => Some(name),
+                _ => None,
+            };
+            match field {
+                Some('X') => {
+                    tunes += 1;
+                    if tunes > 1 {
+                        log::warn!("Only the first tune of the ABC is read");
+                        break;
+                    }
+                }
+                Some(name) => {
+                    let value = trimmed[2..].split('%').next().unwrap_or("");
+                    parser.field(name, value);
+                }
+                None if parser.in_body => parser.music(trimmed),
+                None => parser.unsupported("line of text"),
+            }
+        }
+        if !parser.in_body {
+            return Err("No K: field in the ABC".to_string());
+        }
+        Ok(parser.finish())
+    }
+}
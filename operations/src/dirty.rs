@@ -0,0 +1,129 @@
+use std::{collections::BTreeSet, ops::Range};
+
+use crate::{Context, Event, Pulse, Score};
+
+// Which measures, by index, need laying out again since the view was last drawn.
+// A fresh context starts with everything dirty since nothing has been drawn yet.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Dirty {
+    Measures(BTreeSet<usize>),
+    #[default]
+    All,
+}
+
+impl Dirty {
+    pub fn clean() -> Self {
+        Dirty::Measures(BTreeSet::new())
+    }
+
+    pub fn is_clean(&self) -> bool {
+        matches!(self, Dirty::Measures(m) if m.is_empty())
+    }
+
+    pub fn merge(&mut self, other: Dirty) {
+        match (&mut *self, other) {
+            (Dirty::Measures(measures), Dirty::Measures(other)) => measures.extend(other),
+            (_, _) => *self = Dirty::All,
+        }
+    }
+}
+
+// The part of the score an operation draws differently, worked out before it's
+// applied from the selections it acts on
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    // Whatever starts from the first pulse up to the second, and the measures it runs on into
+    Between(Pulse, Pulse),
+    // Everything from the measure holding the pulse to the end, like after a new key
+    From(Pulse),
+    All,
+}
+
+impl Score {
+    // The events of a layer from `begin` on, found without going through the whole score
+    pub(crate) fn events_from(&self, staff: u32, layer: u32, begin: Pulse) -> impl Iterator<Item=&Event> {
+        let probe = Event { staff, layer, start: begin, ..Default::default() };
+        self.events.range(probe..).take_while(move |e| e.staff == staff && e.layer == layer)
+    }
+
+    // The events of a layer starting from `begin` up to and including `end`
+    pub(crate) fn events_between(&self, staff: u32, layer: u32, begin: Pulse, end: Pulse) -> impl Iterator<Item=&Event> {
+        self.events_from(staff, layer, begin).take_while(move |e| e.start <= end)
+    }
+
+    // The measures to draw again after changes made to a score which used to end at `end`
+    pub(crate) fn dirty_measures(&self, changes: &[Change], end: Pulse) -> Dirty {
+        let measures = self.measures();
+        let first = |at: Pulse| measures.partition_point(|(start, _)| *start <= at).saturating_sub(1);
+        let last = |end: Pulse| measures.partition_point(|(start, _)| *start < end).saturating_sub(1);
+
+        let mut dirty = BTreeSet::new();
+        for change in changes {
+            match *change {
+                Change::Between(begin, end) => dirty.extend(first(begin)..=last(end).max(first(begin))),
+                Change::From(at) => dirty.extend(first(at)..measures.len()),
+                Change::All => return Dirty::All,
+            }
+        }
+        if !changes.is_empty() {
+            dirty.extend(self.resized_measures(&measures, end));
+        }
+        Dirty::Measures(dirty)
+    }
+
+    // The measures from wherever the shorter of this score and one which used
+    // to end at `end` stops, as the last measure is only filled up to the end
+    fn resized_measures(&self, measures: &[(Pulse, Pulse)], end: Pulse) -> Range<usize> {
+        let new_end = self.end();
+        if new_end == end {
+            return 0..0;
+        }
+        let first = measures.partition_point(|(start, _)| *start <= new_end.min(end)).saturating_sub(1);
+        first..measures.len()
+    }
+
+    // The measures of an edit, worked out on the other side of it, to draw
+    // again on going back to this score from one which ended at `end`
+    pub(crate) fn restored_measures(&self, dirty: Dirty, end: Pulse) -> Dirty {
+        match dirty {
+            Dirty::Measures(mut dirty) => {
+                let measures = self.measures();
+                dirty.retain(|i| *i < measures.len());
+                dirty.extend(self.resized_measures(&measures, end));
+                Dirty::Measures(dirty)
+            }
+            Dirty::All => Dirty::All,
+        }
+    }
+}
+
+impl Context {
+    // The span of the events in each selection, for operations which change only those
+    pub(crate) fn selected_events(&self, selections: &[u32]) -> Vec<Change> {
+        selections.iter().filter_map(|selection_id| {
+            let selection = &self.selections.0[*selection_id as usize];
+            let end = self.score.events_between(selection.staff, selection.layer, selection.begin.0, selection.end.0)
+                .map(|e| e.end())
+                .max()?;
+            Some(Change::Between(selection.begin.0, end))
+        }).collect()
+    }
+
+    // Everything from the start of each selection on. The opening key, clef
+    // and so on are in the header, so changing them changes everything.
+    pub(crate) fn changes_from(&self, selections: &[u32]) -> Vec<Change> {
+        selections.iter().map(|selection_id| {
+            let pulse = self.selections.0[*selection_id as usize].begin.0;
+            if pulse > Pulse::default() {
+                Change::From(pulse)
+            } else {
+                Change::All
+            }
+        }).collect()
+    }
+
+    // What has changed since the last call, leaving the context clean
+    pub fn take_dirty(&mut self) -> Dirty {
+        std::mem::replace(&mut self.dirty, Dirty::clean())
+    }
+}
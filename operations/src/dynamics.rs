@@ -0,0 +1,63 @@
+use serde::{Serialize, Deserialize};
+
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Dynamic {
+    PPP,
+    PP,
+    P,
+    MP,
+    #[default]
+    MF,
+    F,
+    FF,
+    FFF,
+}
+
+impl Dynamic {
+    pub fn velocity(&self) -> u8 {
+        match self {
+            Dynamic::PPP => 16,
+            Dynamic::PP => 33,
+            Dynamic::P => 49,
+            Dynamic::MP => 64,
+            Dynamic::MF => 80,
+            Dynamic::F => 96,
+            Dynamic::FF => 112,
+            Dynamic::FFF => 127,
+        }
+    }
+}
+
+impl std::str::FromStr for Dynamic {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "ppp" => Ok(Dynamic::PPP),
+            "pp" => Ok(Dynamic::PP),
+            "p" => Ok(Dynamic::P),
+            "mp" => Ok(Dynamic::MP),
+            "mf" => Ok(Dynamic::MF),
+            "f" => Ok(Dynamic::F),
+            "ff" => Ok(Dynamic::FF),
+            "fff" => Ok(Dynamic::FFF),
+            other => Err(format!("Unknown dynamic {}", other)),
+        }
+    }
+}
+
+// Written the way MEI and most notation spells them, like "mf"
+impl std::fmt::Display for Dynamic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Dynamic::PPP => "ppp",
+            Dynamic::PP => "pp",
+            Dynamic::P => "p",
+            Dynamic::MP => "mp",
+            Dynamic::MF => "mf",
+            Dynamic::F => "f",
+            Dynamic::FF => "ff",
+            Dynamic::FFF => "fff",
+        };
+        write!(f, "{}", name)
+    }
+}
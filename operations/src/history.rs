@@ -0,0 +1,255 @@
+use crate::{Context, Dirty, Operation, Score, Selections};
+
+#[derive(Clone, Debug)]
+struct Snapshot {
+    score: Score,
+    selections: Selections,
+    next_id: u32,
+    // The measures drawn differently in the context the snapshot was taken
+    // from, which are the same ones going back as going forward
+    dirty: Dirty,
+}
+
+impl Snapshot {
+    fn take(ctx: &Context) -> Self {
+        Self {
+            score: ctx.score.clone(),
+            selections: ctx.selections.clone(),
+            next_id: ctx.next_id,
+            dirty: Dirty::clean(),
+        }
+    }
+
+    fn restore(self, ctx: &mut Context) -> Self {
+        let mut current = Self::take(ctx);
+        let end = ctx.score.end();
+        ctx.score = self.score;
+        ctx.selections = self.selections;
+        ctx.next_id = self.next_id;
+        let dirty = ctx.score.restored_measures(self.dirty, end);
+        ctx.dirty.merge(dirty.clone());
+        current.dirty = dirty;
+        current
+    }
+}
+
+// Each snapshot holds a whole score, so only the latest edits are kept
+const MAX_UNDO: usize = 200;
+
+// Undo entries are snapshots of the context taken before an edit. Only edits
+// which change the score are recorded, so moving the selections around
+// doesn't fill the history up.
+#[derive(Clone, Default)]
+pub struct History {
+    undo: Vec<Snapshot>,
+    redo: Vec<Snapshot>,
+    group: Option<Snapshot>,
+    group_depth: u32,
+}
+
+impl History {
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    fn record(&mut self, before: Snapshot, ctx_score: &Score) {
+        if &before.score != ctx_score {
+            self.undo.push(before);
+            self.redo.clear();
+            if self.undo.len() > MAX_UNDO {
+                self.undo.remove(0);
+            }
+        }
+    }
+}
+
+// The snapshots are left out as they would print the score over and over
+impl std::fmt::Debug for History {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("History")
+            .field("undo", &self.undo.len())
+            .field("redo", &self.redo.len())
+            .field("group_depth", &self.group_depth)
+            .finish()
+    }
+}
+
+impl Context {
+    pub fn apply(&mut self, operation: &dyn Operation) {
+        let changes = operation.changes(self);
+        let end = self.score.end();
+        let before = if self.history.group_depth > 0 {
+            None
+        } else {
+            Some(Snapshot::take(self))
+        };
+        operation.apply(self);
+        let dirty = self.score.dirty_measures(&changes, end);
+        self.dirty.merge(dirty.clone());
+        match before {
+            Some(mut before) => {
+                before.dirty = dirty;
+                self.history.record(before, &self.score);
+            }
+            None => {
+                if let Some(group) = &mut self.history.group {
+                    group.dirty.merge(dirty);
+                }
+            }
+        }
+    }
+
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    pub fn clear_history(&mut self) {
+        self.history = History::default();
+    }
+
+    // Everything applied between begin_group and the matching end_group is
+    // undone and redone as a single step. Groups may nest.
+    pub fn begin_group(&mut self) {
+        if self.history.group_depth == 0 {
+            self.history.group = Some(Snapshot::take(self));
+        }
+        self.history.group_depth += 1;
+    }
+
+    pub fn end_group(&mut self) {
+        if self.history.group_depth == 0 {
+            return;
+        }
+        self.history.group_depth -= 1;
+        if self.history.group_depth == 0 {
+            if let Some(before) = self.history.group.take() {
+                self.history.record(before, &self.score);
+            }
+        }
+    }
+
+    pub fn undo(&mut self) -> bool {
+        if self.history.group_depth > 0 {
+            return false;
+        }
+        if let Some(snapshot) = self.history.undo.pop() {
+            let current = snapshot.restore(self);
+            self.history.redo.push(current);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn redo(&mut self) -> bool {
+        if self.history.group_depth > 0 {
+            return false;
+        }
+        if let Some(snapshot) = self.history.redo.pop() {
+            let current = snapshot.restore(self);
+            self.history.undo.push(current);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use super::MAX_UNDO;
+
+    fn append(ctx: &mut Context, class: PitchName) {
+        ctx.apply(&AppendNote {
+            note: Note::new(Pitch { class, accidental: Accidental::Natural }, Octave(4)),
+            duration: NoteValue::new(4),
+            selections: None,
+        });
+    }
+
+    fn classes(ctx: &Context) -> Vec<PitchName> {
+        ctx.score.events_on_layer(0, 0).map(|e| e.notes()[0].pitch.class).collect()
+    }
+
+    #[test]
+    fn undo_and_redo() {
+        let mut ctx = Context::default();
+        append(&mut ctx, PitchName::C);
+        append(&mut ctx, PitchName::D);
+        assert!(ctx.undo());
+        assert_eq!(classes(&ctx), vec![PitchName::C]);
+        // The cursor goes back to where it was too
+        assert_eq!(ctx.selections.0[0].begin.0, Pulse::new(1, 4));
+        assert!(ctx.redo());
+        assert_eq!(classes(&ctx), vec![PitchName::C, PitchName::D]);
+        assert!(!ctx.redo());
+        assert!(ctx.undo() && ctx.undo());
+        assert!(!ctx.undo());
+        assert!(classes(&ctx).is_empty());
+    }
+
+    #[test]
+    fn moving_the_selection_isnt_recorded() {
+        let mut ctx = Context::default();
+        append(&mut ctx, PitchName::C);
+        ctx.apply(&MoveSelections {
+            delta: Duration::Event(-1),
+            selections: vec![0],
+        });
+        assert_eq!(ctx.history().undo.len(), 1);
+    }
+
+    #[test]
+    fn a_new_edit_clears_redo() {
+        let mut ctx = Context::default();
+        append(&mut ctx, PitchName::C);
+        ctx.undo();
+        assert!(ctx.history().can_redo());
+        append(&mut ctx, PitchName::E);
+        assert!(!ctx.history().can_redo());
+        assert_eq!(classes(&ctx), vec![PitchName::E]);
+    }
+
+    #[test]
+    fn groups_undo_as_one_step() {
+        let mut ctx = Context::default();
+        append(&mut ctx, PitchName::C);
+        ctx.begin_group();
+        append(&mut ctx, PitchName::D);
+        ctx.begin_group();
+        append(&mut ctx, PitchName::E);
+        ctx.end_group();
+        // Still inside the outer group, so there's nothing to undo yet
+        assert!(!ctx.undo());
+        append(&mut ctx, PitchName::F);
+        ctx.end_group();
+        assert!(ctx.undo());
+        assert_eq!(classes(&ctx), vec![PitchName::C]);
+        assert!(ctx.redo());
+        assert_eq!(classes(&ctx), vec![PitchName::C, PitchName::D, PitchName::E, PitchName::F]);
+    }
+
+    #[test]
+    fn only_the_latest_edits_are_kept() {
+        let mut ctx = Context::default();
+        for _ in 0..MAX_UNDO + 10 {
+            append(&mut ctx, PitchName::C);
+        }
+        assert_eq!(ctx.history().undo.len(), MAX_UNDO);
+        while ctx.undo() {}
+        assert_eq!(classes(&ctx).len(), 10);
+    }
+
+    #[test]
+    fn unmatched_end_group_is_ignored() {
+        let mut ctx = Context::default();
+        ctx.end_group();
+        append(&mut ctx, PitchName::C);
+        assert!(ctx.undo());
+    }
+}
@@ -1,322 +1,817 @@
-#![feature(btree_retain, map_first_last)]
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
 use serde::{Serialize, Deserialize};
 
-#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+mod abc;
+mod dirty;
+mod dynamics;
+mod history;
+mod lilypond;
+mod mei;
+mod metadata;
+mod midi;
+mod musicxml;
+mod pitch;
+mod rhythm;
+mod timeline;
+
+pub use dirty::{Change, Dirty};
+pub use dynamics::Dynamic;
+pub use history::History;
+pub use lilypond::PitchMode;
+pub use metadata::Metadata;
+pub use pitch::*;
+pub use rhythm::*;
+pub use timeline::Timeline;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Score {
+    #[serde(default = "default_parts")]
+    pub parts: Vec<Part>,
+    #[serde(default)]
+    pub meters: Timeline<Meter>,
+    #[serde(default)]
+    pub keys: Timeline<Key>,
+    #[serde(default)]
+    pub tempos: Timeline<Tempo>,
+    #[serde(default)]
+    pub metadata: Metadata,
     events: BTreeSet<Event>,
 }
 
+fn default_parts() -> Vec<Part> {
+    vec![Part::default()]
+}
+
+impl Default for Score {
+    fn default() -> Self {
+        Self {
+            parts: default_parts(),
+            meters: Timeline::default(),
+            keys: Timeline::default(),
+            tempos: Timeline::default(),
+            metadata: Metadata::default(),
+            events: BTreeSet::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Part {
+    pub name: String,
+    pub staves: Vec<Staff>,
+    // General MIDI program, counted from zero
+    #[serde(default)]
+    pub program: u8,
+}
+
+impl Default for Part {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            staves: vec![Staff::default()],
+            program: 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Staff {
+    pub lines: u32,
+    #[serde(default)]
+    pub clefs: Timeline<Clef>,
+    #[serde(default)]
+    pub dynamics: Timeline<Dynamic>,
+}
+
+impl Default for Staff {
+    fn default() -> Self {
+        Self {
+            lines: 5,
+            clefs: Timeline::default(),
+            dynamics: Timeline::default(),
+        }
+    }
+}
+
+impl Staff {
+    pub fn clef_at(&self, pulse: Pulse) -> Clef {
+        self.clefs.at(pulse).copied().unwrap_or_default()
+    }
+
+    pub fn dynamic_at(&self, pulse: Pulse) -> Dynamic {
+        self.dynamics.at(pulse).copied().unwrap_or_default()
+    }
+}
+
 impl Score {
+    pub fn staff_count(&self) -> u32 {
+        self.parts.iter().map(|p| p.staves.len() as u32).sum()
+    }
+
+    // Staves are numbered from zero across all parts, in score order.
+    pub fn staves(&self) -> impl Iterator<Item=(usize, &Staff)> {
+        self.parts.iter().enumerate().flat_map(|(i, p)| p.staves.iter().map(move |s| (i, s)))
+    }
+
+    pub fn staff(&self, staff: u32) -> Option<&Staff> {
+        self.staves().nth(staff as usize).map(|(_, s)| s)
+    }
+
+    pub fn staff_mut(&mut self, staff: u32) -> Option<&mut Staff> {
+        self.parts.iter_mut().flat_map(|p| p.staves.iter_mut()).nth(staff as usize)
+    }
+
+    pub fn part_of_staff(&self, staff: u32) -> Option<usize> {
+        self.staves().nth(staff as usize).map(|(part, _)| part)
+    }
+
+    pub fn events_on_staff(&self, staff: u32) -> impl Iterator<Item=&Event> {
+        self.events.iter().filter(move |e| e.staff == staff)
+    }
+
+    pub fn events_on_layer(&self, staff: u32, layer: u32) -> impl Iterator<Item=&Event> {
+        self.events.iter().filter(move |e| e.staff == staff && e.layer == layer)
+    }
+
+    // The layers which have events on them, always including the first.
+    pub fn layers_on_staff(&self, staff: u32) -> Vec<u32> {
+        let layers: BTreeSet<u32> = std::iter::once(0).chain(self.events_on_staff(staff).map(|e| e.layer)).collect();
+        layers.into_iter().collect()
+    }
+
+    pub fn end(&self) -> Pulse {
+        self.events.iter().map(|e| e.end()).max().unwrap_or_default()
+    }
+
+    pub fn meter_at(&self, pulse: Pulse) -> Meter {
+        self.meters.at(pulse).copied().unwrap_or_default()
+    }
+
+    pub fn key_at(&self, pulse: Pulse) -> Key {
+        self.keys.at(pulse).copied().unwrap_or_default()
+    }
+
+    pub fn tempo_at(&self, pulse: Pulse) -> Tempo {
+        self.tempos.at(pulse).copied().unwrap_or_default()
+    }
+
+    // The start and end of each measure needed to hold every event. A meter
+    // change which doesn't fall on a barline cuts the measure before it short.
+    pub fn measures(&self) -> Vec<(Pulse, Pulse)> {
+        let end = self.end();
+        let mut measures = vec![];
+        let mut measure_start = Pulse::default();
+        while measure_start < end {
+            let mut measure_end = measure_start + self.meter_at(measure_start).length();
+            if let Some((change, _)) = self.meters.next_change(measure_start, measure_end) {
+                measure_end = change;
+            }
+            measures.push((measure_start, measure_end));
+            measure_start = measure_end;
+        }
+        measures
+    }
+
+    // The events of every staff and layer, split at the barlines
+    fn segments(&self, measures: &[(Pulse, Pulse)]) -> BTreeMap<(u32, u32), Vec<Segment<'_>>> {
+        let mut segments = BTreeMap::new();
+        for staff in 0..self.staff_count() {
+            for layer in self.layers_on_staff(staff) {
+                segments.insert((staff, layer), split_at_barlines(self.events_on_layer(staff, layer), measures));
+            }
+        }
+        segments
+    }
+
+    fn first_staff_of_part(&self, part: usize) -> u32 {
+        self.parts[..part].iter().map(|p| p.staves.len() as u32).sum()
+    }
+
+    fn shift_staves(&mut self, from: u32, delta: i32) {
+        let mut new_events = BTreeSet::new();
+        while let Some(mut e) = self.events.pop_first() {
+            if e.staff >= from {
+                e.staff = (e.staff as i32 + delta) as u32;
+            }
+            new_events.insert(e);
+        }
+        self.events = new_events;
+    }
+
+    // The MEI with the program writing it named in the encodingDesc. The
+    // version is the program's own, which this crate can't know.
+    pub fn to_mei_from(&self, application: &str, version: &str) -> ir::Mei {
+        let mut mei = self.to_mei();
+        if let Some(head) = &mut mei.mei_head {
+            head.encoding_desc = Some(ir::EncodingDesc {
+                app_info: Some(ir::AppInfo {
+                    applications: vec![ir::Application {
+                        version: Some(version.to_string()),
+                        name: application.to_string(),
+                    }],
+                }),
+            });
+        }
+        mei
+    }
+
     pub fn to_mei(&self) -> ir::Mei {
-        let mut mei = ir::Mei::default();
-        mei.mei_head = Some(ir::MeiHead {
+        let mut mei = ir::Mei {
+            mei_head: Some(self.metadata.to_mei_head()),
             ..Default::default()
-        });
+        };
         let mut section = ir::Section::default();
-
-        let mut layer = ir::Layer::default();
-        layer.n = Some(1);
-        let mut beat = Pulse::default();
-        for event in &self.events {
-            if event.start > beat {
-                let rest = event.start - beat;
-                let dur = (1.0 / (rest.0 as f32 / 16.0*4.0)).floor() as u32;
-                layer.events.push(ir::EventLike::Rest(ir::Rest { dur: Some(dur) }));
-            }
-            let dur = (1.0 / (event.duration.0 as f32 / 16.0*4.0)).floor() as u32;
-            let accid = if event.note.pitch.accidental != Accidental::Natural {
-                Some(event.note.pitch.accidental.to_string())
-            } else {
-                None
-            };
-            layer.events.push(ir::EventLike::Note(ir::Note {
-                xml_id: Some(format!("note_{}", event.event_id)),
-                pname: Some(event.note.pitch.class.to_string()),
-                accid,
-                oct: event.note.octave.0,
-                dur: Some(dur),
-                ..Default::default()
-            }));
-            beat = event.start + event.duration;
-            if beat.0 % 4 == 0 {
-                let n = layer.n;
-                let measure = ir::Measure {
-                    n: Some(1),
-                    staves: vec![ir::Staff {
-                        layers: vec![layer],
-                        ..Default::default()
-                    }]
-                };
-                section.measures.push(measure);
-                layer = ir::Layer::default();
-                layer.n = n.map(|n| n+1);
+        for (_, score_def, measure) in self.write_measures(None) {
+            if let Some(score_def) = score_def {
+                section.children.push(ir::SectionLike::ScoreDef(score_def));
             }
+            section.children.push(ir::SectionLike::Measure(measure));
         }
-        if !layer.events.is_empty() {
-            let measure = ir::Measure {
-                n: Some(1),
-                staves: vec![ir::Staff {
-                    layers: vec![layer],
+
+        let mut staff_grp = ir::StaffGrp {
+            symbol: if self.parts.len() > 1 { Some("bracket".to_string()) } else { None },
+            ..Default::default()
+        };
+        let mut staff_n = 1;
+        for part in &self.parts {
+            let mut staff_defs = vec![];
+            for staff in &part.staves {
+                let mut staff_def = ir::StaffDef {
+                    n: Some(staff_n),
+                    lines: Some(staff.lines),
                     ..Default::default()
-                }]
-            };
-            section.measures.push(measure);
+                };
+                staff.clef_at(Pulse::default()).apply_to_staff_def(&mut staff_def);
+                staff_defs.push(ir::StaffGrpLike::StaffDef(staff_def));
+                staff_n += 1;
+            }
+            let label = if part.name.is_empty() { None } else { Some(part.name.clone()) };
+            if staff_defs.len() == 1 {
+                if let Some(ir::StaffGrpLike::StaffDef(staff_def)) = staff_defs.first_mut() {
+                    staff_def.label = label;
+                }
+                staff_grp.children.extend(staff_defs);
+            } else {
+                staff_grp.children.push(ir::StaffGrpLike::StaffGrp(ir::StaffGrp {
+                    label,
+                    symbol: Some("brace".to_string()),
+                    bar_thru: Some(true),
+                    children: staff_defs,
+                }));
+            }
         }
 
+        let initial_meter = self.meter_at(Pulse::default());
+        let initial_key = self.key_at(Pulse::default());
         mei.music = Some(ir::Music {
             body: Some(ir::Body {
                 mdivs: vec![
                     ir::MDiv {
                         score: Some(ir::Score {
                             score_def: Some(ir::ScoreDef {
-                                meter_count: Some(4),
-                                meter_unit: Some(4),
-                                key_sig: Some("0".to_string()),
-                                key_mode: Some("major".to_string()),
-                                staff_grp: Some(ir::StaffGrp {
-                                    staff_def: Some(ir::StaffDef {
-                                        n: Some(1),
-                                        clef_line: Some(2),
-                                        clef_shape: Some("G".to_string()),
-                                        clef_dis_place: Some("below".to_string()),
-                                        lines: Some(5),
-                                        ..Default::default()
-                                    }),
-                                    ..Default::default()
-                                }),
-                                ..Default::default()
+                                meter_count: Some(initial_meter.count),
+                                meter_unit: Some(initial_meter.unit),
+                                key_sig: Some(initial_key.to_mei_sig()),
+                                key_mode: Some(initial_key.to_mei_mode()),
+                                midi_bpm: Some(self.tempo_at(Pulse::default()).bpm),
+                                staff_grp: Some(staff_grp),
                             }),
                             sections: vec![section],
-                            ..Default::default()
                         }),
-                        ..Default::default()
                     }],
-                    ..Default::default()
             }),
-            ..Default::default()
         });
 
          mei
     }
+
+    // The measures of the score as MEI, each with the scoreDef for anything
+    // which changes at its start. Accidentals depend on the measures before, so
+    // those are still gone through when only some are wanted.
+    pub fn measures_to_mei(&self, indices: &BTreeSet<usize>) -> Vec<(usize, Option<ir::ScoreDef>, ir::Measure)> {
+        self.write_measures(Some(indices))
+    }
+
+    fn write_measures(&self, indices: Option<&BTreeSet<usize>>) -> Vec<(usize, Option<ir::ScoreDef>, ir::Measure)> {
+        let end = self.end();
+        let measures = self.measures();
+        let staff_count = self.staff_count();
+        let segments = self.segments(&measures);
+        let mut accidental_states: Vec<AccidentalState> = (0..staff_count).map(|_| AccidentalState::new(self.key_at(Pulse::default()))).collect();
+        let staff_marks: Vec<Vec<(Pulse, Mark)>> = (0..staff_count).map(|staff| self.marks(staff)).collect();
+        let mut written = vec![];
+        for (i, &(measure_start, measure_end)) in measures.iter().enumerate() {
+            if let Some(indices) = indices {
+                if indices.range(i..).next().is_none() {
+                    break;
+                }
+                if !indices.contains(&i) {
+                    for (staff, state) in accidental_states.iter_mut().enumerate() {
+                        state.next_measure(self.key_at(measure_start));
+                        accidentals_in_measure(state, &segments, staff as u32, (measure_start, measure_end));
+                    }
+                    continue;
+                }
+            }
+            let mut score_def = None;
+            if i > 0 {
+                let previous = measures[i - 1].0;
+                let mut changes = ir::ScoreDef::default();
+                let meter = self.meter_at(measure_start);
+                if meter != self.meter_at(previous) {
+                    changes.meter_count = Some(meter.count);
+                    changes.meter_unit = Some(meter.unit);
+                }
+                let key = self.key_at(measure_start);
+                if key != self.key_at(previous) {
+                    changes.key_sig = Some(key.to_mei_sig());
+                    changes.key_mode = Some(key.to_mei_mode());
+                }
+                let tempo = self.tempo_at(measure_start);
+                if tempo != self.tempo_at(previous) {
+                    changes.midi_bpm = Some(tempo.bpm);
+                }
+                if changes != ir::ScoreDef::default() {
+                    score_def = Some(changes);
+                }
+            }
+            let fill_to = if measure_end < end { measure_end } else { end };
+            let mut measure = ir::Measure {
+                n: Some(i as u32 + 1),
+                ..Default::default()
+            };
+            for (staff, (_, staff_info)) in self.staves().enumerate() {
+                let staff = staff as u32;
+                let mut mei_staff = ir::Staff {
+                    n: Some(staff + 1),
+                    ..Default::default()
+                };
+                let state = &mut accidental_states[staff as usize];
+                state.next_measure(self.key_at(measure_start));
+                let accidentals = accidentals_in_measure(state, &segments, staff, (measure_start, measure_end));
+                for (i, (&(_, layer), layer_segments)) in segments.range((staff, 0)..=(staff, u32::MAX)).enumerate() {
+                    // Only the first voice gets rests, gaps in the others are left as space
+                    let rests = i == 0;
+                    // Clef changes go in the first voice, the opening clef is in the staffDef
+                    let mut marks: Vec<(Pulse, Mark)> = if i == 0 {
+                        staff_marks[staff as usize].iter().filter(|(p, _)| *p > Pulse::default() && *p >= measure_start && *p < measure_end).copied().collect()
+                    } else {
+                        vec![]
+                    };
+                    let mut beat = measure_start;
+                    let mut writer = VoiceWriter::new(MeiLayer);
+                    for segment in layer_segments.iter().filter(|s| s.start >= measure_start && s.start < measure_end) {
+                        writer.fill(&mut beat, segment.start, rests, &mut marks, segment.event.duration.tuplet);
+                        writer.push_marks_at(segment.start, &mut marks);
+                        segment.write(&mut writer, accidentals.get(&segment.event.id()), self.key_at(segment.start));
+                        beat = segment.start + segment.length;
+                    }
+                    writer.fill(&mut beat, fill_to, rests, &mut marks, None);
+                    // Anything left falls after the last note of the measure
+                    writer.push_marks_at(measure_end, &mut marks);
+                    let mut mei_layer = ir::Layer {
+                        n: Some(layer + 1),
+                        ..Default::default()
+                    };
+                    mei_layer.events = writer.finish().1;
+                    mei_staff.layers.push(mei_layer);
+                }
+                measure.staves.push(mei_staff);
+                // Dynamics hang off the measure, placed by beat within it
+                let beat_length = Pulse::new(1, self.meter_at(measure_start).unit as i64);
+                for (pulse, dynamic) in staff_info.dynamics.iter().filter(|(p, _)| *p >= measure_start && *p < measure_end) {
+                    let beat = (pulse - measure_start) / beat_length;
+                    measure.dynams.push(ir::Dynam {
+                        staff: Some(staff + 1),
+                        tstamp: Some(format!("{}", 1.0 + beat.num() as f64 / beat.den() as f64)),
+                        text: dynamic.to_string(),
+                    });
+                }
+            }
+            written.push((i, score_def, measure));
+        }
+        written
+    }
 }
 
-#[derive(Clone, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
-pub struct Event {
-    event_id: u32,
-    note: Note,
-    duration: Pulse,
+// The part of an event which falls within a single measure. Events which cross
+// a barline are split into several segments which get tied together.
+struct Segment<'a> {
+    event: &'a Event,
     start: Pulse,
+    length: Pulse,
+    piece: usize,
+    last: bool,
 }
 
-impl Event {
-    pub fn id(&self) -> u32 {
-        self.event_id
+impl<'a> Segment<'a> {
+    // The values the segment is written with and how each is tied, using the
+    // MEI names: "i" starts a tie, "m" continues it and "t" ends it.
+    fn values(&self) -> Vec<(NoteValue, Option<&'static str>)> {
+        if self.piece == 0 && self.last {
+            return vec![(self.event.duration, None)];
+        }
+        let values = NoteValue::decompose(self.length, self.event.duration.tuplet);
+        let count = values.len();
+        values.into_iter().enumerate().map(|(i, value)| {
+            let tie = if self.piece == 0 && i == 0 {
+                "i"
+            } else if self.last && i + 1 == count {
+                "t"
+            } else {
+                "m"
+            };
+            (value, Some(tie))
+        }).collect()
     }
-}
 
-impl Ord for Event {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.start.cmp(&other.start)
+    // Only the start of an event shows accidentals, the rest is tied to it
+    fn write(&self, writer: &mut VoiceWriter<MeiLayer>, accidentals: Option<&Vec<AccidentalDisplay>>, key: Key) {
+        for (i, (value, tie)) in self.values().into_iter().enumerate() {
+            let first = self.piece == 0 && i == 0;
+            let suffix = if first { None } else { Some(format!("{}-{}", self.piece, i)) };
+            let accidentals = if first { accidentals } else { None };
+            writer.push(value, self.event.to_mei(value, suffix, tie, accidentals, key));
+        }
     }
 }
 
-impl PartialOrd for Event {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.start.cmp(&other.start))
+// Something written in a voice which takes no time
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Mark {
+    Clef(Clef),
+    Key(Key),
+    Meter(Meter),
+    Tempo(Tempo),
+    Dynamic(Dynamic),
+}
+
+impl Score {
+    // The marks of a staff by where they fall, in reverse so the next one can
+    // be popped off the end. The opening clef, key and meter are included for
+    // formats which don't have a header to put them in, and a key or meter
+    // which repeats the one before is left out. Only the first staff gets
+    // tempos since they apply to the whole score.
+    fn marks(&self, staff: u32) -> Vec<(Pulse, Mark)> {
+        let start = Pulse::default();
+        let staff_info = self.staff(staff).cloned().unwrap_or_default();
+        let mut marks = vec![(start, Mark::Clef(staff_info.clef_at(start)))];
+        marks.extend(staff_info.clefs.iter().filter(|(p, _)| *p > start).map(|(p, clef)| (p, Mark::Clef(*clef))));
+        let mut previous = self.key_at(start);
+        marks.push((start, Mark::Key(previous)));
+        for (p, key) in self.keys.iter().filter(|(p, _)| *p > start) {
+            if *key != previous {
+                marks.push((p, Mark::Key(*key)));
+            }
+            previous = *key;
+        }
+        let mut previous = self.meter_at(start);
+        marks.push((start, Mark::Meter(previous)));
+        for (p, meter) in self.meters.iter().filter(|(p, _)| *p > start) {
+            if *meter != previous {
+                marks.push((p, Mark::Meter(*meter)));
+            }
+            previous = *meter;
+        }
+        if staff == 0 {
+            marks.extend(self.tempos.iter().map(|(p, tempo)| (p, Mark::Tempo(*tempo))));
+        }
+        marks.extend(staff_info.dynamics.iter().map(|(p, dynamic)| (p, Mark::Dynamic(*dynamic))));
+        marks.sort_by_key(|(p, _)| *p);
+        marks.reverse();
+        marks
     }
 }
 
-#[derive(Copy, Clone, Default, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
-pub struct Pulse(pub i32);
+// How a format writes the rests, marks and tuplets of a voice
+trait VoiceFormat {
+    type Token;
 
+    // A rest, or a spacer in the voices after the first
+    fn rest(&mut self, value: NoteValue, rests: bool) -> Self::Token;
 
-impl std::ops::Add for Pulse {
-    type Output = Self;
-    fn add(self, other: Self) -> Self {
-        Pulse(self.0+other.0)
-    }
-}
-impl std::ops::AddAssign for Pulse {
-    fn add_assign(&mut self, other: Self) {
-        self.0 += other.0;
-    }
-}
-impl std::ops::Sub for Pulse {
-    type Output = Self;
-    fn sub(self, other: Self) -> Self {
-        Pulse(self.0-other.0)
-    }
-}
-impl std::ops::SubAssign for Pulse {
-    fn sub_assign(&mut self, other: Self) {
-        self.0 -= other.0;
-    }
+    // The token for a mark, or None when it's kept back for the next value or
+    // isn't written in the voice at all
+    fn mark(&mut self, mark: Mark) -> Option<Self::Token>;
+
+    // The tokens a finished tuplet is written as
+    fn tuplet(&mut self, group: TupletGroup<Self::Token>) -> Vec<Self::Token>;
 }
 
+struct TupletGroup<T> {
+    tuplet: Tuplet,
+    written: Pulse,
+    span: Pulse,
+    // How many values it holds, leaving out marks
+    values: u32,
+    tokens: Vec<T>,
+}
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
-pub struct Note {
-    pub pitch: Pitch,
-    pub octave: Octave,
+// Collects one measure of a voice, grouping runs of tuplet values and filling
+// gaps with rests. A tuplet is closed once it holds `num` of its first value,
+// or when a value with a different ratio comes along. Marks are passed in
+// reverse order, so the next one can be popped off the end.
+struct VoiceWriter<F: VoiceFormat> {
+    format: F,
+    tokens: Vec<F::Token>,
+    tuplet: Option<TupletGroup<F::Token>>,
 }
 
-impl Default for Note {
-    fn default() -> Self {
+impl<F: VoiceFormat> VoiceWriter<F> {
+    fn new(format: F) -> Self {
         Self {
-            pitch: Default::default(),
-            octave: Octave(4)
+            format,
+            tokens: vec![],
+            tuplet: None,
         }
     }
-}
 
-impl Note {
-    pub fn transpose(&mut self, semitones: i32) {
-        for _ in 0..semitones.abs() {
-            if semitones > 0 {
-                *self = self.successor();
-            } else {
-                *self = self.precurser();
+    fn push(&mut self, value: NoteValue, token: F::Token) {
+        if let Some(tuplet) = value.tuplet {
+            if self.tuplet.as_ref().map(|open| open.tuplet != tuplet).unwrap_or(false) {
+                self.close_tuplet();
+            }
+            let open = self.tuplet.get_or_insert_with(|| TupletGroup {
+                tuplet,
+                written: Pulse::default(),
+                span: NoteValue::new(value.base).written_length() * Pulse::whole(tuplet.num as i64),
+                values: 0,
+                tokens: vec![],
+            });
+            open.tokens.push(token);
+            open.values += 1;
+            open.written += value.written_length();
+            if open.written >= open.span {
+                self.close_tuplet();
+            }
+        } else {
+            self.close_tuplet();
+            self.tokens.push(token);
+        }
+    }
+
+    fn push_mark(&mut self, mark: Mark) {
+        if let Some(token) = self.format.mark(mark) {
+            match &mut self.tuplet {
+                Some(open) => open.tokens.push(token),
+                None => self.tokens.push(token),
             }
         }
     }
 
-    pub fn semitones(&self) -> u32 {
-        self.octave.0 * 12 + self.pitch.semitones()
+    // Fills the gap up to `to`, breaking the rests for any marks on the way.
+    // Marks at `to` itself are left for what comes next, so changes at a
+    // barline start the next measure.
+    fn fill(&mut self, beat: &mut Pulse, to: Pulse, rests: bool, marks: &mut Vec<(Pulse, Mark)>, next: Option<Tuplet>) {
+        while let Some((pulse, _)) = marks.last() {
+            if *pulse >= to {
+                break;
+            }
+            let (pulse, mark) = marks.pop().unwrap();
+            if pulse > *beat {
+                self.push_rests(pulse - *beat, rests, next);
+                *beat = pulse;
+            }
+            self.push_mark(mark);
+        }
+        if to > *beat {
+            self.push_rests(to - *beat, rests, next);
+            *beat = to;
+        }
     }
 
-    pub fn from_semitones(semitones: u32) -> Self {
-        let octave = semitones / 12;
-        let pitch = Pitch::from_semitones(semitones - octave*12);
-        Self {
-            pitch,
-            octave: Octave(octave)
+    fn push_marks_at(&mut self, at: Pulse, marks: &mut Vec<(Pulse, Mark)>) {
+        while marks.last().map(|(pulse, _)| *pulse <= at).unwrap_or(false) {
+            let (_, mark) = marks.pop().unwrap();
+            self.push_mark(mark);
         }
     }
 
-    pub fn successor(&self) -> Self {
-        Self::from_semitones(self.semitones() + 1)
+    // Rests are written in the tuplet which is still open or else that of
+    // the value which comes next
+    fn push_rests(&mut self, length: Pulse, rests: bool, next: Option<Tuplet>) {
+        let around = self.tuplet.as_ref().map(|open| open.tuplet).or(next);
+        for value in NoteValue::decompose(length, around) {
+            let token = self.format.rest(value, rests);
+            self.push(value, token);
+        }
+    }
+
+    fn close_tuplet(&mut self) {
+        if let Some(open) = self.tuplet.take() {
+            let tokens = self.format.tuplet(open);
+            self.tokens.extend(tokens);
+        }
     }
 
-    pub fn precurser(&self) -> Self {
-        Self::from_semitones(self.semitones() - 1)
+    fn finish(mut self) -> (F, Vec<F::Token>) {
+        self.close_tuplet();
+        (self.format, self.tokens)
     }
 }
 
+// MEI layers, where the only marks are clef changes. Dynamics hang off the
+// measure and the other changes go in a scoreDef.
+struct MeiLayer;
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
-pub struct Pitch {
-    pub class: PitchName,
-    pub accidental: Accidental
-}
+impl VoiceFormat for MeiLayer {
+    type Token = ir::EventLike;
 
-impl Default for Pitch {
-    fn default() -> Self {
-        Self {
-            class: PitchName::A,
-            accidental: Accidental::Natural
+    fn rest(&mut self, value: NoteValue, rests: bool) -> ir::EventLike {
+        let dur = Some(value.base);
+        let dots = if value.dots > 0 { Some(value.dots) } else { None };
+        if rests {
+            ir::EventLike::Rest(ir::Rest { dur, dots })
+        } else {
+            ir::EventLike::Space(ir::Space { dur, dots })
         }
     }
-}
 
-impl Pitch {
-    fn semitones(&self) -> u32 {
-        let base = self.class.semitones();
-        match self.accidental {
-            Accidental::Flat => base - 1,
-            Accidental::Natural => base,
-            Accidental::Sharp => base + 1,
+    fn mark(&mut self, mark: Mark) -> Option<ir::EventLike> {
+        match mark {
+            Mark::Clef(clef) => Some(ir::EventLike::Clef(clef.to_mei())),
+            _ => None,
         }
     }
 
-    fn from_semitones(semitones: u32) -> Self {
-        match semitones {
-            0 => Pitch { class: PitchName::A, accidental: Accidental::Natural },
-            1 => Pitch { class: PitchName::A, accidental: Accidental::Sharp },
-            2 => Pitch { class: PitchName::B, accidental: Accidental::Natural },
-            3 => Pitch { class: PitchName::C, accidental: Accidental::Natural },
-            4 => Pitch { class: PitchName::C, accidental: Accidental::Sharp },
-            5 => Pitch { class: PitchName::D, accidental: Accidental::Natural },
-            6 => Pitch { class: PitchName::D, accidental: Accidental::Sharp },
-            7 => Pitch { class: PitchName::E, accidental: Accidental::Natural },
-            8 => Pitch { class: PitchName::F, accidental: Accidental::Natural },
-            9 => Pitch { class: PitchName::F, accidental: Accidental::Sharp },
-            10 => Pitch { class: PitchName::G, accidental: Accidental::Natural },
-            11 => Pitch { class: PitchName::G, accidental: Accidental::Sharp },
-            _ => unreachable!(),
-        }
+    fn tuplet(&mut self, group: TupletGroup<ir::EventLike>) -> Vec<ir::EventLike> {
+        vec![ir::EventLike::Tuplet(ir::Tuplet {
+            num: Some(group.tuplet.num),
+            numbase: Some(group.tuplet.numbase),
+            events: group.tokens,
+        })]
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
-pub enum PitchName {
-    A,
-    B,
-    C,
-    D,
-    E,
-    F,
-    G,
+// Accidentals carry on through the measure across all the voices of a staff,
+// so they're worked out for every event starting in it before any is written
+fn accidentals_in_measure(state: &mut AccidentalState, segments: &BTreeMap<(u32, u32), Vec<Segment<'_>>>, staff: u32, (measure_start, measure_end): (Pulse, Pulse)) -> HashMap<u32, Vec<AccidentalDisplay>> {
+    let mut starts: Vec<&Segment> = segments.range((staff, 0)..=(staff, u32::MAX))
+        .flat_map(|(_, layer_segments)| layer_segments.iter())
+        .filter(|s| s.piece == 0 && s.start >= measure_start && s.start < measure_end)
+        .collect();
+    starts.sort_by_key(|s| s.start);
+    starts.iter()
+        .map(|s| (s.event.id(), s.event.notes.iter().map(|note| state.display(note)).collect()))
+        .collect()
 }
 
-impl PitchName {
-    fn semitones(&self) -> u32 {
-        match self {
-            PitchName::A => 0,
-            PitchName::B => 2,
-            PitchName::C => 3,
-            PitchName::D => 5,
-            PitchName::E => 7,
-            PitchName::F => 8,
-            PitchName::G => 10,
-        }
+// Cautionary accidentals which the accidental state would have added anyway
+// aren't marked on the notes of a read event, the same way to_mei decides
+// them. `shown` says which notes were written with one, and events have to
+// be settled in the order they start.
+fn settle_cautionary(state: &mut AccidentalState, event: &mut Event, shown: &[bool]) {
+    for (note, shown) in event.notes.iter_mut().zip(shown) {
+        let automatic = state.display(note) == AccidentalDisplay::Cautionary;
+        note.cautionary = *shown && !automatic;
     }
 }
 
-impl std::fmt::Display for PitchName {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let name = match self {
-            PitchName::A => "a",
-            PitchName::B => "b",
-            PitchName::C => "c",
-            PitchName::D => "d",
-            PitchName::E => "e",
-            PitchName::F => "f",
-            PitchName::G => "g",
-        };
-        write!(f, "{}", name)
+fn split_at_barlines<'a>(events: impl Iterator<Item=&'a Event>, measures: &[(Pulse, Pulse)]) -> Vec<Segment<'a>> {
+    let mut segments = vec![];
+    for event in events {
+        let end = event.end();
+        let mut start = event.start;
+        for (piece, &(_, measure_end)) in measures.iter().filter(|(_, measure_end)| *measure_end > event.start).enumerate() {
+            let last = end <= measure_end;
+            let segment_end = if last { end } else { measure_end };
+            segments.push(Segment {
+                event,
+                start,
+                length: segment_end - start,
+                piece,
+                last,
+            });
+            if last {
+                break;
+            }
+            start = measure_end;
+        }
     }
+    segments
 }
 
+#[derive(Clone, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Event {
+    event_id: u32,
+    #[serde(default)]
+    staff: u32,
+    #[serde(default)]
+    layer: u32,
+    #[serde(alias = "note", deserialize_with = "deserialize_notes")]
+    notes: Vec<Note>,
+    #[serde(deserialize_with = "rhythm::deserialize_note_value")]
+    duration: NoteValue,
+    start: Pulse,
+}
 
+// Events held a single note before there were chords
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SavedNotes {
+    Chord(Vec<Note>),
+    Single(Note),
+}
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
-pub enum Accidental {
-    Sharp,
-    Flat,
-    Natural
+fn deserialize_notes<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<Note>, D::Error> {
+    Ok(match SavedNotes::deserialize(deserializer)? {
+        SavedNotes::Chord(notes) => notes,
+        SavedNotes::Single(note) => vec![note],
+    })
 }
 
-impl std::fmt::Display for Accidental {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let name = match self {
-            Accidental::Sharp => "s",
-            Accidental::Flat => "f",
-            Accidental::Natural => "n",
+impl Event {
+    pub fn id(&self) -> u32 {
+        self.event_id
+    }
+
+    pub fn staff(&self) -> u32 {
+        self.staff
+    }
+
+    pub fn layer(&self) -> u32 {
+        self.layer
+    }
+
+    pub fn notes(&self) -> &[Note] {
+        &self.notes
+    }
+
+    pub fn start(&self) -> Pulse {
+        self.start
+    }
+
+    pub fn duration(&self) -> NoteValue {
+        self.duration
+    }
+
+    pub fn length(&self) -> Pulse {
+        self.duration.length()
+    }
+
+    pub fn end(&self) -> Pulse {
+        self.start + self.length()
+    }
+
+    pub fn is_chord(&self) -> bool {
+        self.notes.len() > 1
+    }
+
+    fn to_mei(&self, value: NoteValue, id_suffix: Option<String>, tie: Option<&str>, accidentals: Option<&Vec<AccidentalDisplay>>, key: Key) -> ir::EventLike {
+        let display = |i: usize| accidentals.and_then(|a| a.get(i)).copied().unwrap_or(AccidentalDisplay::Hidden);
+        let xml_id = match id_suffix {
+            Some(suffix) => format!("note_{}-{}", self.event_id, suffix),
+            None => format!("note_{}", self.event_id),
         };
-        write!(f, "{}", name)
+        let dur = Some(value.base);
+        let dots = if value.dots > 0 { Some(value.dots) } else { None };
+        let tie = tie.map(|t| t.to_string());
+        if let [note] = self.notes.as_slice() {
+            let mut note = note.to_mei(display(0), key);
+            note.xml_id = Some(xml_id);
+            note.dur = dur;
+            note.dots = dots;
+            note.tie = tie;
+            ir::EventLike::Note(note)
+        } else {
+            ir::EventLike::Chord(ir::Chord {
+                notes: self.notes.iter().enumerate().map(|(i, note)| {
+                    let mut note = note.to_mei(display(i), key);
+                    note.xml_id = Some(format!("{}_{}", xml_id, i));
+                    note
+                }).collect(),
+                xml_id: Some(xml_id),
+                dur,
+                dots,
+                tie,
+            })
+        }
     }
 }
 
+// Events in different voices, or which have been moved on top of each other,
+// may share a start so the id is needed to keep them distinct in the set.
+impl Ord for Event {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.staff, self.layer, self.start, self.event_id).cmp(&(other.staff, other.layer, other.start, other.event_id))
+    }
+}
 
-#[derive(Copy, Clone, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
-pub struct Octave(pub u32);
+impl PartialOrd for Event {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
 
 #[derive(Clone, Default, Debug, Serialize, Deserialize)]
 pub struct Selection {
     pub begin: Location,
     pub end: Location,
+    #[serde(default)]
+    pub staff: u32,
+    #[serde(default)]
+    pub layer: u32,
 }
 
 #[derive(Copy, Clone, Default, Debug, Serialize, Deserialize)]
@@ -324,32 +819,58 @@ pub struct Location(pub Pulse);
 
 pub trait Operation {
     fn apply(&self, ctx: &mut Context);
+    // What will need drawing again once the operation is applied, asked beforehand
+    fn changes(&self, _ctx: &Context) -> Vec<Change> {
+        vec![Change::All]
+    }
 }
 
 pub struct AppendNote {
     pub note: Note,
-    pub duration: Pulse,
+    pub duration: NoteValue,
     pub selections: Option<Vec<u32>>,
 }
 
-impl Operation for AppendNote {
-    fn apply(&self, ctx: &mut Context) {
-        let selections = if let Some(selections) = self.selections.clone() {
+impl AppendNote {
+    fn selections(&self, ctx: &Context) -> Vec<u32> {
+        if let Some(selections) = self.selections.clone() {
             selections
         } else {
             (0..ctx.selections.0.len() as u32).collect()
-        };
-        for selection_id in selections {
+        }
+    }
+}
+
+impl Operation for AppendNote {
+    fn apply(&self, ctx: &mut Context) {
+        for selection_id in self.selections(ctx) {
             let selection = &ctx.selections.0[selection_id as usize];
-            let mut location = selection.end;
+            let location = selection.end;
+            let staff = selection.staff;
+            let layer = selection.layer;
             ctx.insert_event_at_location(location, Event {
-                note: self.note,
+                staff,
+                layer,
+                notes: vec![self.note],
                 start: location.0,
                 duration: self.duration,
                 ..Default::default()
             });
         }
     }
+
+    // Anything after the new note moves along to make room
+    fn changes(&self, ctx: &Context) -> Vec<Change> {
+        self.selections(ctx).into_iter().map(|selection_id| {
+            let selection = &ctx.selections.0[selection_id as usize];
+            let location = selection.end.0;
+            if ctx.score.events_from(selection.staff, selection.layer, location).next().is_some() {
+                Change::From(location)
+            } else {
+                Change::Between(location, location + self.duration.length())
+            }
+        }).collect()
+    }
 }
 
 pub enum Duration {
@@ -365,28 +886,17 @@ pub struct MoveSelections {
 impl Operation for MoveSelections {
     fn apply(&self, ctx: &mut Context) {
         for selection_id in &self.selections {
-            let selection_begin = ctx.selections.0[*selection_id as usize].begin;
-            let delta_pulse = match self.delta {
-                Duration::Pulse(p) => p,
-                Duration::Event(d) => {
-                    let mut idx = ctx.score.events.len() as i32 -1;
-                    for (i, event) in ctx.score.events.iter().enumerate() {
-                        if event.start > selection_begin.0 {
-                            idx = i as i32;
-                            break
-                        }
-                    }
-                    let initial = ctx.score.events.iter().nth(idx as usize).unwrap().start;
-                    idx += d;
-                    idx = idx.max(0).min(ctx.score.events.len() as i32 -1);
-                    ctx.score.events.iter().nth(idx as usize).unwrap().start - initial
-                }
-            };
+            let selection = &ctx.selections.0[*selection_id as usize];
+            let delta_pulse = ctx.delta_pulse(&self.delta, selection.staff, selection.layer, selection.begin);
             let selection = &mut ctx.selections.0[*selection_id as usize];
             selection.begin.0 += delta_pulse;
             selection.end.0 += delta_pulse;
         }
     }
+
+    fn changes(&self, _ctx: &Context) -> Vec<Change> {
+        vec![]
+    }
 }
 
 pub struct MoveSelectionsEnd {
@@ -397,73 +907,91 @@ pub struct MoveSelectionsEnd {
 impl Operation for MoveSelectionsEnd {
     fn apply(&self, ctx: &mut Context) {
         for selection_id in &self.selections {
-            let selection_end = ctx.selections.0[*selection_id as usize].end;
-            let delta_pulse = match self.delta {
-                Duration::Pulse(p) => p,
-                Duration::Event(d) => {
-                    let mut idx = ctx.score.events.len() as i32 -1;
-                    for (i, event) in ctx.score.events.iter().enumerate() {
-                        if event.start > selection_end.0 {
-                            idx = i as i32;
-                            break
-                        }
-                    }
-                    let initial = ctx.score.events.iter().nth(idx as usize).unwrap().start;
-                    idx += d;
-                    idx = idx.max(0).min(ctx.score.events.len() as i32 -1);
-                    ctx.score.events.iter().nth(idx as usize).unwrap().start - initial
-                }
-            };
+            let selection = &ctx.selections.0[*selection_id as usize];
+            let delta_pulse = ctx.delta_pulse(&self.delta, selection.staff, selection.layer, selection.end);
             let selection = &mut ctx.selections.0[*selection_id as usize];
             selection.end.0 += delta_pulse;
-            println!("{:?}", delta_pulse);
             if selection.end.0 < selection.begin.0 {
                 std::mem::swap(&mut selection.end, &mut selection.begin);
             }
         }
     }
+
+    fn changes(&self, _ctx: &Context) -> Vec<Change> {
+        vec![]
+    }
 }
 
-pub struct MoveSelectionsContents {
-    pub delta: Duration,
+pub struct MoveSelectionsStaff {
+    pub delta: i32,
     pub selections: Vec<u32>
 }
 
-impl Operation for MoveSelectionsContents {
+impl Operation for MoveSelectionsStaff {
+    fn apply(&self, ctx: &mut Context) {
+        let last = ctx.score.staff_count() as i32 - 1;
+        for selection_id in &self.selections {
+            let selection = &mut ctx.selections.0[*selection_id as usize];
+            selection.staff = (selection.staff as i32 + self.delta).max(0).min(last) as u32;
+        }
+    }
+
+    fn changes(&self, _ctx: &Context) -> Vec<Change> {
+        vec![]
+    }
+}
+
+pub struct SetSelectionsLayer {
+    pub layer: u32,
+    pub selections: Vec<u32>
+}
+
+impl Operation for SetSelectionsLayer {
+    fn apply(&self, ctx: &mut Context) {
+        for selection_id in &self.selections {
+            ctx.selections.0[*selection_id as usize].layer = self.layer;
+        }
+    }
+
+    fn changes(&self, _ctx: &Context) -> Vec<Change> {
+        vec![]
+    }
+}
+
+pub struct MoveSelectionsContents {
+    pub delta: Duration,
+    pub selections: Vec<u32>
+}
+
+impl MoveSelectionsContents {
+    // Where an event on the selection's layer starts once the selection has moved
+    fn moved(selection: &Selection, delta_pulse: Pulse, start: Pulse) -> Pulse {
+        if start >= selection.begin.0 && start <= selection.end.0 {
+            start + delta_pulse
+        } else {
+            if delta_pulse > Pulse::default() {
+                if start <= selection.end.0 + delta_pulse {
+                    return start - delta_pulse;
+                }
+            } else {
+                if start >= selection.begin.0 + delta_pulse {
+                    return start - delta_pulse;
+                }
+            }
+            start
+        }
+    }
+}
+
+impl Operation for MoveSelectionsContents {
     fn apply(&self, ctx: &mut Context) {
         for selection_id in &self.selections {
-            let selection_begin = ctx.selections.0[*selection_id as usize].begin;
-            let delta_pulse = match self.delta {
-                Duration::Pulse(p) => p,
-                Duration::Event(d) => {
-                    let mut idx = ctx.score.events.len() as i32 -1;
-                    for (i, event) in ctx.score.events.iter().enumerate() {
-                        if event.start > selection_begin.0 {
-                            idx = i as i32;
-                            break
-                        }
-                    }
-                    let initial = ctx.score.events.iter().nth(idx as usize).unwrap().start;
-                    idx += d;
-                    idx = idx.max(0).min(ctx.score.events.len() as i32 -1);
-                    ctx.score.events.iter().nth(idx as usize).unwrap().start - initial
-                }
-            };
             let selection = ctx.selections.0[*selection_id as usize].clone();
+            let delta_pulse = ctx.delta_pulse(&self.delta, selection.staff, selection.layer, selection.begin);
             let mut new_events = BTreeSet::new();
             while let Some(mut e) = ctx.score.events.pop_first() {
-                if e.start >= selection.begin.0 && e.start <= selection.end.0 {
-                    e.start += delta_pulse;
-                } else {
-                    if delta_pulse.0 > 0 {
-                        if e.start <= selection.end.0 + delta_pulse {
-                            e.start -= delta_pulse;
-                        }
-                    } else {
-                        if e.start >= selection.begin.0 + delta_pulse {
-                            e.start -= delta_pulse;
-                        }
-                    }
+                if e.staff == selection.staff && e.layer == selection.layer {
+                    e.start = Self::moved(&selection, delta_pulse, e.start);
                 }
                 new_events.insert(e);
             }
@@ -474,10 +1002,38 @@ impl Operation for MoveSelectionsContents {
             selection.end.0 += delta_pulse;
         }
     }
+
+    // Covers both where the events were and where they end up
+    fn changes(&self, ctx: &Context) -> Vec<Change> {
+        self.selections.iter().filter_map(|selection_id| {
+            let selection = &ctx.selections.0[*selection_id as usize];
+            let delta_pulse = ctx.delta_pulse(&self.delta, selection.staff, selection.layer, selection.begin);
+            ctx.score.events_from(selection.staff, selection.layer, Pulse::default())
+                .filter_map(|e| {
+                    let start = Self::moved(selection, delta_pulse, e.start);
+                    if start == e.start {
+                        None
+                    } else {
+                        Some((start.min(e.start), (start + e.length()).max(e.end())))
+                    }
+                })
+                .reduce(|(begin, end), (b, e)| (begin.min(b), end.max(e)))
+                .map(|(begin, end)| Change::Between(begin, end))
+        }).collect()
+    }
+}
+
+pub enum Transposition {
+    // Semitones, spelled to fit the key
+    Chromatic(i32),
+    Interval(Interval),
+    // Steps along the scale of the key, keeping notes outside it just as far outside
+    Diatonic(i32),
+    QuarterTones(i32),
 }
 
 pub struct TransposeSelectionsContents {
-    pub semitones: i32,
+    pub transposition: Transposition,
     pub selections: Vec<u32>
 }
 
@@ -487,14 +1043,350 @@ impl Operation for TransposeSelectionsContents {
             let selection = ctx.selections.0[*selection_id as usize].clone();
             let mut new_events = BTreeSet::new();
             while let Some(mut e) = ctx.score.events.pop_first() {
-                if e.start >= selection.begin.0 && e.start <= selection.end.0 {
-                    e.note.transpose(self.semitones);
+                if selection.contains(&e) {
+                    let key = ctx.score.key_at(e.start);
+                    for note in &mut e.notes {
+                        match self.transposition {
+                            Transposition::Chromatic(semitones) => note.transpose(semitones, key),
+                            Transposition::Interval(interval) => note.transpose_by_interval(interval, key),
+                            Transposition::Diatonic(steps) => note.transpose_diatonic(steps, key),
+                            Transposition::QuarterTones(quarter_tones) => note.transpose_quarter_tones(quarter_tones, key),
+                        }
+                    }
                 }
                 new_events.insert(e);
             }
             ctx.score.events = new_events;
         }
     }
+
+    fn changes(&self, ctx: &Context) -> Vec<Change> {
+        ctx.selected_events(&self.selections)
+    }
+}
+
+// Adds a pitch to every event in the selections, turning them into chords.
+pub struct AddPitch {
+    pub note: Note,
+    pub selections: Vec<u32>
+}
+
+impl Operation for AddPitch {
+    fn apply(&self, ctx: &mut Context) {
+        for selection_id in &self.selections {
+            let selection = ctx.selections.0[*selection_id as usize].clone();
+            let mut new_events = BTreeSet::new();
+            while let Some(mut e) = ctx.score.events.pop_first() {
+                if selection.contains(&e) && !e.notes.contains(&self.note) {
+                    e.notes.push(self.note);
+                }
+                new_events.insert(e);
+            }
+            ctx.score.events = new_events;
+        }
+    }
+
+    fn changes(&self, ctx: &Context) -> Vec<Change> {
+        ctx.selected_events(&self.selections)
+    }
+}
+
+// Removes any note with the given pitch name and octave from the events in the
+// selections. An event always keeps at least one note.
+pub struct RemovePitch {
+    pub class: PitchName,
+    pub octave: Octave,
+    pub selections: Vec<u32>
+}
+
+impl Operation for RemovePitch {
+    fn apply(&self, ctx: &mut Context) {
+        for selection_id in &self.selections {
+            let selection = ctx.selections.0[*selection_id as usize].clone();
+            let mut new_events = BTreeSet::new();
+            while let Some(mut e) = ctx.score.events.pop_first() {
+                if selection.contains(&e) {
+                    let remaining: Vec<Note> = e.notes.iter().copied().filter(|n| n.pitch.class != self.class || n.octave != self.octave).collect();
+                    if !remaining.is_empty() {
+                        e.notes = remaining;
+                    }
+                }
+                new_events.insert(e);
+            }
+            ctx.score.events = new_events;
+        }
+    }
+
+    fn changes(&self, ctx: &Context) -> Vec<Change> {
+        ctx.selected_events(&self.selections)
+    }
+}
+
+// Steps every note in the selections to its next accidental, see Accidental::next
+pub struct CycleSelectionsAccidental {
+    pub selections: Vec<u32>
+}
+
+impl Operation for CycleSelectionsAccidental {
+    fn apply(&self, ctx: &mut Context) {
+        for selection_id in &self.selections {
+            let selection = ctx.selections.0[*selection_id as usize].clone();
+            let mut new_events = BTreeSet::new();
+            while let Some(mut e) = ctx.score.events.pop_first() {
+                if selection.contains(&e) {
+                    for note in &mut e.notes {
+                        note.pitch.accidental = note.pitch.accidental.next();
+                    }
+                }
+                new_events.insert(e);
+            }
+            ctx.score.events = new_events;
+        }
+    }
+
+    fn changes(&self, ctx: &Context) -> Vec<Change> {
+        ctx.selected_events(&self.selections)
+    }
+}
+
+// Sets how many cents the notes in the selections sound away from their written pitch
+pub struct SetSelectionsDetune {
+    pub cents: i32,
+    pub selections: Vec<u32>
+}
+
+impl Operation for SetSelectionsDetune {
+    fn apply(&self, ctx: &mut Context) {
+        for selection_id in &self.selections {
+            let selection = ctx.selections.0[*selection_id as usize].clone();
+            let mut new_events = BTreeSet::new();
+            while let Some(mut e) = ctx.score.events.pop_first() {
+                if selection.contains(&e) {
+                    for note in &mut e.notes {
+                        note.detune = self.cents;
+                    }
+                }
+                new_events.insert(e);
+            }
+            ctx.score.events = new_events;
+        }
+    }
+
+    // Only heard, the written notes stay the same
+    fn changes(&self, _ctx: &Context) -> Vec<Change> {
+        vec![]
+    }
+}
+
+pub struct ToggleSelectionsCautionary {
+    pub selections: Vec<u32>
+}
+
+impl Operation for ToggleSelectionsCautionary {
+    fn apply(&self, ctx: &mut Context) {
+        for selection_id in &self.selections {
+            let selection = ctx.selections.0[*selection_id as usize].clone();
+            let mut new_events = BTreeSet::new();
+            while let Some(mut e) = ctx.score.events.pop_first() {
+                if selection.contains(&e) {
+                    for note in &mut e.notes {
+                        note.cautionary = !note.cautionary;
+                    }
+                }
+                new_events.insert(e);
+            }
+            ctx.score.events = new_events;
+        }
+    }
+
+    fn changes(&self, ctx: &Context) -> Vec<Change> {
+        ctx.selected_events(&self.selections)
+    }
+}
+
+// Sets the meter from the start of each selection onward. `None` removes a
+// meter change at that point.
+pub struct SetMeter {
+    pub meter: Option<Meter>,
+    pub selections: Vec<u32>
+}
+
+impl Operation for SetMeter {
+    fn apply(&self, ctx: &mut Context) {
+        for selection_id in &self.selections {
+            let pulse = ctx.selections.0[*selection_id as usize].begin.0;
+            match self.meter {
+                Some(meter) => ctx.score.meters.insert(pulse, meter),
+                None => {
+                    ctx.score.meters.remove(pulse);
+                }
+            }
+        }
+    }
+
+    // The measure a change falls in can be cut short, and every barline after it moves
+    fn changes(&self, ctx: &Context) -> Vec<Change> {
+        let measures = ctx.score.measures();
+        self.selections.iter().map(|selection_id| {
+            let pulse = ctx.selections.0[*selection_id as usize].begin.0;
+            match measures.iter().rev().find(|(start, _)| *start <= pulse) {
+                Some(&(start, _)) if start > Pulse::default() => Change::From(start),
+                _ => Change::All,
+            }
+        }).collect()
+    }
+}
+
+// Sets the key from the start of each selection onward. `None` removes a
+// key change at that point.
+pub struct SetKey {
+    pub key: Option<Key>,
+    pub selections: Vec<u32>
+}
+
+impl Operation for SetKey {
+    fn apply(&self, ctx: &mut Context) {
+        for selection_id in &self.selections {
+            let pulse = ctx.selections.0[*selection_id as usize].begin.0;
+            match self.key {
+                Some(key) => ctx.score.keys.insert(pulse, key),
+                None => {
+                    ctx.score.keys.remove(pulse);
+                }
+            }
+        }
+    }
+
+    fn changes(&self, ctx: &Context) -> Vec<Change> {
+        ctx.changes_from(&self.selections)
+    }
+}
+
+// Sets the clef of each selection's staff from the start of the selection
+// onward. `None` removes a clef change at that point.
+pub struct SetClef {
+    pub clef: Option<Clef>,
+    pub selections: Vec<u32>
+}
+
+impl Operation for SetClef {
+    fn apply(&self, ctx: &mut Context) {
+        for selection_id in &self.selections {
+            let selection = &ctx.selections.0[*selection_id as usize];
+            let pulse = selection.begin.0;
+            let staff = selection.staff;
+            if let Some(staff) = ctx.score.staff_mut(staff) {
+                match self.clef {
+                    Some(clef) => staff.clefs.insert(pulse, clef),
+                    None => {
+                        staff.clefs.remove(pulse);
+                    }
+                }
+            }
+        }
+    }
+
+    fn changes(&self, ctx: &Context) -> Vec<Change> {
+        ctx.changes_from(&self.selections)
+    }
+}
+
+// Sets the tempo from the start of each selection onward. `None` removes a
+// tempo change at that point.
+pub struct SetTempo {
+    pub tempo: Option<Tempo>,
+    pub selections: Vec<u32>
+}
+
+impl Operation for SetTempo {
+    fn apply(&self, ctx: &mut Context) {
+        for selection_id in &self.selections {
+            let pulse = ctx.selections.0[*selection_id as usize].begin.0;
+            match self.tempo {
+                Some(tempo) => ctx.score.tempos.insert(pulse, tempo),
+                None => {
+                    ctx.score.tempos.remove(pulse);
+                }
+            }
+        }
+    }
+
+    // Tempos are only written where they change, so the next change may come or go
+    fn changes(&self, ctx: &Context) -> Vec<Change> {
+        self.selections.iter().flat_map(|selection_id| {
+            let pulse = ctx.selections.0[*selection_id as usize].begin.0;
+            if pulse == Pulse::default() {
+                return vec![Change::All];
+            }
+            let next = ctx.score.tempos.iter().map(|(p, _)| p).find(|p| *p > pulse).unwrap_or(pulse);
+            vec![Change::Between(pulse, pulse), Change::Between(next, next)]
+        }).collect()
+    }
+}
+
+// Marks a dynamic on each selection's staff at the start of the selection.
+// `None` removes the mark there.
+pub struct SetDynamic {
+    pub dynamic: Option<Dynamic>,
+    pub selections: Vec<u32>
+}
+
+impl Operation for SetDynamic {
+    fn apply(&self, ctx: &mut Context) {
+        for selection_id in &self.selections {
+            let selection = &ctx.selections.0[*selection_id as usize];
+            let pulse = selection.begin.0;
+            let staff = selection.staff;
+            if let Some(staff) = ctx.score.staff_mut(staff) {
+                match self.dynamic {
+                    Some(dynamic) => staff.dynamics.insert(pulse, dynamic),
+                    None => {
+                        staff.dynamics.remove(pulse);
+                    }
+                }
+            }
+        }
+    }
+
+    fn changes(&self, ctx: &Context) -> Vec<Change> {
+        self.selections.iter().map(|selection_id| {
+            let pulse = ctx.selections.0[*selection_id as usize].begin.0;
+            Change::Between(pulse, pulse)
+        }).collect()
+    }
+}
+
+// Sets the MIDI program of the part holding each selection's staff
+pub struct SetPartProgram {
+    pub program: u8,
+    pub selections: Vec<u32>
+}
+
+impl Operation for SetPartProgram {
+    fn apply(&self, ctx: &mut Context) {
+        for selection_id in &self.selections {
+            let staff = ctx.selections.0[*selection_id as usize].staff;
+            if let Some(part) = ctx.score.part_of_staff(staff) {
+                ctx.score.parts[part].program = self.program.min(127);
+            }
+        }
+    }
+
+    // Only heard, the program isn't written in the score
+    fn changes(&self, _ctx: &Context) -> Vec<Change> {
+        vec![]
+    }
+}
+
+// Replaces the title, composer and so on
+pub struct SetMetadata {
+    pub metadata: Metadata,
+}
+
+impl Operation for SetMetadata {
+    fn apply(&self, ctx: &mut Context) {
+        ctx.score.metadata = self.metadata.clone();
+    }
 }
 
 pub struct DeleteSelections {
@@ -505,17 +1397,62 @@ impl Operation for DeleteSelections {
     fn apply(&self, ctx: &mut Context) {
         for selection_id in &self.selections {
             let selection = &ctx.selections.0[*selection_id as usize];
-            ctx.score.events.retain(|event| {
-                if event.start >= selection.begin.0 && event.start <= selection.end.0 {
-                    false
-                } else {
-                    true
+            ctx.score.events.retain(|event| !selection.contains(event));
+        }
+    }
+
+    fn changes(&self, ctx: &Context) -> Vec<Change> {
+        ctx.selected_events(&self.selections)
+    }
+}
+
+pub struct AddPart {
+    pub name: String,
+    pub staves: u32,
+}
+
+impl Operation for AddPart {
+    fn apply(&self, ctx: &mut Context) {
+        ctx.score.parts.push(Part {
+            name: self.name.clone(),
+            staves: (0..self.staves.max(1)).map(|_| Staff::default()).collect(),
+            ..Default::default()
+        });
+    }
+}
+
+// Adds a staff to the bottom of the part containing each selection's staff.
+pub struct AddStaff {
+    pub selections: Vec<u32>
+}
+
+impl Operation for AddStaff {
+    fn apply(&self, ctx: &mut Context) {
+        let mut parts: Vec<usize> = self.selections.iter().filter_map(|selection_id| {
+            let staff = ctx.selections.0[*selection_id as usize].staff;
+            ctx.score.part_of_staff(staff)
+        }).collect();
+        parts.sort();
+        parts.dedup();
+        for part in parts.into_iter().rev() {
+            let new_staff = ctx.score.first_staff_of_part(part) + ctx.score.parts[part].staves.len() as u32;
+            ctx.score.shift_staves(new_staff, 1);
+            for selection in &mut ctx.selections.0 {
+                if selection.staff >= new_staff {
+                    selection.staff += 1;
                 }
-            });
+            }
+            ctx.score.parts[part].staves.push(Staff::default());
         }
     }
 }
 
+impl Selection {
+    pub fn contains(&self, event: &Event) -> bool {
+        event.staff == self.staff && event.layer == self.layer && event.start >= self.begin.0 && event.start <= self.end.0
+    }
+}
+
 #[derive(Clone, Default, Debug, Serialize, Deserialize)]
 pub struct Selections(pub Vec<Selection>);
 
@@ -524,37 +1461,56 @@ pub struct Context {
     pub score: Score,
     pub selections: Selections,
     next_id: u32,
+    #[serde(skip)]
+    history: History,
+    #[serde(skip)]
+    dirty: Dirty,
 }
 
 impl Default for Context {
     fn default() -> Self {
         Self {
             score: Score::default(),
-            selections: Selections(vec![Selection { begin: Location(Pulse(0)), end: Location(Pulse(0)) }]),
+            selections: Selections(vec![Selection { begin: Location(Pulse::default()), end: Location(Pulse::default()), staff: 0, layer: 0 }]),
             next_id: 0,
+            history: History::default(),
+            dirty: Dirty::default(),
         }
     }
 }
 
 impl Context {
+    // Starts editing an existing score with a single selection at its start
+    pub fn from_score(score: Score) -> Self {
+        let next_id = score.events.iter().map(|e| e.event_id + 1).max().unwrap_or(0);
+        Self {
+            score,
+            next_id,
+            ..Default::default()
+        }
+    }
+
     fn insert_event_at_location(&mut self, location: Location, mut event: Event) {
         let mut new_events = BTreeSet::new();
         let insertion_beat = location.0;
 
-        while let Some(mut event) = self.score.events.pop_first() {
-            if event.start >= insertion_beat {
-                event.start += event.duration;
+        while let Some(mut e) = self.score.events.pop_first() {
+            if e.staff == event.staff && e.layer == event.layer && e.start >= insertion_beat {
+                e.start += event.length();
             }
-            new_events.insert(event);
+            new_events.insert(e);
         }
         self.score.events = new_events;
 
         for selection in &mut self.selections.0 {
+            if selection.staff != event.staff || selection.layer != event.layer {
+                continue;
+            }
             if selection.begin.0 >= insertion_beat {
-                selection.begin.0 += event.duration;
+                selection.begin.0 += event.length();
             }
             if selection.end.0 >= insertion_beat {
-                selection.end.0 += event.duration;
+                selection.end.0 += event.length();
             }
         }
 
@@ -564,16 +1520,348 @@ impl Context {
         self.score.events.insert(event);
     }
 
+    fn delta_pulse(&self, delta: &Duration, staff: u32, layer: u32, from: Location) -> Pulse {
+        match delta {
+            Duration::Pulse(p) => *p,
+            Duration::Event(d) => {
+                let starts: Vec<Pulse> = self.score.events_on_layer(staff, layer).map(|e| e.start).collect();
+                if starts.is_empty() {
+                    return Pulse::default();
+                }
+                let mut idx = starts.len() as i32 - 1;
+                for (i, start) in starts.iter().enumerate() {
+                    if *start > from.0 {
+                        idx = i as i32;
+                        break
+                    }
+                }
+                let initial = starts[idx as usize];
+                idx += d;
+                idx = idx.max(0).min(starts.len() as i32 - 1);
+                starts[idx as usize] - initial
+            }
+        }
+    }
+
     pub fn events_in_selection(&self, selection: usize) -> impl Iterator<Item=&Event> {
         let selection = &self.selections.0[selection];
-        let mut iter = self.score.events.iter();
-        std::iter::from_fn(move || {
-            while let Some(e) = iter.next() {
-                if e.start >= selection.begin.0 && e.start <= selection.end.0 {
-                    return Some(e);
-                }
+        self.score.events.iter().filter(move |e| selection.contains(e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(event_id: u32, duration: NoteValue, start: Pulse) -> Event {
+        Event {
+            event_id,
+            duration,
+            start,
+            ..Default::default()
+        }
+    }
+
+    fn four_four(count: i64) -> Vec<(Pulse, Pulse)> {
+        (0..count).map(|i| (Pulse::whole(i), Pulse::whole(i + 1))).collect()
+    }
+
+    fn first_measure(mei: &ir::Mei) -> &ir::Measure {
+        let section = &mei.music.as_ref().unwrap().body.as_ref().unwrap().mdivs[0].score.as_ref().unwrap().sections[0];
+        section.children.iter().find_map(|c| match c {
+            ir::SectionLike::Measure(m) => Some(m),
+            _ => None,
+        }).unwrap()
+    }
+
+    // A whole note on a staff
+    fn staff_event(event_id: u32, staff: u32, start: Pulse) -> Event {
+        Event {
+            staff,
+            notes: vec![Note::default()],
+            ..event(event_id, NoteValue::new(1), start)
+        }
+    }
+
+    #[test]
+    fn notes_within_a_measure_are_left_whole() {
+        let events = [event(0, NoteValue::new(2), Pulse::new(1, 4))];
+        let segments = split_at_barlines(events.iter(), &four_four(2));
+        assert_eq!(segments.len(), 1);
+        assert_eq!((segments[0].start, segments[0].length), (Pulse::new(1, 4), Pulse::new(1, 2)));
+        assert_eq!(segments[0].values(), vec![(NoteValue::new(2), None)]);
+    }
+
+    #[test]
+    fn notes_crossing_a_barline_are_tied() {
+        let events = [event(0, NoteValue::new(2), Pulse::new(3, 4))];
+        let segments = split_at_barlines(events.iter(), &four_four(2));
+        let pieces: Vec<_> = segments.iter().map(|s| (s.start, s.length, s.piece, s.last)).collect();
+        assert_eq!(pieces, vec![
+            (Pulse::new(3, 4), Pulse::new(1, 4), 0, false),
+            (Pulse::whole(1), Pulse::new(1, 4), 1, true),
+        ]);
+        assert_eq!(segments[0].values(), vec![(NoteValue::new(4), Some("i"))]);
+        assert_eq!(segments[1].values(), vec![(NoteValue::new(4), Some("t"))]);
+    }
+
+    #[test]
+    fn long_notes_carry_the_tie_through_whole_measures() {
+        // A dotted whole from the last beat of the first bar to the first beat of the third
+        let events = [event(0, NoteValue::dotted(1, 1), Pulse::new(3, 4))];
+        let segments = split_at_barlines(events.iter(), &four_four(3));
+        let ties: Vec<_> = segments.iter().flat_map(|s| s.values()).collect();
+        assert_eq!(ties, vec![
+            (NoteValue::new(4), Some("i")),
+            (NoteValue::new(1), Some("m")),
+            (NoteValue::new(4), Some("t")),
+        ]);
+    }
+
+    #[test]
+    fn lengths_which_arent_one_value_are_tied_within_the_measure() {
+        // A dotted half from the sixth eighth, leaving three eighths on each side
+        let events = [event(0, NoteValue::dotted(2, 1), Pulse::new(5, 8))];
+        let segments = split_at_barlines(events.iter(), &four_four(2));
+        assert_eq!(segments[0].length, Pulse::new(3, 8));
+        assert_eq!(segments[0].values(), vec![
+            (NoteValue::new(4), Some("i")),
+            (NoteValue::new(8), Some("m")),
+        ]);
+        assert_eq!(segments[1].values(), vec![
+            (NoteValue::new(4), Some("m")),
+            (NoteValue::new(8), Some("t")),
+        ]);
+    }
+
+    #[test]
+    fn parts_and_staves_are_written_as_staff_groups() {
+        let mut score = Score::default();
+        score.parts[0].name = "Flute".to_string();
+        score.parts.push(Part {
+            name: "Piano".to_string(),
+            staves: vec![Staff::default(), Staff::default()],
+            ..Default::default()
+        });
+        for staff in 0..3 {
+            score.events.insert(staff_event(staff, staff, Pulse::default()));
+        }
+        let mei = score.to_mei();
+        let score_mei = mei.music.as_ref().unwrap().body.as_ref().unwrap().mdivs[0].score.as_ref().unwrap();
+
+        let staff_grp = score_mei.score_def.as_ref().unwrap().staff_grp.as_ref().unwrap();
+        assert_eq!(staff_grp.symbol.as_deref(), Some("bracket"));
+        assert_eq!(staff_grp.children.len(), 2);
+        match &staff_grp.children[0] {
+            ir::StaffGrpLike::StaffDef(def) => {
+                assert_eq!(def.n, Some(1));
+                assert_eq!(def.label.as_deref(), Some("Flute"));
+            }
+            other => panic!("{:?}", other),
+        }
+        match &staff_grp.children[1] {
+            ir::StaffGrpLike::StaffGrp(grp) => {
+                assert_eq!(grp.label.as_deref(), Some("Piano"));
+                assert_eq!(grp.symbol.as_deref(), Some("brace"));
+                let ns: Vec<_> = grp.children.iter().map(|c| match c {
+                    ir::StaffGrpLike::StaffDef(def) => def.n,
+                    other => panic!("{:?}", other),
+                }).collect();
+                assert_eq!(ns, vec![Some(2), Some(3)]);
+            }
+            other => panic!("{:?}", other),
+        }
+
+        let measure = first_measure(&mei);
+        assert_eq!(measure.staves.iter().map(|s| s.n).collect::<Vec<_>>(), vec![Some(1), Some(2), Some(3)]);
+        for staff in &measure.staves {
+            match &staff.layers[0].events[0] {
+                ir::EventLike::Note(note) => assert_eq!(note.xml_id, Some(format!("note_{}", staff.n.unwrap() - 1))),
+                other => panic!("{:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn voices_are_written_as_numbered_layers() {
+        let mut score = Score::default();
+        score.events.insert(staff_event(0, 0, Pulse::default()));
+        // Overlaps the first voice, and leaves a gap before it
+        let mut upper = staff_event(1, 0, Pulse::new(1, 2));
+        upper.layer = 2;
+        upper.duration = NoteValue::new(2);
+        score.events.insert(upper);
+        assert_eq!(score.layers_on_staff(0), vec![0, 2]);
+        assert_eq!(score.events_on_staff(0).count(), 2);
+
+        let mei = score.to_mei();
+        let layers = &first_measure(&mei).staves[0].layers;
+        assert_eq!(layers.iter().map(|l| l.n).collect::<Vec<_>>(), vec![Some(1), Some(3)]);
+        assert!(matches!(layers[0].events[..], [ir::EventLike::Note(_)]));
+        // The gap in the second voice is space rather than rests
+        assert!(matches!(layers[1].events[..], [ir::EventLike::Space(_), ir::EventLike::Note(_)]));
+    }
+
+    #[test]
+    fn events_at_the_same_start_are_kept_apart() {
+        let mut score = Score::default();
+        score.events.insert(staff_event(0, 0, Pulse::default()));
+        score.events.insert(staff_event(1, 0, Pulse::default()));
+        assert_eq!(score.events.iter().map(|e| e.id()).collect::<Vec<_>>(), vec![0, 1]);
+    }
+
+    fn first_event(ctx: &Context) -> ir::EventLike {
+        let mut mei = ctx.score.to_mei();
+        let mut score = mei.music.take().unwrap().body.take().unwrap().mdivs.remove(0).score.take().unwrap();
+        let mut measure = score.sections.remove(0).children.into_iter().find_map(|c| match c {
+            ir::SectionLike::Measure(m) => Some(m),
+            _ => None,
+        }).unwrap();
+        measure.staves.remove(0).layers.remove(0).events.remove(0)
+    }
+
+    #[test]
+    fn adding_and_removing_pitches_makes_and_unmakes_chords() {
+        let mut ctx = Context::default();
+        let c = Note::new(Pitch { class: PitchName::C, accidental: Accidental::Natural }, Octave(4));
+        let e = Note::new(Pitch { class: PitchName::E, accidental: Accidental::Natural }, Octave(4));
+        ctx.apply(&AppendNote { note: c, duration: NoteValue::new(4), selections: None });
+        ctx.selections.0[0] = Selection { begin: Location(Pulse::default()), end: Location(Pulse::default()), staff: 0, layer: 0 };
+
+        ctx.apply(&AddPitch { note: e, selections: vec![0] });
+        match first_event(&ctx) {
+            ir::EventLike::Chord(chord) => {
+                assert_eq!(chord.xml_id.as_deref(), Some("note_0"));
+                assert_eq!(chord.notes.iter().map(|n| n.pname.clone().unwrap()).collect::<Vec<_>>(), vec!["c", "e"]);
+            }
+            other => panic!("{:?}", other),
+        }
+
+        ctx.apply(&RemovePitch { class: PitchName::E, octave: Octave(4), selections: vec![0] });
+        match first_event(&ctx) {
+            ir::EventLike::Note(note) => assert_eq!(note.pname.as_deref(), Some("c")),
+            other => panic!("{:?}", other),
+        }
+        // The last note of an event can't be removed
+        ctx.apply(&RemovePitch { class: PitchName::C, octave: Octave(4), selections: vec![0] });
+        assert!(matches!(first_event(&ctx), ir::EventLike::Note(_)));
+    }
+
+    #[test]
+    fn gaps_in_a_quintuplet_are_rests_within_it() {
+        let mut score = Score::default();
+        let quintuplet = NoteValue { base: 16, dots: 0, tuplet: Some(Tuplet { num: 5, numbase: 4 }) };
+        for i in [0, 1, 3, 4] {
+            let mut e = staff_event(i, 0, Pulse::new(i as i64, 20));
+            e.duration = quintuplet;
+            score.events.insert(e);
+        }
+        let mei = score.to_mei();
+        let events = &first_measure(&mei).staves[0].layers[0].events;
+        match &events[0] {
+            ir::EventLike::Tuplet(tuplet) => {
+                assert_eq!((tuplet.num, tuplet.numbase), (Some(5), Some(4)));
+                assert!(matches!(tuplet.events[..], [
+                    ir::EventLike::Note(_),
+                    ir::EventLike::Note(_),
+                    ir::EventLike::Rest(ir::Rest { dur: Some(16), .. }),
+                    ir::EventLike::Note(_),
+                    ir::EventLike::Note(_),
+                ]));
             }
-            return None;
-        })
+            other => panic!("{:?}", other),
+        }
+        assert!(!events[1..].iter().any(|e| matches!(e, ir::EventLike::Tuplet(_))));
+    }
+
+    #[test]
+    fn barlines_follow_meter_changes() {
+        let mut score = Score::default();
+        score.meters.insert(Pulse::whole(1), Meter { count: 3, unit: 4 });
+        score.events.insert(event(0, NoteValue::new(1), Pulse::default()));
+        score.events.insert(event(1, NoteValue::new(1), Pulse::whole(1)));
+        assert_eq!(score.measures(), vec![
+            (Pulse::default(), Pulse::whole(1)),
+            (Pulse::whole(1), Pulse::new(7, 4)),
+            (Pulse::new(7, 4), Pulse::new(10, 4)),
+        ]);
+        let segments = split_at_barlines(score.events.iter(), &score.measures());
+        assert_eq!(segments.iter().map(|s| s.length).collect::<Vec<_>>(), vec![
+            Pulse::whole(1),
+            Pulse::new(3, 4),
+            Pulse::new(1, 4),
+        ]);
+    }
+
+    #[test]
+    fn clefs_are_written_per_staff_and_where_they_change() {
+        let mut score = Score::default();
+        let mut bass = Staff::default();
+        bass.clefs.insert(Pulse::default(), Clef::bass());
+        score.parts[0].staves.push(bass);
+        score.parts[0].staves[0].clefs.insert(Pulse::new(1, 2), Clef::alto());
+        for (i, &start) in [Pulse::default(), Pulse::new(3, 4)].iter().enumerate() {
+            score.events.insert(Event {
+                notes: vec![Note::default()],
+                ..event(i as u32, NoteValue::new(4), start)
+            });
+        }
+        let mei = score.to_mei();
+
+        let score_def = mei.music.as_ref().unwrap().body.as_ref().unwrap().mdivs[0].score.as_ref().unwrap().score_def.as_ref().unwrap();
+        let clefs: Vec<_> = match &score_def.staff_grp.as_ref().unwrap().children[0] {
+            ir::StaffGrpLike::StaffGrp(grp) => grp.children.iter().map(|c| match c {
+                ir::StaffGrpLike::StaffDef(def) => (def.clef_shape.clone().unwrap(), def.clef_line.unwrap()),
+                other => panic!("{:?}", other),
+            }).collect(),
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(clefs, vec![("G".to_string(), 2), ("F".to_string(), 4)]);
+
+        let events = &first_measure(&mei).staves[0].layers[0].events;
+        assert!(matches!(events[..], [
+            ir::EventLike::Note(_),
+            ir::EventLike::Rest(_),
+            ir::EventLike::Clef(ir::Clef { line: Some(3), .. }),
+            ir::EventLike::Rest(_),
+            ir::EventLike::Note(_),
+        ]), "{:?}", events);
+        let shape = match &events[2] {
+            ir::EventLike::Clef(clef) => clef.shape.as_deref(),
+            _ => None,
+        };
+        assert_eq!(shape, Some("C"));
+        // The opening clef is only in the staffDef
+        assert!(!first_measure(&mei).staves[1].layers[0].events.iter().any(|e| matches!(e, ir::EventLike::Clef(_))));
+    }
+
+    #[test]
+    fn measures_are_written_as_they_are_in_the_whole_score() {
+        let mut score = Score::default();
+        score.keys.insert(Pulse::whole(2), Key { fifths: 1, mode: Mode::Major });
+        for (i, &accidental) in [Accidental::Sharp, Accidental::Natural, Accidental::Natural].iter().enumerate() {
+            let mut e = event(i as u32, NoteValue::new(1), Pulse::whole(i as i64));
+            e.notes = vec![Note::new(Pitch { class: PitchName::C, accidental }, Octave(4))];
+            score.events.insert(e);
+        }
+        let mei = score.to_mei();
+        let section = &mei.music.as_ref().unwrap().body.as_ref().unwrap().mdivs[0].score.as_ref().unwrap().sections[0];
+        let whole: Vec<&ir::Measure> = section.children.iter().filter_map(|c| match c {
+            ir::SectionLike::Measure(m) => Some(m),
+            _ => None,
+        }).collect();
+
+        let written = score.measures_to_mei(&vec![1, 2].into_iter().collect());
+        assert_eq!(written.iter().map(|(i, _, _)| *i).collect::<Vec<_>>(), vec![1, 2]);
+        for (i, _, measure) in &written {
+            assert_eq!(measure, whole[*i]);
+        }
+        // The C after the C sharp is still cautionary
+        match &written[0].2.staves[0].layers[0].events[0] {
+            ir::EventLike::Note(note) => assert_eq!(note.accids[0].func.as_deref(), Some("caution")),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(written[0].1, None);
+        assert_eq!(written[1].1.as_ref().and_then(|d| d.key_sig.clone()), Some(Key { fifths: 1, mode: Mode::Major }.to_mei_sig()));
     }
 }
@@ -0,0 +1,303 @@
+use std::collections::BTreeMap;
+
+use crate::{Accidental, Clef, ClefShape, Key, Mark, Meter, Mode, Note, NoteValue, Pitch, PitchName, Pulse, Score, Segment, TupletGroup, VoiceFormat, VoiceWriter};
+
+// How the octave of each note is written
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PitchMode {
+    // Every note carries its own octave, c' being middle C
+    Absolute,
+    // Each note is taken as the nearest one to the note before it and only
+    // needs marks when it leaps more than a fourth
+    Relative,
+}
+
+// Writes indented text a line at a time
+#[derive(Default)]
+struct LyWriter {
+    out: String,
+    depth: usize,
+}
+
+impl LyWriter {
+    fn line(&mut self, text: &str) {
+        if !text.is_empty() {
+            for _ in 0..self.depth {
+                self.out.push_str("  ");
+            }
+        }
+        self.out.push_str(text);
+        self.out.push('\n');
+    }
+
+    fn open(&mut self, text: &str) {
+        self.line(text);
+        self.depth += 1;
+    }
+
+    fn close(&mut self, text: &str) {
+        self.depth -= 1;
+        self.line(text);
+    }
+}
+
+fn quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// Note names in LilyPond's default Dutch spelling
+fn pitch_name(pitch: Pitch) -> String {
+    let suffix = match pitch.accidental {
+        Accidental::Natural => "",
+        Accidental::Sharp => "is",
+        Accidental::Flat => "es",
+        Accidental::DoubleSharp => "isis",
+        Accidental::DoubleFlat => "eses",
+        Accidental::QuarterSharp => "ih",
+        Accidental::QuarterFlat => "eh",
+        Accidental::ThreeQuarterSharp => "isih",
+        Accidental::ThreeQuarterFlat => "eseh",
+    };
+    // E and A flat are "es" and "as" rather than "ees" and "aes"
+    match (pitch.class, suffix) {
+        (PitchName::E, "es") | (PitchName::E, "eses") | (PitchName::A, "es") | (PitchName::A, "eses") => format!("{}{}", pitch.class, &suffix[1..]),
+        _ => format!("{}{}", pitch.class, suffix),
+    }
+}
+
+fn octave_marks(octaves: i32) -> String {
+    if octaves > 0 {
+        "'".repeat(octaves as usize)
+    } else {
+        ",".repeat(-octaves as usize)
+    }
+}
+
+fn duration(value: NoteValue) -> String {
+    format!("{}{}", value.base, ".".repeat(value.dots as usize))
+}
+
+fn clef_command(clef: Clef) -> String {
+    let name = match (clef.shape, clef.line) {
+        (ClefShape::G, 1) => "french",
+        (ClefShape::G, 2) => "treble",
+        (ClefShape::C, 1) => "soprano",
+        (ClefShape::C, 2) => "mezzosoprano",
+        (ClefShape::C, 3) => "alto",
+        (ClefShape::C, 4) => "tenor",
+        (ClefShape::C, 5) => "baritone",
+        (ClefShape::F, 3) => "varbaritone",
+        (ClefShape::F, 4) => "bass",
+        (ClefShape::F, 5) => "subbass",
+        (shape, line) => {
+            log::warn!("LilyPond has no {:?} clef on line {}, using the usual line", shape, line);
+            match shape {
+                ClefShape::G => "treble",
+                ClefShape::C => "alto",
+                ClefShape::F => "bass",
+            }
+        }
+    };
+    match clef.octave {
+        0 => format!("\\clef {}", name),
+        octave => format!("\\clef \"{}{}{}\"", name, if octave < 0 { "_" } else { "^" }, 7 * octave.abs() + 1),
+    }
+}
+
+fn key_command(key: Key) -> String {
+    let mode = match key.mode {
+        Mode::Major => "major",
+        Mode::Minor => "minor",
+    };
+    format!("\\key {} \\{}", pitch_name(key.tonic()), mode)
+}
+
+fn time_command(meter: Meter) -> String {
+    format!("\\time {}/{}", meter.count, meter.unit)
+}
+
+// Tracks the previous pitch in relative mode, by letter steps above C0
+struct PitchWriter {
+    mode: PitchMode,
+    previous: i32,
+}
+
+impl PitchWriter {
+    fn new(mode: PitchMode) -> Self {
+        // \relative c' starts from middle C, the C of the fourth octave
+        Self { mode, previous: 4 * 7 }
+    }
+
+    fn note(&mut self, note: &Note, cautionary: bool) -> String {
+        let octaves = match self.mode {
+            PitchMode::Absolute => note.octave.0 as i32 - 3,
+            PitchMode::Relative => {
+                // The same letter within a fourth either way of the previous note
+                let step = note.step();
+                let nearest = step + (self.previous - step + 3).div_euclid(7) * 7;
+                self.previous = step;
+                (step - nearest) / 7
+            }
+        };
+        let cautionary = if cautionary && note.cautionary { "?" } else { "" };
+        format!("{}{}{}", pitch_name(note.pitch), octave_marks(octaves), cautionary)
+    }
+
+    // Each note of a chord follows the one before it and the chord as a whole
+    // is followed from its first note
+    fn notes(&mut self, notes: &[Note], cautionary: bool) -> String {
+        if let [note] = notes {
+            return self.note(note, cautionary);
+        }
+        let mut written = vec![];
+        let mut first = None;
+        for note in notes {
+            written.push(self.note(note, cautionary));
+            first.get_or_insert(self.previous);
+        }
+        if let Some(first) = first {
+            self.previous = first;
+        }
+        format!("<{}>", written.join(" "))
+    }
+}
+
+// Voices in LyWriter, with runs of tuplet values wrapped in \tuplet
+struct LyVoice;
+
+impl VoiceFormat for LyVoice {
+    type Token = String;
+
+    fn rest(&mut self, value: NoteValue, rests: bool) -> String {
+        format!("{}{}", if rests { "r" } else { "s" }, duration(value))
+    }
+
+    fn mark(&mut self, mark: Mark) -> Option<String> {
+        Some(match mark {
+            Mark::Clef(clef) => clef_command(clef),
+            Mark::Key(key) => key_command(key),
+            Mark::Meter(meter) => time_command(meter),
+            Mark::Tempo(tempo) => format!("\\tempo 4 = {}", tempo.bpm),
+            Mark::Dynamic(dynamic) => format!("<>\\{}", dynamic),
+        })
+    }
+
+    fn tuplet(&mut self, group: TupletGroup<String>) -> Vec<String> {
+        vec![format!("\\tuplet {}/{} {{ {} }}", group.tuplet.num, group.tuplet.numbase, group.tokens.join(" "))]
+    }
+}
+
+const VOICES: [&str; 4] = ["\\voiceOne", "\\voiceTwo", "\\voiceThree", "\\voiceFour"];
+
+impl Score {
+    // Writes a LilyPond file with a staff per staff of the score, grouped in a
+    // StaffGroup when there are several parts and a GrandStaff for parts with
+    // more than one staff. Each layer is a voice and every measure is a line
+    // ending in a bar check.
+    pub fn to_lilypond(&self, mode: PitchMode) -> String {
+        let mut measures = self.measures();
+        if measures.is_empty() {
+            measures.push((Pulse::default(), self.meter_at(Pulse::default()).length()));
+        }
+        let segments = self.segments(&measures);
+
+        let mut ly = LyWriter::default();
+        ly.line("\\version \"2.22.0\"");
+        ly.line("");
+        let fields = [
+            ("title", &self.metadata.title),
+            ("composer", &self.metadata.composer),
+            ("poet", &self.metadata.lyricist),
+            ("arranger", &self.metadata.arranger),
+        ];
+        if fields.iter().any(|(_, value)| !value.is_empty()) {
+            ly.open("\\header {");
+            for (field, value) in fields.iter().filter(|(_, value)| !value.is_empty()) {
+                ly.line(&format!("{} = {}", field, quote(value)));
+            }
+            ly.close("}");
+            ly.line("");
+        }
+
+        ly.open("\\score {");
+        let grouped = self.parts.len() > 1;
+        if grouped {
+            ly.open("\\new StaffGroup <<");
+        }
+        let mut staff = 0;
+        for part in &self.parts {
+            let with = if part.name.is_empty() { String::new() } else { format!(" \\with {{ instrumentName = {} }}", quote(&part.name)) };
+            if part.staves.len() > 1 {
+                ly.open(&format!("\\new GrandStaff{} <<", with));
+                for _ in &part.staves {
+                    self.write_lilypond_staff(&mut ly, staff, "", &measures, &segments, mode);
+                    staff += 1;
+                }
+                ly.close(">>");
+            } else {
+                self.write_lilypond_staff(&mut ly, staff, &with, &measures, &segments, mode);
+                staff += 1;
+            }
+        }
+        if grouped {
+            ly.close(">>");
+        }
+        ly.line("\\layout { }");
+        ly.close("}");
+        ly.out
+    }
+
+    fn write_lilypond_staff(&self, ly: &mut LyWriter, staff: u32, with: &str, measures: &[(Pulse, Pulse)], segments: &BTreeMap<(u32, u32), Vec<Segment<'_>>>, mode: PitchMode) {
+        let opening = match mode {
+            PitchMode::Absolute => "{",
+            PitchMode::Relative => "\\relative c' {",
+        };
+        let voices: Vec<Vec<String>> = segments.range((staff, 0)..=(staff, u32::MAX))
+            .enumerate()
+            .map(|(i, (_, layer_segments))| self.lilypond_voice(staff, i == 0, layer_segments, measures, mode))
+            .collect();
+        if let [lines] = voices.as_slice() {
+            ly.open(&format!("\\new Staff{} {}", with, opening));
+            for line in lines {
+                ly.line(line);
+            }
+            ly.close("}");
+        } else {
+            ly.open(&format!("\\new Staff{} <<", with));
+            for (i, lines) in voices.iter().enumerate() {
+                ly.open(&format!("\\new Voice {}", opening));
+                if let Some(voice) = VOICES.get(i) {
+                    ly.line(voice);
+                }
+                for line in lines {
+                    ly.line(line);
+                }
+                ly.close("}");
+            }
+            ly.close(">>");
+        }
+    }
+
+    fn lilypond_voice(&self, staff: u32, first: bool, layer_segments: &[Segment<'_>], measures: &[(Pulse, Pulse)], mode: PitchMode) -> Vec<String> {
+        let mut marks = if first { self.marks(staff) } else { vec![] };
+        let mut pitches = PitchWriter::new(mode);
+        let mut lines = vec![];
+        for &(measure_start, measure_end) in measures {
+            let mut line = VoiceWriter::new(LyVoice);
+            let mut beat = measure_start;
+            for segment in layer_segments.iter().filter(|s| s.start >= measure_start && s.start < measure_end) {
+                line.fill(&mut beat, segment.start, first, &mut marks, segment.event.duration.tuplet);
+                line.push_marks_at(segment.start, &mut marks);
+                for (n, (value, tie)) in segment.values().into_iter().enumerate() {
+                    let notes = pitches.notes(&segment.event.notes, segment.piece == 0 && n == 0);
+                    let tie = if matches!(tie, Some("i") | Some("m")) { "~" } else { "" };
+                    line.push(value, format!("{}{}{}", notes, duration(value), tie));
+                }
+                beat = segment.start + segment.length;
+            }
+            line.fill(&mut beat, measure_end, first, &mut marks, None);
+            lines.push(format!("{} |", line.finish().1.join(" ")));
+        }
+        lines
+    }
+}
@@ -0,0 +1,446 @@
+use std::collections::HashMap;
+
+use crate::{
+    Accidental, AccidentalState, Clef, Dynamic, Event, Key, Metadata, Meter, Note, NoteValue, Octave, Part, Pitch, PitchName, Pulse,
+    Score, Staff, Tempo, Timeline, Tuplet, SHORTEST_BASE,
+};
+
+// The opening values are left out when they're the defaults, as they are in a
+// new score, so reading back what to_mei wrote gives the same timelines
+pub(crate) fn set_at<T: PartialEq + Default>(timeline: &mut Timeline<T>, pulse: Pulse, value: T) {
+    if pulse.is_zero() && value == T::default() {
+        return;
+    }
+    timeline.insert(pulse, value);
+}
+
+// tstamp is a float, so the nearest fraction with a small denominator is taken
+fn beats_to_pulse(beats: f64) -> Pulse {
+    for den in 1..=1024 {
+        let num = (beats * den as f64).round();
+        if (beats * den as f64 - num).abs() < 1e-6 {
+            return Pulse::new(num as i64, den);
+        }
+    }
+    Pulse::new((beats * 1024.0).round() as i64, 1024)
+}
+
+fn apply_score_def(score: &mut Score, score_def: &ir::ScoreDef, pulse: Pulse) -> Result<(), String> {
+    if let (Some(count), Some(unit)) = (score_def.meter_count, score_def.meter_unit) {
+        if count == 0 || !unit.is_power_of_two() || unit > SHORTEST_BASE {
+            return Err(format!("Unsupported meter {}/{}", count, unit));
+        }
+        set_at(&mut score.meters, pulse, Meter { count, unit });
+    }
+    if let Some(sig) = &score_def.key_sig {
+        set_at(&mut score.keys, pulse, Key::from_mei(sig, score_def.key_mode.as_deref())?);
+    }
+    if let Some(bpm) = score_def.midi_bpm {
+        set_at(&mut score.tempos, pulse, Tempo { bpm });
+    }
+    Ok(())
+}
+
+fn read_staff(staff_def: &ir::StaffDef) -> Result<Staff, String> {
+    let mut staff = Staff {
+        lines: staff_def.lines.unwrap_or(5),
+        ..Default::default()
+    };
+    if staff_def.clef_shape.is_some() {
+        let clef = Clef::from_mei(staff_def.clef_shape.as_deref(), staff_def.clef_line, staff_def.clef_dis, staff_def.clef_dis_place.as_deref())?;
+        set_at(&mut staff.clefs, Pulse::default(), clef);
+    }
+    Ok(staff)
+}
+
+fn collect_staves(children: &[ir::StaffGrpLike], staves: &mut Vec<Staff>) -> Result<(), String> {
+    for child in children {
+        match child {
+            ir::StaffGrpLike::StaffDef(staff_def) => staves.push(read_staff(staff_def)?),
+            ir::StaffGrpLike::StaffGrp(group) => collect_staves(&group.children, staves)?,
+        }
+    }
+    Ok(())
+}
+
+// Each child of the outer group is a part: a lone staffDef is a part with one
+// staff and a nested group holds the staves of a bigger one
+fn read_parts(staff_grp: &ir::StaffGrp) -> Result<Vec<Part>, String> {
+    let mut parts = vec![];
+    for child in &staff_grp.children {
+        let (label, staves) = match child {
+            ir::StaffGrpLike::StaffDef(staff_def) => (&staff_def.label, vec![read_staff(staff_def)?]),
+            ir::StaffGrpLike::StaffGrp(group) => {
+                let mut staves = vec![];
+                collect_staves(&group.children, &mut staves)?;
+                (&group.label, staves)
+            }
+        };
+        if staves.is_empty() {
+            continue;
+        }
+        parts.push(Part {
+            name: label.clone().unwrap_or_default(),
+            staves,
+            ..Default::default()
+        });
+    }
+    Ok(parts)
+}
+
+// The note and whether its accidental was shown as cautionary. Notes with no
+// accidental at all take theirs from the key.
+fn read_note(note: &ir::Note, key: Key) -> Result<(Note, bool), String> {
+    let class: PitchName = note.pname.as_deref().ok_or_else(|| "Note without a pname".to_string())?.parse()?;
+    let cautionary = note.accids.iter().any(|a| a.func.as_deref() == Some("caution") || a.enclose.is_some());
+    // Written accidentals first, then the sounding ones, each either on the note or an <accid> in it
+    let accidental = match note.accid.as_deref()
+        .or_else(|| note.accids.iter().find_map(|a| a.accid.as_deref()))
+        .or(note.accid_ges.as_deref())
+        .or_else(|| note.accids.iter().find_map(|a| a.accid_ges.as_deref())) {
+        Some(accidental) => accidental.parse()?,
+        None => Accidental::from_alteration(key.alteration(class)).unwrap_or(Accidental::Natural),
+    };
+    Ok((Note::new(Pitch { class, accidental }, Octave(note.oct)), cautionary))
+}
+
+fn read_value(dur: Option<u32>, dots: Option<u32>, tuplet: Option<Tuplet>) -> Result<NoteValue, String> {
+    let base = dur.unwrap_or(4);
+    if !base.is_power_of_two() || base > SHORTEST_BASE {
+        return Err(format!("Unsupported dur {}", base));
+    }
+    let dots = dots.unwrap_or(0);
+    if dots > 2 {
+        return Err(format!("Unsupported dots {}", dots));
+    }
+    Ok(NoteValue { base, dots, tuplet })
+}
+
+// Ids look like "note_12", with a suffix on the later pieces of tied notes
+fn read_id(xml_id: &Option<String>) -> Option<u32> {
+    xml_id.as_deref()?.strip_prefix("note_")?.split(['-', '_']).next()?.parse().ok()
+}
+
+// Tied values waiting to be joined into the event they started
+struct TieChain {
+    event: usize,
+    length: Pulse,
+    tuplet: Option<Tuplet>,
+    // The later pieces and where they start, for when they can't be joined
+    pieces: Vec<(Read, Pulse)>,
+}
+
+impl TieChain {
+    // A single value for the whole chain, which may need the tuplet of its pieces
+    fn value(&self) -> Option<NoteValue> {
+        NoteValue::from_length(self.length).or_else(|| {
+            let tuplet = self.tuplet?;
+            let value = NoteValue::from_length(self.length / tuplet.ratio())?;
+            Some(NoteValue { tuplet: Some(tuplet), ..value })
+        })
+    }
+}
+
+// A note or chord as read, before its id and cautionary flags are settled
+struct Read {
+    id: Option<u32>,
+    tie: Option<String>,
+    value: NoteValue,
+    notes: Vec<(Note, bool)>,
+}
+
+struct Reader<'a> {
+    score: &'a mut Score,
+    events: Vec<(Option<u32>, Event)>,
+    ties: HashMap<(u32, u32), TieChain>,
+    // Events which start in the current measure of a staff with the
+    // cautionary accidentals they were shown with
+    starts: Vec<(Pulse, usize, Vec<bool>)>,
+}
+
+impl<'a> Reader<'a> {
+    fn read_layer(&mut self, events: &[ir::EventLike], staff: u32, layer: u32, position: &mut Pulse, tuplet: Option<Tuplet>, key: Key) -> Result<(), String> {
+        for event in events {
+            let read = match event {
+                ir::EventLike::Note(note) => Read {
+                    id: read_id(&note.xml_id),
+                    tie: note.tie.clone(),
+                    value: read_value(note.dur, note.dots, tuplet)?,
+                    notes: vec![read_note(note, key)?],
+                },
+                ir::EventLike::Chord(chord) => Read {
+                    id: read_id(&chord.xml_id),
+                    tie: chord.tie.clone(),
+                    value: read_value(chord.dur, chord.dots, tuplet)?,
+                    notes: chord.notes.iter().map(|note| read_note(note, key)).collect::<Result<_, _>>()?,
+                },
+                ir::EventLike::Rest(ir::Rest { dur, dots }) | ir::EventLike::Space(ir::Space { dur, dots }) => {
+                    *position += read_value(*dur, *dots, tuplet)?.length();
+                    continue;
+                }
+                ir::EventLike::Beam(beam) => {
+                    self.read_layer(&beam.events, staff, layer, position, tuplet, key)?;
+                    continue;
+                }
+                ir::EventLike::Tuplet(inner) => {
+                    let inner_tuplet = match (inner.num, inner.numbase) {
+                        (Some(0), _) | (_, Some(0)) => return Err("Tuplet with a count of 0".to_string()),
+                        (Some(num), Some(numbase)) => Some(Tuplet { num, numbase }),
+                        _ => tuplet,
+                    };
+                    self.read_layer(&inner.events, staff, layer, position, inner_tuplet, key)?;
+                    continue;
+                }
+                ir::EventLike::Clef(clef) => {
+                    let clef = Clef::from_mei(clef.shape.as_deref(), clef.line, clef.dis, clef.dis_place.as_deref())?;
+                    if let Some(staff) = self.score.staff_mut(staff) {
+                        staff.clefs.insert(*position, clef);
+                    }
+                    continue;
+                }
+            };
+            let length = read.value.length();
+            self.push(read, staff, layer, *position);
+            *position += length;
+        }
+        Ok(())
+    }
+
+    // The later pieces of a tie lengthen the event which started it, or are
+    // kept as events of their own when no single value covers the chain
+    fn push(&mut self, read: Read, staff: u32, layer: u32, start: Pulse) {
+        let tie = read.tie.as_deref();
+        if tie == Some("m") || tie == Some("t") {
+            let last = tie == Some("t");
+            if let Some(chain) = self.ties.get_mut(&(staff, layer)) {
+                chain.length += read.value.length();
+                chain.pieces.push((read, start));
+                if last {
+                    let chain = self.ties.remove(&(staff, layer)).unwrap();
+                    match chain.value() {
+                        Some(value) => self.events[chain.event].1.duration = value,
+                        None => {
+                            log::warn!("Tied notes at {:?} don't add up to a single value, keeping them apart", start);
+                            self.keep_pieces(chain, staff, layer);
+                        }
+                    }
+                }
+                return;
+            }
+        }
+        if tie == Some("i") {
+            if let Some(chain) = self.ties.remove(&(staff, layer)) {
+                log::warn!("A tie before {:?} is never ended, keeping its notes apart", start);
+                self.keep_pieces(chain, staff, layer);
+            }
+            self.ties.insert((staff, layer), TieChain {
+                event: self.events.len(),
+                length: read.value.length(),
+                tuplet: read.value.tuplet,
+                pieces: vec![],
+            });
+        }
+        self.add(read, staff, layer, start);
+    }
+
+    // The event which started the chain keeps its own value and the later
+    // pieces become events of their own
+    fn keep_pieces(&mut self, chain: TieChain, staff: u32, layer: u32) {
+        // Pieces of a note split at barlines share its id
+        let id = self.events[chain.event].0;
+        for (read, start) in chain.pieces {
+            let read = Read { id: read.id.filter(|i| Some(*i) != id), ..read };
+            self.add(read, staff, layer, start);
+        }
+    }
+
+    // Ties still open once every measure is read
+    fn end_ties(&mut self) {
+        let mut open: Vec<((u32, u32), TieChain)> = self.ties.drain().collect();
+        open.sort_by_key(|(layer, _)| *layer);
+        for ((staff, layer), chain) in open {
+            log::warn!("A tie on staff {} layer {} is never ended, keeping its notes apart", staff + 1, layer + 1);
+            self.keep_pieces(chain, staff, layer);
+        }
+    }
+
+    fn add(&mut self, read: Read, staff: u32, layer: u32, start: Pulse) {
+        let index = self.events.len();
+        self.starts.push((start, index, read.notes.iter().map(|(_, cautionary)| *cautionary).collect()));
+        self.events.push((read.id, Event {
+            event_id: 0,
+            staff,
+            layer,
+            notes: read.notes.into_iter().map(|(note, _)| note).collect(),
+            duration: read.value,
+            start,
+        }));
+    }
+
+    // Settles the cautionary accidentals of the events read since last time
+    fn settle_cautionary(&mut self, state: &mut AccidentalState) {
+        let mut starts = std::mem::take(&mut self.starts);
+        starts.sort_by_key(|(start, _, _)| *start);
+        for (_, index, shown) in starts {
+            crate::settle_cautionary(state, &mut self.events[index].1, &shown);
+        }
+    }
+}
+
+impl Score {
+    // Rebuilds a score from MEI like to_mei writes. Staff groups become parts,
+    // tied notes become single events where one value covers them and layers
+    // keep their numbers.
+    pub fn from_mei(mei: &ir::Mei) -> Result<Self, String> {
+        let mut mdivs = mei.music.iter().flat_map(|m| m.body.iter()).flat_map(|b| b.mdivs.iter()).filter_map(|m| m.score.as_ref());
+        let mei_score = mdivs.next().ok_or_else(|| "No score in the MEI".to_string())?;
+        if mdivs.next().is_some() {
+            log::warn!("Only the first mdiv of the MEI is read");
+        }
+        let score_def = mei_score.score_def.as_ref().ok_or_else(|| "No scoreDef in the MEI".to_string())?;
+
+        let mut score = Score {
+            parts: read_parts(score_def.staff_grp.as_ref().ok_or_else(|| "No staffGrp in the MEI".to_string())?)?,
+            metadata: mei.mei_head.as_ref().map(Metadata::from_mei_head).unwrap_or_default(),
+            ..Default::default()
+        };
+        if score.parts.is_empty() {
+            return Err("No staves in the MEI".to_string());
+        }
+        apply_score_def(&mut score, score_def, Pulse::default())?;
+        let staff_count = score.staff_count();
+
+        let mut states: Vec<AccidentalState> = (0..staff_count).map(|_| AccidentalState::new(score.key_at(Pulse::default()))).collect();
+        let mut reader = Reader {
+            score: &mut score,
+            events: vec![],
+            ties: HashMap::new(),
+            starts: vec![],
+        };
+        let mut measure_start = Pulse::default();
+        for child in mei_score.sections.iter().flat_map(|s| s.children.iter()) {
+            let measure = match child {
+                ir::SectionLike::ScoreDef(score_def) => {
+                    apply_score_def(reader.score, score_def, measure_start)?;
+                    continue;
+                }
+                ir::SectionLike::Measure(measure) => measure,
+            };
+            let key = reader.score.key_at(measure_start);
+            let mut measure_end = measure_start;
+            for (i, mei_staff) in measure.staves.iter().enumerate() {
+                let staff = mei_staff.n.map(|n| n.saturating_sub(1)).unwrap_or(i as u32);
+                if staff >= staff_count {
+                    log::warn!("Skipping staff {} which has no staffDef", staff + 1);
+                    continue;
+                }
+                let state = &mut states[staff as usize];
+                state.next_measure(key);
+                for (j, mei_layer) in mei_staff.layers.iter().enumerate() {
+                    let layer = mei_layer.n.map(|n| n.saturating_sub(1)).unwrap_or(j as u32);
+                    let mut position = measure_start;
+                    reader.read_layer(&mei_layer.events, staff, layer, &mut position, None, key)?;
+                    measure_end = measure_end.max(position);
+                }
+                reader.settle_cautionary(state);
+            }
+
+            let beat_length = Pulse::new(1, reader.score.meter_at(measure_start).unit as i64);
+            for dynam in &measure.dynams {
+                let dynamic: Dynamic = match dynam.text.trim().parse() {
+                    Ok(dynamic) => dynamic,
+                    Err(e) => {
+                        log::warn!("{}", e);
+                        continue;
+                    }
+                };
+                let beat = dynam.tstamp.as_deref().and_then(|t| t.parse::<f64>().ok()).unwrap_or(1.0) - 1.0;
+                let pulse = measure_start + beats_to_pulse(beat) * beat_length;
+                if let Some(staff) = reader.score.staff_mut(dynam.staff.unwrap_or(1).saturating_sub(1)) {
+                    staff.dynamics.insert(pulse, dynamic);
+                }
+            }
+
+            if measure_end == measure_start {
+                measure_end = measure_start + reader.score.meter_at(measure_start).length();
+            }
+            measure_start = measure_end;
+        }
+
+        reader.end_ties();
+
+        // Events keep their ids where they had them and the rest follow on
+        let events = reader.events;
+        let mut next_id = events.iter().filter_map(|(id, _)| id.map(|id| id + 1)).max().unwrap_or(0);
+        for (id, mut event) in events {
+            event.event_id = id.unwrap_or_else(|| {
+                next_id += 1;
+                next_id - 1
+            });
+            score.events.insert(event);
+        }
+        Ok(score)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Mode;
+
+    fn f(accid: Option<&str>, accid_ges: Option<&str>, accids: Vec<ir::Accid>) -> ir::Note {
+        ir::Note {
+            pname: Some("f".to_string()),
+            oct: 4,
+            accid: accid.map(|a| a.to_string()),
+            accid_ges: accid_ges.map(|a| a.to_string()),
+            accids,
+            ..Default::default()
+        }
+    }
+
+    fn accid(accid: Option<&str>, accid_ges: Option<&str>) -> ir::Accid {
+        ir::Accid {
+            accid: accid.map(|a| a.to_string()),
+            accid_ges: accid_ges.map(|a| a.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn accidental(note: &ir::Note) -> Accidental {
+        read_note(note, Key { fifths: 1, mode: Mode::Major }).unwrap().0.pitch.accidental
+    }
+
+    #[test]
+    fn notes_take_their_accidental_from_the_key() {
+        assert_eq!(accidental(&f(None, None, vec![])), Accidental::Sharp);
+    }
+
+    #[test]
+    fn sounding_accidentals() {
+        assert_eq!(accidental(&f(None, Some("n"), vec![])), Accidental::Natural);
+        // Verovio and Humdrum put them on an <accid> inside the note
+        assert_eq!(accidental(&f(None, None, vec![accid(None, Some("n"))])), Accidental::Natural);
+        assert_eq!(accidental(&f(None, None, vec![accid(None, Some("ff"))])), Accidental::DoubleFlat);
+    }
+
+    #[test]
+    fn written_accidentals_come_first() {
+        assert_eq!(accidental(&f(Some("s"), Some("n"), vec![])), Accidental::Sharp);
+        assert_eq!(accidental(&f(None, Some("n"), vec![accid(Some("f"), None)])), Accidental::Flat);
+        assert_eq!(accidental(&f(None, None, vec![accid(Some("x"), Some("n"))])), Accidental::DoubleSharp);
+    }
+
+    #[test]
+    fn cautionary_accidentals() {
+        let caution = ir::Accid {
+            accid: Some("s".to_string()),
+            func: Some("caution".to_string()),
+            ..Default::default()
+        };
+        let (note, cautionary) = read_note(&f(None, None, vec![caution]), Key::default()).unwrap();
+        assert_eq!(note.pitch.accidental, Accidental::Sharp);
+        assert!(cautionary);
+        assert!(read_note(&ir::Note::default(), Key::default()).is_err());
+    }
+}
@@ -0,0 +1,87 @@
+use serde::{Serialize, Deserialize};
+
+// What the score is and who wrote it. Empty fields are left out of the MEI,
+// apart from the title, which the titleStmt can't do without.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub composer: String,
+    #[serde(default)]
+    pub lyricist: String,
+    #[serde(default)]
+    pub arranger: String,
+}
+
+impl Metadata {
+    fn people(&self) -> [(&'static str, &String); 3] {
+        [("composer", &self.composer), ("lyricist", &self.lyricist), ("arranger", &self.arranger)]
+    }
+
+    // Verovio's automatic page header shows the title from the titleStmt and
+    // the people in its respStmt by role
+    pub(crate) fn to_mei_head(&self) -> ir::MeiHead {
+        let title = || ir::Title {
+            text: self.title.clone(),
+            ..Default::default()
+        };
+        let pers_names: Vec<ir::PersName> = self.people().iter()
+            .filter(|(_, name)| !name.is_empty())
+            .map(|(role, name)| ir::PersName {
+                role: Some(role.to_string()),
+                text: name.to_string(),
+            })
+            .collect();
+        let work_list = if self.title.is_empty() && self.composer.is_empty() {
+            None
+        } else {
+            Some(ir::WorkList {
+                works: vec![ir::Work {
+                    titles: vec![title()],
+                    composer: if self.composer.is_empty() { None } else { Some(self.composer.clone()) },
+                }],
+            })
+        };
+        ir::MeiHead {
+            file_desc: Some(ir::FileDesc {
+                title_stmt: Some(ir::TitleStmt {
+                    titles: vec![title()],
+                    resp_stmts: if pers_names.is_empty() { vec![] } else { vec![ir::RespStmt { pers_names }] },
+                }),
+                pub_stmt: Some(ir::PubStmt::default()),
+            }),
+            encoding_desc: None,
+            work_list,
+        }
+    }
+
+    // Falls back on the first work for anything the titleStmt doesn't have
+    pub(crate) fn from_mei_head(head: &ir::MeiHead) -> Self {
+        let title_stmt = head.file_desc.as_ref().and_then(|f| f.title_stmt.as_ref());
+        let work = head.work_list.as_ref().and_then(|w| w.works.first());
+        let person = |role: &str| {
+            title_stmt.iter()
+                .flat_map(|t| t.resp_stmts.iter())
+                .flat_map(|r| r.pers_names.iter())
+                .find(|p| p.role.as_deref() == Some(role))
+                .map(|p| p.text.trim().to_string())
+                .unwrap_or_default()
+        };
+        let mut metadata = Metadata {
+            title: title_stmt.and_then(|t| t.titles.first()).map(|t| t.text.trim().to_string()).unwrap_or_default(),
+            composer: person("composer"),
+            lyricist: person("lyricist"),
+            arranger: person("arranger"),
+        };
+        if let Some(work) = work {
+            if metadata.title.is_empty() {
+                metadata.title = work.titles.first().map(|t| t.text.trim().to_string()).unwrap_or_default();
+            }
+            if metadata.composer.is_empty() {
+                metadata.composer = work.composer.as_deref().unwrap_or("").trim().to_string();
+            }
+        }
+        metadata
+    }
+}
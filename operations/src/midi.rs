@@ -0,0 +1,729 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+
+use crate::{Clef, Context, Event, Key, Meter, Mode, Note, NoteValue, Part, Pulse, Score, Staff, Tempo};
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.pos + n > self.data.len() {
+            return Err(format!("MIDI file ends early at byte {}", self.data.len()));
+        }
+        let bytes = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    // Variable length quantities keep seven bits per byte, high bit set on all but the last
+    fn var_len(&mut self) -> Result<u32, String> {
+        let mut value = 0u32;
+        for _ in 0..4 {
+            let byte = self.u8()?;
+            value = (value << 7) | (byte & 0x7f) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("Variable length quantity is too long".to_string())
+    }
+}
+
+struct MidiNote {
+    track: usize,
+    channel: u8,
+    key: u8,
+    start: u64,
+    end: u64,
+}
+
+#[derive(Default)]
+struct MidiFile {
+    ticks_per_quarter: u64,
+    notes: Vec<MidiNote>,
+    meters: Vec<(u64, Meter)>,
+    keys: Vec<(u64, Key)>,
+    tempos: Vec<(u64, Tempo)>,
+    track_names: HashMap<usize, String>,
+    // The first program change on each track and channel
+    programs: HashMap<(usize, u8), u8>,
+}
+
+fn parse(data: &[u8]) -> Result<MidiFile, String> {
+    let mut reader = Reader::new(data);
+    if reader.bytes(4)? != b"MThd" {
+        return Err("Not a MIDI file".to_string());
+    }
+    let header_length = reader.u32()? as usize;
+    let header = reader.bytes(header_length)?;
+    if header.len() < 6 {
+        return Err("MIDI header is too short".to_string());
+    }
+    let division = u16::from_be_bytes([header[4], header[5]]);
+    if division & 0x8000 != 0 {
+        return Err("SMPTE timed MIDI files aren't supported".to_string());
+    }
+    let mut file = MidiFile {
+        ticks_per_quarter: division.max(1) as u64,
+        ..Default::default()
+    };
+
+    let mut track = 0;
+    while !reader.is_empty() {
+        let id = reader.bytes(4)?;
+        let length = reader.u32()? as usize;
+        let chunk = reader.bytes(length)?;
+        // Unknown chunks are allowed and should be skipped
+        if id == b"MTrk" {
+            parse_track(chunk, track, &mut file)?;
+            track += 1;
+        }
+    }
+    Ok(file)
+}
+
+fn parse_track(data: &[u8], track: usize, file: &mut MidiFile) -> Result<(), String> {
+    let mut reader = Reader::new(data);
+    let mut tick = 0u64;
+    let mut status = 0u8;
+    // Start ticks of the notes held down on each channel and key
+    let mut sounding: HashMap<(u8, u8), Vec<u64>> = HashMap::new();
+    while !reader.is_empty() {
+        tick += reader.var_len()? as u64;
+        let byte = reader.u8()?;
+        match byte {
+            0xff => {
+                let kind = reader.u8()?;
+                let length = reader.var_len()? as usize;
+                let meta = reader.bytes(length)?;
+                match kind {
+                    0x03 if !meta.is_empty() => {
+                        file.track_names.insert(track, String::from_utf8_lossy(meta).trim().to_string());
+                    }
+                    0x58 if meta.len() >= 2 => {
+                        let meter = Meter { count: meta[0] as u32, unit: 1 << meta[1].min(7) };
+                        if meter.count > 0 {
+                            file.meters.push((tick, meter));
+                        }
+                    }
+                    0x59 if meta.len() >= 2 => {
+                        let fifths = meta[0] as i8 as i32;
+                        let mode = if meta[1] == 1 { Mode::Minor } else { Mode::Major };
+                        if fifths.abs() <= 7 {
+                            file.keys.push((tick, Key { fifths, mode }));
+                        }
+                    }
+                    0x51 if meta.len() >= 3 => {
+                        let micros = u32::from_be_bytes([0, meta[0], meta[1], meta[2]]).max(1);
+                        file.tempos.push((tick, Tempo { bpm: ((60_000_000 + micros / 2) / micros).max(1) }));
+                    }
+                    0x2f => break,
+                    _ => {}
+                }
+            }
+            0xf0 | 0xf7 => {
+                let length = reader.var_len()? as usize;
+                reader.bytes(length)?;
+            }
+            0xf1..=0xfe => return Err(format!("Unexpected status {:#x} in track {}", byte, track)),
+            _ => {
+                // Without the high bit this is running status, reusing the last status byte
+                let first = if byte & 0x80 != 0 {
+                    status = byte;
+                    reader.u8()?
+                } else if status != 0 {
+                    byte
+                } else {
+                    return Err(format!("Running status without a status byte in track {}", track));
+                };
+                let channel = status & 0x0f;
+                match status & 0xf0 {
+                    0x80 | 0x90 => {
+                        let velocity = reader.u8()?;
+                        if status & 0xf0 == 0x90 && velocity > 0 {
+                            sounding.entry((channel, first)).or_default().push(tick);
+                        } else if let Some(starts) = sounding.get_mut(&(channel, first)) {
+                            if !starts.is_empty() {
+                                let start = starts.remove(0);
+                                file.notes.push(MidiNote { track, channel, key: first, start, end: tick });
+                            }
+                        }
+                    }
+                    0xa0 | 0xb0 | 0xe0 => {
+                        reader.u8()?;
+                    }
+                    0xc0 => {
+                        file.programs.entry((track, channel)).or_insert(first);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    // Anything still held ends with the track
+    for ((channel, key), starts) in sounding {
+        for start in starts {
+            file.notes.push(MidiNote { track, channel, key, start, end: tick });
+        }
+    }
+    Ok(())
+}
+
+// The grid must be positive
+fn quantize(pulse: Pulse, grid: Pulse) -> Pulse {
+    let steps = pulse / grid;
+    let rounded = (steps.num() * 2 + steps.den()).div_euclid(steps.den() * 2);
+    Pulse::whole(rounded) * grid
+}
+
+// C0, the lowest note a score can hold
+const LOWEST_KEY: u8 = 12;
+
+// The keys sounding together, by start and end
+type Chords = BTreeMap<(Pulse, Pulse), Vec<u8>>;
+
+impl Context {
+    // Reads a standard MIDI file, rounding note starts and ends to `grid`.
+    // Each channel of each track becomes a staff in its own part. Notes which
+    // start and end together become chords and overlapping notes are spread
+    // over layers. A note which doesn't fit a single value is split into
+    // events of the values which make it up, one after another.
+    pub fn from_midi(data: &[u8], grid: Pulse) -> Result<Self, String> {
+        if grid <= Pulse::default() {
+            return Err(format!("Can't quantize to a grid of {}/{}", grid.num(), grid.den()));
+        }
+        let file = parse(data)?;
+        let ticks_per_whole = file.ticks_per_quarter as i64 * 4;
+        let to_pulse = |tick: u64| quantize(Pulse::new(tick as i64, ticks_per_whole), grid);
+
+        let mut ctx = Context::default();
+        for (tick, meter) in &file.meters {
+            ctx.score.meters.insert(to_pulse(*tick), *meter);
+        }
+        for (tick, key) in &file.keys {
+            ctx.score.keys.insert(to_pulse(*tick), *key);
+        }
+        for (tick, tempo) in &file.tempos {
+            ctx.score.tempos.insert(to_pulse(*tick), *tempo);
+        }
+
+        // Chords for each track and channel
+        let mut staves: BTreeMap<(usize, u8), Chords> = BTreeMap::new();
+        let mut too_low = 0;
+        for note in &file.notes {
+            if note.key < LOWEST_KEY {
+                too_low += 1;
+                continue;
+            }
+            let start = to_pulse(note.start);
+            let end = to_pulse(note.end).max(start + grid);
+            staves.entry((note.track, note.channel)).or_default().entry((start, end)).or_default().push(note.key);
+        }
+        if too_low > 0 {
+            log::warn!("Skipping {} notes below C0, which a score can't hold", too_low);
+        }
+
+        ctx.score.parts.clear();
+        for (staff, (&(track, channel), chords)) in staves.iter().enumerate() {
+            let channels = staves.keys().filter(|(t, _)| *t == track).count();
+            let mut name = file.track_names.get(&track).cloned().unwrap_or_default();
+            if channels > 1 {
+                name = format!("{} (channel {})", name, channel + 1).trim().to_string();
+            }
+            let keys: Vec<u8> = chords.values().flatten().copied().collect();
+            let average = keys.iter().map(|&k| k as u32).sum::<u32>() / keys.len().max(1) as u32;
+            let mut staff_info = Staff::default();
+            if average < 60 {
+                staff_info.clefs.insert(Pulse::default(), Clef::bass());
+            }
+            ctx.score.parts.push(Part {
+                name,
+                staves: vec![staff_info],
+                program: file.programs.get(&(track, channel)).copied().unwrap_or(0),
+            });
+
+            // The end of the last event in each layer
+            let mut layer_ends: Vec<Pulse> = vec![];
+            for (&(start, end), keys) in chords {
+                let layer = match layer_ends.iter().position(|&e| e <= start) {
+                    Some(layer) => layer,
+                    None => {
+                        layer_ends.push(Pulse::default());
+                        layer_ends.len() - 1
+                    }
+                };
+                layer_ends[layer] = end;
+                let key = ctx.score.key_at(start);
+                let mut keys = keys.clone();
+                keys.sort_unstable();
+                keys.dedup();
+                // Spelling in the key can only fail right at C0, which C major spells as C
+                let notes: Vec<Note> = keys.iter()
+                    .map(|&k| k as i32 - LOWEST_KEY as i32)
+                    .filter_map(|semitones| Note::from_semitones_in_key(semitones, key).or_else(|| Note::from_semitones(semitones)))
+                    .collect();
+                let mut piece_start = start;
+                for duration in NoteValue::decompose(end - start, None) {
+                    ctx.score.events.insert(Event {
+                        event_id: ctx.next_id,
+                        staff: staff as u32,
+                        layer: layer as u32,
+                        notes: notes.clone(),
+                        duration,
+                        start: piece_start,
+                    });
+                    ctx.next_id += 1;
+                    piece_start += duration.length();
+                }
+            }
+        }
+        if ctx.score.parts.is_empty() {
+            ctx.score.parts.push(Part::default());
+        }
+        Ok(ctx)
+    }
+}
+
+const TICKS_PER_QUARTER: u16 = 480;
+// Pitch bends are sent assuming the usual range of two semitones either way
+const BEND_RANGE_CENTS: i32 = 200;
+
+// Every channel but the percussion one
+const MELODIC_CHANNELS: u32 = 15;
+
+// Staves go round the channels, skipping the percussion channel, so past the
+// fifteenth they share programs and bends with earlier staves.
+fn channel(staff: usize) -> u8 {
+    let channel = (staff % MELODIC_CHANNELS as usize) as u8;
+    if channel < 9 { channel } else { channel + 1 }
+}
+
+fn write_var_len(out: &mut Vec<u8>, mut value: u32) {
+    let mut bytes = vec![(value & 0x7f) as u8];
+    value >>= 7;
+    while value > 0 {
+        bytes.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.reverse();
+    out.extend(bytes);
+}
+
+// Events are sorted by tick and then `order`, so note offs come before note
+// ons at the same tick and set up messages come first of all.
+struct TrackEvent {
+    tick: u64,
+    order: u8,
+    bytes: Vec<u8>,
+}
+
+fn write_track(out: &mut Vec<u8>, mut events: Vec<TrackEvent>) {
+    events.sort_by_key(|e| (e.tick, e.order));
+    let mut data = vec![];
+    let mut tick = 0;
+    for event in &events {
+        write_var_len(&mut data, (event.tick - tick) as u32);
+        data.extend(&event.bytes);
+        tick = event.tick;
+    }
+    data.extend([0x00, 0xff, 0x2f, 0x00]);
+    out.extend(b"MTrk");
+    out.extend((data.len() as u32).to_be_bytes());
+    out.extend(data);
+}
+
+fn meta(tick: u64, kind: u8, data: &[u8]) -> TrackEvent {
+    let mut bytes = vec![0xff, kind];
+    write_var_len(&mut bytes, data.len() as u32);
+    bytes.extend(data);
+    TrackEvent { tick, order: 0, bytes }
+}
+
+fn to_tick(pulse: Pulse) -> u64 {
+    let ticks = pulse * Pulse::whole(TICKS_PER_QUARTER as i64 * 4);
+    ((ticks.num() * 2 + ticks.den()).div_euclid(ticks.den() * 2)).max(0) as u64
+}
+
+impl Score {
+    // Writes a type 1 standard MIDI file. The first track holds the meters,
+    // keys and tempos, then each staff gets a track and a channel, skipping
+    // the percussion channel. Velocities follow the dynamics marked on the
+    // staff and detuned or quarter tone notes are bent, which affects the
+    // whole channel so a chord can only have one bend.
+    pub fn to_midi(&self) -> Vec<u8> {
+        let mut out = vec![];
+        out.extend(b"MThd");
+        out.extend(6u32.to_be_bytes());
+        out.extend(1u16.to_be_bytes());
+        out.extend((self.staff_count() as u16 + 1).to_be_bytes());
+        out.extend(TICKS_PER_QUARTER.to_be_bytes());
+
+        // Each timeline is written from the start, whether or not it has an entry there
+        let changes = |pulses: Vec<Pulse>| std::iter::once(Pulse::default()).chain(pulses.into_iter().filter(|p| !p.is_zero()));
+        let mut conductor = vec![];
+        for pulse in changes(self.meters.iter().map(|(p, _)| p).collect()) {
+            let meter = self.meter_at(pulse);
+            let unit_power = meter.unit.trailing_zeros() as u8;
+            // The count only gets a byte
+            let count = u8::try_from(meter.count).unwrap_or_else(|_| {
+                log::warn!("Writing the meter {} at {:?} with a count of {}", meter, pulse, u8::MAX);
+                u8::MAX
+            });
+            conductor.push(meta(to_tick(pulse), 0x58, &[count, unit_power, 24, 8]));
+        }
+        for pulse in changes(self.keys.iter().map(|(p, _)| p).collect()) {
+            let key = self.key_at(pulse);
+            let mode = match key.mode {
+                Mode::Major => 0,
+                Mode::Minor => 1,
+            };
+            conductor.push(meta(to_tick(pulse), 0x59, &[key.fifths as i8 as u8, mode]));
+        }
+        for pulse in changes(self.tempos.iter().map(|(p, _)| p).collect()) {
+            let micros = self.tempo_at(pulse).micros_per_quarter().to_be_bytes();
+            conductor.push(meta(to_tick(pulse), 0x51, &micros[1..]));
+        }
+        write_track(&mut out, conductor);
+
+        if self.staff_count() > MELODIC_CHANNELS {
+            log::warn!("{} staves share {} MIDI channels", self.staff_count(), MELODIC_CHANNELS);
+        }
+
+        for (staff, (part, staff_info)) in self.staves().enumerate() {
+            let part = &self.parts[part];
+            let channel = channel(staff);
+            let mut events = vec![];
+            if !part.name.is_empty() {
+                events.push(meta(0, 0x03, part.name.as_bytes()));
+            }
+            events.push(TrackEvent { tick: 0, order: 1, bytes: vec![0xc0 | channel, part.program.min(127)] });
+            // Bends are tracked in time order across all the layers
+            let mut staff_events: Vec<&Event> = self.events_on_staff(staff as u32).collect();
+            staff_events.sort_by_key(|e| e.start());
+            let mut bend = 0;
+            for event in staff_events {
+                let start = to_tick(event.start());
+                let end = to_tick(event.end());
+                let velocity = staff_info.dynamic_at(event.start()).velocity();
+                for note in event.notes() {
+                    let (key, cents) = note.midi_key();
+                    let cents = cents.clamp(-BEND_RANGE_CENTS, BEND_RANGE_CENTS);
+                    if cents != bend {
+                        let value = (8192 + cents * 8192 / BEND_RANGE_CENTS).clamp(0, 16383) as u16;
+                        events.push(TrackEvent { tick: start, order: 2, bytes: vec![0xe0 | channel, (value & 0x7f) as u8, (value >> 7) as u8] });
+                        bend = cents;
+                    }
+                    events.push(TrackEvent { tick: end, order: 1, bytes: vec![0x80 | channel, key, 0] });
+                    events.push(TrackEvent { tick: start, order: 3, bytes: vec![0x90 | channel, key, velocity] });
+                }
+            }
+            write_track(&mut out, events);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Accidental, Dynamic, Octave, Pitch, PitchName};
+
+    // A file with 96 ticks to the quarter
+    fn smf(format: u16, tracks: &[&[u8]]) -> Vec<u8> {
+        let mut out = b"MThd".to_vec();
+        out.extend(6u32.to_be_bytes());
+        out.extend(format.to_be_bytes());
+        out.extend((tracks.len() as u16).to_be_bytes());
+        out.extend(96u16.to_be_bytes());
+        for track in tracks {
+            out.extend(b"MTrk");
+            out.extend((track.len() as u32).to_be_bytes());
+            out.extend(*track);
+        }
+        out
+    }
+
+    fn notes(file: &MidiFile) -> Vec<(usize, u8, u8, u64, u64)> {
+        let mut notes: Vec<_> = file.notes.iter().map(|n| (n.track, n.channel, n.key, n.start, n.end)).collect();
+        notes.sort_unstable();
+        notes
+    }
+
+    #[test]
+    fn variable_length_quantities() {
+        for &(bytes, value) in &[
+            (&[0x00][..], 0),
+            (&[0x7f][..], 0x7f),
+            (&[0x81, 0x00][..], 0x80),
+            (&[0xc0, 0x00][..], 0x2000),
+            (&[0xff, 0xff, 0x7f][..], 0x1fffff),
+            (&[0xff, 0xff, 0xff, 0x7f][..], 0x0fffffff),
+        ] {
+            assert_eq!(Reader::new(bytes).var_len(), Ok(value));
+            let mut written = vec![];
+            write_var_len(&mut written, value);
+            assert_eq!(written, bytes);
+        }
+        assert!(Reader::new(&[0x80, 0x80, 0x80, 0x80, 0x00]).var_len().is_err());
+        assert!(Reader::new(&[0x81]).var_len().is_err());
+    }
+
+    #[test]
+    fn running_status() {
+        let track = [
+            0x00, 0x90, 0x3c, 0x40,
+            // The same status for D, then note ons with no velocity to end both
+            0x60, 0x3e, 0x40,
+            0x60, 0x3c, 0x00,
+            0x00, 0x3e, 0x00,
+            0x00, 0xff, 0x2f, 0x00,
+        ];
+        let file = parse(&smf(0, &[&track])).unwrap();
+        assert_eq!(notes(&file), vec![(0, 0, 0x3c, 0, 192), (0, 0, 0x3e, 96, 192)]);
+
+        let without_status = [0x00, 0x3c, 0x40, 0x00, 0xff, 0x2f, 0x00];
+        assert!(parse(&smf(0, &[&without_status])).is_err());
+    }
+
+    #[test]
+    fn format_0_splits_channels_into_parts() {
+        let track = [
+            0x00, 0xff, 0x03, 0x04, b'D', b'u', b'e', b't',
+            0x00, 0xc1, 0x28,
+            0x00, 0x90, 0x48, 0x50,
+            0x00, 0x91, 0x30, 0x50,
+            0x83, 0x00, 0x80, 0x48, 0x00,
+            0x00, 0x81, 0x30, 0x00,
+            0x00, 0xff, 0x2f, 0x00,
+        ];
+        let ctx = Context::from_midi(&smf(0, &[&track]), Pulse::new(1, 16)).unwrap();
+        let parts: Vec<_> = ctx.score.parts.iter().map(|p| (p.name.as_str(), p.program)).collect();
+        assert_eq!(parts, vec![("Duet (channel 1)", 0), ("Duet (channel 2)", 0x28)]);
+        // A low channel gets a bass clef
+        assert_eq!(ctx.score.parts[1].staves[0].clefs.at(Pulse::default()), Some(&Clef::bass()));
+        let events: Vec<_> = ctx.score.events.iter().map(|e| (e.staff(), e.duration(), e.notes()[0].semitones())).collect();
+        assert_eq!(events, vec![(0, NoteValue::new(1), 60), (1, NoteValue::new(1), 36)]);
+    }
+
+    #[test]
+    fn format_1_reads_the_conductor_track() {
+        let conductor = [
+            0x00, 0xff, 0x58, 0x04, 0x03, 0x02, 0x18, 0x08,
+            0x00, 0xff, 0x59, 0x02, 0xfe, 0x01,
+            // 500000 microseconds a quarter
+            0x00, 0xff, 0x51, 0x03, 0x07, 0xa1, 0x20,
+            0x00, 0xff, 0x2f, 0x00,
+        ];
+        let melody = [
+            0x00, 0xff, 0x03, 0x03, b'O', b'b', b'y',
+            0x00, 0x90, 0x3e, 0x40,
+            0x60, 0x80, 0x3e, 0x40,
+            0x00, 0xff, 0x2f, 0x00,
+        ];
+        // Unknown chunks are skipped
+        let mut data = smf(1, &[&conductor]);
+        data.extend(b"XYZW\x00\x00\x00\x02ab");
+        data.extend(&smf(1, &[&melody])[14..]);
+
+        let ctx = Context::from_midi(&data, Pulse::new(1, 16)).unwrap();
+        assert_eq!(ctx.score.meter_at(Pulse::default()), Meter { count: 3, unit: 4 });
+        assert_eq!(ctx.score.key_at(Pulse::default()), Key { fifths: -2, mode: Mode::Minor });
+        assert_eq!(ctx.score.tempo_at(Pulse::default()), Tempo { bpm: 120 });
+        assert_eq!(ctx.score.parts.len(), 1);
+        assert_eq!(ctx.score.parts[0].name, "Oby");
+    }
+
+    #[test]
+    fn overlapping_notes() {
+        let track = [
+            0x00, 0x90, 0x3c, 0x40,
+            // E comes in before C ends, then C is struck again while still held
+            0x60, 0x90, 0x40, 0x40,
+            0x00, 0x90, 0x3c, 0x40,
+            0x60, 0x80, 0x3c, 0x40,
+            0x60, 0x80, 0x40, 0x40,
+            0x00, 0x80, 0x3c, 0x40,
+            0x00, 0xff, 0x2f, 0x00,
+        ];
+        let data = smf(0, &[&track]);
+        // The first note on is ended by the first note off
+        assert_eq!(notes(&parse(&data).unwrap()), vec![(0, 0, 0x3c, 0, 192), (0, 0, 0x3c, 96, 288), (0, 0, 0x40, 96, 288)]);
+
+        // E and the second C make a chord in a layer of their own
+        let ctx = Context::from_midi(&data, Pulse::new(1, 16)).unwrap();
+        let events: Vec<_> = ctx.score.events.iter().map(|e| (e.layer(), e.start(), e.duration(), e.notes().len())).collect();
+        assert_eq!(events, vec![
+            (0, Pulse::default(), NoteValue::new(2), 1),
+            (1, Pulse::new(1, 4), NoteValue::new(2), 2),
+        ]);
+    }
+
+    #[test]
+    fn notes_which_arent_one_value_are_split() {
+        let track = [
+            // Five eighths, then a quarter straight after
+            0x00, 0x90, 0x3c, 0x40,
+            0x81, 0x70, 0x80, 0x3c, 0x40,
+            0x00, 0x90, 0x3e, 0x40,
+            0x60, 0x80, 0x3e, 0x40,
+            0x00, 0xff, 0x2f, 0x00,
+        ];
+        let ctx = Context::from_midi(&smf(0, &[&track]), Pulse::new(1, 16)).unwrap();
+        let events: Vec<_> = ctx.score.events.iter().map(|e| (e.layer(), e.start(), e.duration(), e.notes()[0].semitones())).collect();
+        assert_eq!(events, vec![
+            (0, Pulse::default(), NoteValue::new(2), 48),
+            (0, Pulse::new(1, 2), NoteValue::new(8), 48),
+            (0, Pulse::new(5, 8), NoteValue::new(4), 50),
+        ]);
+    }
+
+    #[test]
+    fn keys_below_c0_are_skipped() {
+        let track = [
+            0x00, 0x90, 0x05, 0x40,
+            0x00, 0x90, 0x3c, 0x40,
+            0x60, 0x80, 0x05, 0x40,
+            0x00, 0x80, 0x3c, 0x40,
+            // A note on its own below C0 leaves no event at all
+            0x00, 0x90, 0x0b, 0x40,
+            0x60, 0x80, 0x0b, 0x40,
+            0x00, 0x90, 0x0c, 0x40,
+            0x60, 0x80, 0x0c, 0x40,
+            0x00, 0xff, 0x2f, 0x00,
+        ];
+        let ctx = Context::from_midi(&smf(0, &[&track]), Pulse::new(1, 16)).unwrap();
+        let events: Vec<_> = ctx.score.events.iter()
+            .map(|e| (e.start(), e.notes().iter().map(|n| n.semitones()).collect::<Vec<_>>()))
+            .collect();
+        assert_eq!(events, vec![(Pulse::default(), vec![48]), (Pulse::new(1, 2), vec![0])]);
+    }
+
+    #[test]
+    fn quantizing() {
+        let grid = Pulse::new(1, 16);
+        assert_eq!(quantize(Pulse::new(1, 20), grid), Pulse::new(1, 16));
+        assert_eq!(quantize(Pulse::new(1, 40), grid), Pulse::default());
+        // Halfway rounds up
+        assert_eq!(quantize(Pulse::new(3, 32), grid), Pulse::new(1, 8));
+        let data = smf(0, &[&[0x00, 0xff, 0x2f, 0x00]]);
+        assert!(Context::from_midi(&data, Pulse::default()).is_err());
+        assert!(Context::from_midi(&data, Pulse::new(-1, 8)).is_err());
+    }
+
+    #[test]
+    fn malformed_files() {
+        assert!(parse(b"RIFF\x00\x00\x00\x06\x00\x00\x00\x01\x00\x60").is_err());
+        let mut truncated = smf(0, &[&[0x00, 0x90, 0x3c, 0x40, 0x00, 0xff, 0x2f, 0x00]]);
+        truncated.truncate(truncated.len() - 3);
+        assert!(parse(&truncated).is_err());
+        let smpte = [b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 0, 0xe7, 0x28];
+        assert!(parse(&smpte).is_err());
+    }
+
+    fn quarter(event_id: u32, accidental: Accidental, start: Pulse) -> Event {
+        Event {
+            event_id,
+            notes: vec![Note::new(Pitch { class: PitchName::C, accidental }, Octave(4))],
+            duration: NoteValue::new(4),
+            start,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn writes_a_type_1_file() {
+        let mut score = Score::default();
+        score.tempos.insert(Pulse::whole(1), Tempo { bpm: 60 });
+        let staff = &mut score.parts[0].staves[0];
+        staff.dynamics.insert(Pulse::default(), Dynamic::F);
+        staff.dynamics.insert(Pulse::new(1, 4), Dynamic::P);
+        score.events.insert(quarter(0, Accidental::Natural, Pulse::default()));
+        score.events.insert(quarter(1, Accidental::QuarterSharp, Pulse::new(1, 4)));
+
+        let mut expected = vec![];
+        // Two tracks of 480 ticks to the quarter
+        expected.extend(b"MThd\x00\x00\x00\x06\x00\x01\x00\x02\x01\xe0");
+        expected.extend(b"MTrk\x00\x00\x00\x21");
+        expected.extend([
+            // 4/4, no sharps or flats and 120 then 60 to the quarter a whole note later
+            0x00, 0xff, 0x58, 0x04, 0x04, 0x02, 0x18, 0x08,
+            0x00, 0xff, 0x59, 0x02, 0x00, 0x00,
+            0x00, 0xff, 0x51, 0x03, 0x07, 0xa1, 0x20,
+            0x8f, 0x00, 0xff, 0x51, 0x03, 0x0f, 0x42, 0x40,
+            0x00, 0xff, 0x2f, 0x00,
+        ]);
+        expected.extend(b"MTrk\x00\x00\x00\x1d");
+        expected.extend([
+            0x00, 0xc0, 0x00,
+            // Middle C forte
+            0x00, 0x90, 0x3c, 0x60,
+            0x83, 0x60, 0x80, 0x3c, 0x00,
+            // A quarter tone above is C sharp bent down a quarter of the range, piano
+            0x00, 0xe0, 0x00, 0x30,
+            0x00, 0x90, 0x3d, 0x31,
+            0x83, 0x60, 0x80, 0x3d, 0x00,
+            0x00, 0xff, 0x2f, 0x00,
+        ]);
+        assert_eq!(score.to_midi(), expected);
+    }
+
+    #[test]
+    fn bends_are_only_sent_when_they_change() {
+        let mut score = Score::default();
+        let mut detuned = quarter(0, Accidental::Natural, Pulse::default());
+        detuned.notes[0].detune = 30;
+        score.events.insert(detuned.clone());
+        detuned.event_id = 1;
+        detuned.start = Pulse::new(1, 4);
+        score.events.insert(detuned);
+        score.events.insert(quarter(2, Accidental::Natural, Pulse::new(1, 2)));
+
+        let midi = score.to_midi();
+        let track = &midi[midi.windows(4).rposition(|w| w == b"MTrk").unwrap() + 8..];
+        let bends: Vec<_> = track.windows(3).filter(|w| w[0] == 0xe0).map(|w| u16::from(w[1]) | u16::from(w[2]) << 7).collect();
+        // Thirty cents up, then back to the middle
+        assert_eq!(bends, vec![9420, 8192]);
+    }
+
+    #[test]
+    fn channels_skip_percussion() {
+        let channels: Vec<u8> = (0..17).map(channel).collect();
+        assert_eq!(channels, vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 10, 11, 12, 13, 14, 15, 0, 1]);
+
+        let mut score = Score::default();
+        score.parts[0].staves = vec![Staff::default(); 11];
+        score.parts[0].program = 41;
+        let midi = score.to_midi();
+        let programs: Vec<_> = midi.windows(2).filter(|w| w[0] & 0xf0 == 0xc0 && w[1] == 41).map(|w| w[0] & 0x0f).collect();
+        assert_eq!(programs, vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 10, 11]);
+    }
+
+    #[test]
+    fn meter_counts_past_a_byte_are_clamped() {
+        let mut score = Score::default();
+        score.meters.insert(Pulse::default(), Meter { count: 300, unit: 8 });
+        let midi = score.to_midi();
+        let at = midi.windows(3).position(|w| w == [0xff, 0x58, 0x04]).unwrap();
+        assert_eq!(midi[at + 3..at + 5], [0xff, 0x03]);
+    }
+}
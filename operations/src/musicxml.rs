@@ -0,0 +1,947 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+
+use sxd_document::dom::Element;
+use sxd_document::parser;
+
+use crate::{
+    Accidental, AccidentalDisplay, AccidentalState, Clef, ClefShape, Context, Dynamic, Event, Key, Mark, Meter, Mode, Note, NoteValue, Octave,
+    Part, Pitch, PitchName, Pulse, Score, Staff, Tempo, Tuplet, TupletGroup, SHORTEST_BASE, VoiceFormat, VoiceWriter,
+};
+
+// Writes indented XML a line at a time
+struct XmlWriter {
+    out: String,
+    depth: usize,
+}
+
+impl XmlWriter {
+    fn line(&mut self, text: &str) {
+        for _ in 0..self.depth {
+            self.out.push_str("  ");
+        }
+        self.out.push_str(text);
+        self.out.push('\n');
+    }
+
+    // `tag` may carry attributes, like `measure number="1"`
+    fn open(&mut self, tag: &str) {
+        self.line(&format!("<{}>", tag));
+        self.depth += 1;
+    }
+
+    fn close(&mut self, name: &str) {
+        self.depth -= 1;
+        self.line(&format!("</{}>", name));
+    }
+
+    fn leaf(&mut self, name: &str, text: &str) {
+        self.line(&format!("<{0}>{1}</{0}>", name, escape(text)));
+    }
+
+    fn empty(&mut self, tag: &str) {
+        self.line(&format!("<{}/>", tag));
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn gcd(a: i64, b: i64) -> i64 {
+    if b == 0 { a.abs() } else { gcd(b, a % b) }
+}
+
+// Whether a value starts or stops a tuplet bracket
+#[derive(Copy, Clone, Default)]
+struct Bracket {
+    start: bool,
+    stop: bool,
+}
+
+// What goes in one voice of a measure, in order
+enum Item {
+    Note {
+        notes: Vec<Note>,
+        value: NoteValue,
+        tie: Option<&'static str>,
+        accidentals: Option<Vec<AccidentalDisplay>>,
+        bracket: Bracket,
+    },
+    Rest {
+        value: NoteValue,
+        bracket: Bracket,
+    },
+    Forward(Pulse),
+    Clef(Clef),
+}
+
+impl Item {
+    fn length(&self) -> Pulse {
+        match self {
+            Item::Note { value, .. } | Item::Rest { value, .. } => value.length(),
+            Item::Forward(length) => *length,
+            Item::Clef(_) => Pulse::default(),
+        }
+    }
+
+    fn bracket_mut(&mut self) -> Option<&mut Bracket> {
+        match self {
+            Item::Note { bracket, .. } | Item::Rest { bracket, .. } => Some(bracket),
+            _ => None,
+        }
+    }
+}
+
+// The numbered voices of one staff in one measure
+type Voices = Vec<(u32, Vec<Item>)>;
+
+// Voices in MusicXML, where gaps in the voices after the first are skipped
+// with <forward> and a tuplet is bracketed from its first value to its last
+struct XmlVoice;
+
+impl VoiceFormat for XmlVoice {
+    type Token = Item;
+
+    fn rest(&mut self, value: NoteValue, rests: bool) -> Item {
+        if rests {
+            Item::Rest { value, bracket: Bracket::default() }
+        } else {
+            Item::Forward(value.length())
+        }
+    }
+
+    // Keys, meters and tempos are written for the whole measure
+    fn mark(&mut self, mark: Mark) -> Option<Item> {
+        match mark {
+            Mark::Clef(clef) => Some(Item::Clef(clef)),
+            _ => None,
+        }
+    }
+
+    fn tuplet(&mut self, group: TupletGroup<Item>) -> Vec<Item> {
+        let mut items = group.tokens;
+        if let Some(bracket) = items.iter_mut().find_map(Item::bracket_mut) {
+            bracket.start = true;
+        }
+        if let Some(bracket) = items.iter_mut().rev().find_map(Item::bracket_mut) {
+            bracket.stop = true;
+        }
+        items
+    }
+}
+
+// A gap is skipped with a single <forward>, however many values it took
+fn join_forwards(items: Vec<Item>) -> Vec<Item> {
+    let mut joined: Vec<Item> = vec![];
+    for item in items {
+        match (joined.last_mut(), item) {
+            (Some(Item::Forward(length)), Item::Forward(more)) => *length += more,
+            (_, item) => joined.push(item),
+        }
+    }
+    joined
+}
+
+fn type_name(base: u32) -> &'static str {
+    match base {
+        1 => "whole",
+        2 => "half",
+        4 => "quarter",
+        8 => "eighth",
+        16 => "16th",
+        32 => "32nd",
+        64 => "64th",
+        _ => "128th",
+    }
+}
+
+fn accidental_name(accidental: Accidental) -> &'static str {
+    match accidental {
+        Accidental::Sharp => "sharp",
+        Accidental::Flat => "flat",
+        Accidental::Natural => "natural",
+        Accidental::DoubleSharp => "double-sharp",
+        Accidental::DoubleFlat => "flat-flat",
+        Accidental::QuarterSharp => "quarter-sharp",
+        Accidental::QuarterFlat => "quarter-flat",
+        Accidental::ThreeQuarterSharp => "three-quarters-sharp",
+        Accidental::ThreeQuarterFlat => "three-quarters-flat",
+    }
+}
+
+fn write_clef(xml: &mut XmlWriter, clef: &Clef, number: Option<usize>) {
+    match number {
+        Some(number) => xml.open(&format!("clef number=\"{}\"", number)),
+        None => xml.open("clef"),
+    }
+    let sign = match clef.shape {
+        ClefShape::G => "G",
+        ClefShape::F => "F",
+        ClefShape::C => "C",
+    };
+    xml.leaf("sign", sign);
+    xml.leaf("line", &clef.line.to_string());
+    if clef.octave != 0 {
+        xml.leaf("clef-octave-change", &clef.octave.to_string());
+    }
+    xml.close("clef");
+}
+
+// Beams join eighths and shorter within a beat, or within a dotted beat in
+// compound meters. Each beam level gets begin, continue or end, and a note
+// with no neighbour on its level gets a hook.
+fn beams(items: &[Item], group: Pulse) -> Vec<Vec<(u32, &'static str)>> {
+    let level = |value: &NoteValue| (value.base.trailing_zeros() as i32 - 2).max(0) as u32;
+    let mut result = vec![vec![]; items.len()];
+    let mut runs: Vec<Vec<(usize, u32)>> = vec![];
+    let mut run: Vec<(usize, u32)> = vec![];
+    let mut run_group = -1;
+    let mut beat = Pulse::default();
+    for (i, item) in items.iter().enumerate() {
+        let groups = beat / group;
+        let item_group = groups.num().div_euclid(groups.den());
+        match item {
+            Item::Note { value, .. } if level(value) > 0 && item_group == run_group => run.push((i, level(value))),
+            Item::Note { value, .. } if level(value) > 0 => {
+                runs.push(std::mem::take(&mut run));
+                run.push((i, level(value)));
+                run_group = item_group;
+            }
+            Item::Clef(_) => {}
+            _ => {
+                runs.push(std::mem::take(&mut run));
+                run_group = -1;
+            }
+        }
+        beat += item.length();
+    }
+    runs.push(run);
+    for run in runs.into_iter().filter(|r| r.len() > 1) {
+        for (n, &(i, levels)) in run.iter().enumerate() {
+            let before = if n > 0 { run[n - 1].1 } else { 0 };
+            let after = run.get(n + 1).map(|r| r.1).unwrap_or(0);
+            for l in 1..=levels {
+                let state = match (before >= l, after >= l) {
+                    (false, true) => "begin",
+                    (true, true) => "continue",
+                    (true, false) => "end",
+                    (false, false) if n + 1 < run.len() => "forward hook",
+                    (false, false) => "backward hook",
+                };
+                result[i].push((l, state));
+            }
+        }
+    }
+    result
+}
+
+impl Score {
+    // Writes a partwise MusicXML document. Each part lists its staves in
+    // turn and each layer of a staff is a voice, with a backup to the start
+    // of the measure between voices.
+    pub fn to_musicxml(&self) -> String {
+        let mut measures = self.measures();
+        if measures.is_empty() {
+            measures.push((Pulse::default(), self.meter_at(Pulse::default()).length()));
+        }
+        let segments = self.segments(&measures);
+
+        // The voices of every staff in every measure, worked out up front so
+        // the divisions can cover every length used
+        let mut contents: Vec<Vec<Voices>> = vec![];
+        let staff_marks: Vec<Vec<(Pulse, Mark)>> = (0..self.staff_count()).map(|staff| self.marks(staff)).collect();
+        let mut accidental_states: Vec<AccidentalState> = (0..self.staff_count()).map(|_| AccidentalState::new(self.key_at(Pulse::default()))).collect();
+        for &(measure_start, measure_end) in &measures {
+            let mut staves = vec![];
+            for staff in 0..self.staff_count() {
+                let state = &mut accidental_states[staff as usize];
+                state.next_measure(self.key_at(measure_start));
+                let accidentals = crate::accidentals_in_measure(state, &segments, staff, (measure_start, measure_end));
+                let mut voices = vec![];
+                for (i, (&(_, layer), layer_segments)) in segments.range((staff, 0)..=(staff, u32::MAX)).enumerate() {
+                    let first = i == 0;
+                    // Clef changes go in the first voice, the opening clef is in the first measure's attributes
+                    let mut marks: Vec<(Pulse, Mark)> = if first {
+                        staff_marks[staff as usize].iter().filter(|(p, _)| *p > Pulse::default() && *p >= measure_start && *p < measure_end).copied().collect()
+                    } else {
+                        vec![]
+                    };
+                    let mut writer = VoiceWriter::new(XmlVoice);
+                    let mut beat = measure_start;
+                    for segment in layer_segments.iter().filter(|s| s.start >= measure_start && s.start < measure_end) {
+                        writer.fill(&mut beat, segment.start, first, &mut marks, segment.event.duration.tuplet);
+                        writer.push_marks_at(segment.start, &mut marks);
+                        for (n, (value, tie)) in segment.values().into_iter().enumerate() {
+                            let accidentals = if segment.piece == 0 && n == 0 { accidentals.get(&segment.event.id()).cloned() } else { None };
+                            writer.push(value, Item::Note { notes: segment.event.notes.clone(), value, tie, accidentals, bracket: Bracket::default() });
+                        }
+                        beat = segment.start + segment.length;
+                    }
+                    // The first voice is filled out to the barline, the others end at their last note
+                    if first {
+                        writer.fill(&mut beat, measure_end, true, &mut marks, None);
+                    }
+                    voices.push((layer, join_forwards(writer.finish().1)));
+                }
+                staves.push(voices);
+            }
+            contents.push(staves);
+        }
+
+        let mut divisions = 1;
+        for item in contents.iter().flatten().flatten().flat_map(|(_, items)| items) {
+            let quarters = item.length() * Pulse::whole(4);
+            divisions = divisions / gcd(divisions, quarters.den()) * quarters.den();
+        }
+        let duration = |length: Pulse| {
+            let d = length * Pulse::whole(4 * divisions);
+            (d.num() / d.den()).to_string()
+        };
+
+        let mut xml = XmlWriter { out: String::new(), depth: 0 };
+        xml.line("<?xml version=\"1.0\" encoding=\"UTF-8\"?>");
+        xml.line("<!DOCTYPE score-partwise PUBLIC \"-//Recordare//DTD MusicXML 4.0 Partwise//EN\" \"http://www.musicxml.org/dtds/partwise.dtd\">");
+        xml.open("score-partwise version=\"4.0\"");
+        xml.open("part-list");
+        for (p, part) in self.parts.iter().enumerate() {
+            xml.open(&format!("score-part id=\"P{}\"", p + 1));
+            let name = if part.name.is_empty() { format!("Part {}", p + 1) } else { part.name.clone() };
+            xml.leaf("part-name", &name);
+            xml.open(&format!("score-instrument id=\"P{}-I1\"", p + 1));
+            xml.leaf("instrument-name", &name);
+            xml.close("score-instrument");
+            xml.open(&format!("midi-instrument id=\"P{}-I1\"", p + 1));
+            xml.leaf("midi-program", &(part.program as u32 + 1).to_string());
+            xml.close("midi-instrument");
+            xml.close("score-part");
+        }
+        xml.close("part-list");
+
+        let mut first_staff = 0;
+        for (p, part) in self.parts.iter().enumerate() {
+            let staff_count = part.staves.len();
+            xml.open(&format!("part id=\"P{}\"", p + 1));
+            for (m, &(measure_start, _)) in measures.iter().enumerate() {
+                xml.open(&format!("measure number=\"{}\"", m + 1));
+                let previous = if m > 0 { Some(measures[m - 1].0) } else { None };
+                let meter = self.meter_at(measure_start);
+                let key = self.key_at(measure_start);
+                let meter_changed = previous.map(|p| self.meter_at(p) != meter).unwrap_or(true);
+                let key_changed = previous.map(|p| self.key_at(p) != key).unwrap_or(true);
+                if meter_changed || key_changed {
+                    xml.open("attributes");
+                    if m == 0 {
+                        xml.leaf("divisions", &divisions.to_string());
+                    }
+                    if key_changed {
+                        xml.open("key");
+                        xml.leaf("fifths", &key.fifths.to_string());
+                        xml.leaf("mode", &key.to_mei_mode());
+                        xml.close("key");
+                    }
+                    if meter_changed {
+                        xml.open("time");
+                        xml.leaf("beats", &meter.count.to_string());
+                        xml.leaf("beat-type", &meter.unit.to_string());
+                        xml.close("time");
+                    }
+                    if m == 0 {
+                        if staff_count > 1 {
+                            xml.leaf("staves", &staff_count.to_string());
+                        }
+                        for (s, staff) in part.staves.iter().enumerate() {
+                            let number = if staff_count > 1 { Some(s + 1) } else { None };
+                            write_clef(&mut xml, &staff.clef_at(Pulse::default()), number);
+                        }
+                    }
+                    xml.close("attributes");
+                }
+                let tempo = self.tempo_at(measure_start);
+                if p == 0 && previous.map(|p| self.tempo_at(p) != tempo).unwrap_or(true) {
+                    xml.empty(&format!("sound tempo=\"{}\"", tempo.bpm));
+                }
+
+                // Eighths are beamed by beat, or by dotted beat in compound meters
+                let compound = meter.count.is_multiple_of(3) && meter.count > 3 && meter.unit >= 8;
+                let group = Pulse::new(if compound { 3 } else { 1 }, meter.unit as i64);
+                let mut position = Pulse::default();
+                for s in 0..staff_count {
+                    for (layer, items) in &contents[m][first_staff + s] {
+                        if !position.is_zero() {
+                            xml.open("backup");
+                            xml.leaf("duration", &duration(position));
+                            xml.close("backup");
+                            position = Pulse::default();
+                        }
+                        let voice = (s * 4 + *layer as usize + 1).to_string();
+                        let staff_number = if staff_count > 1 { Some((s + 1).to_string()) } else { None };
+                        let item_beams = beams(items, group);
+                        for (item, item_beams) in items.iter().zip(item_beams) {
+                            let (value, bracket) = match item {
+                                Item::Clef(clef) => {
+                                    xml.open("attributes");
+                                    let number = if staff_count > 1 { Some(s + 1) } else { None };
+                                    write_clef(&mut xml, clef, number);
+                                    xml.close("attributes");
+                                    continue;
+                                }
+                                Item::Forward(length) => {
+                                    xml.open("forward");
+                                    xml.leaf("duration", &duration(*length));
+                                    xml.leaf("voice", &voice);
+                                    if let Some(staff_number) = &staff_number {
+                                        xml.leaf("staff", staff_number);
+                                    }
+                                    xml.close("forward");
+                                    position += *length;
+                                    continue;
+                                }
+                                Item::Note { value, bracket, .. } | Item::Rest { value, bracket } => (*value, *bracket),
+                            };
+                            position += value.length();
+
+                            let notes: Vec<Option<(usize, &Note)>> = match item {
+                                Item::Note { notes, .. } => notes.iter().enumerate().map(Some).collect(),
+                                _ => vec![None],
+                            };
+                            for (n, note) in notes.into_iter().enumerate() {
+                                xml.open("note");
+                                if n > 0 {
+                                    xml.empty("chord");
+                                }
+                                match note {
+                                    Some((_, note)) => {
+                                        xml.open("pitch");
+                                        xml.leaf("step", &note.pitch.class.to_string().to_uppercase());
+                                        let cents = note.pitch.accidental.cents();
+                                        if cents != 0 {
+                                            xml.leaf("alter", &format!("{}", cents as f64 / 100.0));
+                                        }
+                                        xml.leaf("octave", &note.octave.0.to_string());
+                                        xml.close("pitch");
+                                    }
+                                    None => xml.empty("rest"),
+                                }
+                                xml.leaf("duration", &duration(value.length()));
+                                let tie = match item {
+                                    Item::Note { tie, .. } => *tie,
+                                    _ => None,
+                                };
+                                let (tie_stop, tie_start) = match tie {
+                                    Some("i") => (false, true),
+                                    Some("m") => (true, true),
+                                    Some("t") => (true, false),
+                                    _ => (false, false),
+                                };
+                                if tie_stop {
+                                    xml.empty("tie type=\"stop\"");
+                                }
+                                if tie_start {
+                                    xml.empty("tie type=\"start\"");
+                                }
+                                xml.leaf("voice", &voice);
+                                xml.leaf("type", type_name(value.base));
+                                for _ in 0..value.dots {
+                                    xml.empty("dot");
+                                }
+                                if let (Some((i, note)), Item::Note { accidentals, .. }) = (note, item) {
+                                    let display = accidentals.as_ref().and_then(|a| a.get(i)).copied().unwrap_or(AccidentalDisplay::Hidden);
+                                    let name = accidental_name(note.pitch.accidental);
+                                    match display {
+                                        AccidentalDisplay::Written => xml.leaf("accidental", name),
+                                        AccidentalDisplay::Cautionary => xml.line(&format!("<accidental cautionary=\"yes\" parentheses=\"yes\">{}</accidental>", name)),
+                                        AccidentalDisplay::Hidden => {}
+                                    }
+                                }
+                                if let Some(t) = value.tuplet {
+                                    xml.open("time-modification");
+                                    xml.leaf("actual-notes", &t.num.to_string());
+                                    xml.leaf("normal-notes", &t.numbase.to_string());
+                                    xml.close("time-modification");
+                                }
+                                if let Some(staff_number) = &staff_number {
+                                    xml.leaf("staff", staff_number);
+                                }
+                                if n == 0 {
+                                    for (level, state) in &item_beams {
+                                        xml.line(&format!("<beam number=\"{}\">{}</beam>", level, state));
+                                    }
+                                }
+                                let tuplet_marks = n == 0 && (bracket.start || bracket.stop);
+                                if tie_start || tie_stop || tuplet_marks {
+                                    xml.open("notations");
+                                    if tie_stop {
+                                        xml.empty("tied type=\"stop\"");
+                                    }
+                                    if tie_start {
+                                        xml.empty("tied type=\"start\"");
+                                    }
+                                    if n == 0 && bracket.start {
+                                        xml.empty("tuplet type=\"start\"");
+                                    }
+                                    if n == 0 && bracket.stop {
+                                        xml.empty("tuplet type=\"stop\"");
+                                    }
+                                    xml.close("notations");
+                                }
+                                xml.close("note");
+                            }
+                        }
+                    }
+                }
+                xml.close("measure");
+            }
+            xml.close("part");
+            first_staff += staff_count;
+        }
+        xml.close("score-partwise");
+        xml.out
+    }
+}
+
+fn child<'d>(element: Element<'d>, name: &str) -> Option<Element<'d>> {
+    element.children().into_iter().filter_map(|c| c.element()).find(|e| e.name().local_part() == name)
+}
+
+fn children<'d>(element: Element<'d>) -> impl Iterator<Item=Element<'d>> {
+    element.children().into_iter().filter_map(|c| c.element())
+}
+
+fn text(element: Element) -> String {
+    element.children().into_iter().filter_map(|c| c.text()).map(|t| t.text()).collect::<String>().trim().to_string()
+}
+
+fn child_text(element: Element, name: &str) -> Option<String> {
+    child(element, name).map(text)
+}
+
+fn child_number<T: std::str::FromStr>(element: Element, name: &str) -> Option<T> {
+    child_text(element, name).and_then(|t| t.parse().ok())
+}
+
+// sxd-document only understands SYSTEM doctypes and MusicXML uses PUBLIC
+// ones, so the declaration is dropped before parsing
+fn strip_doctype(text: &str) -> String {
+    match text.find("<!DOCTYPE") {
+        Some(start) => {
+            let rest = &text[start..];
+            let end = match (rest.find('['), rest.find('>')) {
+                (Some(bracket), Some(close)) if bracket < close => rest.find("]>").map(|e| e + 2),
+                (_, close) => close.map(|e| e + 1),
+            };
+            match end {
+                Some(end) => format!("{}{}", &text[..start], &rest[end..]),
+                None => text.to_string(),
+            }
+        }
+        None => text.to_string(),
+    }
+}
+
+fn base_of_type(name: &str) -> Option<u32> {
+    (0..=SHORTEST_BASE.trailing_zeros()).map(|power| 1 << power).find(|&base| type_name(base) == name)
+}
+
+// Joins the event read last onto the one its ties start from, once all of its
+// notes are in. `ties` holds whether each note stops and starts a tie, and a
+// chord is only joined when every note is tied to the same notes before it.
+// Gives the index the event ends up at.
+fn join_ties(events: &mut Vec<Event>, open_ties: &mut HashMap<(u32, u32), usize>, ties: &[(bool, bool)]) -> usize {
+    let index = events.len() - 1;
+    let cents = |event: &Event| {
+        let mut cents: Vec<i32> = event.notes.iter().map(|n| n.cents()).collect();
+        cents.sort_unstable();
+        cents
+    };
+    let (key, start) = ((events[index].staff, events[index].layer), events[index].start);
+    let stops = ties.iter().filter(|(stop, _)| *stop).count();
+    let starts = ties.iter().filter(|(_, start)| *start).count();
+    let mut joined = index;
+    if stops > 0 {
+        match open_ties.remove(&key) {
+            Some(tied) if stops == ties.len() && events[tied].end() == start && cents(&events[tied]) == cents(&events[index]) => {
+                match NoteValue::from_length(events[tied].length() + events[index].length()) {
+                    Some(value) => {
+                        events[tied].duration = value;
+                        events.pop();
+                        joined = tied;
+                    }
+                    None => log::warn!("Tied notes at {:?} don't add up to a single value, keeping them apart", start),
+                }
+            }
+            _ => log::warn!("Can't join the tie ending at {:?}", start),
+        }
+    }
+    if starts == ties.len() {
+        open_ties.insert(key, joined);
+    } else if starts > 0 {
+        log::warn!("Only some notes of the chord at {:?} are tied, leaving the tie out", start);
+    }
+    joined
+}
+
+// Counts elements which have no equivalent here so each kind is reported once
+#[derive(Default)]
+struct Unsupported(BTreeMap<String, u32>);
+
+impl Unsupported {
+    fn add(&mut self, name: &str) {
+        *self.0.entry(name.to_string()).or_default() += 1;
+    }
+
+    fn report(&self) {
+        for (name, count) in &self.0 {
+            log::warn!("Ignored {} unsupported <{}> element(s)", count, name);
+        }
+    }
+}
+
+impl Context {
+    // Reads a compressed .mxl file, which is a zip holding the score and a
+    // container file pointing at it
+    pub fn from_mxl(data: &[u8]) -> Result<Self, String> {
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(data)).map_err(|e| format!("Not an mxl file: {}", e))?;
+        let read = |archive: &mut zip::ZipArchive<std::io::Cursor<&[u8]>>, name: &str| -> Result<String, String> {
+            let mut file = archive.by_name(name).map_err(|e| format!("Can't read {}: {}", name, e))?;
+            let mut text = String::new();
+            file.read_to_string(&mut text).map_err(|e| format!("Can't read {}: {}", name, e))?;
+            Ok(text)
+        };
+        let root = match read(&mut archive, "META-INF/container.xml") {
+            Ok(container) => {
+                let package = parser::parse(&strip_doctype(&container)).map_err(|e| format!("Bad container.xml: {:?}", e))?;
+                let document = package.as_document();
+                document.root().children().into_iter().filter_map(|c| c.element()).next()
+                    .and_then(|container| child(container, "rootfiles"))
+                    .and_then(|rootfiles| child(rootfiles, "rootfile"))
+                    .and_then(|rootfile| rootfile.attribute_value("full-path").map(|p| p.to_string()))
+            }
+            Err(_) => None,
+        };
+        let root = match root {
+            Some(root) => root,
+            None => archive.file_names()
+                .find(|n| !n.starts_with("META-INF") && (n.ends_with(".musicxml") || n.ends_with(".xml")))
+                .map(|n| n.to_string())
+                .ok_or_else(|| "No score in the mxl file".to_string())?,
+        };
+        let text = read(&mut archive, &root)?;
+        Self::from_musicxml(&text)
+    }
+
+    // Reads a partwise MusicXML document. Each part keeps its staves and the
+    // voices on a staff become its layers, in the order they first appear.
+    // Tied notes are joined into one event when a single value covers them.
+    // Anything without an equivalent is skipped with a warning.
+    pub fn from_musicxml(text: &str) -> Result<Self, String> {
+        let (ctx, unsupported) = Self::read_musicxml(text)?;
+        unsupported.report();
+        Ok(ctx)
+    }
+
+    fn read_musicxml(text: &str) -> Result<(Self, Unsupported), String> {
+        let package = parser::parse(&strip_doctype(text)).map_err(|e| format!("Bad MusicXML: {:?}", e))?;
+        let document = package.as_document();
+        let root = document.root().children().into_iter().filter_map(|c| c.element()).next().ok_or_else(|| "Empty MusicXML document".to_string())?;
+        match root.name().local_part() {
+            "score-partwise" => {}
+            "score-timewise" => return Err("Timewise MusicXML isn't supported".to_string()),
+            other => return Err(format!("Not a MusicXML score: <{}>", other)),
+        }
+
+        let mut unsupported = Unsupported::default();
+        let mut ctx = Context::default();
+        ctx.score.parts.clear();
+
+        let mut part_info: HashMap<String, (String, u8)> = HashMap::new();
+        if let Some(part_list) = child(root, "part-list") {
+            for score_part in children(part_list).filter(|e| e.name().local_part() == "score-part") {
+                let id = score_part.attribute_value("id").unwrap_or("").to_string();
+                let name = child_text(score_part, "part-name").unwrap_or_default();
+                let program = child(score_part, "midi-instrument")
+                    .and_then(|m| child_number::<u8>(m, "midi-program"))
+                    .map(|p| p.saturating_sub(1).min(127))
+                    .unwrap_or(0);
+                part_info.insert(id, (name, program));
+            }
+        }
+
+        let mut events: Vec<Event> = vec![];
+        for part in children(root).filter(|e| e.name().local_part() == "part") {
+            let id = part.attribute_value("id").unwrap_or("");
+            let (name, program) = part_info.get(id).cloned().unwrap_or_default();
+            let first_staff = ctx.score.staff_count();
+            let staff_count = part.children().into_iter().filter_map(|c| c.element())
+                .filter_map(|m| child(m, "attributes"))
+                .filter_map(|a| child_number::<u32>(a, "staves"))
+                .max()
+                .unwrap_or(1)
+                .max(1);
+            ctx.score.parts.push(Part {
+                name,
+                staves: (0..staff_count).map(|_| Staff::default()).collect(),
+                program,
+            });
+
+            let mut divisions = 1i64;
+            let mut measure_start = Pulse::default();
+            // Layers are handed out to voices per staff as they turn up
+            let mut layers: HashMap<(u32, String), u32> = HashMap::new();
+            // The event index of notes waiting for the end of a tie, by staff and layer
+            let mut open_ties: HashMap<(u32, u32), usize> = HashMap::new();
+            let mut last_event: Option<usize> = None;
+            // The ties of each note of the last event, until the chord is complete
+            let mut chord_ties: Option<Vec<(bool, bool)>> = None;
+            for measure in children(part).filter(|e| e.name().local_part() == "measure") {
+                let mut position = measure_start;
+                let mut measure_end = measure_start;
+                for element in children(measure) {
+                    let length_of = |element: Element| child_number::<i64>(element, "duration").map(|d| Pulse::new(d, 4 * divisions)).unwrap_or_default();
+                    match element.name().local_part() {
+                        "attributes" => {
+                            if let Some(d) = child_number::<i64>(element, "divisions") {
+                                divisions = d.max(1);
+                            }
+                            if let Some(key) = child(element, "key") {
+                                match child_number::<i32>(key, "fifths") {
+                                    Some(fifths) if fifths.abs() <= 7 => {
+                                        let mode = if child_text(key, "mode").as_deref() == Some("minor") { Mode::Minor } else { Mode::Major };
+                                        ctx.score.keys.insert(position, Key { fifths, mode });
+                                    }
+                                    _ => unsupported.add("key"),
+                                }
+                            }
+                            if let Some(time) = child(element, "time") {
+                                let count = child_number::<u32>(time, "beats");
+                                let unit = child_number::<u32>(time, "beat-type");
+                                match (count, unit) {
+                                    (Some(count), Some(unit)) if count > 0 && unit.is_power_of_two() && unit <= SHORTEST_BASE => {
+                                        ctx.score.meters.insert(position, Meter { count, unit });
+                                    }
+                                    _ => unsupported.add("time"),
+                                }
+                            }
+                            for clef in children(element).filter(|e| e.name().local_part() == "clef") {
+                                let number: u32 = clef.attribute_value("number").and_then(|n| n.parse().ok()).unwrap_or(1);
+                                let shape = match child_text(clef, "sign").as_deref() {
+                                    Some("G") => ClefShape::G,
+                                    Some("F") => ClefShape::F,
+                                    Some("C") => ClefShape::C,
+                                    _ => {
+                                        unsupported.add("clef");
+                                        continue;
+                                    }
+                                };
+                                let line = child_number(clef, "line").unwrap_or(match shape {
+                                    ClefShape::G => 2,
+                                    ClefShape::F => 4,
+                                    ClefShape::C => 3,
+                                });
+                                let octave = child_number(clef, "clef-octave-change").unwrap_or(0);
+                                if let Some(staff) = ctx.score.staff_mut(first_staff + number.clamp(1, staff_count) - 1) {
+                                    staff.clefs.insert(position, Clef { shape, line, octave });
+                                }
+                            }
+                        }
+                        "backup" => {
+                            position -= length_of(element);
+                            if position < measure_start {
+                                position = measure_start;
+                            }
+                        }
+                        "forward" => {
+                            position += length_of(element);
+                        }
+                        "direction" => {
+                            let staff: u32 = child_number(element, "staff").unwrap_or(1);
+                            let offset = child_number::<i64>(element, "offset").map(|d| Pulse::new(d, 4 * divisions)).unwrap_or_default();
+                            for direction_type in children(element).filter(|e| e.name().local_part() == "direction-type") {
+                                for kind in children(direction_type) {
+                                    match kind.name().local_part() {
+                                        "dynamics" => {
+                                            let dynamic = children(kind).next().and_then(|d| d.name().local_part().parse::<Dynamic>().ok());
+                                            match (dynamic, ctx.score.staff_mut(first_staff + staff.clamp(1, staff_count) - 1)) {
+                                                (Some(dynamic), Some(staff)) => staff.dynamics.insert(position + offset, dynamic),
+                                                _ => unsupported.add("dynamics"),
+                                            }
+                                        }
+                                        other => unsupported.add(other),
+                                    }
+                                }
+                            }
+                            if let Some(bpm) = child(element, "sound").and_then(|s| s.attribute_value("tempo")).and_then(|t| t.parse::<f64>().ok()) {
+                                ctx.score.tempos.insert(position + offset, Tempo { bpm: bpm.round().max(1.0) as u32 });
+                            }
+                        }
+                        "sound" => {
+                            if let Some(bpm) = element.attribute_value("tempo").and_then(|t| t.parse::<f64>().ok()) {
+                                ctx.score.tempos.insert(position, Tempo { bpm: bpm.round().max(1.0) as u32 });
+                            }
+                        }
+                        "note" => {
+                            let chord = child(element, "chord").is_some();
+                            for ignored in ["grace", "cue", "unpitched", "lyric"] {
+                                if child(element, ignored).is_some() {
+                                    unsupported.add(ignored);
+                                }
+                            }
+                            if child(element, "grace").is_some() || child(element, "cue").is_some() {
+                                continue;
+                            }
+                            if !chord {
+                                if let Some(ties) = chord_ties.take() {
+                                    last_event = Some(join_ties(&mut events, &mut open_ties, &ties));
+                                }
+                            }
+                            let length = length_of(element);
+                            let start = if chord { events.get(last_event.unwrap_or(usize::MAX)).map(|e| e.start).unwrap_or(position) } else { position };
+                            if !chord {
+                                position += length;
+                                measure_end = measure_end.max(position);
+                            }
+                            let pitch = match child(element, "pitch") {
+                                Some(pitch) => pitch,
+                                None => continue,
+                            };
+                            let class = match child_text(pitch, "step").and_then(|s| s.to_lowercase().parse::<PitchName>().ok()) {
+                                Some(class) => class,
+                                None => {
+                                    unsupported.add("pitch");
+                                    continue;
+                                }
+                            };
+                            let cents = child_number::<f64>(pitch, "alter").map(|a| (a * 100.0).round() as i32).unwrap_or(0);
+                            let written = (cents as f64 / 50.0).round() as i32 * 50;
+                            let accidental = match Accidental::from_cents(written) {
+                                Some(accidental) => accidental,
+                                None => {
+                                    unsupported.add("alter");
+                                    Accidental::Natural
+                                }
+                            };
+                            let mut note = Note::new(Pitch { class, accidental }, Octave(child_number(pitch, "octave").unwrap_or(4)));
+                            note.detune = cents - accidental.cents();
+                            note.cautionary = child(element, "accidental")
+                                .map(|a| a.attribute_value("cautionary") == Some("yes") || a.attribute_value("parentheses") == Some("yes"))
+                                .unwrap_or(false);
+
+                            let staff = first_staff + child_number::<u32>(element, "staff").unwrap_or(1).clamp(1, staff_count) - 1;
+                            let voice = child_text(element, "voice").unwrap_or_else(|| "1".to_string());
+                            let next_layer = layers.keys().filter(|(s, _)| *s == staff).count() as u32;
+                            let layer = *layers.entry((staff, voice)).or_insert(next_layer);
+
+                            let ties: Vec<String> = children(element).filter(|e| e.name().local_part() == "tie").filter_map(|t| t.attribute_value("type").map(|t| t.to_string())).collect();
+                            let tie = (ties.iter().any(|t| t == "stop"), ties.iter().any(|t| t == "start"));
+                            if chord {
+                                if let (Some(event), Some(chord_ties)) = (last_event.and_then(|i| events.get_mut(i)), &mut chord_ties) {
+                                    event.notes.push(note);
+                                    chord_ties.push(tie);
+                                    continue;
+                                }
+                            }
+
+                            // The written value is only trusted when it adds up to
+                            // the duration, and otherwise the duration decides
+                            let base = child_text(element, "type").and_then(|t| base_of_type(&t));
+                            let dots = children(element).filter(|e| e.name().local_part() == "dot").count() as u32;
+                            let tuplet = child(element, "time-modification").and_then(|t| {
+                                Some(Tuplet { num: child_number(t, "actual-notes")?, numbase: child_number(t, "normal-notes")? })
+                            });
+                            let tuplet = match tuplet {
+                                Some(tuplet) if tuplet.num == 0 || tuplet.numbase == 0 => {
+                                    unsupported.add("time-modification");
+                                    None
+                                }
+                                tuplet => tuplet,
+                            };
+                            let base = if dots > 2 {
+                                unsupported.add("dot");
+                                None
+                            } else {
+                                base
+                            };
+                            let duration = match base.map(|base| NoteValue { base, dots, tuplet }) {
+                                Some(value) if value.length() == length || child(element, "duration").is_none() => value,
+                                _ => NoteValue::longest_within(length),
+                            };
+                            chord_ties = Some(vec![tie]);
+                            last_event = Some(events.len());
+                            events.push(Event {
+                                event_id: 0,
+                                staff,
+                                layer,
+                                notes: vec![note],
+                                duration,
+                                start,
+                            });
+                        }
+                        "print" | "barline" => {}
+                        other => unsupported.add(other),
+                    }
+                }
+                measure_start = measure_end.max(position).max(measure_start + if measure_end == measure_start {
+                    ctx.score.meter_at(measure_start).length()
+                } else {
+                    Pulse::default()
+                });
+            }
+            if let Some(ties) = chord_ties.take() {
+                join_ties(&mut events, &mut open_ties, &ties);
+            }
+        }
+
+        for mut event in events {
+            event.event_id = ctx.next_id;
+            ctx.next_id += 1;
+            ctx.score.events.insert(event);
+        }
+        if ctx.score.parts.is_empty() {
+            ctx.score.parts.push(Part::default());
+        }
+        Ok((ctx, unsupported))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsupported_elements_are_counted() {
+        let (ctx, unsupported) = Context::read_musicxml(r#"<score-partwise version="4.0">
+  <part id="P1">
+    <measure number="1">
+      <attributes><divisions>1</divisions><key><fifths>9</fifths></key></attributes>
+      <harmony><root><root-step>C</root-step></root></harmony>
+      <direction><direction-type><words>dolce</words></direction-type></direction>
+      <note><grace/><pitch><step>B</step><octave>4</octave></pitch><type>eighth</type></note>
+      <note><pitch><step>C</step><octave>5</octave></pitch><duration>2</duration><lyric><text>la</text></lyric></note>
+      <note><pitch><step>D</step><octave>5</octave></pitch><duration>2</duration><lyric><text>la</text></lyric></note>
+    </measure>
+  </part>
+</score-partwise>"#).unwrap();
+        let counts: Vec<(&str, u32)> = unsupported.0.iter().map(|(name, &count)| (name.as_str(), count)).collect();
+        assert_eq!(counts, vec![("grace", 1), ("harmony", 1), ("key", 1), ("lyric", 2), ("words", 1)]);
+        // The grace note is left out but the lyrics' notes are kept
+        assert_eq!(ctx.score.events.len(), 2);
+    }
+
+    #[test]
+    fn values_which_dont_add_up_fall_back_to_the_duration() {
+        let (ctx, unsupported) = Context::read_musicxml(r#"<score-partwise version="4.0">
+  <part id="P1">
+    <measure number="1">
+      <attributes><divisions>2</divisions></attributes>
+      <note><pitch><step>C</step><octave>5</octave></pitch><duration>2</duration><type>quarter</type><time-modification><actual-notes>0</actual-notes><normal-notes>2</normal-notes></time-modification></note>
+      <note><pitch><step>D</step><octave>5</octave></pitch><duration>2</duration><type>eighth</type><dot/><dot/><dot/></note>
+      <note><pitch><step>E</step><octave>5</octave></pitch><duration>4</duration><type>quarter</type></note>
+    </measure>
+  </part>
+</score-partwise>"#).unwrap();
+        let counts: Vec<(&str, u32)> = unsupported.0.iter().map(|(name, &count)| (name.as_str(), count)).collect();
+        assert_eq!(counts, vec![("dot", 1), ("time-modification", 1)]);
+        let durations: Vec<NoteValue> = ctx.score.events_on_staff(0).map(|e| e.duration()).collect();
+        assert_eq!(durations, vec![NoteValue::new(4), NoteValue::new(4), NoteValue::new(2)]);
+    }
+}
@@ -0,0 +1,1019 @@
+use std::collections::HashMap;
+
+use serde::{Serialize, Deserialize};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Note {
+    pub pitch: Pitch,
+    pub octave: Octave,
+    // Always show the accidental, in parentheses if it wouldn't otherwise be needed
+    #[serde(default)]
+    pub cautionary: bool,
+    // Cents on top of the written pitch. Only heard, never written.
+    #[serde(default)]
+    pub detune: i32,
+}
+
+impl Default for Note {
+    fn default() -> Self {
+        Self {
+            pitch: Default::default(),
+            octave: Octave(4),
+            cautionary: false,
+            detune: 0,
+        }
+    }
+}
+
+impl Note {
+    pub fn new(pitch: Pitch, octave: Octave) -> Self {
+        Self {
+            pitch,
+            octave,
+            cautionary: false,
+            detune: 0,
+        }
+    }
+
+    pub(crate) fn to_mei(self, display: AccidentalDisplay, key: Key) -> ir::Note {
+        let accidental = self.pitch.accidental;
+        let mut note = ir::Note {
+            pname: Some(self.pitch.class.to_string()),
+            oct: self.octave.0,
+            ..Default::default()
+        };
+        match display {
+            AccidentalDisplay::Written => note.accid = Some(accidental.to_string()),
+            AccidentalDisplay::Cautionary => note.accids.push(ir::Accid {
+                accid: Some(accidental.to_string()),
+                func: Some("caution".to_string()),
+                enclose: Some("paren".to_string()),
+                ..Default::default()
+            }),
+            AccidentalDisplay::Hidden => {
+                // A natural only needs spelling out when the key would alter the note
+                if accidental != Accidental::Natural || key.alteration(self.pitch.class) != 0 {
+                    note.accid_ges = Some(accidental.to_string());
+                }
+            }
+        }
+        note
+    }
+
+    // Moves the note by some number of semitones, spelling the result to fit the key
+    pub fn transpose(&mut self, semitones: i32, key: Key) {
+        self.set_cents(self.cents() + semitones * 100, key);
+    }
+
+    // Moves the note by quarter tones, keeping the letter if there is an
+    // accidental for it
+    pub fn transpose_quarter_tones(&mut self, quarter_tones: i32, key: Key) {
+        match Accidental::from_cents(self.pitch.accidental.cents() + quarter_tones * 50) {
+            Some(accidental) => self.pitch.accidental = accidental,
+            None => self.set_cents(self.cents() + quarter_tones * 50, key),
+        }
+    }
+
+    // Semitones above C0, rounded down for quarter tones
+    pub fn semitones(&self) -> i32 {
+        self.octave.0 as i32 * 12 + self.pitch.semitones()
+    }
+
+    // The written pitch in cents above C0
+    pub fn cents(&self) -> i32 {
+        self.octave.0 as i32 * 1200 + self.pitch.class.semitones() * 100 + self.pitch.accidental.cents()
+    }
+
+    // The nearest MIDI key, C4 being 60, and how many cents the note sounds
+    // above or below it
+    pub fn midi_key(&self) -> (u8, i32) {
+        let cents = self.cents() + self.detune + 1200;
+        let key = (cents + 50).div_euclid(100);
+        (key.clamp(0, 127) as u8, cents - key * 100)
+    }
+
+    // Spells a pitch given in cents above C0. Anything finer than a quarter
+    // tone is rounded away, and pitches below C0 leave the note as it was.
+    fn set_cents(&mut self, cents: i32, key: Key) {
+        let quarter_tones = (cents + 25).div_euclid(50);
+        if quarter_tones < 0 {
+            log::warn!("Can't move a note below C0, so it's left where it was");
+            return;
+        }
+        let semitones = quarter_tones.div_euclid(2);
+        let quarter = quarter_tones.rem_euclid(2) * 50;
+        // A quarter tone is added to the spelling below or taken off the one
+        // above, whichever needs the smaller accidental
+        let best = [(semitones, quarter), (semitones + 1, quarter - 100)].iter()
+            .filter_map(|&(semitones, quarter)| {
+                let spelled = Self::from_semitones_in_key(semitones, key)?;
+                let accidental = Accidental::from_cents(spelled.pitch.accidental.cents() + quarter)?;
+                Some((Pitch { class: spelled.pitch.class, accidental }, spelled.octave))
+            })
+            .min_by_key(|(pitch, _)| pitch.accidental.cents().abs());
+        if let Some((pitch, octave)) = best {
+            self.pitch = pitch;
+            self.octave = octave;
+        }
+    }
+
+    // Moves the note by an interval, keeping the distance between letter names
+    // so a minor sixth up from E is always a C. Falls back to spelling in the
+    // key if the result would need more accidentals than we can write.
+    pub fn transpose_by_interval(&mut self, interval: Interval, key: Key) {
+        let target = self.cents() + interval.semitones * 100;
+        let step = self.step() + interval.steps;
+        let class = PitchName::from_step(step);
+        let octave = step.div_euclid(7);
+        let alteration = target - (octave * 12 + class.semitones()) * 100;
+        match Accidental::from_cents(alteration) {
+            Some(accidental) if octave >= 0 => {
+                self.pitch = Pitch { class, accidental };
+                self.octave = Octave(octave as u32);
+            }
+            _ => self.set_cents(target, key),
+        }
+    }
+
+    // Moves the note along the scale of the key. Notes which are inflected
+    // away from the key keep the same inflection rather than landing on the
+    // scale, so a B flat in C major goes up to C flat, not C, and a melody
+    // with chromatic notes keeps its shape.
+    pub fn transpose_diatonic(&mut self, steps: i32, key: Key) {
+        let inflection = self.pitch.accidental.cents() - key.alteration(self.pitch.class) * 100;
+        let step = self.step() + steps;
+        let class = PitchName::from_step(step);
+        let octave = step.div_euclid(7);
+        let alteration = key.alteration(class) * 100 + inflection;
+        let target = (octave * 12 + class.semitones()) * 100 + alteration;
+        match Accidental::from_cents(alteration) {
+            Some(accidental) if octave >= 0 => {
+                self.pitch = Pitch { class, accidental };
+                self.octave = Octave(octave as u32);
+            }
+            _ => self.set_cents(target, key),
+        }
+    }
+
+    // Letter steps above C0
+    pub fn step(&self) -> i32 {
+        self.octave.0 as i32 * 7 + self.pitch.class.step()
+    }
+
+    pub fn from_semitones(semitones: i32) -> Option<Self> {
+        Self::from_semitones_in_key(semitones, Key::default())
+    }
+
+    // None below C0, where there's no octave to write the note in
+    pub fn from_semitones_in_key(semitones: i32, key: Key) -> Option<Self> {
+        let pitch = key.spell(semitones);
+        // B sharp and C flat belong to the octave of their letter, not their sound
+        let octave = (semitones - pitch.semitones()).div_euclid(12);
+        if octave < 0 {
+            return None;
+        }
+        Some(Self::new(pitch, Octave(octave as u32)))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum AccidentalDisplay {
+    // Implied by the key signature or an earlier note in the measure
+    Hidden,
+    Written,
+    Cautionary,
+}
+
+// Follows the accidentals in effect through the measures of one staff to
+// decide which ones have to be printed. An accidental lasts until the end of
+// the measure and only applies to its own octave. Notes which were altered in
+// the previous measure get a cautionary accidental when they go back.
+pub(crate) struct AccidentalState {
+    key: Key,
+    // Alterations in cents
+    altered: HashMap<(PitchName, u32), i32>,
+    previous: HashMap<(PitchName, u32), i32>,
+}
+
+impl AccidentalState {
+    pub(crate) fn new(key: Key) -> Self {
+        Self {
+            key,
+            altered: HashMap::new(),
+            previous: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn next_measure(&mut self, key: Key) {
+        self.previous = std::mem::take(&mut self.altered);
+        self.key = key;
+    }
+
+    pub(crate) fn display(&mut self, note: &Note) -> AccidentalDisplay {
+        let place = (note.pitch.class, note.octave.0);
+        let alteration = note.pitch.accidental.cents();
+        let current = self.altered.get(&place).copied().unwrap_or_else(|| self.key.alteration(note.pitch.class) * 100);
+        if alteration != current {
+            self.altered.insert(place, alteration);
+            AccidentalDisplay::Written
+        } else if note.cautionary {
+            AccidentalDisplay::Cautionary
+        } else if !self.altered.contains_key(&place) && self.previous.get(&place).map(|&p| p != alteration).unwrap_or(false) {
+            self.altered.insert(place, alteration);
+            AccidentalDisplay::Cautionary
+        } else {
+            AccidentalDisplay::Hidden
+        }
+    }
+}
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pitch {
+    pub class: PitchName,
+    pub accidental: Accidental
+}
+
+impl Default for Pitch {
+    fn default() -> Self {
+        Self {
+            class: PitchName::A,
+            accidental: Accidental::Natural
+        }
+    }
+}
+
+impl Pitch {
+    // Semitones above C in the same octave, rounded down for quarter tones.
+    // May be negative for C flat.
+    pub fn semitones(&self) -> i32 {
+        self.class.semitones() + self.accidental.alteration()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum PitchName {
+    A,
+    B,
+    C,
+    D,
+    E,
+    F,
+    G,
+}
+
+impl PitchName {
+    pub const ALL: [PitchName; 7] = [
+        PitchName::C,
+        PitchName::D,
+        PitchName::E,
+        PitchName::F,
+        PitchName::G,
+        PitchName::A,
+        PitchName::B,
+    ];
+
+    pub fn semitones(&self) -> i32 {
+        match self {
+            PitchName::C => 0,
+            PitchName::D => 2,
+            PitchName::E => 4,
+            PitchName::F => 5,
+            PitchName::G => 7,
+            PitchName::A => 9,
+            PitchName::B => 11,
+        }
+    }
+
+    // Position of the letter within the octave, counting from C
+    pub fn step(&self) -> i32 {
+        match self {
+            PitchName::C => 0,
+            PitchName::D => 1,
+            PitchName::E => 2,
+            PitchName::F => 3,
+            PitchName::G => 4,
+            PitchName::A => 5,
+            PitchName::B => 6,
+        }
+    }
+
+    pub fn from_step(step: i32) -> Self {
+        Self::ALL[step.rem_euclid(7) as usize]
+    }
+
+    // Position of the natural note on the circle of fifths, counting from C
+    pub(crate) fn fifths(&self) -> i32 {
+        match self {
+            PitchName::F => -1,
+            PitchName::C => 0,
+            PitchName::G => 1,
+            PitchName::D => 2,
+            PitchName::A => 3,
+            PitchName::E => 4,
+            PitchName::B => 5,
+        }
+    }
+}
+
+impl std::fmt::Display for PitchName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            PitchName::A => "a",
+            PitchName::B => "b",
+            PitchName::C => "c",
+            PitchName::D => "d",
+            PitchName::E => "e",
+            PitchName::F => "f",
+            PitchName::G => "g",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl std::str::FromStr for PitchName {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "a" => Ok(PitchName::A),
+            "b" => Ok(PitchName::B),
+            "c" => Ok(PitchName::C),
+            "d" => Ok(PitchName::D),
+            "e" => Ok(PitchName::E),
+            "f" => Ok(PitchName::F),
+            "g" => Ok(PitchName::G),
+            _ => Err(format!("Unknown pitch name {}", s)),
+        }
+    }
+}
+
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Accidental {
+    Sharp,
+    Flat,
+    Natural,
+    DoubleSharp,
+    DoubleFlat,
+    QuarterSharp,
+    QuarterFlat,
+    ThreeQuarterSharp,
+    ThreeQuarterFlat,
+}
+
+impl Accidental {
+    pub fn cents(&self) -> i32 {
+        match self {
+            Accidental::DoubleFlat => -200,
+            Accidental::ThreeQuarterFlat => -150,
+            Accidental::Flat => -100,
+            Accidental::QuarterFlat => -50,
+            Accidental::Natural => 0,
+            Accidental::QuarterSharp => 50,
+            Accidental::Sharp => 100,
+            Accidental::ThreeQuarterSharp => 150,
+            Accidental::DoubleSharp => 200,
+        }
+    }
+
+    pub fn from_cents(cents: i32) -> Option<Self> {
+        match cents {
+            -200 => Some(Accidental::DoubleFlat),
+            -150 => Some(Accidental::ThreeQuarterFlat),
+            -100 => Some(Accidental::Flat),
+            -50 => Some(Accidental::QuarterFlat),
+            0 => Some(Accidental::Natural),
+            50 => Some(Accidental::QuarterSharp),
+            100 => Some(Accidental::Sharp),
+            150 => Some(Accidental::ThreeQuarterSharp),
+            200 => Some(Accidental::DoubleSharp),
+            _ => None,
+        }
+    }
+
+    // Whole semitones, rounded down for quarter tones
+    pub fn alteration(&self) -> i32 {
+        self.cents().div_euclid(100)
+    }
+
+    pub fn from_alteration(alteration: i32) -> Option<Self> {
+        Self::from_cents(alteration * 100)
+    }
+
+    // Natural, sharp, double sharp, double flat, flat and round again. Quarter
+    // tones move on to the next of those above them.
+    pub fn next(&self) -> Self {
+        Self::from_alteration((self.alteration() + 3).rem_euclid(5) - 2).unwrap()
+    }
+}
+
+impl std::fmt::Display for Accidental {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Accidental::Sharp => "s",
+            Accidental::Flat => "f",
+            Accidental::Natural => "n",
+            Accidental::DoubleSharp => "x",
+            Accidental::DoubleFlat => "ff",
+            Accidental::QuarterSharp => "qs",
+            Accidental::QuarterFlat => "qf",
+            Accidental::ThreeQuarterSharp => "3qs",
+            Accidental::ThreeQuarterFlat => "3qf",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Parses the MEI names written by Display, plus "ss" for a double sharp
+impl std::str::FromStr for Accidental {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "s" => Ok(Accidental::Sharp),
+            "f" => Ok(Accidental::Flat),
+            "n" => Ok(Accidental::Natural),
+            "x" | "ss" => Ok(Accidental::DoubleSharp),
+            "ff" => Ok(Accidental::DoubleFlat),
+            "qs" => Ok(Accidental::QuarterSharp),
+            "qf" => Ok(Accidental::QuarterFlat),
+            "3qs" => Ok(Accidental::ThreeQuarterSharp),
+            "3qf" => Ok(Accidental::ThreeQuarterFlat),
+            _ => Err(format!("Unknown accidental {}", s)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Octave(pub u32);
+
+// An interval as a number of letter steps and semitones, so a major third is
+// two steps and four semitones while a diminished fourth is three and four.
+// Both are negative for descending intervals.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Interval {
+    pub steps: i32,
+    pub semitones: i32,
+}
+
+impl Interval {
+    pub fn octave() -> Self {
+        Interval { steps: 7, semitones: 12 }
+    }
+
+    pub fn inverted(&self) -> Self {
+        Interval { steps: -self.steps, semitones: -self.semitones }
+    }
+}
+
+// Parses names like "P5", "m6", "M3", "A4", "dd7" or "-M2" for a descending second
+impl std::str::FromStr for Interval {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (descending, name) = match s.strip_prefix('-') {
+            Some(name) => (true, name),
+            None => (false, s.strip_prefix('+').unwrap_or(s)),
+        };
+        let split = name.find(|c: char| c.is_ascii_digit()).ok_or_else(|| format!("Interval {} has no number", s))?;
+        let (quality, number) = name.split_at(split);
+        let number: i32 = number.parse().map_err(|_| format!("Bad interval number {}", number))?;
+        if number < 1 {
+            return Err(format!("Bad interval number {}", number));
+        }
+        let steps = number - 1;
+        let simple = steps.rem_euclid(7);
+        let natural = PitchName::from_step(simple).semitones() + steps.div_euclid(7) * 12;
+        let perfect = simple == 0 || simple == 3 || simple == 4;
+        let alteration = match (quality, perfect) {
+            ("P", true) => 0,
+            ("M", false) => 0,
+            ("m", false) => -1,
+            (q, _) if !q.is_empty() && q.chars().all(|c| c == 'A') => q.len() as i32,
+            (q, true) if !q.is_empty() && q.chars().all(|c| c == 'd') => -(q.len() as i32),
+            (q, false) if !q.is_empty() && q.chars().all(|c| c == 'd') => -(q.len() as i32) - 1,
+            _ => return Err(format!("Unknown interval {}", s)),
+        };
+        let interval = Interval { steps, semitones: natural + alteration };
+        Ok(if descending { interval.inverted() } else { interval })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Mode {
+    Major,
+    Minor,
+}
+
+// A key signature, as a count of sharps (positive) or flats (negative).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Key {
+    pub fifths: i32,
+    pub mode: Mode,
+}
+
+impl Default for Key {
+    fn default() -> Self {
+        Key {
+            fifths: 0,
+            mode: Mode::Major,
+        }
+    }
+}
+
+impl Key {
+    // The alteration the key signature applies to a letter
+    pub fn alteration(&self, class: PitchName) -> i32 {
+        let position = class.fifths();
+        if self.fifths > 0 && position + 2 <= self.fifths {
+            // Sharps are added F C G D A E B, F being -1 on the circle
+            1
+        } else if self.fifths < 0 && position - 6 >= self.fifths {
+            // Flats are added B E A D G C F, B being 5 on the circle
+            -1
+        } else {
+            0
+        }
+    }
+
+    pub fn tonic(&self) -> Pitch {
+        let fifths = match self.mode {
+            Mode::Major => self.fifths,
+            Mode::Minor => self.fifths + 3,
+        };
+        // Natural letters cover -1..=5 on the circle, each 7 further is one more sharp
+        let alteration = (fifths + 1).div_euclid(7);
+        let natural = fifths - alteration * 7;
+        let class = *PitchName::ALL.iter().find(|c| c.fifths() == natural).unwrap();
+        Pitch {
+            class,
+            accidental: Accidental::from_alteration(alteration).unwrap_or(Accidental::Natural),
+        }
+    }
+
+    // Picks a spelling for a pitch. Notes in the key are spelled as the key
+    // would have them, anything else is spelled as a natural if possible and
+    // otherwise with sharps in sharp keys and flats in flat keys.
+    pub fn spell(&self, semitones: i32) -> Pitch {
+        let pitch_class = semitones.rem_euclid(12);
+        let mut best = None;
+        for &class in &PitchName::ALL {
+            let alteration = (pitch_class - class.semitones() + 6).rem_euclid(12) - 6;
+            let accidental = match Accidental::from_alteration(alteration) {
+                Some(accidental) => accidental,
+                None => continue,
+            };
+            let score = if alteration == self.alteration(class) {
+                0
+            } else if alteration == 0 {
+                1
+            } else if alteration.abs() > 1 {
+                4
+            } else if (alteration > 0) == (self.fifths >= 0) {
+                2
+            } else {
+                3
+            };
+            if best.map(|(s, _)| score < s).unwrap_or(true) {
+                best = Some((score, Pitch { class, accidental }));
+            }
+        }
+        best.unwrap().1
+    }
+
+    pub fn to_mei_sig(&self) -> String {
+        if self.fifths > 0 {
+            format!("{}s", self.fifths)
+        } else if self.fifths < 0 {
+            format!("{}f", -self.fifths)
+        } else {
+            "0".to_string()
+        }
+    }
+
+    pub fn to_mei_mode(&self) -> String {
+        match self.mode {
+            Mode::Major => "major".to_string(),
+            Mode::Minor => "minor".to_string(),
+        }
+    }
+
+    // Reads the key.sig and key.mode attributes written by to_mei_sig and to_mei_mode
+    pub fn from_mei(sig: &str, mode: Option<&str>) -> Result<Self, String> {
+        let fifths = if sig == "0" {
+            0
+        } else if let Some(count) = sig.strip_suffix('s') {
+            count.parse::<i32>().map_err(|_| format!("Bad key signature {}", sig))?
+        } else if let Some(count) = sig.strip_suffix('f') {
+            -count.parse::<i32>().map_err(|_| format!("Bad key signature {}", sig))?
+        } else {
+            return Err(format!("Bad key signature {}", sig));
+        };
+        if fifths.abs() > 7 {
+            return Err(format!("Unsupported key signature {}", sig));
+        }
+        let mode = match mode {
+            Some("minor") => Mode::Minor,
+            _ => Mode::Major,
+        };
+        Ok(Key { fifths, mode })
+    }
+}
+
+// Parses names like "D", "bb", "f#m" or "Eb minor". Lower case tonics are minor.
+impl std::str::FromStr for Key {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let mut chars = s.chars();
+        let letter = chars.next().ok_or_else(|| "Empty key".to_string())?;
+        let class: PitchName = letter.to_string().parse()?;
+        let rest = chars.as_str();
+        let (alteration, rest) = if rest.starts_with('#') || rest.starts_with('s') {
+            (1, &rest[1..])
+        } else if rest.starts_with('b') || rest.starts_with('f') {
+            (-1, &rest[1..])
+        } else {
+            (0, rest)
+        };
+        let mode = match rest.trim() {
+            "" => if letter.is_lowercase() { Mode::Minor } else { Mode::Major },
+            "m" | "min" | "minor" => Mode::Minor,
+            "M" | "maj" | "major" => Mode::Major,
+            other => return Err(format!("Unknown mode {}", other)),
+        };
+        let tonic_fifths = class.fifths() + alteration * 7;
+        let fifths = match mode {
+            Mode::Major => tonic_fifths,
+            Mode::Minor => tonic_fifths - 3,
+        };
+        if fifths.abs() > 7 {
+            return Err(format!("{} needs more than seven accidentals", s));
+        }
+        Ok(Key { fifths, mode })
+    }
+}
+
+impl std::fmt::Display for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let tonic = self.tonic();
+        let accidental = match tonic.accidental {
+            Accidental::Sharp => "#",
+            Accidental::Flat => "b",
+            Accidental::Natural => "",
+            Accidental::DoubleSharp => "x",
+            Accidental::DoubleFlat => "bb",
+            // Key signatures never have quarter tones
+            _ => "",
+        };
+        write!(f, "{}{} {}", tonic.class.to_string().to_uppercase(), accidental, self.to_mei_mode())
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ClefShape {
+    G,
+    F,
+    C,
+}
+
+// `line` counts staff lines from the bottom and `octave` shifts the clef by
+// octaves, so a tenor voice's treble clef has an octave of -1.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Clef {
+    pub shape: ClefShape,
+    pub line: u32,
+    pub octave: i32,
+}
+
+impl Default for Clef {
+    fn default() -> Self {
+        Clef::treble()
+    }
+}
+
+impl Clef {
+    pub fn treble() -> Self {
+        Clef { shape: ClefShape::G, line: 2, octave: 0 }
+    }
+
+    pub fn bass() -> Self {
+        Clef { shape: ClefShape::F, line: 4, octave: 0 }
+    }
+
+    pub fn alto() -> Self {
+        Clef { shape: ClefShape::C, line: 3, octave: 0 }
+    }
+
+    pub fn tenor() -> Self {
+        Clef { shape: ClefShape::C, line: 4, octave: 0 }
+    }
+
+    fn shape_name(&self) -> &'static str {
+        match self.shape {
+            ClefShape::G => "G",
+            ClefShape::F => "F",
+            ClefShape::C => "C",
+        }
+    }
+
+    fn dis(&self) -> (Option<u32>, Option<String>) {
+        match self.octave {
+            0 => (None, None),
+            o if o > 0 => (Some(7 * o as u32 + 1), Some("above".to_string())),
+            o => (Some(7 * -o as u32 + 1), Some("below".to_string())),
+        }
+    }
+
+    pub(crate) fn apply_to_staff_def(&self, staff_def: &mut ir::StaffDef) {
+        let (dis, dis_place) = self.dis();
+        staff_def.clef_shape = Some(self.shape_name().to_string());
+        staff_def.clef_line = Some(self.line);
+        staff_def.clef_dis = dis;
+        staff_def.clef_dis_place = dis_place;
+    }
+
+    // Reads the shape, line and displacement of a clef from MEI
+    pub(crate) fn from_mei(shape: Option<&str>, line: Option<u32>, dis: Option<u32>, dis_place: Option<&str>) -> Result<Self, String> {
+        let shape = match shape {
+            Some("G") => ClefShape::G,
+            Some("F") => ClefShape::F,
+            Some("C") => ClefShape::C,
+            other => return Err(format!("Unsupported clef shape {:?}", other)),
+        };
+        let line = line.unwrap_or(match shape {
+            ClefShape::G => 2,
+            ClefShape::F => 4,
+            ClefShape::C => 3,
+        });
+        let octaves = dis.map(|d| (d as i32 - 1) / 7).unwrap_or(0);
+        let octave = if dis_place == Some("below") { -octaves } else { octaves };
+        Ok(Clef { shape, line, octave })
+    }
+
+    pub(crate) fn to_mei(self) -> ir::Clef {
+        let (dis, dis_place) = self.dis();
+        ir::Clef {
+            shape: Some(self.shape_name().to_string()),
+            line: Some(self.line),
+            dis,
+            dis_place,
+        }
+    }
+}
+
+// Parses names like "bass", "alto" or "treble8vb"
+impl std::str::FromStr for Clef {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_ascii_lowercase();
+        let (name, octave) = if let Some(name) = s.strip_suffix("8vb") {
+            (name, -1)
+        } else if let Some(name) = s.strip_suffix("8va") {
+            (name, 1)
+        } else if let Some(name) = s.strip_suffix("15mb") {
+            (name, -2)
+        } else if let Some(name) = s.strip_suffix("15ma") {
+            (name, 2)
+        } else {
+            (s.as_str(), 0)
+        };
+        let clef = match name.trim_end_matches(['_', '-']) {
+            "treble" | "g" => Clef::treble(),
+            "bass" | "f" => Clef::bass(),
+            "alto" | "c" => Clef::alto(),
+            "tenor" => Clef::tenor(),
+            "soprano" => Clef { shape: ClefShape::C, line: 1, octave: 0 },
+            "mezzo" => Clef { shape: ClefShape::C, line: 2, octave: 0 },
+            "baritone" => Clef { shape: ClefShape::F, line: 3, octave: 0 },
+            "french" => Clef { shape: ClefShape::G, line: 1, octave: 0 },
+            other => return Err(format!("Unknown clef {}", other)),
+        };
+        Ok(Clef { octave, ..clef })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(s: &str) -> Key {
+        s.parse().unwrap()
+    }
+
+    fn pitch(class: PitchName, accidental: Accidental) -> Pitch {
+        Pitch { class, accidental }
+    }
+
+    #[test]
+    fn keys_from_names() {
+        assert_eq!(key("D"), Key { fifths: 2, mode: Mode::Major });
+        assert_eq!(key("bb"), Key { fifths: -5, mode: Mode::Minor });
+        assert_eq!(key("f#m"), Key { fifths: 3, mode: Mode::Minor });
+        assert_eq!(key("Eb minor"), Key { fifths: -6, mode: Mode::Minor });
+        assert_eq!(key("Cs major"), Key { fifths: 7, mode: Mode::Major });
+        assert!("Fb".parse::<Key>().is_err());
+        assert!("C dorian".parse::<Key>().is_err());
+        assert!("".parse::<Key>().is_err());
+    }
+
+    #[test]
+    fn keys_read_back_their_names() {
+        for fifths in -7..=7 {
+            for &mode in &[Mode::Major, Mode::Minor] {
+                let k = Key { fifths, mode };
+                assert_eq!(k.to_string().parse::<Key>(), Ok(k));
+            }
+        }
+        assert_eq!(Key { fifths: -3, mode: Mode::Minor }.to_string(), "C minor");
+    }
+
+    #[test]
+    fn spelling_follows_the_key() {
+        assert_eq!(key("C").spell(0), pitch(PitchName::C, Accidental::Natural));
+        assert_eq!(key("C").spell(6), pitch(PitchName::F, Accidental::Sharp));
+        assert_eq!(key("F").spell(10), pitch(PitchName::B, Accidental::Flat));
+        assert_eq!(key("F").spell(1), pitch(PitchName::D, Accidental::Flat));
+        assert_eq!(key("E").spell(3), pitch(PitchName::D, Accidental::Sharp));
+        assert_eq!(key("C#").spell(5), pitch(PitchName::E, Accidental::Sharp));
+        assert_eq!(key("Cb").spell(11), pitch(PitchName::C, Accidental::Flat));
+        // Negative and octave shifted semitones spell the same
+        assert_eq!(key("D").spell(-6), key("D").spell(6));
+    }
+
+    fn interval(s: &str) -> Interval {
+        s.parse().unwrap()
+    }
+
+    fn note(class: PitchName, accidental: Accidental, octave: u32) -> Note {
+        Note::new(pitch(class, accidental), Octave(octave))
+    }
+
+    fn transposed(mut n: Note, by: &str, key: Key) -> Note {
+        n.transpose_by_interval(interval(by), key);
+        n
+    }
+
+    #[test]
+    fn intervals_from_names() {
+        assert_eq!(interval("P5"), Interval { steps: 4, semitones: 7 });
+        assert_eq!(interval("m6"), Interval { steps: 5, semitones: 8 });
+        assert_eq!(interval("+M3"), Interval { steps: 2, semitones: 4 });
+        assert_eq!(interval("A4"), Interval { steps: 3, semitones: 6 });
+        assert_eq!(interval("d5"), Interval { steps: 4, semitones: 6 });
+        assert_eq!(interval("dd7"), Interval { steps: 6, semitones: 8 });
+        assert_eq!(interval("M9"), Interval { steps: 8, semitones: 14 });
+        assert_eq!(interval("P8"), Interval::octave());
+        assert_eq!(interval("-M2"), Interval { steps: -1, semitones: -2 });
+        for bad in &["P3", "M5", "5", "P0", "X4", "m"] {
+            assert!(bad.parse::<Interval>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn intervals_keep_the_letter_distance() {
+        let c = Key::default();
+        assert_eq!(transposed(note(PitchName::E, Accidental::Natural, 4), "m6", c), note(PitchName::C, Accidental::Natural, 5));
+        assert_eq!(transposed(note(PitchName::C, Accidental::Natural, 4), "A4", c), note(PitchName::F, Accidental::Sharp, 4));
+        assert_eq!(transposed(note(PitchName::C, Accidental::Natural, 4), "d5", c), note(PitchName::G, Accidental::Flat, 4));
+        assert_eq!(transposed(note(PitchName::C, Accidental::Natural, 4), "d3", c), note(PitchName::E, Accidental::DoubleFlat, 4));
+        assert_eq!(transposed(note(PitchName::D, Accidental::Natural, 4), "-M3", c), note(PitchName::B, Accidental::Flat, 3));
+        // Quarter tones come along
+        assert_eq!(transposed(note(PitchName::C, Accidental::QuarterSharp, 4), "P5", c), note(PitchName::G, Accidental::QuarterSharp, 4));
+    }
+
+    #[test]
+    fn intervals_fall_back_to_the_key() {
+        // E triple sharp and B triple flat can't be written, so the sounding pitch
+        // is spelled in the key
+        assert_eq!(transposed(note(PitchName::B, Accidental::DoubleSharp, 4), "A4", Key::default()), note(PitchName::G, Accidental::Natural, 5));
+        assert_eq!(transposed(note(PitchName::F, Accidental::Flat, 4), "d4", key("Bb")), note(PitchName::A, Accidental::Flat, 4));
+    }
+
+    #[test]
+    fn chromatic_and_diatonic_transposition() {
+        // The same six semitones are spelled by the key when moving chromatically
+        let mut n = note(PitchName::C, Accidental::Natural, 4);
+        n.transpose(6, key("F"));
+        assert_eq!(n, note(PitchName::G, Accidental::Flat, 4));
+        let mut n = note(PitchName::C, Accidental::Natural, 4);
+        n.transpose(6, key("G"));
+        assert_eq!(n, note(PitchName::F, Accidental::Sharp, 4));
+
+        // Moving along the scale picks up the key's accidentals...
+        let mut n = note(PitchName::E, Accidental::Natural, 4);
+        n.transpose_diatonic(1, key("D"));
+        assert_eq!(n, note(PitchName::F, Accidental::Sharp, 4));
+        // ...and keeps any inflection away from it
+        let mut n = note(PitchName::B, Accidental::Flat, 4);
+        n.transpose_diatonic(1, key("C"));
+        assert_eq!(n, note(PitchName::C, Accidental::Flat, 5));
+        let mut n = note(PitchName::D, Accidental::Natural, 4);
+        n.transpose_diatonic(-2, key("D"));
+        assert_eq!(n, note(PitchName::B, Accidental::Natural, 3));
+    }
+
+    #[test]
+    fn nothing_goes_below_c0() {
+        let c0 = note(PitchName::C, Accidental::Natural, 0);
+        let mut n = c0;
+        n.transpose(-1, key("C"));
+        assert_eq!(n, c0);
+        n.transpose_diatonic(-1, key("C"));
+        assert_eq!(n, c0);
+        assert_eq!(transposed(c0, "-m2", key("C")), c0);
+        assert_eq!(Note::from_semitones(-1), None);
+        // C flat is still written in octave 0
+        assert_eq!(Note::from_semitones_in_key(-1, key("Cb")), Some(note(PitchName::C, Accidental::Flat, 0)));
+        let mut n = note(PitchName::D, Accidental::Natural, 0);
+        n.transpose(-2, key("C"));
+        assert_eq!(n, c0);
+    }
+
+    #[test]
+    fn accidentals_last_until_the_barline() {
+        use AccidentalDisplay::*;
+        let mut state = AccidentalState::new(Key::default());
+        let f_sharp = note(PitchName::F, Accidental::Sharp, 4);
+        assert_eq!(state.display(&f_sharp), Written);
+        assert_eq!(state.display(&f_sharp), Hidden);
+        // Only in their own octave
+        assert_eq!(state.display(&note(PitchName::F, Accidental::Sharp, 5)), Written);
+        assert_eq!(state.display(&note(PitchName::F, Accidental::Natural, 4)), Written);
+        assert_eq!(state.display(&f_sharp), Written);
+
+        state.next_measure(Key::default());
+        assert_eq!(state.display(&f_sharp), Written);
+    }
+
+    #[test]
+    fn going_back_after_a_barline_is_cautionary() {
+        use AccidentalDisplay::*;
+        let mut state = AccidentalState::new(Key::default());
+        let c = note(PitchName::C, Accidental::Natural, 4);
+        state.display(&note(PitchName::C, Accidental::Sharp, 4));
+        state.next_measure(Key::default());
+        assert_eq!(state.display(&c), Cautionary);
+        assert_eq!(state.display(&c), Hidden);
+        // Only for the measure straight after
+        state.next_measure(Key::default());
+        state.next_measure(Key::default());
+        assert_eq!(state.display(&c), Hidden);
+    }
+
+    #[test]
+    fn accidentals_follow_the_key() {
+        use AccidentalDisplay::*;
+        let mut state = AccidentalState::new(key("G"));
+        assert_eq!(state.display(&note(PitchName::F, Accidental::Sharp, 4)), Hidden);
+        assert_eq!(state.display(&note(PitchName::F, Accidental::Natural, 5)), Written);
+        // A new key at the barline
+        state.next_measure(key("F"));
+        assert_eq!(state.display(&note(PitchName::B, Accidental::Flat, 4)), Hidden);
+        assert_eq!(state.display(&note(PitchName::F, Accidental::Natural, 4)), Hidden);
+        // Asked for on the note itself
+        let mut cautionary = note(PitchName::B, Accidental::Flat, 4);
+        cautionary.cautionary = true;
+        assert_eq!(state.display(&cautionary), Cautionary);
+    }
+
+    #[test]
+    fn quarter_tones_bend_the_nearest_key() {
+        assert_eq!(note(PitchName::C, Accidental::Natural, 4).midi_key(), (60, 0));
+        assert_eq!(note(PitchName::A, Accidental::Natural, 4).midi_key(), (69, 0));
+        // Halfway between rounds up and bends down
+        assert_eq!(note(PitchName::C, Accidental::QuarterSharp, 4).midi_key(), (61, -50));
+        assert_eq!(note(PitchName::C, Accidental::QuarterFlat, 4).midi_key(), (60, -50));
+        assert_eq!(note(PitchName::E, Accidental::ThreeQuarterFlat, 4).midi_key(), (63, -50));
+        assert_eq!(note(PitchName::C, Accidental::Flat, 4).midi_key(), (59, 0));
+        // Out of range keys are clamped
+        assert_eq!(note(PitchName::G, Accidental::Natural, 12).midi_key().0, 127);
+    }
+
+    #[test]
+    fn detune_is_heard_but_not_written() {
+        let mut n = note(PitchName::A, Accidental::Natural, 4);
+        n.detune = -14;
+        assert_eq!(n.midi_key(), (69, -14));
+        n.detune = 60;
+        assert_eq!(n.midi_key(), (70, -40));
+        assert_eq!(n.cents(), note(PitchName::A, Accidental::Natural, 4).cents());
+    }
+
+    #[test]
+    fn transposing_by_quarter_tones() {
+        let mut n = note(PitchName::C, Accidental::Natural, 4);
+        n.transpose_quarter_tones(1, Key::default());
+        assert_eq!(n, note(PitchName::C, Accidental::QuarterSharp, 4));
+        n.transpose_quarter_tones(2, Key::default());
+        assert_eq!(n, note(PitchName::C, Accidental::ThreeQuarterSharp, 4));
+        // Past the double sharp the letter has to change
+        let mut n = note(PitchName::C, Accidental::DoubleSharp, 4);
+        n.transpose_quarter_tones(1, Key::default());
+        assert_eq!(n, note(PitchName::D, Accidental::QuarterSharp, 4));
+        // Whole semitones spell quarter tones the same way
+        let mut n = note(PitchName::B, Accidental::QuarterFlat, 4);
+        n.transpose(1, Key::default());
+        assert_eq!(n.cents(), note(PitchName::C, Accidental::QuarterFlat, 5).cents());
+    }
+}
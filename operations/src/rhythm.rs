@@ -0,0 +1,446 @@
+use std::convert::TryFrom;
+
+use serde::{Serialize, Deserialize};
+
+// An exact position or length in the score, measured in whole notes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "SavedPulse")]
+pub struct Pulse {
+    num: i64,
+    den: i64,
+}
+
+// Files saved before pulses were fractions hold a whole number of quarter notes
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SavedPulse {
+    Fraction { num: i64, den: i64 },
+    Quarters(i64),
+}
+
+impl TryFrom<SavedPulse> for Pulse {
+    type Error = String;
+    fn try_from(saved: SavedPulse) -> Result<Self, Self::Error> {
+        match saved {
+            SavedPulse::Fraction { den: 0, .. } => Err("Pulse with a zero denominator".to_string()),
+            SavedPulse::Fraction { num, den } => Ok(Pulse::new(num, den)),
+            SavedPulse::Quarters(quarters) => Ok(Pulse::new(quarters, 4)),
+        }
+    }
+}
+
+impl Default for Pulse {
+    fn default() -> Self {
+        Pulse { num: 0, den: 1 }
+    }
+}
+
+fn gcd(mut a: i64, mut b: i64) -> i64 {
+    while b != 0 {
+        let t = a % b;
+        a = b;
+        b = t;
+    }
+    a.abs()
+}
+
+impl Pulse {
+    pub fn new(num: i64, den: i64) -> Self {
+        assert!(den != 0, "Pulse with a zero denominator");
+        let sign = if den < 0 { -1 } else { 1 };
+        let divisor = gcd(num, den).max(1);
+        Pulse {
+            num: sign * num / divisor,
+            den: sign * den / divisor,
+        }
+    }
+
+    pub fn whole(n: i64) -> Self {
+        Pulse::new(n, 1)
+    }
+
+    pub fn num(&self) -> i64 {
+        self.num
+    }
+
+    pub fn den(&self) -> i64 {
+        self.den
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.num == 0
+    }
+}
+
+impl Ord for Pulse {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.num as i128 * other.den as i128).cmp(&(other.num as i128 * self.den as i128))
+    }
+}
+
+impl PartialOrd for Pulse {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl std::ops::Add for Pulse {
+    type Output = Self;
+    fn add(self, other: Self) -> Self {
+        Pulse::new(self.num * other.den + other.num * self.den, self.den * other.den)
+    }
+}
+impl std::ops::AddAssign for Pulse {
+    fn add_assign(&mut self, other: Self) {
+        *self = *self + other;
+    }
+}
+impl std::ops::Sub for Pulse {
+    type Output = Self;
+    fn sub(self, other: Self) -> Self {
+        Pulse::new(self.num * other.den - other.num * self.den, self.den * other.den)
+    }
+}
+impl std::ops::SubAssign for Pulse {
+    fn sub_assign(&mut self, other: Self) {
+        *self = *self - other;
+    }
+}
+impl std::ops::Neg for Pulse {
+    type Output = Self;
+    fn neg(self) -> Self {
+        Pulse::new(-self.num, self.den)
+    }
+}
+impl std::ops::Mul for Pulse {
+    type Output = Self;
+    fn mul(self, other: Self) -> Self {
+        Pulse::new(self.num * other.num, self.den * other.den)
+    }
+}
+impl std::ops::Div for Pulse {
+    type Output = Self;
+    fn div(self, other: Self) -> Self {
+        Pulse::new(self.num * other.den, self.den * other.num)
+    }
+}
+
+pub const SHORTEST_BASE: u32 = 128;
+
+// `num` notes in the time of `numbase`, so a triplet is 3 in the time of 2.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tuplet {
+    pub num: u32,
+    pub numbase: u32,
+}
+
+impl Tuplet {
+    pub fn triplet() -> Self {
+        Tuplet { num: 3, numbase: 2 }
+    }
+
+    pub fn ratio(&self) -> Pulse {
+        Pulse::new(self.numbase as i64, self.num as i64)
+    }
+}
+
+// A written duration. `base` is the MEI dur value: 1 for a whole note, 2 for a
+// half and so on down to 128.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NoteValue {
+    pub base: u32,
+    pub dots: u32,
+    pub tuplet: Option<Tuplet>,
+}
+
+impl Default for NoteValue {
+    fn default() -> Self {
+        NoteValue {
+            base: 4,
+            dots: 0,
+            tuplet: None,
+        }
+    }
+}
+
+// Durations used to be saved as a pulse, before there were note values
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SavedNoteValue {
+    Value(NoteValue),
+    Length(Pulse),
+}
+
+pub(crate) fn deserialize_note_value<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<NoteValue, D::Error> {
+    Ok(match SavedNoteValue::deserialize(deserializer)? {
+        SavedNoteValue::Value(value) => value,
+        SavedNoteValue::Length(length) => NoteValue::from_length(length).unwrap_or_else(|| NoteValue::longest_within(length)),
+    })
+}
+
+impl NoteValue {
+    pub fn new(base: u32) -> Self {
+        NoteValue {
+            base,
+            ..Default::default()
+        }
+    }
+
+    pub fn dotted(base: u32, dots: u32) -> Self {
+        NoteValue {
+            base,
+            dots,
+            ..Default::default()
+        }
+    }
+
+    // The length as written, ignoring any tuplet
+    pub fn written_length(&self) -> Pulse {
+        // Each dot adds half of the previous value: 1/b * (2 - 1/2^dots)
+        let scale = 1i64 << self.dots;
+        Pulse::new(2 * scale - 1, self.base as i64 * scale)
+    }
+
+    pub fn length(&self) -> Pulse {
+        match self.tuplet {
+            Some(tuplet) => self.written_length() * tuplet.ratio(),
+            None => self.written_length(),
+        }
+    }
+
+    pub fn shorter(&self) -> Self {
+        NoteValue {
+            base: (self.base * 2).min(SHORTEST_BASE),
+            ..*self
+        }
+    }
+
+    pub fn longer(&self) -> Self {
+        NoteValue {
+            base: (self.base / 2).max(1),
+            ..*self
+        }
+    }
+
+    // The plain values with up to two dots, longest first
+    fn plain_values() -> impl Iterator<Item=NoteValue> {
+        (0..=SHORTEST_BASE.trailing_zeros()).flat_map(|power| (0..=2).map(move |dots| NoteValue::dotted(1 << power, dots)))
+    }
+
+    // The single plain value with exactly this length, if there is one
+    pub fn from_length(length: Pulse) -> Option<NoteValue> {
+        Self::plain_values().find(|value| value.length() == length)
+    }
+
+    // The longest plain value which fits in the length, or the shortest
+    // value there is if none do
+    pub fn longest_within(length: Pulse) -> NoteValue {
+        Self::plain_values().filter(|value| value.length() <= length).max_by_key(|value| value.length()).unwrap_or_else(|| NoteValue::new(SHORTEST_BASE))
+    }
+
+    // Splits a length into note values, longest first. Lengths which aren't a
+    // whole number of 128ths are written in the tuplet of the music around
+    // them when that fits, and otherwise with triplets. Anything still left
+    // over is dropped.
+    pub fn decompose(length: Pulse, around: Option<Tuplet>) -> Vec<NoteValue> {
+        let fits = |tuplet: Option<Tuplet>| {
+            let written = tuplet.map(|tuplet| length / tuplet.ratio()).unwrap_or(length);
+            (written * Pulse::whole(SHORTEST_BASE as i64)).den() == 1
+        };
+        let tuplet = [None, around, Some(Tuplet::triplet())].iter().copied()
+            .find(|&tuplet| fits(tuplet))
+            .unwrap_or_else(|| around.or_else(|| Some(Tuplet::triplet())));
+        let order = match tuplet {
+            Some(tuplet) => vec![Some(tuplet), None],
+            None => vec![None],
+        };
+        let mut values = vec![];
+        let mut remaining = length;
+        for tuplet in order {
+            let mut base = 1;
+            while base <= SHORTEST_BASE && remaining > Pulse::default() {
+                let value = NoteValue {
+                    base,
+                    dots: 0,
+                    tuplet,
+                };
+                if value.length() <= remaining {
+                    remaining -= value.length();
+                    values.push(value);
+                } else {
+                    base *= 2;
+                }
+            }
+        }
+        if remaining > Pulse::default() {
+            log::warn!("Dropping unrepresentable length {:?}", remaining);
+        }
+        values
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Meter {
+    pub count: u32,
+    pub unit: u32,
+}
+
+impl Default for Meter {
+    fn default() -> Self {
+        Meter {
+            count: 4,
+            unit: 4,
+        }
+    }
+}
+
+impl Meter {
+    pub fn length(&self) -> Pulse {
+        Pulse::new(self.count as i64, self.unit as i64)
+    }
+}
+
+impl std::str::FromStr for Meter {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (count, unit) = s.split_once('/').ok_or_else(|| format!("Meter should look like 3/4, got {}", s))?;
+        let count: u32 = count.trim().parse().map_err(|_| format!("Bad meter count {}", count))?;
+        let unit: u32 = unit.trim().parse().map_err(|_| format!("Bad meter unit {}", unit))?;
+        if count == 0 || !unit.is_power_of_two() || unit > SHORTEST_BASE {
+            return Err(format!("Unsupported meter {}/{}", count, unit));
+        }
+        Ok(Meter { count, unit })
+    }
+}
+
+impl std::fmt::Display for Meter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.count, self.unit)
+    }
+}
+
+// Quarter notes per minute
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tempo {
+    pub bpm: u32,
+}
+
+impl Default for Tempo {
+    fn default() -> Self {
+        Tempo { bpm: 120 }
+    }
+}
+
+impl Tempo {
+    pub fn micros_per_quarter(&self) -> u32 {
+        60_000_000 / self.bpm.max(1)
+    }
+}
+
+impl std::str::FromStr for Tempo {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bpm: u32 = s.trim().parse().map_err(|_| format!("Bad tempo {}", s))?;
+        if bpm == 0 {
+            return Err("Tempo has to be above zero".to_string());
+        }
+        Ok(Tempo { bpm })
+    }
+}
+
+impl std::fmt::Display for Tempo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.bpm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pulses_stay_in_lowest_terms() {
+        assert_eq!(Pulse::new(2, 8), Pulse::new(1, 4));
+        assert_eq!(Pulse::new(3, -6), Pulse::new(-1, 2));
+        assert_eq!(Pulse::new(0, 5), Pulse::default());
+        assert_eq!((Pulse::new(-2, 4).num(), Pulse::new(-2, 4).den()), (-1, 2));
+    }
+
+    #[test]
+    fn pulse_arithmetic() {
+        let third = Pulse::new(1, 3);
+        let quarter = Pulse::new(1, 4);
+        assert_eq!(third + quarter, Pulse::new(7, 12));
+        assert_eq!(quarter - third, Pulse::new(-1, 12));
+        assert_eq!(-quarter, Pulse::new(-1, 4));
+        assert_eq!(third * quarter, Pulse::new(1, 12));
+        assert_eq!(third / quarter, Pulse::new(4, 3));
+        let mut p = Pulse::whole(1);
+        p += third;
+        p -= Pulse::new(2, 3);
+        assert_eq!(p, Pulse::new(2, 3));
+        assert!(Pulse::new(1, 3) > Pulse::new(1, 4));
+        assert!(Pulse::new(-1, 3) < Pulse::new(-1, 4));
+    }
+
+    #[test]
+    fn note_value_lengths() {
+        assert_eq!(NoteValue::new(4).length(), Pulse::new(1, 4));
+        assert_eq!(NoteValue::dotted(4, 1).length(), Pulse::new(3, 8));
+        assert_eq!(NoteValue::dotted(2, 2).length(), Pulse::new(7, 8));
+        let triplet = NoteValue { base: 8, dots: 0, tuplet: Some(Tuplet::triplet()) };
+        assert_eq!(triplet.length(), Pulse::new(1, 12));
+        assert_eq!(triplet.written_length(), Pulse::new(1, 8));
+    }
+
+    #[test]
+    fn from_length_finds_exact_values_only() {
+        assert_eq!(NoteValue::from_length(Pulse::new(3, 8)), Some(NoteValue::dotted(4, 1)));
+        assert_eq!(NoteValue::from_length(Pulse::new(1, 128)), Some(NoteValue::new(128)));
+        assert_eq!(NoteValue::from_length(Pulse::new(5, 8)), None);
+        assert_eq!(NoteValue::from_length(Pulse::new(1, 12)), None);
+    }
+
+    #[test]
+    fn longest_within() {
+        assert_eq!(NoteValue::longest_within(Pulse::new(5, 8)), NoteValue::new(2));
+        assert_eq!(NoteValue::longest_within(Pulse::new(7, 8)), NoteValue::dotted(2, 2));
+        assert_eq!(NoteValue::longest_within(Pulse::whole(3)), NoteValue::dotted(1, 2));
+        // Nothing fits, so the shortest there is
+        assert_eq!(NoteValue::longest_within(Pulse::new(1, 1000)), NoteValue::new(128));
+    }
+
+    #[test]
+    fn decompose() {
+        assert_eq!(NoteValue::decompose(Pulse::new(5, 8), None), vec![NoteValue::new(2), NoteValue::new(8)]);
+        assert_eq!(NoteValue::decompose(Pulse::whole(2), None), vec![NoteValue::new(1), NoteValue::new(1)]);
+        assert_eq!(NoteValue::decompose(Pulse::default(), None), vec![]);
+        let triplet = |base| NoteValue { base, dots: 0, tuplet: Some(Tuplet::triplet()) };
+        // A third of a whole note is a triplet half
+        assert_eq!(NoteValue::decompose(Pulse::new(1, 3), None), vec![triplet(2)]);
+        let total = NoteValue::decompose(Pulse::new(5, 12), None).iter().fold(Pulse::default(), |sum, v| sum + v.length());
+        assert_eq!(total, Pulse::new(5, 12));
+    }
+
+    #[test]
+    fn decompose_uses_the_tuplet_around_the_gap() {
+        let quintuplet = Tuplet { num: 5, numbase: 4 };
+        // One missing sixteenth of a 5:4 group
+        assert_eq!(NoteValue::decompose(Pulse::new(1, 20), Some(quintuplet)), vec![NoteValue { base: 16, dots: 0, tuplet: Some(quintuplet) }]);
+        let total = NoteValue::decompose(Pulse::new(3, 20), Some(quintuplet)).iter().fold(Pulse::default(), |sum, v| sum + v.length());
+        assert_eq!(total, Pulse::new(3, 20));
+        // Plain lengths stay plain, and triplet lengths are still triplets
+        assert_eq!(NoteValue::decompose(Pulse::new(1, 4), Some(quintuplet)), vec![NoteValue::new(4)]);
+        assert_eq!(NoteValue::decompose(Pulse::new(1, 12), Some(quintuplet))[0].tuplet, Some(Tuplet::triplet()));
+    }
+
+    #[test]
+    fn saved_pulses() {
+        assert_eq!(serde_json::from_str::<Pulse>(r#"{"num": 2, "den": 6}"#).unwrap(), Pulse::new(1, 3));
+        // Whole numbers are quarter notes from before pulses were fractions
+        assert_eq!(serde_json::from_str::<Pulse>("6").unwrap(), Pulse::new(3, 2));
+        assert!(serde_json::from_str::<Pulse>(r#"{"num": 1, "den": 0}"#).is_err());
+        let p = Pulse::new(5, 12);
+        assert_eq!(serde_json::from_str::<Pulse>(&serde_json::to_string(&p).unwrap()).unwrap(), p);
+    }
+}
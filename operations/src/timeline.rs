@@ -0,0 +1,58 @@
+use std::collections::BTreeMap;
+
+use serde::{Serialize, Deserialize, Serializer, Deserializer};
+
+use crate::Pulse;
+
+// Values which change at points in the score, like meters or keys. Each entry
+// stays in effect until the next one. Stored as a list of pairs in JSON since
+// map keys have to be strings there.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Timeline<T>(BTreeMap<Pulse, T>);
+
+impl<T> Default for Timeline<T> {
+    fn default() -> Self {
+        Timeline(BTreeMap::new())
+    }
+}
+
+impl<T> Timeline<T> {
+    pub fn insert(&mut self, pulse: Pulse, value: T) {
+        self.0.insert(pulse, value);
+    }
+
+    pub fn remove(&mut self, pulse: Pulse) -> Option<T> {
+        self.0.remove(&pulse)
+    }
+
+    // The value in effect at the given pulse
+    pub fn at(&self, pulse: Pulse) -> Option<&T> {
+        self.0.range(..=pulse).next_back().map(|(_, v)| v)
+    }
+
+    // The first change strictly after `begin` and strictly before `end`
+    pub fn next_change(&self, begin: Pulse, end: Pulse) -> Option<(Pulse, &T)> {
+        self.0.range(begin..end).find(|(p, _)| **p > begin).map(|(p, v)| (*p, v))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(Pulse, &T)> {
+        self.0.iter().map(|(p, v)| (*p, v))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<T: Serialize> Serialize for Timeline<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter())
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Timeline<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pairs = Vec::<(Pulse, T)>::deserialize(deserializer)?;
+        Ok(Timeline(pairs.into_iter().collect()))
+    }
+}
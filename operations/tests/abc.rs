@@ -0,0 +1,203 @@
+use operations::*;
+
+mod common;
+use common::*;
+
+fn assert_round_trip(score: &Score) {
+    let abc = score.to_abc();
+    assert_eq!(&Score::from_abc(&abc).unwrap(), score, "{}", abc);
+}
+
+// The notes of a layer as (start, pitches, value) for comparing
+type Written = (Pulse, Vec<(PitchName, Accidental, u32)>, NoteValue);
+
+fn layer(score: &Score, staff: u32, layer: u32) -> Vec<Written> {
+    score.events_on_layer(staff, layer)
+        .map(|e| (e.start(), e.notes().iter().map(|n| (n.pitch.class, n.pitch.accidental, n.octave.0)).collect(), e.duration()))
+        .collect()
+}
+
+#[test]
+fn header_fields() {
+    let score = Score::from_abc("X:1\nT:Drowsy Maggie\nC:Trad.\nM:3/4\nL:1/4\nQ:1/4=90\nK:Em\nE F G|\n").unwrap();
+    assert_eq!(score.metadata.title, "Drowsy Maggie");
+    assert_eq!(score.metadata.composer, "Trad.");
+    assert_eq!(score.meter_at(Pulse::default()), Meter { count: 3, unit: 4 });
+    assert_eq!(score.key_at(Pulse::default()), Key { fifths: 1, mode: Mode::Minor });
+    assert_eq!(score.tempo_at(Pulse::default()), Tempo { bpm: 90 });
+    assert_eq!(layer(&score, 0, 0), vec![
+        (Pulse::default(), vec![(PitchName::E, Accidental::Natural, 4)], NoteValue::new(4)),
+        (Pulse::new(1, 4), vec![(PitchName::F, Accidental::Sharp, 4)], NoteValue::new(4)),
+        (Pulse::new(1, 2), vec![(PitchName::G, Accidental::Natural, 4)], NoteValue::new(4)),
+    ]);
+}
+
+#[test]
+fn octaves_accidentals_and_lengths() {
+    let score = Score::from_abc("X:1\nM:4/4\nL:1/8\nK:D\nC,, c'2 ^g/ g/ =f3/2 _B,// B,//|f ^^c __e f>g a<b|\n").unwrap();
+    let n = |class, accidental, octave| vec![(class, accidental, octave)];
+    assert_eq!(layer(&score, 0, 0), vec![
+        // The key signature applies in every octave
+        (Pulse::default(), n(PitchName::C, Accidental::Sharp, 2), NoteValue::new(8)),
+        (Pulse::new(1, 8), n(PitchName::C, Accidental::Sharp, 6), NoteValue::new(4)),
+        (Pulse::new(3, 8), n(PitchName::G, Accidental::Sharp, 5), NoteValue::new(16)),
+        // Accidentals last until the barline
+        (Pulse::new(7, 16), n(PitchName::G, Accidental::Sharp, 5), NoteValue::new(16)),
+        (Pulse::new(1, 2), n(PitchName::F, Accidental::Natural, 5), NoteValue::dotted(8, 1)),
+        (Pulse::new(11, 16), n(PitchName::B, Accidental::Flat, 3), NoteValue::new(32)),
+        (Pulse::new(23, 32), n(PitchName::B, Accidental::Flat, 3), NoteValue::new(32)),
+        (Pulse::new(3, 4), n(PitchName::F, Accidental::Sharp, 5), NoteValue::new(8)),
+        (Pulse::new(7, 8), n(PitchName::C, Accidental::DoubleSharp, 5), NoteValue::new(8)),
+        (Pulse::whole(1), n(PitchName::E, Accidental::DoubleFlat, 5), NoteValue::new(8)),
+        (Pulse::new(9, 8), n(PitchName::F, Accidental::Sharp, 5), NoteValue::dotted(8, 1)),
+        (Pulse::new(21, 16), n(PitchName::G, Accidental::Natural, 5), NoteValue::new(16)),
+        (Pulse::new(11, 8), n(PitchName::A, Accidental::Natural, 5), NoteValue::new(16)),
+        (Pulse::new(23, 16), n(PitchName::B, Accidental::Natural, 5), NoteValue::dotted(8, 1)),
+    ]);
+}
+
+#[test]
+fn chords_ties_rests_and_tuplets() {
+    let score = Score::from_abc("X:1\nM:2/4\nL:1/8\nK:C\n[CEG]2 z [c_e]|(3ABc d2-|d2 z2|\n").unwrap();
+    let triplet = NoteValue { base: 8, dots: 0, tuplet: Some(Tuplet::triplet()) };
+    assert_eq!(layer(&score, 0, 0), vec![
+        (Pulse::default(), vec![(PitchName::C, Accidental::Natural, 4), (PitchName::E, Accidental::Natural, 4), (PitchName::G, Accidental::Natural, 4)], NoteValue::new(4)),
+        (Pulse::new(3, 8), vec![(PitchName::C, Accidental::Natural, 5), (PitchName::E, Accidental::Flat, 5)], NoteValue::new(8)),
+        (Pulse::new(1, 2), vec![(PitchName::A, Accidental::Natural, 4)], triplet),
+        (Pulse::new(7, 12), vec![(PitchName::B, Accidental::Natural, 4)], triplet),
+        (Pulse::new(2, 3), vec![(PitchName::C, Accidental::Natural, 5)], triplet),
+        // Tied over the barline into a single half note
+        (Pulse::new(3, 4), vec![(PitchName::D, Accidental::Natural, 5)], NoteValue::new(2)),
+    ]);
+}
+
+#[test]
+fn repeats_are_written_out() {
+    let score = Score::from_abc("X:1\nM:2/4\nL:1/4\nK:C\nC D|:E F|1G A:|2c d|]\n").unwrap();
+    let pitches: Vec<PitchName> = score.events_on_layer(0, 0).map(|e| e.notes()[0].pitch.class).collect();
+    use PitchName::*;
+    assert_eq!(pitches, vec![C, D, E, F, G, A, E, F, C, D]);
+    let starts: Vec<Pulse> = score.events_on_layer(0, 0).map(|e| e.start()).collect();
+    assert_eq!(starts, (0..10).map(|i| Pulse::new(i, 4)).collect::<Vec<_>>());
+}
+
+#[test]
+fn pickup_bar() {
+    let score = Score::from_abc("X:1\nM:3/4\nL:1/4\nK:G\nD|G2 B|d3|]\n").unwrap();
+    assert_eq!(score.measures(), vec![
+        (Pulse::default(), Pulse::new(1, 4)),
+        (Pulse::new(1, 4), Pulse::whole(1)),
+        (Pulse::whole(1), Pulse::new(7, 4)),
+    ]);
+}
+
+#[test]
+fn export() {
+    let mut ctx = Context::default();
+    ctx.apply(&SetMetadata {
+        metadata: Metadata {
+            title: "Little Tune".to_string(),
+            ..Default::default()
+        },
+    });
+    ctx.apply(&SetKey {
+        key: Some(Key { fifths: 2, mode: Mode::Major }),
+        selections: vec![0],
+    });
+    ctx.apply(&SetMeter {
+        meter: Some(Meter { count: 6, unit: 8 }),
+        selections: vec![0],
+    });
+    for (class, accidental, octave) in [
+        (PitchName::D, Accidental::Natural, 4),
+        (PitchName::F, Accidental::Sharp, 4),
+        (PitchName::A, Accidental::Natural, 4),
+        (PitchName::D, Accidental::Natural, 5),
+        (PitchName::C, Accidental::Sharp, 5),
+        (PitchName::B, Accidental::Natural, 4),
+    ] {
+        append(&mut ctx, note(class, accidental, octave), NoteValue::new(8));
+    }
+    append(&mut ctx, note(PitchName::A, Accidental::Natural, 4), NoteValue::dotted(4, 1));
+    append(&mut ctx, note(PitchName::A, Accidental::Natural, 3), NoteValue::new(4));
+    place(&mut ctx, 0, 0, Pulse::new(3, 4));
+    ctx.apply(&AddPitch {
+        note: note(PitchName::C, Accidental::Sharp, 5),
+        selections: vec![0],
+    });
+    assert_eq!(ctx.score.to_abc(), "X:1
+T:Little Tune
+M:6/8
+L:1/8
+K:D
+DFA dcB | [Ac]3 A,2 z |]
+");
+}
+
+#[test]
+fn round_trips() {
+    let mut ctx = Context::default();
+    ctx.apply(&SetKey {
+        key: Some(Key { fifths: -3, mode: Mode::Minor }),
+        selections: vec![0],
+    });
+    append(&mut ctx, note(PitchName::C, Accidental::Natural, 5), NoteValue::dotted(8, 1));
+    append(&mut ctx, note(PitchName::B, Accidental::Natural, 4), NoteValue::new(16));
+    append(&mut ctx, note(PitchName::A, Accidental::Flat, 4), NoteValue::new(4));
+    append(&mut ctx, note(PitchName::G, Accidental::Natural, 4), NoteValue::new(2));
+    // Ids come out in reading order, so the overlay goes in with its bar
+    place(&mut ctx, 0, 1, Pulse::new(1, 4));
+    append(&mut ctx, note(PitchName::E, Accidental::Flat, 4), NoteValue::new(4));
+    place(&mut ctx, 0, 0, Pulse::whole(1));
+    append(&mut ctx, note(PitchName::B, Accidental::Natural, 4), NoteValue::new(2));
+    append(&mut ctx, note(PitchName::D, Accidental::QuarterSharp, 5), NoteValue { base: 4, dots: 0, tuplet: Some(Tuplet::triplet()) });
+    append(&mut ctx, note(PitchName::E, Accidental::Flat, 5), NoteValue { base: 4, dots: 0, tuplet: Some(Tuplet::triplet()) });
+    append(&mut ctx, note(PitchName::F, Accidental::Natural, 5), NoteValue { base: 4, dots: 0, tuplet: Some(Tuplet::triplet()) });
+    append(&mut ctx, note(PitchName::C, Accidental::Natural, 3), NoteValue::new(1));
+    place(&mut ctx, 0, 0, Pulse::new(1, 2));
+    ctx.apply(&ToggleSelectionsCautionary { selections: vec![0] });
+    ctx.apply(&SetDynamic {
+        dynamic: Some(Dynamic::PP),
+        selections: vec![0],
+    });
+    place(&mut ctx, 0, 0, Pulse::new(3, 2));
+    ctx.apply(&SetClef {
+        clef: Some(Clef::bass()),
+        selections: vec![0],
+    });
+    place(&mut ctx, 0, 0, Pulse::whole(1));
+    ctx.apply(&SetMeter {
+        meter: Some(Meter { count: 2, unit: 4 }),
+        selections: vec![0],
+    });
+    ctx.apply(&SetTempo {
+        tempo: Some(Tempo { bpm: 66 }),
+        selections: vec![0],
+    });
+    ctx.apply(&AddPart {
+        name: "Cello".to_string(),
+        staves: 1,
+    });
+    place(&mut ctx, 1, 0, Pulse::default());
+    append(&mut ctx, note(PitchName::C, Accidental::Natural, 3), NoteValue::new(1));
+    assert_round_trip(&ctx.score);
+}
+
+#[test]
+fn malformed_bodies_dont_panic() {
+    // Nothing to lengthen or repeat before the first note
+    for body in [">c d", "<<c d", ":|c d|", "|]:|c d"] {
+        let score = Score::from_abc(&format!("X:1\nK:C\n{}\n", body)).unwrap();
+        assert_eq!(score.events_on_layer(0, 0).count(), 2, "{}", body);
+    }
+    assert!(Score::from_abc("X:1\nK:C\n>\n").is_ok());
+}
+
+#[test]
+fn lengths_too_short_to_write_are_left_out() {
+    let slashes = "/".repeat(70);
+    for body in [format!("c{} D", slashes), "c/99999999999999999999 D".to_string(), format!("[ce]{} D", slashes)] {
+        let score = Score::from_abc(&format!("X:1\nK:C\n{}\n", body)).unwrap();
+        assert_eq!(layer(&score, 0, 0), vec![(Pulse::default(), vec![(PitchName::D, Accidental::Natural, 4)], NoteValue::new(8))], "{}", body);
+    }
+}
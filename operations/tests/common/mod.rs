@@ -0,0 +1,34 @@
+// Fixtures shared by the integration tests. Not every test uses all of them.
+#![allow(dead_code)]
+
+use operations::*;
+
+pub fn note(class: PitchName, accidental: Accidental, octave: u32) -> Note {
+    Note::new(Pitch { class, accidental }, Octave(octave))
+}
+
+// Puts the cursor on a staff and layer. Appending moves it along.
+pub fn place(ctx: &mut Context, staff: u32, layer: u32, at: Pulse) {
+    ctx.selections.0[0] = Selection {
+        begin: Location(at),
+        end: Location(at),
+        staff,
+        layer,
+    };
+}
+
+pub fn append(ctx: &mut Context, note: Note, duration: NoteValue) {
+    ctx.apply(&AppendNote {
+        note,
+        duration,
+        selections: None,
+    });
+}
+
+pub fn triplet(base: u32) -> NoteValue {
+    NoteValue {
+        base,
+        dots: 0,
+        tuplet: Some(Tuplet::triplet()),
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::BTreeSet;
+
+use operations::*;
+
+mod common;
+use common::*;
+
+fn measures(indices: &[usize]) -> Dirty {
+    Dirty::Measures(indices.iter().copied().collect::<BTreeSet<_>>())
+}
+
+// Four bars of whole notes, already drawn
+fn four_bars() -> Context {
+    let mut ctx = Context::default();
+    for class in [PitchName::C, PitchName::D, PitchName::E, PitchName::F] {
+        append(&mut ctx, note(class, Accidental::Natural, 4), NoteValue::new(1));
+    }
+    ctx.take_dirty();
+    ctx
+}
+
+#[test]
+fn new_contexts_need_drawing() {
+    let mut ctx = Context::default();
+    assert_eq!(ctx.take_dirty(), Dirty::All);
+    assert!(ctx.take_dirty().is_clean());
+}
+
+#[test]
+fn only_the_edited_measure_is_dirty() {
+    let mut ctx = four_bars();
+    place(&mut ctx, 0, 0, Pulse::whole(1));
+    ctx.apply(&AddPitch {
+        note: note(PitchName::A, Accidental::Natural, 4),
+        selections: vec![0],
+    });
+    assert_eq!(ctx.take_dirty(), measures(&[1]));
+
+    // Undoing puts the same measure back
+    ctx.undo();
+    assert_eq!(ctx.take_dirty(), measures(&[1]));
+}
+
+#[test]
+fn undoing_a_trailing_delete_dirties_the_measures_it_brings_back() {
+    let mut ctx = four_bars();
+    place(&mut ctx, 0, 0, Pulse::whole(2));
+    ctx.selections.0[0].end = Location(Pulse::whole(4));
+    ctx.apply(&DeleteSelections {
+        selections: vec![0],
+    });
+    assert_eq!(ctx.take_dirty(), measures(&[1]));
+
+    ctx.undo();
+    assert_eq!(ctx.take_dirty(), measures(&[1, 2, 3]));
+    // Going forward again only leaves what's still in the score
+    ctx.redo();
+    assert_eq!(ctx.take_dirty(), measures(&[1]));
+}
+
+#[test]
+fn key_changes_dirty_the_rest_of_the_score() {
+    let mut ctx = four_bars();
+    place(&mut ctx, 0, 0, Pulse::whole(2));
+    ctx.apply(&SetKey {
+        key: Some(Key { fifths: 2, mode: Mode::Major }),
+        selections: vec![0],
+    });
+    assert_eq!(ctx.take_dirty(), measures(&[2, 3]));
+}
+
+#[test]
+fn appending_dirties_the_new_measure() {
+    let mut ctx = four_bars();
+    append(&mut ctx, note(PitchName::G, Accidental::Natural, 4), NoteValue::new(2));
+    assert_eq!(ctx.take_dirty(), measures(&[4]));
+}
+
+#[test]
+fn moving_the_cursor_leaves_it_clean() {
+    let mut ctx = four_bars();
+    ctx.apply(&MoveSelectionsEnd {
+        delta: Duration::Event(-1),
+        selections: vec![0],
+    });
+    assert!(ctx.take_dirty().is_clean());
+}
+
+#[test]
+fn header_changes_dirty_everything() {
+    let mut ctx = four_bars();
+    ctx.apply(&SetMetadata {
+        metadata: Metadata {
+            title: "Scale".to_string(),
+            ..Default::default()
+        },
+    });
+    assert_eq!(ctx.take_dirty(), Dirty::All);
+}
+
+#[test]
+fn moved_notes_dirty_where_they_were_and_where_they_went() {
+    let mut ctx = four_bars();
+    place(&mut ctx, 0, 0, Pulse::whole(0));
+    ctx.apply(&MoveSelectionsContents {
+        delta: Duration::Pulse(Pulse::whole(1)),
+        selections: vec![0],
+    });
+    // Swapped with the note after it
+    assert_eq!(ctx.take_dirty(), measures(&[0, 1]));
+}
+
+#[test]
+fn detuning_leaves_it_clean() {
+    let mut ctx = four_bars();
+    place(&mut ctx, 0, 0, Pulse::whole(1));
+    ctx.apply(&SetSelectionsDetune {
+        cents: 20,
+        selections: vec![0],
+    });
+    assert!(ctx.take_dirty().is_clean());
+    // It's still an edit which can be undone
+    assert!(ctx.undo());
+}
@@ -0,0 +1,105 @@
+use operations::*;
+use strong_xml::XmlRead;
+
+// What Verovio's load_humdrum gives back for
+//
+//   **kern
+//   *clefG2
+//   *k[f#]
+//   *M3/4
+//   =1
+//   4g
+//   8f#L
+//   8fnJ
+//   4f
+//   =2
+//   4e 4g
+//   4r
+//   4d
+//   *-
+//
+// Verovio keeps the sounding accidentals on <accid> elements inside the
+// notes and uses ids of its own.
+const VEROVIO_MEI: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<mei xmlns="http://www.music-encoding.org/ns/mei" meiversion="4.0.1">
+  <meiHead>
+    <fileDesc>
+      <titleStmt>
+        <title>Waltz</title>
+      </titleStmt>
+    </fileDesc>
+  </meiHead>
+  <music>
+    <body>
+      <mdiv xml:id="mdiv-0000001">
+        <score xml:id="score-0000001">
+          <scoreDef xml:id="scoredef-0000001" key.sig="1s" meter.count="3" meter.unit="4">
+            <staffGrp xml:id="staffgrp-0000001">
+              <staffDef xml:id="staffdef-0000001" n="1" lines="5" clef.shape="G" clef.line="2" />
+            </staffGrp>
+          </scoreDef>
+          <section xml:id="section-L1F1">
+            <measure xml:id="measure-L5" n="1">
+              <staff xml:id="staff-L5F1N1" n="1">
+                <layer xml:id="layer-L5F1N1" n="1">
+                  <note xml:id="note-L6F1" dur="4" oct="4" pname="g" />
+                  <beam xml:id="beam-L7F1-L8F1">
+                    <note xml:id="note-L7F1" dur="8" oct="4" pname="f">
+                      <accid xml:id="accid-L7F1" accid.ges="s" />
+                    </note>
+                    <note xml:id="note-L8F1" dur="8" oct="4" pname="f">
+                      <accid xml:id="accid-L8F1" accid="n" />
+                    </note>
+                  </beam>
+                  <note xml:id="note-L9F1" dur="4" oct="4" pname="f">
+                    <accid xml:id="accid-L9F1" accid.ges="n" />
+                  </note>
+                </layer>
+              </staff>
+            </measure>
+            <measure xml:id="measure-L10" n="2">
+              <staff xml:id="staff-L10F1N1" n="1">
+                <layer xml:id="layer-L10F1N1" n="1">
+                  <chord xml:id="chord-L11F1" dur="4">
+                    <note xml:id="note-L11F1S1" oct="4" pname="e" />
+                    <note xml:id="note-L11F1S2" oct="4" pname="g" />
+                  </chord>
+                  <rest xml:id="rest-L12F1" dur="4" />
+                  <note xml:id="note-L13F1" dur="4" oct="4" pname="d" />
+                </layer>
+              </staff>
+            </measure>
+          </section>
+        </score>
+      </mdiv>
+    </body>
+  </music>
+</mei>
+"#;
+
+#[test]
+fn humdrum_through_verovio() {
+    let mei = ir::Mei::from_str(VEROVIO_MEI).unwrap();
+    let score = Score::from_mei(&mei).unwrap();
+    assert_eq!(score.metadata.title, "Waltz");
+    assert_eq!(score.key_at(Pulse::default()), Key { fifths: 1, mode: Mode::Major });
+    assert_eq!(score.meter_at(Pulse::default()), Meter { count: 3, unit: 4 });
+
+    let natural = Accidental::Natural;
+    let events: Vec<_> = score.events_on_layer(0, 0)
+        .map(|e| (e.start(), e.notes().iter().map(|n| (n.pitch.class, n.pitch.accidental, n.octave.0)).collect::<Vec<_>>(), e.duration()))
+        .collect();
+    assert_eq!(events, vec![
+        (Pulse::default(), vec![(PitchName::G, natural, 4)], NoteValue::new(4)),
+        (Pulse::new(1, 4), vec![(PitchName::F, Accidental::Sharp, 4)], NoteValue::new(8)),
+        (Pulse::new(3, 8), vec![(PitchName::F, natural, 4)], NoteValue::new(8)),
+        // Still natural from earlier in the bar, which only the accid.ges says
+        (Pulse::new(1, 2), vec![(PitchName::F, natural, 4)], NoteValue::new(4)),
+        (Pulse::new(3, 4), vec![(PitchName::E, natural, 4), (PitchName::G, natural, 4)], NoteValue::new(4)),
+        (Pulse::new(5, 4), vec![(PitchName::D, natural, 4)], NoteValue::new(4)),
+    ]);
+
+    // Verovio's ids aren't ours, so every event gets a new one
+    let ids: Vec<u32> = score.events_on_layer(0, 0).map(|e| e.id()).collect();
+    assert_eq!(ids, vec![0, 1, 2, 3, 4, 5]);
+}
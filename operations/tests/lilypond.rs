@@ -0,0 +1,191 @@
+use operations::*;
+
+mod common;
+use common::*;
+
+// A tune in G with leaps both ways and a note tied over a barline
+fn melody() -> Score {
+    let mut ctx = Context::default();
+    ctx.apply(&SetKey {
+        key: Some(Key { fifths: 1, mode: Mode::Major }),
+        selections: vec![0],
+    });
+    ctx.apply(&SetMeter {
+        meter: Some(Meter { count: 3, unit: 4 }),
+        selections: vec![0],
+    });
+    append(&mut ctx, note(PitchName::G, Accidental::Natural, 4), NoteValue::new(4));
+    append(&mut ctx, note(PitchName::B, Accidental::Natural, 4), NoteValue::new(4));
+    append(&mut ctx, note(PitchName::D, Accidental::Natural, 5), NoteValue::new(4));
+    append(&mut ctx, note(PitchName::G, Accidental::Natural, 5), NoteValue::new(2));
+    append(&mut ctx, note(PitchName::F, Accidental::Sharp, 5), NoteValue::new(4));
+    append(&mut ctx, note(PitchName::E, Accidental::Natural, 4), NoteValue::new(2));
+    append(&mut ctx, note(PitchName::D, Accidental::Natural, 4), NoteValue::new(4));
+    append(&mut ctx, note(PitchName::B, Accidental::Natural, 3), NoteValue::new(2));
+    append(&mut ctx, note(PitchName::A, Accidental::Natural, 3), NoteValue::new(2));
+    ctx.score
+}
+
+#[test]
+fn melody_in_relative_mode() {
+    assert_eq!(melody().to_lilypond(PitchMode::Relative), r#"\version "2.22.0"
+
+\score {
+  \new Staff \relative c' {
+    \clef treble \key g \major \time 3/4 g'4 b4 d4 |
+    g2 fis4 |
+    e,2 d4 |
+    b2 a4~ |
+    a4 r2 |
+  }
+  \layout { }
+}
+"#);
+}
+
+#[test]
+fn melody_in_absolute_mode() {
+    assert_eq!(melody().to_lilypond(PitchMode::Absolute), r#"\version "2.22.0"
+
+\score {
+  \new Staff {
+    \clef treble \key g \major \time 3/4 g'4 b'4 d''4 |
+    g''2 fis''4 |
+    e'2 d'4 |
+    b2 a4~ |
+    a4 r2 |
+  }
+  \layout { }
+}
+"#);
+}
+
+// Setting the key or meter it already has doesn't write it again
+#[test]
+fn repeated_keys_and_meters_are_left_out() {
+    let mut score = melody();
+    score.keys.insert(Pulse::new(3, 4), Key { fifths: 1, mode: Mode::Major });
+    score.meters.insert(Pulse::new(3, 2), Meter { count: 3, unit: 4 });
+    score.keys.insert(Pulse::new(9, 4), Key { fifths: 2, mode: Mode::Major });
+    assert_eq!(score.to_lilypond(PitchMode::Absolute), r#"\version "2.22.0"
+
+\score {
+  \new Staff {
+    \clef treble \key g \major \time 3/4 g'4 b'4 d''4 |
+    g''2 fis''4 |
+    e'2 d'4 |
+    \key d \major b2 a4~ |
+    a4 r2 |
+  }
+  \layout { }
+}
+"#);
+}
+
+#[test]
+fn voices_tuplets_chords_and_clef_changes() {
+    let mut ctx = Context::default();
+    append(&mut ctx, note(PitchName::C, Accidental::Natural, 5), triplet(8));
+    append(&mut ctx, note(PitchName::D, Accidental::Natural, 5), triplet(8));
+    append(&mut ctx, note(PitchName::E, Accidental::Natural, 5), triplet(8));
+    append(&mut ctx, note(PitchName::F, Accidental::Natural, 5), NoteValue::new(2));
+    place(&mut ctx, 0, 0, Pulse::new(3, 2));
+    ctx.apply(&SetClef {
+        clef: Some(Clef::bass()),
+        selections: vec![0],
+    });
+    append(&mut ctx, note(PitchName::C, Accidental::Natural, 3), NoteValue::new(2));
+    place(&mut ctx, 0, 0, Pulse::new(3, 2));
+    ctx.apply(&AddPitch {
+        note: note(PitchName::E, Accidental::Flat, 3),
+        selections: vec![0],
+    });
+    ctx.apply(&ToggleSelectionsCautionary { selections: vec![0] });
+    place(&mut ctx, 0, 1, Pulse::default());
+    append(&mut ctx, note(PitchName::G, Accidental::Natural, 4), NoteValue::new(2));
+    append(&mut ctx, note(PitchName::A, Accidental::Natural, 4), NoteValue::new(2));
+    assert_eq!(ctx.score.to_lilypond(PitchMode::Relative), r#"\version "2.22.0"
+
+\score {
+  \new Staff <<
+    \new Voice \relative c' {
+      \voiceOne
+      \clef treble \key c \major \time 4/4 \tuplet 3/2 { c'8 d8 e8 } f2 r4 |
+      r2 \clef bass <c,,? es?>2 |
+    }
+    \new Voice \relative c' {
+      \voiceTwo
+      g'2 a2 |
+      s1 |
+    }
+  >>
+  \layout { }
+}
+"#);
+}
+
+#[test]
+fn parts_header_tempo_and_dynamics() {
+    let mut ctx = Context::default();
+    ctx.score.parts[0].name = "Violin".to_string();
+    ctx.apply(&SetMetadata {
+        metadata: Metadata {
+            title: "Sonata \"No. 1\"".to_string(),
+            composer: "A. Composer".to_string(),
+            lyricist: "A. Poet".to_string(),
+            arranger: "An Arranger".to_string(),
+        },
+    });
+    ctx.apply(&AddPart {
+        name: "Piano".to_string(),
+        staves: 2,
+    });
+    ctx.apply(&SetTempo {
+        tempo: Some(Tempo { bpm: 100 }),
+        selections: vec![0],
+    });
+    ctx.apply(&SetDynamic {
+        dynamic: Some(Dynamic::F),
+        selections: vec![0],
+    });
+    append(&mut ctx, note(PitchName::A, Accidental::Natural, 4), NoteValue::new(1));
+    place(&mut ctx, 1, 0, Pulse::default());
+    append(&mut ctx, note(PitchName::C, Accidental::Natural, 5), NoteValue::new(1));
+    place(&mut ctx, 2, 0, Pulse::default());
+    ctx.apply(&SetClef {
+        clef: Some(Clef::bass()),
+        selections: vec![0],
+    });
+    append(&mut ctx, note(PitchName::C, Accidental::Natural, 3), NoteValue::new(2));
+    ctx.apply(&SetDynamic {
+        dynamic: Some(Dynamic::MP),
+        selections: vec![0],
+    });
+    append(&mut ctx, note(PitchName::G, Accidental::Natural, 2), NoteValue::new(2));
+    assert_eq!(ctx.score.to_lilypond(PitchMode::Relative), r#"\version "2.22.0"
+
+\header {
+  title = "Sonata \"No. 1\""
+  composer = "A. Composer"
+  poet = "A. Poet"
+  arranger = "An Arranger"
+}
+
+\score {
+  \new StaffGroup <<
+    \new Staff \with { instrumentName = "Violin" } \relative c' {
+      \clef treble \key c \major \time 4/4 \tempo 4 = 100 <>\f a'1 |
+    }
+    \new GrandStaff \with { instrumentName = "Piano" } <<
+      \new Staff \relative c' {
+        \clef treble \key c \major \time 4/4 c'1 |
+      }
+      \new Staff \relative c' {
+        \clef bass \key c \major \time 4/4 c,2 <>\mp g2 |
+      }
+    >>
+  >>
+  \layout { }
+}
+"#);
+}
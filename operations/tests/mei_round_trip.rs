@@ -0,0 +1,268 @@
+use operations::*;
+
+mod common;
+use common::*;
+
+fn assert_round_trip(score: &Score) {
+    let mei = score.to_mei();
+    assert_eq!(&Score::from_mei(&mei).unwrap(), score);
+}
+
+fn mei_score(mei: &mut ir::Mei) -> &mut ir::Score {
+    mei.music.as_mut().unwrap().body.as_mut().unwrap().mdivs[0].score.as_mut().unwrap()
+}
+
+// The notes of the first layer of the first staff
+fn notes_mut(mei: &mut ir::Mei) -> impl Iterator<Item=&mut ir::Note> {
+    mei_score(mei).sections[0].children.iter_mut().filter_map(|child| match child {
+        ir::SectionLike::Measure(measure) => Some(measure),
+        _ => None,
+    }).flat_map(|measure| measure.staves[0].layers[0].events.iter_mut()).filter_map(|event| match event {
+        ir::EventLike::Note(note) => Some(note),
+        _ => None,
+    })
+}
+
+#[test]
+fn empty_score() {
+    assert_round_trip(&Score::default());
+}
+
+#[test]
+fn melody_in_a_key() {
+    let mut ctx = Context::default();
+    ctx.apply(&SetKey {
+        key: Some(Key { fifths: 2, mode: Mode::Major }),
+        selections: vec![0],
+    });
+    append(&mut ctx, note(PitchName::D, Accidental::Natural, 4), NoteValue::new(4));
+    append(&mut ctx, note(PitchName::F, Accidental::Sharp, 4), NoteValue::dotted(8, 1));
+    append(&mut ctx, note(PitchName::F, Accidental::Natural, 4), NoteValue::new(16));
+    append(&mut ctx, note(PitchName::F, Accidental::Natural, 4), NoteValue::new(4));
+    append(&mut ctx, note(PitchName::C, Accidental::Natural, 5), NoteValue::new(4));
+    append(&mut ctx, note(PitchName::B, Accidental::Flat, 3), NoteValue::new(2));
+    append(&mut ctx, note(PitchName::E, Accidental::QuarterSharp, 5), NoteValue::new(2));
+    assert_round_trip(&ctx.score);
+}
+
+#[test]
+fn notes_across_barlines() {
+    let mut ctx = Context::default();
+    ctx.apply(&SetMeter {
+        meter: Some(Meter { count: 3, unit: 4 }),
+        selections: vec![0],
+    });
+    append(&mut ctx, note(PitchName::C, Accidental::Natural, 4), NoteValue::new(2));
+    append(&mut ctx, note(PitchName::E, Accidental::Natural, 4), NoteValue::new(2));
+    append(&mut ctx, note(PitchName::G, Accidental::Sharp, 4), NoteValue::dotted(1, 1));
+    append(&mut ctx, note(PitchName::A, Accidental::Natural, 4), NoteValue::new(4));
+    append(&mut ctx, note(PitchName::B, Accidental::Natural, 4), NoteValue { base: 4, dots: 0, tuplet: Some(Tuplet::triplet()) });
+    append(&mut ctx, note(PitchName::C, Accidental::Natural, 5), NoteValue { base: 2, dots: 0, tuplet: Some(Tuplet::triplet()) });
+    assert_round_trip(&ctx.score);
+}
+
+#[test]
+fn chords_and_layers() {
+    let mut ctx = Context::default();
+    append(&mut ctx, note(PitchName::C, Accidental::Natural, 4), NoteValue::new(2));
+    append(&mut ctx, note(PitchName::D, Accidental::Natural, 4), NoteValue::new(2));
+    place(&mut ctx, 0, 0, Pulse::default());
+    ctx.apply(&AddPitch {
+        note: note(PitchName::E, Accidental::Flat, 4),
+        selections: vec![0],
+    });
+    ctx.apply(&AddPitch {
+        note: note(PitchName::G, Accidental::Natural, 4),
+        selections: vec![0],
+    });
+    place(&mut ctx, 0, 1, Pulse::new(1, 4));
+    append(&mut ctx, note(PitchName::G, Accidental::Natural, 3), NoteValue::new(4));
+    append(&mut ctx, note(PitchName::E, Accidental::Natural, 3), NoteValue::new(1));
+    assert_round_trip(&ctx.score);
+}
+
+#[test]
+fn parts_staves_and_clefs() {
+    let mut ctx = Context::default();
+    ctx.apply(&AddPart {
+        name: "Piano".to_string(),
+        staves: 2,
+    });
+    append(&mut ctx, note(PitchName::A, Accidental::Natural, 4), NoteValue::new(1));
+    append(&mut ctx, note(PitchName::B, Accidental::Natural, 4), NoteValue::new(1));
+    place(&mut ctx, 1, 0, Pulse::default());
+    append(&mut ctx, note(PitchName::C, Accidental::Natural, 5), NoteValue::new(2));
+    append(&mut ctx, note(PitchName::D, Accidental::Natural, 5), NoteValue::new(2));
+    place(&mut ctx, 2, 0, Pulse::default());
+    ctx.apply(&SetClef {
+        clef: Some(Clef::bass()),
+        selections: vec![0],
+    });
+    append(&mut ctx, note(PitchName::C, Accidental::Natural, 3), NoteValue::new(1));
+    append(&mut ctx, note(PitchName::G, Accidental::Natural, 2), NoteValue::new(2));
+    append(&mut ctx, note(PitchName::C, Accidental::Natural, 4), NoteValue::new(2));
+    place(&mut ctx, 2, 0, Pulse::new(3, 2));
+    ctx.apply(&SetClef {
+        clef: Some(Clef::tenor()),
+        selections: vec![0],
+    });
+    place(&mut ctx, 0, 0, Pulse::whole(1));
+    ctx.apply(&SetClef {
+        clef: Some(Clef { shape: ClefShape::G, line: 2, octave: -1 }),
+        selections: vec![0],
+    });
+    assert_round_trip(&ctx.score);
+}
+
+#[test]
+fn meter_key_and_tempo_changes() {
+    let mut ctx = Context::default();
+    ctx.apply(&SetTempo {
+        tempo: Some(Tempo { bpm: 72 }),
+        selections: vec![0],
+    });
+    for _ in 0..12 {
+        append(&mut ctx, note(PitchName::G, Accidental::Natural, 4), NoteValue::new(4));
+    }
+    place(&mut ctx, 0, 0, Pulse::whole(1));
+    ctx.apply(&SetMeter {
+        meter: Some(Meter { count: 6, unit: 8 }),
+        selections: vec![0],
+    });
+    ctx.apply(&SetKey {
+        key: Some(Key { fifths: -3, mode: Mode::Minor }),
+        selections: vec![0],
+    });
+    place(&mut ctx, 0, 0, Pulse::new(7, 4));
+    ctx.apply(&SetTempo {
+        tempo: Some(Tempo { bpm: 140 }),
+        selections: vec![0],
+    });
+    assert_round_trip(&ctx.score);
+}
+
+#[test]
+fn dynamics() {
+    let mut ctx = Context::default();
+    ctx.apply(&AddStaff { selections: vec![0] });
+    for _ in 0..8 {
+        append(&mut ctx, note(PitchName::F, Accidental::Natural, 4), NoteValue::new(4));
+    }
+    place(&mut ctx, 0, 0, Pulse::default());
+    ctx.apply(&SetDynamic {
+        dynamic: Some(Dynamic::FF),
+        selections: vec![0],
+    });
+    place(&mut ctx, 1, 0, Pulse::new(3, 8));
+    ctx.apply(&SetDynamic {
+        dynamic: Some(Dynamic::PP),
+        selections: vec![0],
+    });
+    place(&mut ctx, 1, 0, Pulse::new(7, 6));
+    ctx.apply(&SetDynamic {
+        dynamic: Some(Dynamic::MP),
+        selections: vec![0],
+    });
+    assert_round_trip(&ctx.score);
+}
+
+#[test]
+fn cautionary_accidentals() {
+    let mut ctx = Context::default();
+    append(&mut ctx, note(PitchName::F, Accidental::Sharp, 4), NoteValue::new(2));
+    append(&mut ctx, note(PitchName::F, Accidental::Sharp, 4), NoteValue::new(2));
+    // The natural in the second measure gets a courtesy accidental on its own
+    append(&mut ctx, note(PitchName::F, Accidental::Natural, 4), NoteValue::new(2));
+    append(&mut ctx, note(PitchName::B, Accidental::Natural, 4), NoteValue::new(2));
+    place(&mut ctx, 0, 0, Pulse::new(1, 2));
+    ctx.apply(&ToggleSelectionsCautionary { selections: vec![0] });
+    place(&mut ctx, 0, 0, Pulse::new(3, 2));
+    ctx.apply(&ToggleSelectionsCautionary { selections: vec![0] });
+    assert_round_trip(&ctx.score);
+}
+
+#[test]
+fn metadata() {
+    let mut ctx = Context::default();
+    ctx.apply(&SetMetadata {
+        metadata: Metadata {
+            title: "Lullaby & Goodnight".to_string(),
+            composer: "J. Brahms".to_string(),
+            lyricist: String::new(),
+            arranger: "Someone Else".to_string(),
+        },
+    });
+    append(&mut ctx, note(PitchName::E, Accidental::Natural, 4), NoteValue::new(1));
+    assert_round_trip(&ctx.score);
+
+    // Only the program writing the file knows its version
+    assert_eq!(ctx.score.to_mei().mei_head.unwrap().encoding_desc, None);
+    let mei = ctx.score.to_mei_from("music_editor", "1.2.3");
+    let app_info = mei.mei_head.unwrap().encoding_desc.unwrap().app_info.unwrap();
+    assert_eq!(app_info.applications[0].version.as_deref(), Some("1.2.3"));
+    assert_eq!(app_info.applications[0].name, "music_editor");
+}
+
+#[test]
+fn ties_which_dont_add_up_keep_their_pieces() {
+    let mut ctx = Context::default();
+    for value in [NoteValue::new(1), NoteValue::new(8), NoteValue::new(8)] {
+        append(&mut ctx, note(PitchName::C, Accidental::Natural, 4), value);
+    }
+    // Tie the three together, though no single value is a whole and a quarter
+    let mut mei = ctx.score.to_mei();
+    for (note, tie) in notes_mut(&mut mei).zip(["i", "m", "t"]) {
+        note.tie = Some(tie.to_string());
+    }
+
+    let score = Score::from_mei(&mei).unwrap();
+    let events: Vec<_> = score.events_on_layer(0, 0).map(|e| (e.id(), e.start(), e.duration())).collect();
+    assert_eq!(events, vec![
+        (0, Pulse::default(), NoteValue::new(1)),
+        (1, Pulse::whole(1), NoteValue::new(8)),
+        (2, Pulse::new(9, 8), NoteValue::new(8)),
+    ]);
+}
+
+#[test]
+fn ties_which_never_end_keep_their_pieces() {
+    let mut ctx = Context::default();
+    for _ in 0..3 {
+        append(&mut ctx, note(PitchName::C, Accidental::Natural, 4), NoteValue::new(4));
+    }
+    // The first tie is cut off by the next one starting and that one by the end
+    let mut mei = ctx.score.to_mei();
+    for (note, tie) in notes_mut(&mut mei).zip(["i", "m", "i"]) {
+        note.tie = Some(tie.to_string());
+    }
+
+    let score = Score::from_mei(&mei).unwrap();
+    let events: Vec<_> = score.events_on_layer(0, 0).map(|e| (e.id(), e.start(), e.duration())).collect();
+    assert_eq!(events, vec![
+        (0, Pulse::default(), NoteValue::new(4)),
+        (1, Pulse::new(1, 4), NoteValue::new(4)),
+        (2, Pulse::new(1, 2), NoteValue::new(4)),
+    ]);
+}
+
+#[test]
+fn unsupported_values_are_errors() {
+    let mut ctx = Context::default();
+    append(&mut ctx, note(PitchName::C, Accidental::Natural, 4), NoteValue::new(4));
+
+    let mut bad_meter = ctx.score.to_mei();
+    mei_score(&mut bad_meter).score_def.as_mut().unwrap().meter_unit = Some(3);
+    assert!(Score::from_mei(&bad_meter).is_err());
+
+    let mut bad_key = ctx.score.to_mei();
+    mei_score(&mut bad_key).score_def.as_mut().unwrap().key_sig = Some("9s".to_string());
+    assert!(Score::from_mei(&bad_key).is_err());
+
+    let mut bad_dur = ctx.score.to_mei();
+    notes_mut(&mut bad_dur).next().unwrap().dur = Some(3);
+    assert!(Score::from_mei(&bad_dur).is_err());
+
+    let mut bad_dots = ctx.score.to_mei();
+    notes_mut(&mut bad_dots).next().unwrap().dots = Some(3);
+    assert!(Score::from_mei(&bad_dots).is_err());
+}
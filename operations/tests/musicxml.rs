@@ -0,0 +1,632 @@
+use operations::*;
+
+mod common;
+use common::*;
+
+// Two bars of 3/4 in D with a chord, a pair of eighths and a note tied over the barline
+fn waltz() -> Score {
+    let mut ctx = Context::default();
+    ctx.apply(&SetKey {
+        key: Some(Key { fifths: 2, mode: Mode::Major }),
+        selections: vec![0],
+    });
+    ctx.apply(&SetMeter {
+        meter: Some(Meter { count: 3, unit: 4 }),
+        selections: vec![0],
+    });
+    append(&mut ctx, note(PitchName::D, Accidental::Natural, 4), NoteValue::new(4));
+    place(&mut ctx, 0, 0, Pulse::default());
+    ctx.apply(&AddPitch {
+        note: note(PitchName::F, Accidental::Sharp, 4),
+        selections: vec![0],
+    });
+    place(&mut ctx, 0, 0, Pulse::new(1, 4));
+    append(&mut ctx, note(PitchName::E, Accidental::Natural, 4), NoteValue::new(8));
+    append(&mut ctx, note(PitchName::F, Accidental::Natural, 4), NoteValue::new(8));
+    append(&mut ctx, note(PitchName::A, Accidental::Natural, 4), NoteValue::new(2));
+    append(&mut ctx, note(PitchName::G, Accidental::Natural, 4), NoteValue::new(4));
+    ctx.score
+}
+
+#[test]
+fn waltz_snapshot() {
+    assert_eq!(waltz().to_musicxml(), r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE score-partwise PUBLIC "-//Recordare//DTD MusicXML 4.0 Partwise//EN" "http://www.musicxml.org/dtds/partwise.dtd">
+<score-partwise version="4.0">
+  <part-list>
+    <score-part id="P1">
+      <part-name>Part 1</part-name>
+      <score-instrument id="P1-I1">
+        <instrument-name>Part 1</instrument-name>
+      </score-instrument>
+      <midi-instrument id="P1-I1">
+        <midi-program>1</midi-program>
+      </midi-instrument>
+    </score-part>
+  </part-list>
+  <part id="P1">
+    <measure number="1">
+      <attributes>
+        <divisions>2</divisions>
+        <key>
+          <fifths>2</fifths>
+          <mode>major</mode>
+        </key>
+        <time>
+          <beats>3</beats>
+          <beat-type>4</beat-type>
+        </time>
+        <clef>
+          <sign>G</sign>
+          <line>2</line>
+        </clef>
+      </attributes>
+      <sound tempo="120"/>
+      <note>
+        <pitch>
+          <step>D</step>
+          <octave>4</octave>
+        </pitch>
+        <duration>2</duration>
+        <voice>1</voice>
+        <type>quarter</type>
+      </note>
+      <note>
+        <chord/>
+        <pitch>
+          <step>F</step>
+          <alter>1</alter>
+          <octave>4</octave>
+        </pitch>
+        <duration>2</duration>
+        <voice>1</voice>
+        <type>quarter</type>
+      </note>
+      <note>
+        <pitch>
+          <step>E</step>
+          <octave>4</octave>
+        </pitch>
+        <duration>1</duration>
+        <voice>1</voice>
+        <type>eighth</type>
+        <beam number="1">begin</beam>
+      </note>
+      <note>
+        <pitch>
+          <step>F</step>
+          <octave>4</octave>
+        </pitch>
+        <duration>1</duration>
+        <voice>1</voice>
+        <type>eighth</type>
+        <accidental>natural</accidental>
+        <beam number="1">end</beam>
+      </note>
+      <note>
+        <pitch>
+          <step>A</step>
+          <octave>4</octave>
+        </pitch>
+        <duration>2</duration>
+        <tie type="start"/>
+        <voice>1</voice>
+        <type>quarter</type>
+        <notations>
+          <tied type="start"/>
+        </notations>
+      </note>
+    </measure>
+    <measure number="2">
+      <note>
+        <pitch>
+          <step>A</step>
+          <octave>4</octave>
+        </pitch>
+        <duration>2</duration>
+        <tie type="stop"/>
+        <voice>1</voice>
+        <type>quarter</type>
+        <notations>
+          <tied type="stop"/>
+        </notations>
+      </note>
+      <note>
+        <pitch>
+          <step>G</step>
+          <octave>4</octave>
+        </pitch>
+        <duration>2</duration>
+        <voice>1</voice>
+        <type>quarter</type>
+      </note>
+      <note>
+        <rest/>
+        <duration>2</duration>
+        <voice>1</voice>
+        <type>quarter</type>
+      </note>
+    </measure>
+  </part>
+</score-partwise>
+"#);
+}
+
+// An oboe over a two staff piano, with a second voice, a triplet, a chord and
+// changes of meter, key, tempo and clef in the second bar
+fn duet() -> Score {
+    let mut ctx = Context::default();
+    ctx.score.parts[0].name = "Oboe".to_string();
+    ctx.score.parts[0].program = 68;
+    ctx.apply(&AddPart { name: "Piano".to_string(), staves: 2 });
+
+    let second_bar = Pulse::whole(1);
+    place(&mut ctx, 0, 0, second_bar);
+    ctx.apply(&SetMeter {
+        meter: Some(Meter { count: 3, unit: 4 }),
+        selections: vec![0],
+    });
+    ctx.apply(&SetKey {
+        key: Some(Key { fifths: -1, mode: Mode::Major }),
+        selections: vec![0],
+    });
+    ctx.apply(&SetTempo {
+        tempo: Some(Tempo { bpm: 90 }),
+        selections: vec![0],
+    });
+
+    place(&mut ctx, 0, 0, Pulse::default());
+    append(&mut ctx, note(PitchName::C, Accidental::Natural, 5), NoteValue::new(4));
+    append(&mut ctx, note(PitchName::D, Accidental::Natural, 5), triplet(8));
+    append(&mut ctx, note(PitchName::E, Accidental::Natural, 5), triplet(8));
+    append(&mut ctx, note(PitchName::F, Accidental::Sharp, 5), triplet(8));
+    append(&mut ctx, note(PitchName::G, Accidental::Natural, 5), NoteValue::new(2));
+    append(&mut ctx, note(PitchName::A, Accidental::Natural, 5), NoteValue::dotted(2, 1));
+    place(&mut ctx, 0, 1, Pulse::default());
+    append(&mut ctx, note(PitchName::C, Accidental::Natural, 4), NoteValue::new(2));
+    append(&mut ctx, note(PitchName::B, Accidental::Flat, 3), NoteValue::new(2));
+
+    place(&mut ctx, 1, 0, Pulse::default());
+    append(&mut ctx, note(PitchName::C, Accidental::Natural, 4), NoteValue::new(1));
+    append(&mut ctx, note(PitchName::F, Accidental::Natural, 4), NoteValue::dotted(2, 1));
+    place(&mut ctx, 1, 0, Pulse::default());
+    ctx.apply(&AddPitch {
+        note: note(PitchName::E, Accidental::Natural, 4),
+        selections: vec![0],
+    });
+
+    place(&mut ctx, 2, 0, Pulse::default());
+    ctx.apply(&SetClef {
+        clef: Some(Clef::bass()),
+        selections: vec![0],
+    });
+    append(&mut ctx, note(PitchName::C, Accidental::Natural, 3), NoteValue::new(1));
+    place(&mut ctx, 2, 0, second_bar + Pulse::new(1, 4));
+    ctx.apply(&SetClef {
+        clef: Some(Clef::tenor()),
+        selections: vec![0],
+    });
+    append(&mut ctx, note(PitchName::B, Accidental::Flat, 3), NoteValue::new(2));
+    ctx.score
+}
+
+fn events(score: &Score) -> Vec<(u32, u32, Pulse, NoteValue, Vec<Note>)> {
+    (0..score.staff_count()).flat_map(|staff| score.events_on_staff(staff)).map(|e| (e.staff(), e.layer(), e.start(), e.duration(), e.notes().to_vec())).collect()
+}
+
+#[test]
+fn round_trips_through_import() {
+    let score = duet();
+    assert_eq!(events(&score).len(), 12);
+    let imported = Context::from_musicxml(&score.to_musicxml()).unwrap().score;
+    assert_eq!(events(&imported), events(&score));
+
+    let parts = |score: &Score| score.parts.iter().map(|p| (p.name.clone(), p.program, p.staves.len())).collect::<Vec<_>>();
+    assert_eq!(parts(&imported), parts(&score));
+    for pulse in [Pulse::default(), Pulse::whole(1), Pulse::new(5, 4), Pulse::new(3, 2)] {
+        assert_eq!(imported.meter_at(pulse), score.meter_at(pulse));
+        assert_eq!(imported.key_at(pulse), score.key_at(pulse));
+        assert_eq!(imported.tempo_at(pulse), score.tempo_at(pulse));
+        for staff in 0..score.staff_count() {
+            assert_eq!(imported.staff(staff).unwrap().clef_at(pulse), score.staff(staff).unwrap().clef_at(pulse));
+        }
+    }
+}
+
+#[test]
+fn waltz_round_trips_through_import() {
+    let score = waltz();
+    let imported = Context::from_musicxml(&score.to_musicxml()).unwrap().score;
+    // The note tied over the barline comes back whole
+    assert_eq!(events(&imported), events(&score));
+}
+
+// The lines of one measure of the first part, as exported
+fn measure(xml: &str, number: usize) -> &str {
+    let start = xml.find(&format!("<measure number=\"{}\">", number)).unwrap();
+    let start = xml[..start].rfind('\n').unwrap() + 1;
+    let end = start + xml[start..].find("</measure>").unwrap() + "</measure>".len();
+    &xml[start..end]
+}
+
+// A gap in a triplet is filled with a triplet rest, inside the bracket
+#[test]
+fn tuplets_are_bracketed_from_their_first_value_to_their_last() {
+    let mut ctx = Context::default();
+    append(&mut ctx, note(PitchName::C, Accidental::Natural, 5), NoteValue::new(4));
+    append(&mut ctx, note(PitchName::D, Accidental::Natural, 5), triplet(8));
+    append(&mut ctx, note(PitchName::E, Accidental::Natural, 5), triplet(8));
+    place(&mut ctx, 0, 0, Pulse::new(1, 2));
+    append(&mut ctx, note(PitchName::G, Accidental::Natural, 5), NoteValue::new(2));
+    let xml = ctx.score.to_musicxml();
+    assert_eq!(measure(&xml, 1), r#"    <measure number="1">
+      <attributes>
+        <divisions>3</divisions>
+        <key>
+          <fifths>0</fifths>
+          <mode>major</mode>
+        </key>
+        <time>
+          <beats>4</beats>
+          <beat-type>4</beat-type>
+        </time>
+        <clef>
+          <sign>G</sign>
+          <line>2</line>
+        </clef>
+      </attributes>
+      <sound tempo="120"/>
+      <note>
+        <pitch>
+          <step>C</step>
+          <octave>5</octave>
+        </pitch>
+        <duration>3</duration>
+        <voice>1</voice>
+        <type>quarter</type>
+      </note>
+      <note>
+        <pitch>
+          <step>D</step>
+          <octave>5</octave>
+        </pitch>
+        <duration>1</duration>
+        <voice>1</voice>
+        <type>eighth</type>
+        <time-modification>
+          <actual-notes>3</actual-notes>
+          <normal-notes>2</normal-notes>
+        </time-modification>
+        <beam number="1">begin</beam>
+        <notations>
+          <tuplet type="start"/>
+        </notations>
+      </note>
+      <note>
+        <pitch>
+          <step>E</step>
+          <octave>5</octave>
+        </pitch>
+        <duration>1</duration>
+        <voice>1</voice>
+        <type>eighth</type>
+        <time-modification>
+          <actual-notes>3</actual-notes>
+          <normal-notes>2</normal-notes>
+        </time-modification>
+        <beam number="1">end</beam>
+      </note>
+      <note>
+        <rest/>
+        <duration>1</duration>
+        <voice>1</voice>
+        <type>eighth</type>
+        <time-modification>
+          <actual-notes>3</actual-notes>
+          <normal-notes>2</normal-notes>
+        </time-modification>
+        <notations>
+          <tuplet type="stop"/>
+        </notations>
+      </note>
+      <note>
+        <pitch>
+          <step>G</step>
+          <octave>5</octave>
+        </pitch>
+        <duration>6</duration>
+        <voice>1</voice>
+        <type>half</type>
+      </note>
+    </measure>"#);
+    assert_eq!(events(&Context::from_musicxml(&xml).unwrap().score), events(&ctx.score));
+}
+
+// Each voice after the first and each staff after the first backs up to the
+// barline, and gaps before a voice's notes are skipped with a forward
+#[test]
+fn staves_and_voices_back_up_to_the_barline() {
+    let mut ctx = Context::default();
+    ctx.score.parts[0].staves.push(Staff::default());
+    append(&mut ctx, note(PitchName::C, Accidental::Natural, 5), NoteValue::new(1));
+    place(&mut ctx, 0, 1, Pulse::new(1, 2));
+    append(&mut ctx, note(PitchName::E, Accidental::Natural, 4), NoteValue::new(2));
+    place(&mut ctx, 1, 0, Pulse::default());
+    ctx.apply(&SetClef {
+        clef: Some(Clef::bass()),
+        selections: vec![0],
+    });
+    append(&mut ctx, note(PitchName::C, Accidental::Natural, 3), NoteValue::new(1));
+    let xml = ctx.score.to_musicxml();
+    assert_eq!(measure(&xml, 1), r#"    <measure number="1">
+      <attributes>
+        <divisions>1</divisions>
+        <key>
+          <fifths>0</fifths>
+          <mode>major</mode>
+        </key>
+        <time>
+          <beats>4</beats>
+          <beat-type>4</beat-type>
+        </time>
+        <staves>2</staves>
+        <clef number="1">
+          <sign>G</sign>
+          <line>2</line>
+        </clef>
+        <clef number="2">
+          <sign>F</sign>
+          <line>4</line>
+        </clef>
+      </attributes>
+      <sound tempo="120"/>
+      <note>
+        <pitch>
+          <step>C</step>
+          <octave>5</octave>
+        </pitch>
+        <duration>4</duration>
+        <voice>1</voice>
+        <type>whole</type>
+        <staff>1</staff>
+      </note>
+      <backup>
+        <duration>4</duration>
+      </backup>
+      <forward>
+        <duration>2</duration>
+        <voice>2</voice>
+        <staff>1</staff>
+      </forward>
+      <note>
+        <pitch>
+          <step>E</step>
+          <octave>4</octave>
+        </pitch>
+        <duration>2</duration>
+        <voice>2</voice>
+        <type>half</type>
+        <staff>1</staff>
+      </note>
+      <backup>
+        <duration>4</duration>
+      </backup>
+      <note>
+        <pitch>
+          <step>C</step>
+          <octave>3</octave>
+        </pitch>
+        <duration>4</duration>
+        <voice>5</voice>
+        <type>whole</type>
+        <staff>2</staff>
+      </note>
+    </measure>"#);
+    assert_eq!(events(&Context::from_musicxml(&xml).unwrap().score), events(&ctx.score));
+}
+
+// A clef change in the middle of a gap splits the rests around it
+#[test]
+fn clefs_change_within_a_measure() {
+    let mut ctx = Context::default();
+    append(&mut ctx, note(PitchName::C, Accidental::Natural, 4), NoteValue::new(4));
+    place(&mut ctx, 0, 0, Pulse::new(1, 2));
+    ctx.apply(&SetClef {
+        clef: Some(Clef::bass()),
+        selections: vec![0],
+    });
+    place(&mut ctx, 0, 0, Pulse::new(3, 4));
+    append(&mut ctx, note(PitchName::C, Accidental::Natural, 3), NoteValue::new(4));
+    let xml = ctx.score.to_musicxml();
+    assert_eq!(measure(&xml, 1), r#"    <measure number="1">
+      <attributes>
+        <divisions>1</divisions>
+        <key>
+          <fifths>0</fifths>
+          <mode>major</mode>
+        </key>
+        <time>
+          <beats>4</beats>
+          <beat-type>4</beat-type>
+        </time>
+        <clef>
+          <sign>G</sign>
+          <line>2</line>
+        </clef>
+      </attributes>
+      <sound tempo="120"/>
+      <note>
+        <pitch>
+          <step>C</step>
+          <octave>4</octave>
+        </pitch>
+        <duration>1</duration>
+        <voice>1</voice>
+        <type>quarter</type>
+      </note>
+      <note>
+        <rest/>
+        <duration>1</duration>
+        <voice>1</voice>
+        <type>quarter</type>
+      </note>
+      <attributes>
+        <clef>
+          <sign>F</sign>
+          <line>4</line>
+        </clef>
+      </attributes>
+      <note>
+        <rest/>
+        <duration>1</duration>
+        <voice>1</voice>
+        <type>quarter</type>
+      </note>
+      <note>
+        <pitch>
+          <step>C</step>
+          <octave>3</octave>
+        </pitch>
+        <duration>1</duration>
+        <voice>1</voice>
+        <type>quarter</type>
+      </note>
+    </measure>"#);
+    let imported = Context::from_musicxml(&xml).unwrap().score;
+    assert_eq!(events(&imported), events(&ctx.score));
+    assert_eq!(imported.staff(0).unwrap().clef_at(Pulse::new(1, 2)), Clef::bass());
+}
+
+fn import(measures: &str) -> Score {
+    let text = format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE score-partwise PUBLIC "-//Recordare//DTD MusicXML 4.0 Partwise//EN" "http://www.musicxml.org/dtds/partwise.dtd">
+<score-partwise version="4.0">
+  <part-list>
+    <score-part id="P1"><part-name>Flute</part-name></score-part>
+  </part-list>
+  <part id="P1">{}</part>
+</score-partwise>"#, measures);
+    Context::from_musicxml(&text).unwrap().score
+}
+
+// A pitched note with anything else the note needs after its duration
+fn pitched(step: &str, octave: u32, duration: u32, rest: &str) -> String {
+    format!("<note><pitch><step>{}</step><octave>{}</octave></pitch><duration>{}</duration>{}</note>", step, octave, duration, rest)
+}
+
+#[test]
+fn durations_follow_the_divisions() {
+    let triplet_eighth = "<type>eighth</type><time-modification><actual-notes>3</actual-notes><normal-notes>2</normal-notes></time-modification>";
+    let score = import(&format!(
+        r#"<measure number="1"><attributes><divisions>3</divisions></attributes>{}{}{}{}{}</measure>
+        <measure number="2"><attributes><divisions>8</divisions></attributes>{}{}</measure>"#,
+        pitched("C", 5, 3, "<type>quarter</type>"),
+        pitched("D", 5, 1, triplet_eighth),
+        pitched("E", 5, 1, triplet_eighth),
+        pitched("F", 5, 1, triplet_eighth),
+        pitched("G", 5, 6, "<type>half</type>"),
+        pitched("A", 5, 28, "<type>half</type><dot/><dot/>"),
+        // No type, so the length decides
+        pitched("B", 5, 4, ""),
+    ));
+    let c = Accidental::Natural;
+    assert_eq!(events(&score), vec![
+        (0, 0, Pulse::default(), NoteValue::new(4), vec![note(PitchName::C, c, 5)]),
+        (0, 0, Pulse::new(1, 4), triplet(8), vec![note(PitchName::D, c, 5)]),
+        (0, 0, Pulse::new(1, 3), triplet(8), vec![note(PitchName::E, c, 5)]),
+        (0, 0, Pulse::new(5, 12), triplet(8), vec![note(PitchName::F, c, 5)]),
+        (0, 0, Pulse::new(1, 2), NoteValue::new(2), vec![note(PitchName::G, c, 5)]),
+        (0, 0, Pulse::whole(1), NoteValue::dotted(2, 2), vec![note(PitchName::A, c, 5)]),
+        (0, 0, Pulse::new(15, 8), NoteValue::new(8), vec![note(PitchName::B, c, 5)]),
+    ]);
+    assert_eq!(score.parts[0].name, "Flute");
+}
+
+#[test]
+fn backup_and_forward_make_voices() {
+    let score = import(&format!(
+        r#"<measure number="1"><attributes><divisions>1</divisions></attributes>
+        {}<backup><duration>4</duration></backup>
+        <forward><duration>2</duration><voice>2</voice></forward>{}</measure>
+        <measure number="2">{}</measure>"#,
+        pitched("C", 5, 4, "<voice>1</voice><type>whole</type>"),
+        pitched("E", 4, 2, "<voice>2</voice><type>half</type>"),
+        pitched("D", 5, 4, "<voice>1</voice><type>whole</type>"),
+    ));
+    let c = Accidental::Natural;
+    assert_eq!(events(&score), vec![
+        (0, 0, Pulse::default(), NoteValue::new(1), vec![note(PitchName::C, c, 5)]),
+        (0, 0, Pulse::whole(1), NoteValue::new(1), vec![note(PitchName::D, c, 5)]),
+        (0, 1, Pulse::new(1, 2), NoteValue::new(2), vec![note(PitchName::E, c, 4)]),
+    ]);
+}
+
+#[test]
+fn chords_share_the_first_notes_start() {
+    let score = import(&format!(
+        r#"<measure number="1"><attributes><divisions>1</divisions></attributes>{}{}{}{}</measure>"#,
+        pitched("C", 4, 2, "<type>half</type>"),
+        "<note><chord/><pitch><step>E</step><alter>-1</alter><octave>4</octave></pitch><duration>2</duration><type>half</type></note>",
+        "<note><chord/><pitch><step>G</step><octave>4</octave></pitch><duration>2</duration><type>half</type></note>",
+        pitched("D", 4, 2, "<type>half</type>"),
+    ));
+    let c = Accidental::Natural;
+    assert_eq!(events(&score), vec![
+        (0, 0, Pulse::default(), NoteValue::new(2), vec![note(PitchName::C, c, 4), note(PitchName::E, Accidental::Flat, 4), note(PitchName::G, c, 4)]),
+        (0, 0, Pulse::new(1, 2), NoteValue::new(2), vec![note(PitchName::D, c, 4)]),
+    ]);
+}
+
+#[test]
+fn ties_are_joined_when_one_value_covers_them() {
+    let start = r#"<tie type="start"/><type>half</type>"#;
+    let score = import(&format!(
+        r#"<measure number="1"><attributes><divisions>2</divisions></attributes>
+        <note><rest/><duration>4</duration></note>{}</measure>
+        <measure number="2">{}{}</measure>"#,
+        pitched("A", 4, 4, start),
+        pitched("A", 4, 4, r#"<tie type="stop"/><tie type="start"/><type>half</type>"#),
+        pitched("A", 4, 1, r#"<tie type="stop"/><type>eighth</type>"#),
+    ));
+    let a = note(PitchName::A, Accidental::Natural, 4);
+    // The whole note can't take another eighth, so that stays tied to it
+    assert_eq!(events(&score), vec![
+        (0, 0, Pulse::new(1, 2), NoteValue::new(1), vec![a]),
+        (0, 0, Pulse::new(3, 2), NoteValue::new(8), vec![a]),
+    ]);
+}
+
+#[test]
+fn tied_chords_are_joined_once_every_note_is_in() {
+    let half = |tie: &str| format!("{}<type>half</type>", tie);
+    let chord = |step: &str, tie: &str| format!("<note><chord/><pitch><step>{}</step><octave>4</octave></pitch><duration>2</duration>{}</note>", step, half(tie));
+    let (start, stop) = (r#"<tie type="start"/>"#, r#"<tie type="stop"/>"#);
+    let score = import(&format!(
+        r#"<measure number="1"><attributes><divisions>1</divisions></attributes>{}{}{}{}</measure>
+        <measure number="2">{}{}{}{}</measure>"#,
+        pitched("C", 4, 2, &half(start)),
+        chord("E", start),
+        pitched("C", 4, 2, &half(stop)),
+        chord("E", stop),
+        pitched("C", 4, 2, &half(start)),
+        chord("E", ""),
+        pitched("C", 4, 2, &half(stop)),
+        chord("E", ""),
+    ));
+    let c_e = vec![note(PitchName::C, Accidental::Natural, 4), note(PitchName::E, Accidental::Natural, 4)];
+    // Only the C of the second bar is tied, which a chord can't hold
+    assert_eq!(events(&score), vec![
+        (0, 0, Pulse::default(), NoteValue::new(1), c_e.clone()),
+        (0, 0, Pulse::whole(1), NoteValue::new(2), c_e.clone()),
+        (0, 0, Pulse::new(3, 2), NoteValue::new(2), c_e),
+    ]);
+}
+
+#[test]
+fn bad_files_are_errors() {
+    assert!(Context::from_musicxml("<score-partwise><part").is_err());
+    assert!(Context::from_musicxml("<score-timewise/>").is_err());
+    assert!(Context::from_musicxml("<html/>").is_err());
+    assert!(Context::from_mxl(b"not a zip").is_err());
+}
+
@@ -0,0 +1,37 @@
+use operations::*;
+
+mod common;
+use common::*;
+
+// A context as the editor saved it before rational pulses, note values and chords
+const OLD_CONTEXT: &str = r#"{
+  "score": {"events": [
+    {"event_id": 0, "note": {"pitch": {"class": "C", "accidental": "Natural"}, "octave": 4}, "duration": 1, "start": 0},
+    {"event_id": 1, "note": {"pitch": {"class": "F", "accidental": "Sharp"}, "octave": 4}, "duration": 2, "start": 1},
+    {"event_id": 2, "note": {"pitch": {"class": "G", "accidental": "Natural"}, "octave": 4}, "duration": 4, "start": 3}
+  ]},
+  "selections": [{"begin": 3, "end": 3}],
+  "next_id": 3
+}"#;
+
+#[test]
+fn old_contexts_still_open() {
+    let ctx: Context = serde_json::from_str(OLD_CONTEXT).unwrap();
+    let events: Vec<(Pulse, Vec<Note>, NoteValue)> = ctx.score.events_on_layer(0, 0).map(|e| (e.start(), e.notes().to_vec(), e.duration())).collect();
+    assert_eq!(events, vec![
+        (Pulse::default(), vec![note(PitchName::C, Accidental::Natural, 4)], NoteValue::new(4)),
+        (Pulse::new(1, 4), vec![note(PitchName::F, Accidental::Sharp, 4)], NoteValue::new(2)),
+        (Pulse::new(3, 4), vec![note(PitchName::G, Accidental::Natural, 4)], NoteValue::new(1)),
+    ]);
+    assert_eq!(ctx.selections.0[0].begin.0, Pulse::new(3, 4));
+}
+
+#[test]
+fn contexts_round_trip_through_json() {
+    let mut ctx = Context::default();
+    append(&mut ctx, note(PitchName::D, Accidental::Flat, 5), triplet(8));
+    append(&mut ctx, note(PitchName::E, Accidental::Natural, 5), NoteValue::dotted(4, 1));
+    let saved = serde_json::to_string(&ctx).unwrap();
+    let opened: Context = serde_json::from_str(&saved).unwrap();
+    assert_eq!(opened.score, ctx.score);
+}
@@ -1,11 +1,9 @@
 use std::{
-    io::{stdout, Write},
-    process::Command,
     fs::File,
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 use simplelog::*;
-use strong_xml::XmlWrite;
+use strong_xml::{XmlRead, XmlWrite};
 
 use crossterm::{
     event::{read, Event, KeyCode, KeyEvent, KeyModifiers},
@@ -15,6 +13,12 @@ use crossterm::{
 
 use operations::*;
 
+mod render;
+
+// Named in the encodingDesc of the MEI the editor exports
+const APPLICATION: &str = env!("CARGO_PKG_NAME");
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
 trait InputState {
     fn handle_key(self: Box<Self>, app: &mut App, c: KeyCode, m: KeyModifiers) -> Box<dyn InputState>;
 }
@@ -22,22 +26,43 @@ trait InputState {
 struct App {
     ctx: Context,
     path: PathBuf,
-    note_duration: Pulse,
+    note_duration: NoteValue,
     note_octave: Octave,
     should_stop: bool,
     verovio: verovio::Verovio,
     view_dirty: bool,
+    // Pages to move by, passed on with the next render
+    page_delta: i32,
 }
-impl Default for App {
-    fn default() -> Self {
-        Self {
+impl App {
+    fn new() -> verovio::Result<Self> {
+        Ok(Self {
             ctx: Context::default(),
             path: "/tmp/score.json".into(),
-            note_duration: Pulse(4),
+            note_duration: NoteValue::new(4),
             note_octave: Octave(4),
             should_stop: false,
-            verovio: verovio::Verovio::new("/usr/local/share/verovio/"),
+            verovio: verovio::Verovio::new("/usr/local/share/verovio/")?,
             view_dirty: true,
+            page_delta: 0,
+        })
+    }
+}
+
+// Read from music_editor.json in the working directory, next to the log
+#[derive(Default, serde::Deserialize)]
+#[serde(default)]
+struct Settings {
+    verovio: verovio::VerovioOptions,
+}
+impl Settings {
+    fn load(path: &str) -> Self {
+        match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                log::warn!("Ignoring {}: {}", path, e);
+                Self::default()
+            }),
+            Err(_) => Self::default(),
         }
     }
 }
@@ -45,91 +70,159 @@ impl Default for App {
 struct Idle;
 impl InputState for Idle {
     fn handle_key(self: Box<Self>, app: &mut App, c: KeyCode, m: KeyModifiers) -> Box<dyn InputState> {
+        if c == KeyCode::Char(':') {
+            return Box::new(CommandLine::default());
+        }
+        if c == KeyCode::Char('u') {
+            if app.ctx.undo() {
+                app.view_dirty = true;
+            }
+            return self;
+        }
+        if c == KeyCode::Char('U') {
+            if app.ctx.redo() {
+                app.view_dirty = true;
+            }
+            return self;
+        }
+        // However many operations a key applies, u takes them all back at once
+        app.ctx.begin_group();
         if c == KeyCode::Esc || c == KeyCode::Char('q') {
             app.should_stop = true;
         } else if c == KeyCode::Left {
             if m.contains(KeyModifiers::SHIFT) {
-                MoveSelectionsEnd {
+                app.ctx.apply(&MoveSelectionsEnd {
                     delta: Duration::Event(-1),
                     selections: vec![0],
-                }.apply(&mut app.ctx);
+                });
             } else if m.contains(KeyModifiers::CONTROL) {
-                MoveSelectionsContents {
+                app.ctx.apply(&MoveSelectionsContents {
                     delta: Duration::Event(-1),
                     selections: vec![0],
-                }.apply(&mut app.ctx);
+                });
             } else {
-                MoveSelections {
+                app.ctx.apply(&MoveSelections {
                     delta: Duration::Event(-1),
                     selections: vec![0],
-                }.apply(&mut app.ctx);
+                });
             }
             app.view_dirty = true;
         } else if c == KeyCode::Right {
             if m.contains(KeyModifiers::SHIFT) {
-                MoveSelectionsEnd {
+                app.ctx.apply(&MoveSelectionsEnd {
                     delta: Duration::Event(1),
                     selections: vec![0],
-                }.apply(&mut app.ctx);
+                });
             } else if m.contains(KeyModifiers::CONTROL) {
-                MoveSelectionsContents {
+                app.ctx.apply(&MoveSelectionsContents {
                     delta: Duration::Event(1),
                     selections: vec![0],
-                }.apply(&mut app.ctx);
+                });
             } else {
-                MoveSelections {
+                app.ctx.apply(&MoveSelections {
                     delta: Duration::Event(1),
                     selections: vec![0],
-                }.apply(&mut app.ctx);
+                });
             }
             app.view_dirty = true;
         } else if c == KeyCode::Up {
             if m.contains(KeyModifiers::CONTROL) {
-                TransposeSelectionsContents {
-                    semitones: 12,
+                app.ctx.apply(&TransposeSelectionsContents {
+                    transposition: Transposition::Interval(Interval::octave()),
+                    selections: vec![0],
+                });
+                app.view_dirty = true;
+            } else if m.contains(KeyModifiers::ALT) {
+                app.ctx.apply(&TransposeSelectionsContents {
+                    transposition: Transposition::Diatonic(1),
                     selections: vec![0],
-                }.apply(&mut app.ctx);
+                });
                 app.view_dirty = true;
             } else if m.contains(KeyModifiers::SHIFT) {
                 app.note_octave.0 += 1;
             } else {
-                TransposeSelectionsContents {
-                    semitones: 1,
+                app.ctx.apply(&TransposeSelectionsContents {
+                    transposition: Transposition::Chromatic(1),
                     selections: vec![0],
-                }.apply(&mut app.ctx);
+                });
                 app.view_dirty = true;
             }
         } else if c == KeyCode::Down {
             if m.contains(KeyModifiers::CONTROL) {
-                TransposeSelectionsContents {
-                    semitones: -12,
+                app.ctx.apply(&TransposeSelectionsContents {
+                    transposition: Transposition::Interval(Interval::octave().inverted()),
                     selections: vec![0],
-                }.apply(&mut app.ctx);
+                });
+                app.view_dirty = true;
+            } else if m.contains(KeyModifiers::ALT) {
+                app.ctx.apply(&TransposeSelectionsContents {
+                    transposition: Transposition::Diatonic(-1),
+                    selections: vec![0],
+                });
                 app.view_dirty = true;
             } else if m.contains(KeyModifiers::SHIFT) {
                 app.note_octave.0 -= 1;
             } else {
-                TransposeSelectionsContents {
-                    semitones: -1,
+                app.ctx.apply(&TransposeSelectionsContents {
+                    transposition: Transposition::Chromatic(-1),
                     selections: vec![0],
-                }.apply(&mut app.ctx);
+                });
                 app.view_dirty = true;
             }
+        } else if c == KeyCode::PageDown {
+            app.page_delta += 1;
+            app.view_dirty = true;
+        } else if c == KeyCode::PageUp {
+            app.page_delta -= 1;
+            app.view_dirty = true;
         } else if c == KeyCode::Backspace {
-            DeleteSelections {
+            app.ctx.apply(&DeleteSelections {
                 selections: vec![0],
-            }.apply(&mut app.ctx);
+            });
+            app.view_dirty = true;
+        } else if let KeyCode::Char('j') = c {
+            app.ctx.apply(&MoveSelectionsStaff {
+                delta: 1,
+                selections: vec![0],
+            });
+            app.view_dirty = true;
+        } else if let KeyCode::Char('k') = c {
+            app.ctx.apply(&MoveSelectionsStaff {
+                delta: -1,
+                selections: vec![0],
+            });
+            app.view_dirty = true;
+        } else if let KeyCode::Char('v') = c {
+            let layer = (app.ctx.selections.0[0].layer + 1) % 4;
+            app.ctx.apply(&SetSelectionsLayer {
+                layer,
+                selections: vec![0],
+            });
+            app.view_dirty = true;
+        } else if let KeyCode::Char('S') = c {
+            app.ctx.apply(&AddStaff {
+                selections: vec![0],
+            });
+            app.view_dirty = true;
+        } else if let KeyCode::Char('P') = c {
+            app.ctx.apply(&AddPart {
+                name: String::new(),
+                staves: 1,
+            });
             app.view_dirty = true;
         } else if let KeyCode::Char('w') = c {
             let data = serde_json::to_string(&app.ctx).unwrap();
-            std::fs::write(&app.path, data);
+            if let Err(e) = std::fs::write(&app.path, data) {
+                log::warn!("Can't save {}: {}", app.path.display(), e);
+            }
         } else if let KeyCode::Char('m') = c {
-            let mei = app.ctx.score.to_mei();
-            let mei_xml = mei.to_string().unwrap();
-            let midi = app.verovio.render_to_midi(&mei_xml);
-            std::fs::write("/tmp/score.midi", midi);
+            let midi = app.ctx.score.to_midi();
+            let path = app.path.with_extension("mid");
+            if let Err(e) = std::fs::write(&path, midi) {
+                log::warn!("Can't write {}: {}", path.display(), e);
+            }
         } else if let KeyCode::Char(c) = c {
-            if let Some(p) = match c {
+            if let Some(p) = match c.to_ascii_lowercase() {
                 'a' => Some(PitchName::A),
                 'b' => Some(PitchName::B),
                 'c' => Some(PitchName::C),
@@ -139,32 +232,463 @@ impl InputState for Idle {
                 'g' => Some(PitchName::G),
                 _ => None,
             } {
-                let operation = AppendNote {
-                    note: Note {
-                        pitch: Pitch {
-                            class: p,
-                            ..Default::default()
-                        },
-                        octave: app.note_octave,
+                // Letters are entered as the key signature would have them
+                let key = app.ctx.score.key_at(app.ctx.selections.0[0].end.0);
+                let note = Note::new(
+                    Pitch {
+                        class: p,
+                        accidental: Accidental::from_alteration(key.alteration(p)).unwrap_or(Accidental::Natural),
                     },
-                    duration: app.note_duration,
-                    selections: None,
-                };
-                operation.apply(&mut app.ctx);
+                    app.note_octave,
+                );
+                if m.contains(KeyModifiers::ALT) {
+                    app.ctx.apply(&RemovePitch {
+                        class: p,
+                        octave: app.note_octave,
+                        selections: vec![0],
+                    });
+                } else if c.is_ascii_uppercase() {
+                    app.ctx.apply(&AddPitch {
+                        note,
+                        selections: vec![0],
+                    });
+                } else {
+                    let operation = AppendNote {
+                        note,
+                        duration: app.note_duration,
+                        selections: None,
+                    };
+                    app.ctx.apply(&operation);
+                }
                 app.view_dirty = true;
-            } else if let Some(d) = match c {
-                '1' => Some(Pulse(4)),
-                '2' => Some(Pulse(2)),
-                '4' => Some(Pulse(1)),
+            } else if let Some(base) = match c {
+                '1' => Some(1),
+                '2' => Some(2),
+                '4' => Some(4),
+                '8' => Some(8),
+                '6' => Some(16),
+                '3' => Some(32),
                 _ => None,
             } {
-                app.note_duration = d;
+                app.note_duration.base = base;
+            } else if c == '[' {
+                app.note_duration = app.note_duration.longer();
+            } else if c == ']' {
+                app.note_duration = app.note_duration.shorter();
+            } else if c == '.' {
+                app.note_duration.dots = (app.note_duration.dots + 1) % 3;
+            } else if c == '#' {
+                app.ctx.apply(&CycleSelectionsAccidental {
+                    selections: vec![0],
+                });
+                app.view_dirty = true;
+            } else if c == '<' || c == '>' {
+                app.ctx.apply(&TransposeSelectionsContents {
+                    transposition: Transposition::QuarterTones(if c == '>' { 1 } else { -1 }),
+                    selections: vec![0],
+                });
+                app.view_dirty = true;
+            } else if c == '(' {
+                app.ctx.apply(&ToggleSelectionsCautionary {
+                    selections: vec![0],
+                });
+                app.view_dirty = true;
+            } else if c == 't' {
+                app.note_duration.tuplet = match app.note_duration.tuplet {
+                    Some(_) => None,
+                    None => Some(Tuplet::triplet()),
+                };
             }
         }
+        app.ctx.end_group();
         self
     }
 }
 
+#[derive(Default)]
+struct CommandLine {
+    text: String,
+}
+impl InputState for CommandLine {
+    fn handle_key(mut self: Box<Self>, app: &mut App, c: KeyCode, _m: KeyModifiers) -> Box<dyn InputState> {
+        match c {
+            KeyCode::Esc => Box::new(Idle),
+            KeyCode::Enter => {
+                // Pasting ABC takes over the keyboard until the tune is in
+                if self.text.trim() == "abc" {
+                    return Box::new(AbcPaste::default());
+                }
+                app.ctx.begin_group();
+                run_command(app, &self.text);
+                app.ctx.end_group();
+                Box::new(Idle)
+            }
+            KeyCode::Backspace => {
+                if self.text.pop().is_none() {
+                    Box::new(Idle)
+                } else {
+                    self
+                }
+            }
+            KeyCode::Char(c) => {
+                self.text.push(c);
+                self
+            }
+            _ => self,
+        }
+    }
+}
+
+// Collects a pasted ABC tune a line at a time. Tunes end at a blank line, so
+// Enter on an empty line reads it in, while Esc gives up on it.
+#[derive(Default)]
+struct AbcPaste {
+    text: String,
+    line: String,
+}
+impl InputState for AbcPaste {
+    fn handle_key(mut self: Box<Self>, app: &mut App, c: KeyCode, _m: KeyModifiers) -> Box<dyn InputState> {
+        match c {
+            KeyCode::Esc => Box::new(Idle),
+            KeyCode::Enter if self.line.trim().is_empty() => {
+                match Score::from_abc(&self.text) {
+                    Ok(score) => {
+                        app.ctx = Context::from_score(score);
+                        app.view_dirty = true;
+                    }
+                    Err(e) => log::warn!("{}", e),
+                }
+                Box::new(Idle)
+            }
+            KeyCode::Enter => {
+                self.text.push_str(&self.line);
+                self.text.push('\n');
+                self.line.clear();
+                self
+            }
+            KeyCode::Backspace => {
+                self.line.pop();
+                self
+            }
+            KeyCode::Tab => {
+                self.line.push(' ');
+                self
+            }
+            KeyCode::Char(c) => {
+                self.line.push(c);
+                self
+            }
+            _ => self,
+        }
+    }
+}
+
+fn run_command(app: &mut App, command: &str) {
+    let mut words = command.split_whitespace();
+    match words.next() {
+        Some("meter") => {
+            let meter = match words.next() {
+                Some("none") => None,
+                Some(meter) => match meter.parse::<Meter>() {
+                    Ok(meter) => Some(meter),
+                    Err(e) => {
+                        log::warn!("{}", e);
+                        return;
+                    }
+                },
+                None => {
+                    log::warn!("Usage: meter <count>/<unit>|none");
+                    return;
+                }
+            };
+            app.ctx.apply(&SetMeter {
+                meter,
+                selections: vec![0],
+            });
+            app.view_dirty = true;
+        }
+        Some("transpose") => {
+            let interval = match words.next().map(|i| i.parse::<Interval>()) {
+                Some(Ok(interval)) => interval,
+                Some(Err(e)) => {
+                    log::warn!("{}", e);
+                    return;
+                }
+                None => {
+                    log::warn!("Usage: transpose [-]<interval>, like m6 or -P4");
+                    return;
+                }
+            };
+            app.ctx.apply(&TransposeSelectionsContents {
+                transposition: Transposition::Interval(interval),
+                selections: vec![0],
+            });
+            app.view_dirty = true;
+        }
+        // Notes outside the key stay outside it, so B flat in C major steps up to C flat
+        Some("step") => {
+            let steps = match words.next().map(|n| n.parse::<i32>()) {
+                Some(Ok(steps)) => steps,
+                _ => {
+                    log::warn!("Usage: step <scale steps> (notes outside the key stay as far outside it)");
+                    return;
+                }
+            };
+            app.ctx.apply(&TransposeSelectionsContents {
+                transposition: Transposition::Diatonic(steps),
+                selections: vec![0],
+            });
+            app.view_dirty = true;
+        }
+        Some("detune") => {
+            let cents = match words.next().map(|n| n.parse::<i32>()) {
+                Some(Ok(cents)) => cents,
+                _ => {
+                    log::warn!("Usage: detune <cents>");
+                    return;
+                }
+            };
+            app.ctx.apply(&SetSelectionsDetune {
+                cents,
+                selections: vec![0],
+            });
+            app.view_dirty = true;
+        }
+        Some("tempo") => {
+            let tempo = match words.next() {
+                Some("none") => None,
+                Some(tempo) => match tempo.parse::<Tempo>() {
+                    Ok(tempo) => Some(tempo),
+                    Err(e) => {
+                        log::warn!("{}", e);
+                        return;
+                    }
+                },
+                None => {
+                    log::warn!("Usage: tempo <bpm>|none");
+                    return;
+                }
+            };
+            app.ctx.apply(&SetTempo {
+                tempo,
+                selections: vec![0],
+            });
+            app.view_dirty = true;
+        }
+        Some("dynamic") => {
+            let dynamic = match words.next() {
+                Some("none") => None,
+                Some(dynamic) => match dynamic.parse::<Dynamic>() {
+                    Ok(dynamic) => Some(dynamic),
+                    Err(e) => {
+                        log::warn!("{}", e);
+                        return;
+                    }
+                },
+                None => {
+                    log::warn!("Usage: dynamic <ppp..fff>|none");
+                    return;
+                }
+            };
+            app.ctx.apply(&SetDynamic {
+                dynamic,
+                selections: vec![0],
+            });
+            app.view_dirty = true;
+        }
+        Some(field @ ("title" | "composer" | "lyricist" | "arranger")) => {
+            // The rest of the line is the text, nothing clears the field
+            let text = words.collect::<Vec<_>>().join(" ");
+            let mut metadata = app.ctx.score.metadata.clone();
+            match field {
+                "title" => metadata.title = text,
+                "composer" => metadata.composer = text,
+                "lyricist" => metadata.lyricist = text,
+                _ => metadata.arranger = text,
+            }
+            app.ctx.apply(&SetMetadata { metadata });
+            app.view_dirty = true;
+        }
+        Some("program") => {
+            let program = match words.next().map(|n| n.parse::<u8>()) {
+                Some(Ok(program)) if program < 128 => program,
+                _ => {
+                    log::warn!("Usage: program <0-127>");
+                    return;
+                }
+            };
+            app.ctx.apply(&SetPartProgram {
+                program,
+                selections: vec![0],
+            });
+        }
+        Some("export") => {
+            // The format follows the extension, MusicXML if there isn't one.
+            // LilyPond files can be followed by "absolute" or "relative".
+            let path = match words.next() {
+                Some(path) => PathBuf::from(path),
+                None => app.path.with_extension("musicxml"),
+            };
+            let data = match path.extension().and_then(|e| e.to_str()).unwrap_or("") {
+                "mid" | "midi" => app.ctx.score.to_midi(),
+                "mei" => match app.ctx.score.to_mei_from(APPLICATION, VERSION).to_string() {
+                    Ok(mei) => mei.into_bytes(),
+                    Err(e) => {
+                        log::warn!("Couldn't write the MEI: {}", e);
+                        return;
+                    }
+                },
+                "musicxml" | "xml" | "" => app.ctx.score.to_musicxml().into_bytes(),
+                "abc" => app.ctx.score.to_abc().into_bytes(),
+                // Humdrum goes through Verovio's own converter
+                "krn" => {
+                    let humdrum = app.ctx.score.to_mei_from(APPLICATION, VERSION).to_string().map_err(|e| e.to_string())
+                        .and_then(|mei| app.verovio.to_humdrum(&mei).map_err(|e| e.to_string()));
+                    match humdrum {
+                        Ok(humdrum) => humdrum.into_bytes(),
+                        Err(e) => {
+                            log::warn!("{}", e);
+                            return;
+                        }
+                    }
+                }
+                // Relative pitches unless asked otherwise, as they're easier to edit by hand
+                "ly" => {
+                    let mode = match words.next() {
+                        Some("absolute") => PitchMode::Absolute,
+                        _ => PitchMode::Relative,
+                    };
+                    app.ctx.score.to_lilypond(mode).into_bytes()
+                }
+                other => {
+                    log::warn!("Don't know how to export .{} files", other);
+                    return;
+                }
+            };
+            if let Err(e) = std::fs::write(&path, data) {
+                log::warn!("Couldn't write {}: {}", path.display(), e);
+            }
+        }
+        Some("key") => {
+            let key = match words.next() {
+                Some("none") => None,
+                Some(tonic) => {
+                    let name: Vec<&str> = std::iter::once(tonic).chain(words).collect();
+                    match name.join(" ").parse::<Key>() {
+                        Ok(key) => Some(key),
+                        Err(e) => {
+                            log::warn!("{}", e);
+                            return;
+                        }
+                    }
+                }
+                None => {
+                    log::warn!("Usage: key <tonic> [major|minor]|none");
+                    return;
+                }
+            };
+            app.ctx.apply(&SetKey {
+                key,
+                selections: vec![0],
+            });
+            app.view_dirty = true;
+        }
+        Some("clef") => {
+            let clef = match words.next() {
+                Some("none") => None,
+                Some(clef) => match clef.parse::<Clef>() {
+                    Ok(clef) => Some(clef),
+                    Err(e) => {
+                        log::warn!("{}", e);
+                        return;
+                    }
+                },
+                None => {
+                    log::warn!("Usage: clef <treble|bass|alto|tenor|...>[8va|8vb]|none");
+                    return;
+                }
+            };
+            app.ctx.apply(&SetClef {
+                clef,
+                selections: vec![0],
+            });
+            app.view_dirty = true;
+        }
+        Some(other) => log::warn!("Unknown command {}", other),
+        None => (),
+    }
+}
+
+// The first selected note, or the one just before the cursor if nothing is selected
+fn cursor_event(ctx: &Context) -> Option<u32> {
+    let selection = &ctx.selections.0[0];
+    ctx.events_in_selection(0).next()
+        .or_else(|| ctx.score.events_on_layer(selection.staff, selection.layer).filter(|e| e.start() < selection.begin.0).last())
+        .map(|e| e.id())
+}
+
+// Only the measures which have changed are sent to be laid out again
+fn render_request(app: &mut App) -> render::Request {
+    let update = match app.ctx.take_dirty() {
+        Dirty::All => Some(render::Update::All(Box::new(app.ctx.score.to_mei()))),
+        Dirty::Measures(measures) if measures.is_empty() => None,
+        Dirty::Measures(mut measures) => {
+            log::debug!("Laying out measures {:?} again", measures);
+            // The measure after a change can gain or lose a cautionary accidental
+            let next: Vec<usize> = measures.iter().map(|i| i + 1).collect();
+            measures.extend(next);
+            Some(render::Update::Measures {
+                count: app.ctx.score.measures().len(),
+                measures: app.ctx.score.measures_to_mei(&measures),
+            })
+        }
+    };
+    app.view_dirty = false;
+    render::Request {
+        update,
+        selected: app.ctx.events_in_selection(0).map(|e| e.id()).collect(),
+        cursor: cursor_event(&app.ctx),
+        page_delta: std::mem::take(&mut app.page_delta),
+    }
+}
+
+// The render thread logs its own errors, so it's only gone if something went
+// badly wrong, and then editing carries on without a view
+fn draw(renderer: &std::sync::mpsc::Sender<render::Request>, app: &mut App) {
+    if renderer.send(render_request(app)).is_err() {
+        log::warn!("The renderer has stopped");
+    }
+}
+
+// Imported files are saved next to the original rather than over it
+fn open(app: &mut App, path: &Path) -> std::result::Result<(), String> {
+    let data = std::fs::read(path).map_err(|e| e.to_string())?;
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    app.ctx = match extension {
+        "mid" | "midi" => Context::from_midi(&data, Pulse::new(1, 16))?,
+        "musicxml" | "xml" => Context::from_musicxml(&String::from_utf8_lossy(&data))?,
+        "mxl" => Context::from_mxl(&data)?,
+        "abc" => Context::from_score(Score::from_abc(&String::from_utf8_lossy(&data))?),
+        "krn" => {
+            let mei = app.verovio.load_humdrum(&String::from_utf8_lossy(&data)).map_err(|e| e.to_string())?;
+            let mei = ir::Mei::from_str(&mei).map_err(|e| format!("Bad MEI from Verovio: {}", e))?;
+            Context::from_score(Score::from_mei(&mei)?)
+        }
+        "mei" => {
+            let text = String::from_utf8_lossy(&data);
+            let mei = ir::Mei::from_str(&text).map_err(|e| format!("Bad MEI: {}", e))?;
+            Context::from_score(Score::from_mei(&mei)?)
+        }
+        _ => {
+            app.ctx = serde_json::from_slice(&data).map_err(|e| e.to_string())?;
+            app.path = path.to_path_buf();
+            return Ok(());
+        }
+    };
+    app.path = path.with_extension("json");
+    Ok(())
+}
+
 fn main() -> Result<()> {
     CombinedLogger::init(
     vec![
@@ -173,78 +697,52 @@ fn main() -> Result<()> {
     ).unwrap();
 
 
-    enable_raw_mode()?;
-    let mut state:Box<dyn InputState> = Box::new(Idle);
-    let mut app = App::default();
+    let mut app = match App::new() {
+        Ok(app) => app,
+        Err(e) => {
+            eprintln!("Couldn't start Verovio: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let settings = Settings::load("music_editor.json");
     if let Some(path) = std::env::args().nth(1) {
-        let data = std::fs::read(&path).unwrap();
-        let ctx: Context = serde_json::from_slice(&data).unwrap();
-        app.path = path.into();
-        app.ctx = ctx;
+        let path = PathBuf::from(path);
+        // Starting on an empty score instead would have it saved somewhere unasked
+        if let Err(e) = open(&mut app, &path) {
+            eprintln!("Couldn't open {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
     }
 
-    let mei = app.ctx.score.to_mei();
-    let mei_xml = mei.to_string().unwrap();
-    let svg = app.verovio.render_data(&mei_xml);
-    let package =  sxd_document::parser::parse(&svg).unwrap();
-    let mut context = sxd_xpath::Context::new();
-    context.set_namespace("svg", "http://www.w3.org/2000/svg");
-    let factory = sxd_xpath::Factory::new();
-    let doc = package.as_document();
-    let mut f = std::fs::File::create("/tmp/test.svg").unwrap();
-    sxd_document::writer::format_document(&doc, &mut f).unwrap();
-    app.view_dirty = false;
+    enable_raw_mode()?;
+    let mut state:Box<dyn InputState> = Box::new(Idle);
 
-    let mut viewer = Command::new("/usr/bin/imv")
-        .arg("-b")
-        .arg("ffffff")
-        .arg("/tmp/test.svg")
-        .spawn()
-        .unwrap();
+    // Drawing happens on its own thread so typing isn't held up by layout
+    let (renderer, requests) = std::sync::mpsc::channel();
+    let options = settings.verovio;
+    let render_thread = std::thread::spawn(move || render::run(options, requests));
+    draw(&renderer, &mut app);
 
-    loop {
-        let event = read()?;
+    let result = loop {
+        let event = match read() {
+            Ok(event) => event,
+            Err(e) => break Err(e),
+        };
         if let Event::Key(KeyEvent { code: k, modifiers: m}) = event {
             state = state.handle_key(&mut app, k, m);
         }
-        log::debug!("{:?}", app.ctx);
+        log::debug!("{:?} {:?}", app.ctx.selections, app.ctx.history());
         if app.view_dirty {
-            let mei = app.ctx.score.to_mei();
-            let mei_xml = mei.to_string().unwrap();
-            let svg = app.verovio.render_data(&mei_xml);
-            let package =  sxd_document::parser::parse(&svg).unwrap();
-            let doc = package.as_document();
-            for e in app.ctx.events_in_selection(0) {
-                let xpath = factory.build(&format!("//svg:g[@id='note_{}']/svg:g/svg:use", e.id())).unwrap().unwrap();
-                let value = xpath.evaluate(&context, doc.root()).unwrap();
-
-                if let sxd_xpath::Value::Nodeset(ns) = value {
-                    for node in ns {
-                        if let sxd_xpath::nodeset::Node::Element(e) = node {
-                            e.set_attribute_value("fill", "red");
-                        }
-                    }
-                }
-
-
-            }
-            let mut f = std::fs::File::create("/tmp/test.svg").unwrap();
-            sxd_document::writer::format_document(&doc, &mut f).unwrap();
-            Command::new("/usr/bin/imv-msg")
-                .arg(&format!("{}", viewer.id()))
-                .arg("close")
-                .status();
-            Command::new("/usr/bin/imv-msg")
-                .arg(&format!("{}", viewer.id()))
-                .arg("open")
-                .arg("/tmp/test.svg")
-                .status();
-            app.view_dirty = false;
+            draw(&renderer, &mut app);
         }
         if app.should_stop {
-            viewer.kill();
-            break
+            break Ok(());
         }
+    };
+    drop(renderer);
+    if render_thread.join().is_err() {
+        log::warn!("The renderer panicked");
     }
+    result?;
     disable_raw_mode()
 }
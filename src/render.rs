@@ -0,0 +1,373 @@
+use std::{
+    process::{Child, Command},
+    sync::mpsc::Receiver,
+};
+
+use strong_xml::XmlWrite;
+use verovio::{Verovio, VerovioOptions};
+
+// The measures written again since the last update, by index, along with any
+// scoreDef in front of them
+pub type Measures = Vec<(usize, Option<ir::ScoreDef>, ir::Measure)>;
+
+// How the score has changed since it was last sent
+pub enum Update {
+    // The whole score, loaded again from scratch
+    All(Box<ir::Mei>),
+    // Only the dirty measures, and how many measures the score has now
+    Measures { count: usize, measures: Measures },
+}
+
+impl Update {
+    fn merge(self, next: Update) -> Update {
+        match (self, next) {
+            (_, Update::All(mei)) => Update::All(mei),
+            (Update::All(mut mei), Update::Measures { count, measures }) => {
+                splice(&mut mei, count, measures);
+                Update::All(mei)
+            }
+            (Update::Measures { measures: mut earlier, .. }, Update::Measures { count, measures }) => {
+                earlier.retain(|(i, _, _)| *i < count && measures.iter().all(|(j, _, _)| i != j));
+                earlier.extend(measures);
+                earlier.sort_by_key(|(i, _, _)| *i);
+                Update::Measures { count, measures: earlier }
+            }
+        }
+    }
+}
+
+// What the editor wants on screen. Requests which pile up while a page is being
+// drawn are merged, so a burst of keys only lays the score out once.
+pub struct Request {
+    // None when the score hasn't changed since the last request
+    pub update: Option<Update>,
+    pub selected: Vec<u32>,
+    // The note to keep in view
+    pub cursor: Option<u32>,
+    // From PageDown and PageUp
+    pub page_delta: i32,
+}
+
+impl Request {
+    fn merge(self, next: Request) -> Request {
+        let update = match (self.update, next.update) {
+            (Some(update), Some(next)) => Some(update.merge(next)),
+            (update, next) => next.or(update),
+        };
+        Request {
+            update,
+            selected: next.selected,
+            cursor: next.cursor,
+            page_delta: self.page_delta + next.page_delta,
+        }
+    }
+}
+
+fn section(mei: &mut ir::Mei) -> Option<&mut ir::Section> {
+    mei.music.as_mut()?.body.as_mut()?.mdivs.first_mut()?.score.as_mut()?.sections.first_mut()
+}
+
+// Puts the measures into the score in place of the ones they were written from
+fn splice(mei: &mut ir::Mei, count: usize, measures: Measures) {
+    let section = match section(mei) {
+        Some(section) => section,
+        None => return,
+    };
+    // A scoreDef goes with the measure after it
+    let mut current: Vec<(Option<ir::ScoreDef>, ir::Measure)> = vec![];
+    let mut score_def = None;
+    for child in section.children.drain(..) {
+        match child {
+            ir::SectionLike::ScoreDef(def) => score_def = Some(def),
+            ir::SectionLike::Measure(measure) => current.push((score_def.take(), measure)),
+        }
+    }
+    current.truncate(count);
+    for (i, score_def, measure) in measures {
+        if i < current.len() {
+            current[i] = (score_def, measure);
+        } else {
+            current.push((score_def, measure));
+        }
+    }
+    for (score_def, measure) in current {
+        if let Some(score_def) = score_def {
+            section.children.push(ir::SectionLike::ScoreDef(score_def));
+        }
+        section.children.push(ir::SectionLike::Measure(measure));
+    }
+}
+
+// The `set` actions which turn one measure into the other. Verovio's editor can
+// only change the attributes of elements it already has, and only moving notes
+// up or down keeps the rest of the layout, so anything else gives None.
+fn pitch_edits(old: &ir::Measure, new: &ir::Measure, edits: &mut Vec<serde_json::Value>) -> Option<()> {
+    if old.n != new.n || old.dynams != new.dynams || old.staves.len() != new.staves.len() {
+        return None;
+    }
+    for (old, new) in old.staves.iter().zip(&new.staves) {
+        if old.n != new.n || old.layers.len() != new.layers.len() {
+            return None;
+        }
+        for (old, new) in old.layers.iter().zip(&new.layers) {
+            if old.n != new.n {
+                return None;
+            }
+            event_edits(&old.events, &new.events, edits)?;
+        }
+    }
+    Some(())
+}
+
+fn event_edits(old: &[ir::EventLike], new: &[ir::EventLike], edits: &mut Vec<serde_json::Value>) -> Option<()> {
+    if old.len() != new.len() {
+        return None;
+    }
+    for (old, new) in old.iter().zip(new) {
+        match (old, new) {
+            (ir::EventLike::Note(old), ir::EventLike::Note(new)) => note_edits(old, new, edits)?,
+            (ir::EventLike::Chord(old), ir::EventLike::Chord(new)) => {
+                if (&old.xml_id, old.dur, old.dots, &old.tie) != (&new.xml_id, new.dur, new.dots, &new.tie) || old.notes.len() != new.notes.len() {
+                    return None;
+                }
+                for (old, new) in old.notes.iter().zip(&new.notes) {
+                    note_edits(old, new, edits)?;
+                }
+            }
+            (ir::EventLike::Beam(old), ir::EventLike::Beam(new)) => event_edits(&old.events, &new.events, edits)?,
+            (ir::EventLike::Tuplet(old), ir::EventLike::Tuplet(new)) => {
+                if (old.num, old.numbase) != (new.num, new.numbase) {
+                    return None;
+                }
+                event_edits(&old.events, &new.events, edits)?;
+            }
+            (old, new) if old == new => {}
+            _ => return None,
+        }
+    }
+    Some(())
+}
+
+fn note_edits(old: &ir::Note, new: &ir::Note, edits: &mut Vec<serde_json::Value>) -> Option<()> {
+    let id = new.xml_id.as_ref()?;
+    let same = old.xml_id == new.xml_id && old.pclass == new.pclass && old.accid == new.accid && old.accid_ges == new.accid_ges
+        && old.dur == new.dur && old.dots == new.dots && old.tie == new.tie && old.accids == new.accids;
+    if !same {
+        return None;
+    }
+    let mut set = |attr: &str, value: String| edits.push(serde_json::json!({
+        "action": "set",
+        "param": { "elementId": id, "attrType": attr, "attrValue": value },
+    }));
+    if old.pname != new.pname {
+        set("pname", new.pname.clone()?);
+    }
+    if old.oct != new.oct {
+        set("oct", new.oct.to_string());
+    }
+    Some(())
+}
+
+struct Renderer {
+    verovio: Verovio,
+    // The score as it was last sent to Verovio, for working out what to change
+    loaded: Option<ir::Mei>,
+    // Counts from 1
+    page: u32,
+    followed: Option<u32>,
+    // The page as Verovio last drew it, so moving the selection only has to recolour it
+    svg: Option<(u32, String)>,
+    viewer: Option<Child>,
+}
+
+impl Renderer {
+    fn new(options: &VerovioOptions) -> verovio::Result<Self> {
+        let mut verovio = Verovio::new("/usr/local/share/verovio/")?;
+        if let Err(e) = verovio.apply_options(options) {
+            log::warn!("Using the default layout: {}", e);
+            verovio.set_options(&VerovioOptions::default())?;
+        }
+        Ok(Self {
+            verovio,
+            loaded: None,
+            page: 1,
+            followed: None,
+            svg: None,
+            viewer: None,
+        })
+    }
+
+    fn load(&mut self, mei: ir::Mei) -> Result<(), String> {
+        let text = mei.to_string().map_err(|e| e.to_string());
+        // Kept even if Verovio refuses it so the next change has something to go on
+        self.loaded = Some(mei);
+        self.verovio.load_data(&text?).map_err(|e| e.to_string())
+    }
+
+    fn update(&mut self, update: Update) -> Result<(), String> {
+        let (count, measures) = match update {
+            Update::All(mei) => return self.load(*mei),
+            Update::Measures { count, measures } => (count, measures),
+        };
+        let mut loaded = self.loaded.take().ok_or("Nothing has been loaded to change")?;
+        let mut edits = vec![];
+        let editable = section(&mut loaded).and_then(|section| {
+            let current: Vec<(Option<&ir::ScoreDef>, &ir::Measure)> = section.children.iter().scan(None, |score_def, child| {
+                Some(match child {
+                    ir::SectionLike::ScoreDef(def) => {
+                        *score_def = Some(def);
+                        None
+                    }
+                    ir::SectionLike::Measure(measure) => Some((score_def.take(), measure)),
+                })
+            }).flatten().collect();
+            if current.len() != count {
+                return None;
+            }
+            for (i, score_def, measure) in &measures {
+                let (old_def, old) = current.get(*i)?;
+                if *old_def != score_def.as_ref() {
+                    return None;
+                }
+                pitch_edits(old, measure, &mut edits)?;
+            }
+            Some(())
+        });
+        splice(&mut loaded, count, measures);
+        if editable.is_none() {
+            log::debug!("Loading the score again for changes Verovio can't edit in place");
+            return self.load(loaded);
+        }
+        self.loaded = Some(loaded);
+        let mut on_page = true;
+        for edit in &edits {
+            if let Err(e) = self.verovio.edit(edit) {
+                log::warn!("Loading the score again after a failed edit: {}", e);
+                let loaded = self.loaded.take().ok_or("Nothing has been loaded to change")?;
+                return self.load(loaded);
+            }
+            let id = edit["param"]["elementId"].as_str().unwrap_or_default();
+            on_page &= self.verovio.page_with_element(id).ok().flatten() == Some(self.page);
+        }
+        if !edits.is_empty() {
+            // Only the page on screen can be fixed up without laying out everything
+            let relaid = if on_page {
+                self.verovio.redo_page_pitch_pos_layout()
+            } else {
+                self.verovio.redo_layout()
+            };
+            relaid.map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn render(&mut self, mut request: Request) {
+        let relaid = request.update.is_some();
+        if let Some(update) = request.update.take() {
+            match self.update(update) {
+                Ok(()) => self.svg = None,
+                Err(e) => log::warn!("Couldn't lay out the score: {}", e),
+            }
+        }
+        self.page = (self.page as i32 + request.page_delta).max(1) as u32;
+        // Editing or moving onto another note brings its page up, otherwise the page keys are left alone
+        if let Some(id) = request.cursor.filter(|_| relaid || request.cursor != self.followed) {
+            match self.verovio.page_with_element(&format!("note_{}", id)) {
+                Ok(Some(page)) => self.page = page,
+                Ok(None) => {}
+                Err(e) => log::warn!("Couldn't find the page with note {}: {}", id, e),
+            }
+        }
+        self.followed = request.cursor;
+        self.page = self.page.min(self.verovio.page_count().unwrap_or(self.page)).max(1);
+        let svg = match self.svg.take() {
+            Some((page, svg)) if page == self.page => svg,
+            last => match self.verovio.render_to_svg(self.page) {
+                Ok(svg) => svg,
+                Err(e) => {
+                    // Whatever was on screen before stays up
+                    log::warn!("Couldn't draw page {}: {}", self.page, e);
+                    self.svg = last;
+                    return;
+                }
+            },
+        };
+        if let Err(e) = highlight(&svg, &request.selected) {
+            log::warn!("Couldn't write the page: {}", e);
+        }
+        self.svg = Some((self.page, svg));
+        self.show();
+    }
+
+    fn show(&mut self) {
+        match &self.viewer {
+            Some(viewer) => {
+                let _ = Command::new("/usr/bin/imv-msg")
+                    .arg(viewer.id().to_string())
+                    .arg("close")
+                    .status();
+                let _ = Command::new("/usr/bin/imv-msg")
+                    .arg(viewer.id().to_string())
+                    .arg("open")
+                    .arg("/tmp/test.svg")
+                    .status();
+            }
+            None => {
+                match Command::new("/usr/bin/imv")
+                    .arg("-b")
+                    .arg("ffffff")
+                    .arg("/tmp/test.svg")
+                    .spawn() {
+                    Ok(viewer) => self.viewer = Some(viewer),
+                    Err(e) => log::warn!("Couldn't start imv: {}", e),
+                }
+            }
+        }
+    }
+}
+
+// Writes the page to /tmp/test.svg with the selected notes in red
+fn highlight(svg: &str, selected: &[u32]) -> Result<(), String> {
+    let package = sxd_document::parser::parse(svg).map_err(|e| format!("{:?}", e))?;
+    let mut context = sxd_xpath::Context::new();
+    context.set_namespace("svg", "http://www.w3.org/2000/svg");
+    let factory = sxd_xpath::Factory::new();
+    let doc = package.as_document();
+    for id in selected {
+        // Notes tied across a barline are split into pieces with ids like note_1-1-0
+        let xpath = factory.build(&format!("//svg:g[@id='note_{0}' or starts-with(@id, 'note_{0}-')]//svg:use", id))
+            .map_err(|e| e.to_string())?
+            .ok_or("Empty XPath")?;
+        let value = xpath.evaluate(&context, doc.root()).map_err(|e| e.to_string())?;
+
+        if let sxd_xpath::Value::Nodeset(ns) = value {
+            for node in ns {
+                if let sxd_xpath::nodeset::Node::Element(e) = node {
+                    e.set_attribute_value("fill", "red");
+                }
+            }
+        }
+    }
+    let mut f = std::fs::File::create("/tmp/test.svg").map_err(|e| e.to_string())?;
+    sxd_document::writer::format_document(&doc, &mut f).map_err(|e| e.to_string())
+}
+
+// Draws requests until the editor hangs up, then closes the viewer
+pub fn run(options: VerovioOptions, requests: Receiver<Request>) {
+    let mut renderer = match Renderer::new(&options) {
+        Ok(renderer) => renderer,
+        Err(e) => {
+            log::warn!("Couldn't start Verovio, nothing will be drawn: {}", e);
+            return;
+        }
+    };
+    while let Ok(mut request) = requests.recv() {
+        while let Ok(next) = requests.try_recv() {
+            request = request.merge(next);
+        }
+        renderer.render(request);
+    }
+    if let Some(mut viewer) = renderer.viewer.take() {
+        let _ = viewer.kill();
+    }
+}
@@ -3,6 +3,6 @@ use verovio::*;
 
 fn main() {
     let pointer = unsafe { vrvToolkit_constructorResourcePath(CString::new("/usr/local/share/verovio/").unwrap().as_ptr()) };
-    let mut options = unsafe { vrvToolkit_getAvailableOptions(pointer) };
+    let options = unsafe { vrvToolkit_getAvailableOptions(pointer) };
     println!("{:?}", unsafe { CStr::from_ptr(options) });
 }
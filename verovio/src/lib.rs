@@ -1,52 +1,280 @@
 use std::{
-    ffi::{CString, CStr},
+    ffi::{CString, CStr, NulError},
+    os::raw::{c_char, c_int},
     path::Path,
+    str::Utf8Error,
 };
 
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+#[allow(non_upper_case_globals)]
 mod bindings;
+mod options;
 
 pub use bindings::*;
+pub use options::*;
+
+#[derive(Debug)]
+pub enum Error {
+    // Text handed to the toolkit had a NUL byte in it
+    Nul(NulError),
+    Utf8(Utf8Error),
+    Json(serde_json::Error),
+    Base64(base64::DecodeError),
+    NonUtf8Path,
+    // The toolkit returned nothing where it should have returned a string
+    NullResult(&'static str),
+    // The toolkit refused the data, along with whatever it logged about it
+    Load(String),
+    // The toolkit couldn't carry out an editor action, with what it logged
+    Edit(String),
+    NoSuchPage(u32),
+    InvalidOption(String),
+    // The toolkit itself couldn't be created
+    NoToolkit,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Nul(e) => write!(f, "Text for Verovio contained a NUL byte: {}", e),
+            Error::Utf8(e) => write!(f, "Verovio returned text that isn't UTF-8: {}", e),
+            Error::Json(e) => write!(f, "Couldn't make sense of Verovio's JSON: {}", e),
+            Error::Base64(e) => write!(f, "Couldn't decode Verovio's MIDI: {}", e),
+            Error::NonUtf8Path => write!(f, "Verovio's resource path must be UTF-8"),
+            Error::NullResult(function) => write!(f, "{} returned nothing", function),
+            Error::Load(log) => write!(f, "Verovio couldn't load the data: {}", log),
+            Error::Edit(log) => write!(f, "Verovio couldn't make the edit: {}", log),
+            Error::NoSuchPage(page) => write!(f, "There is no page {}", page),
+            Error::InvalidOption(e) => write!(f, "{}", e),
+            Error::NoToolkit => write!(f, "Verovio's toolkit couldn't be created"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<NulError> for Error {
+    fn from(e: NulError) -> Self {
+        Error::Nul(e)
+    }
+}
+
+impl From<Utf8Error> for Error {
+    fn from(e: Utf8Error) -> Self {
+        Error::Utf8(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+impl From<base64::DecodeError> for Error {
+    fn from(e: base64::DecodeError) -> Self {
+        Error::Base64(e)
+    }
+}
+
+// What getElementsAtTime reports as sounding at a moment
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub struct ElementsAtTime {
+    #[serde(default)]
+    pub notes: Vec<String>,
+    #[serde(default)]
+    pub page: u32,
+}
 
 pub struct Verovio {
     toolkit: Option<*mut Toolkit>,
 }
 
+// Uses the resources Verovio was built with. Prefer Verovio::new, which says
+// when the toolkit can't be created; here that only shows up as NoToolkit
+// from every call after.
 impl Default for Verovio {
     fn default() -> Self {
         let toolkit = unsafe { vrvToolkit_constructor() as *mut Toolkit };
         Self {
-            toolkit: Some(toolkit)
+            toolkit: if toolkit.is_null() { None } else { Some(toolkit) },
         }
     }
 }
 
 impl Verovio {
-    pub fn new(data_path: impl AsRef<Path>) -> Self {
+    pub fn new(data_path: impl AsRef<Path>) -> Result<Self> {
         let path: &Path = data_path.as_ref();
-        let path = CString::new(path.to_str().unwrap()).unwrap();
+        let path = CString::new(path.to_str().ok_or(Error::NonUtf8Path)?)?;
         let toolkit = unsafe { vrvToolkit_constructorResourcePath(path.as_ptr()) };
-        Self {
+        if toolkit.is_null() {
+            return Err(Error::NullResult("vrvToolkit_constructorResourcePath"));
+        }
+        Ok(Self {
             toolkit: Some(toolkit)
+        })
+    }
+
+    fn toolkit(&self) -> Result<*mut Toolkit> {
+        self.toolkit.ok_or(Error::NoToolkit)
+    }
+
+    // The toolkit owns the strings it returns and reuses the buffer on the next call,
+    // so they're copied out straight away.
+    fn string(function: &'static str, result: *const c_char) -> Result<String> {
+        if result.is_null() {
+            return Err(Error::NullResult(function));
         }
+        Ok(unsafe { CStr::from_ptr(result) }.to_str()?.to_string())
     }
 
-    pub fn render_data(&mut self, data: &str) -> String {
-        let data = CString::new(data).unwrap();
-        let options = CString::new(r#"{"footer": "none"}"#).unwrap();
-        let result = unsafe { vrvToolkit_renderData(self.toolkit.unwrap(), data.as_ptr(), options.as_ptr()) };
-        unsafe { CStr::from_ptr(result) }.to_str().unwrap().to_string()
+    pub fn version(&self) -> Result<String> {
+        Self::string("vrvToolkit_getVersion", unsafe { vrvToolkit_getVersion(self.toolkit()?) })
+    }
+
+    // Everything logged since the last call that cleared it
+    pub fn log(&self) -> Result<String> {
+        Self::string("vrvToolkit_getLog", unsafe { vrvToolkit_getLog(self.toolkit()?) })
+    }
+
+    // Options not mentioned keep their current values
+    pub fn set_options(&mut self, options: &impl Serialize) -> Result<()> {
+        let options = CString::new(serde_json::to_string(options)?)?;
+        unsafe { vrvToolkit_setOptions(self.toolkit()?, options.as_ptr()) };
+        Ok(())
+    }
+
+    pub fn available_options(&self) -> Result<serde_json::Value> {
+        let available = Self::string("vrvToolkit_getAvailableOptions", unsafe { vrvToolkit_getAvailableOptions(self.toolkit()?) })?;
+        Ok(serde_json::from_str(&available)?)
+    }
+
+    // Used for everything rendered from here on
+    pub fn apply_options(&mut self, options: &VerovioOptions) -> Result<()> {
+        options.validate(&self.available_options()?)?;
+        self.set_options(options)
+    }
+
+    // Any struct deriving Deserialize works here, or serde_json::Value for all of them
+    pub fn options<T: DeserializeOwned>(&self, default_values: bool) -> Result<T> {
+        let options = Self::string("vrvToolkit_getOptions", unsafe { vrvToolkit_getOptions(self.toolkit()?, default_values) })?;
+        Ok(serde_json::from_str(&options)?)
+    }
+
+    pub fn load_data(&mut self, data: &str) -> Result<()> {
+        let data = CString::new(data)?;
+        if unsafe { vrvToolkit_loadData(self.toolkit()?, data.as_ptr()) } {
+            Ok(())
+        } else {
+            Err(Error::Load(self.log()?))
+        }
     }
 
-    pub fn render_to_midi(&mut self, data: &str) -> Vec<u8> {
-        // TODO: The actual C++ toolkit has ways to do this without jumping though base64. Maybe we could use that directly?
-        let data = CString::new(data).unwrap();
-        let options = CString::new(r#"{}"#).unwrap();
-        let result = unsafe {
-                vrvToolkit_loadData(self.toolkit.unwrap(), data.as_ptr());
-                vrvToolkit_renderToMIDI(self.toolkit.unwrap(), options.as_ptr())
-        };
-        let encoded = unsafe { CStr::from_ptr(result) }.to_str().unwrap().to_string();
-        base64::decode(&encoded).unwrap()
+    pub fn page_count(&self) -> Result<u32> {
+        Ok(unsafe { vrvToolkit_getPageCount(self.toolkit()?) }.max(0) as u32)
+    }
+
+    // None when the element isn't in the loaded data
+    pub fn page_with_element(&self, id: &str) -> Result<Option<u32>> {
+        let id = CString::new(id)?;
+        let page = unsafe { vrvToolkit_getPageWithElement(self.toolkit()?, id.as_ptr()) };
+        Ok(if page > 0 { Some(page as u32) } else { None })
+    }
+
+    // Pages count from 1, as they do in Verovio
+    pub fn render_to_svg(&mut self, page: u32) -> Result<String> {
+        if page == 0 || page > self.page_count()? {
+            return Err(Error::NoSuchPage(page));
+        }
+        let options = CString::new("{}")?;
+        Self::string("vrvToolkit_renderToSVG", unsafe { vrvToolkit_renderToSVG(self.toolkit()?, page as c_int, options.as_ptr()) })
+    }
+
+    pub fn render_data(&mut self, data: &str) -> Result<String> {
+        let data = CString::new(data)?;
+        // Whatever was last set with apply_options stays in effect
+        let options = CString::new("{}")?;
+        Self::string("vrvToolkit_renderData", unsafe { vrvToolkit_renderData(self.toolkit()?, data.as_ptr(), options.as_ptr()) })
+    }
+
+    pub fn render_to_midi(&mut self, data: &str) -> Result<Vec<u8>> {
+        self.load_data(data)?;
+        let options = CString::new(r#"{}"#)?;
+        let encoded = Self::string("vrvToolkit_renderToMIDI", unsafe { vrvToolkit_renderToMIDI(self.toolkit()?, options.as_ptr()) })?;
+        Ok(base64::decode(&encoded)?)
+    }
+
+    // Timings of every note on and off in the loaded data, as Verovio's JSON
+    pub fn render_to_timemap(&mut self) -> Result<serde_json::Value> {
+        let timemap = Self::string("vrvToolkit_renderToTimemap", unsafe { vrvToolkit_renderToTimemap(self.toolkit()?) })?;
+        Ok(serde_json::from_str(&timemap)?)
+    }
+
+    pub fn element_attr(&self, id: &str) -> Result<serde_json::Value> {
+        let id = CString::new(id)?;
+        let attr = Self::string("vrvToolkit_getElementAttr", unsafe { vrvToolkit_getElementAttr(self.toolkit()?, id.as_ptr()) })?;
+        Ok(serde_json::from_str(&attr)?)
+    }
+
+    // In milliseconds from the start
+    pub fn time_for_element(&self, id: &str) -> Result<f64> {
+        let id = CString::new(id)?;
+        Ok(unsafe { vrvToolkit_getTimeForElement(self.toolkit()?, id.as_ptr()) })
+    }
+
+    pub fn elements_at_time(&self, millisec: i32) -> Result<ElementsAtTime> {
+        let elements = Self::string("vrvToolkit_getElementsAtTime", unsafe { vrvToolkit_getElementsAtTime(self.toolkit()?, millisec) })?;
+        Ok(serde_json::from_str(&elements)?)
+    }
+
+    // Lays the loaded data out again, after the options have changed for example
+    pub fn redo_layout(&mut self) -> Result<()> {
+        unsafe { vrvToolkit_redoLayout(self.toolkit()?) };
+        Ok(())
+    }
+
+    // Lays out the notes of the current page again where they only moved up or
+    // down, which is much quicker than redo_layout
+    pub fn redo_page_pitch_pos_layout(&mut self) -> Result<()> {
+        unsafe { vrvToolkit_redoPagePitchPosLayout(self.toolkit()?) };
+        Ok(())
+    }
+
+    // Changes the loaded data in place with one of Verovio's editor actions,
+    // like {"action": "set", "param": {"elementId": ..., "attrType": ..., "attrValue": ...}}.
+    // The layout isn't redone until asked for.
+    pub fn edit(&mut self, action: &impl Serialize) -> Result<()> {
+        let action = CString::new(serde_json::to_string(action)?)?;
+        if unsafe { vrvToolkit_edit(self.toolkit()?, action.as_ptr()) } {
+            Ok(())
+        } else {
+            Err(Error::Edit(self.log()?))
+        }
+    }
+
+    // Loads a Humdrum file and gives it back as MEI, which the editor knows how to read
+    pub fn load_humdrum(&mut self, data: &str) -> Result<String> {
+        self.set_options(&serde_json::json!({"inputFrom": "humdrum"}))?;
+        let loaded = self.load_data(data);
+        // Back to guessing so later MEI loads aren't read as Humdrum
+        self.set_options(&serde_json::json!({"inputFrom": "auto"}))?;
+        loaded?;
+        let options = CString::new(r#"{"scoreBased": true}"#)?;
+        Self::string("vrvToolkit_getMEI", unsafe { vrvToolkit_getMEI(self.toolkit()?, options.as_ptr()) })
+    }
+
+    // Loads the MEI and gives back Verovio's Humdrum for it, replacing
+    // whatever was loaded before
+    pub fn to_humdrum(&mut self, mei: &str) -> Result<String> {
+        self.load_data(mei)?;
+        let humdrum = Self::string("vrvToolkit_getHumdrum", unsafe { vrvToolkit_getHumdrum(self.toolkit()?) })?;
+        if humdrum.trim().is_empty() {
+            return Err(Error::NullResult("vrvToolkit_getHumdrum"));
+        }
+        Ok(humdrum)
     }
 }
 
@@ -59,3 +287,60 @@ impl Drop for Verovio {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Everything here is checked before the toolkit is needed, so none of
+    // Verovio's resources have to be installed
+    fn without_toolkit() -> Verovio {
+        Verovio { toolkit: None }
+    }
+
+    #[test]
+    fn nul_bytes_are_errors() {
+        let verovio = without_toolkit();
+        assert!(matches!(verovio.element_attr("note\0_1"), Err(Error::Nul(_))));
+        assert!(matches!(verovio.page_with_element("note\0_1"), Err(Error::Nul(_))));
+        assert!(matches!(Verovio::new("/usr/local/share\0/verovio"), Err(Error::Nul(_))));
+    }
+
+    #[test]
+    fn pages_count_from_one() {
+        assert!(matches!(without_toolkit().render_to_svg(0), Err(Error::NoSuchPage(0))));
+    }
+
+    #[test]
+    fn calls_without_a_toolkit_are_errors() {
+        let mut verovio = without_toolkit();
+        assert!(matches!(verovio.version(), Err(Error::NoToolkit)));
+        assert!(matches!(verovio.render_to_svg(1), Err(Error::NoToolkit)));
+        assert!(matches!(verovio.load_data("<mei/>"), Err(Error::NoToolkit)));
+        assert!(matches!(Verovio::string("vrvToolkit_getLog", std::ptr::null()), Err(Error::NullResult("vrvToolkit_getLog"))));
+    }
+
+    #[test]
+    fn errors_say_what_went_wrong() {
+        let nul = CString::new("a\0b").unwrap_err();
+        let utf8 = String::from_utf8(vec![0xff]).unwrap_err().utf8_error();
+        let json = serde_json::from_str::<serde_json::Value>("{").unwrap_err();
+        let base64 = base64::decode("!").unwrap_err();
+        let messages = [
+            (Error::Nul(nul.clone()), format!("Text for Verovio contained a NUL byte: {}", nul)),
+            (Error::Utf8(utf8), format!("Verovio returned text that isn't UTF-8: {}", utf8)),
+            (Error::Json(json), "Couldn't make sense of Verovio's JSON: EOF while parsing an object at line 1 column 1".to_string()),
+            (Error::Base64(base64.clone()), format!("Couldn't decode Verovio's MIDI: {}", base64)),
+            (Error::NonUtf8Path, "Verovio's resource path must be UTF-8".to_string()),
+            (Error::NullResult("vrvToolkit_getMEI"), "vrvToolkit_getMEI returned nothing".to_string()),
+            (Error::Load("[Error] Unknown format".to_string()), "Verovio couldn't load the data: [Error] Unknown format".to_string()),
+            (Error::Edit("No element".to_string()), "Verovio couldn't make the edit: No element".to_string()),
+            (Error::NoSuchPage(3), "There is no page 3".to_string()),
+            (Error::InvalidOption("scale can't be 0".to_string()), "scale can't be 0".to_string()),
+            (Error::NoToolkit, "Verovio's toolkit couldn't be created".to_string()),
+        ];
+        for (error, message) in &messages {
+            assert_eq!(&error.to_string(), message);
+        }
+    }
+}
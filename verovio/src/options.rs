@@ -0,0 +1,190 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{Error, Result};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Breaks {
+    Auto,
+    None,
+    Line,
+    Smart,
+    Encoded,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Header {
+    Auto,
+    None,
+    Encoded,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Footer {
+    Auto,
+    None,
+    Encoded,
+    Always,
+}
+
+// The layout options the editor lets you change, named as Verovio names them.
+// Anything missing from a settings file keeps its default.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct VerovioOptions {
+    pub page_width: u32,
+    pub page_height: u32,
+    // As a percentage
+    pub scale: u32,
+    pub adjust_page_height: bool,
+    pub breaks: Breaks,
+    pub spacing_staff: u32,
+    pub font: String,
+    pub header: Header,
+    pub footer: Footer,
+}
+
+impl Default for VerovioOptions {
+    fn default() -> Self {
+        Self {
+            page_width: 2100,
+            page_height: 2970,
+            scale: 100,
+            adjust_page_height: false,
+            breaks: Breaks::Auto,
+            spacing_staff: 12,
+            font: "Leipzig".to_string(),
+            // The header shows the title and composer from the meiHead
+            header: Header::Auto,
+            footer: Footer::None,
+        }
+    }
+}
+
+impl VerovioOptions {
+    // Checks every option against what vrvToolkit_getAvailableOptions says this build of
+    // Verovio takes, so a bad value in the settings is reported rather than quietly ignored.
+    pub fn validate(&self, available: &Value) -> Result<()> {
+        let options = serde_json::to_value(self)?;
+        let options = options.as_object().expect("VerovioOptions is a struct, so it always serializes to an object");
+        for (name, value) in options {
+            let spec = find_option(available, name).ok_or_else(|| Error::InvalidOption(format!("This Verovio has no {} option", name)))?;
+            if let Some(n) = value.as_f64() {
+                let min = spec.get("min").and_then(Value::as_f64);
+                let max = spec.get("max").and_then(Value::as_f64);
+                if matches!(min, Some(min) if n < min) || matches!(max, Some(max) if n > max) {
+                    return Err(Error::InvalidOption(format!(
+                        "{} is {} but must be between {} and {}",
+                        name,
+                        n,
+                        min.unwrap_or(f64::MIN),
+                        max.unwrap_or(f64::MAX),
+                    )));
+                }
+            }
+            if let (Some(s), Some(values)) = (value.as_str(), spec.get("values").and_then(Value::as_array)) {
+                if !values.iter().any(|v| v.as_str() == Some(s)) {
+                    return Err(Error::InvalidOption(format!("{} can't be {}", name, s)));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Options are listed by group, each group with an "options" object keyed by name
+fn find_option<'a>(available: &'a Value, name: &str) -> Option<&'a Value> {
+    let object = available.as_object()?;
+    if let Some(spec) = object.get("options").and_then(|options| options.get(name)) {
+        return Some(spec);
+    }
+    object.values().find_map(|v| find_option(v, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // Cut down from what vrvToolkit_getAvailableOptions gives, with a footer
+    // that can't be "always" as in older builds
+    fn available() -> Value {
+        json!({
+            "groups": {
+                "0-base": {
+                    "name": "Base short options",
+                    "options": {
+                        "scale": {"default": 100, "max": 1000, "min": 1, "type": "int"},
+                    },
+                },
+                "1-general": {
+                    "name": "Input and page layout options",
+                    "options": {
+                        "adjustPageHeight": {"default": false, "type": "bool"},
+                        "breaks": {"default": "auto", "type": "std::string-list", "values": ["none", "auto", "line", "smart", "encoded"]},
+                        "footer": {"default": "auto", "type": "std::string-list", "values": ["none", "auto", "encoded"]},
+                        "header": {"default": "auto", "type": "std::string-list", "values": ["none", "auto", "encoded"]},
+                        "pageHeight": {"default": 2970, "max": 60000, "min": 100, "type": "int"},
+                        "pageWidth": {"default": 2100, "max": 100000, "min": 100, "type": "int"},
+                    },
+                },
+                "2-generalLayout": {
+                    "name": "General layout",
+                    "options": {
+                        "font": {"default": "Leipzig", "type": "std::string"},
+                        "spacingStaff": {"default": 12, "max": 48, "min": 0, "type": "int"},
+                    },
+                },
+            },
+        })
+    }
+
+    fn message(result: Result<()>) -> String {
+        match result {
+            Err(Error::InvalidOption(message)) => message,
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn options_are_found_in_any_group() {
+        let available = available();
+        assert_eq!(find_option(&available, "scale").and_then(|o| o.get("max")), Some(&json!(1000)));
+        assert_eq!(find_option(&available, "font").and_then(|o| o.get("type")), Some(&json!("std::string")));
+        assert_eq!(find_option(&available, "lyricSize"), None);
+        assert_eq!(find_option(&json!("not an object"), "scale"), None);
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert!(VerovioOptions::default().validate(&available()).is_ok());
+    }
+
+    #[test]
+    fn numbers_out_of_range_are_invalid() {
+        let options = VerovioOptions {
+            scale: 2000,
+            ..Default::default()
+        };
+        assert_eq!(message(options.validate(&available())), "scale is 2000 but must be between 1 and 1000");
+    }
+
+    #[test]
+    fn values_not_listed_are_invalid() {
+        let options = VerovioOptions {
+            footer: Footer::Always,
+            ..Default::default()
+        };
+        assert_eq!(message(options.validate(&available())), "footer can't be always");
+    }
+
+    #[test]
+    fn unknown_options_are_invalid() {
+        let mut available = available();
+        available["groups"]["2-generalLayout"]["options"].as_object_mut().unwrap().remove("spacingStaff");
+        assert_eq!(message(VerovioOptions::default().validate(&available)), "This Verovio has no spacingStaff option");
+    }
+}
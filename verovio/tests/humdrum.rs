@@ -0,0 +1,32 @@
+use verovio::Verovio;
+
+const KERN: &str = "**kern
+*clefG2
+*k[f#]
+*M3/4
+=1
+4g
+8f#L
+8fnJ
+4f
+=2
+4e
+4r
+4d
+*-
+";
+
+// Needs Verovio's resources installed, so it only runs with --ignored
+#[test]
+#[ignore]
+fn humdrum_round_trip() {
+    let mut verovio = Verovio::new("/usr/local/share/verovio/").unwrap();
+    let mei = verovio.load_humdrum(KERN).unwrap();
+    assert!(mei.contains("<mei"), "{}", mei);
+    assert!(mei.contains(r#"pname="f" accid.ges="s""#), "{}", mei);
+    assert!(mei.contains("<beam"), "{}", mei);
+
+    let humdrum = verovio.to_humdrum(&mei).unwrap();
+    assert!(humdrum.starts_with("**kern"), "{}", humdrum);
+    assert!(humdrum.contains("4g"), "{}", humdrum);
+}